@@ -0,0 +1,293 @@
+//! The loader's settings, from `\EFI\THATTE\THATTE.TOML` on the ESP.
+//!
+//! The file is `key = value` lines, which is also the part of TOML the
+//! loader understands: `#` comments, strings in double quotes (with `\\`
+//! and `\"` escapes) or single quotes (taken as they are), and bare words
+//! and numbers. Tables and arrays are not supported.
+//!
+//! ```toml
+//! kernel = '\EFI\THATTE\KERNEL.ELF'   # if no slot boots; or "/EFI/..."
+//! cmdline = "log=debug"
+//! resolution = "1280x800"
+//! timeout = 5                        # seconds to show an error before reset
+//! default_slot = "a"
+//! log = "info"                       # quiet, info or debug
+//! ```
+//!
+//! Every key is optional. A missing file means all defaults; a line that
+//! does not parse, an unknown key or a bad value is reported and skipped,
+//! leaving that setting at its default.
+
+use core::fmt;
+use core::str;
+
+use thatte_bootinfo::slot::Slot;
+
+/// Largest file read; anything bigger is ignored as a whole.
+pub const MAX_SIZE: usize = 16 * 1024;
+
+/// Longest kernel path, in UTF-8 bytes.
+pub const PATH_MAX: usize = 128;
+
+/// Longest kernel command line passed on, in UTF-8 bytes.
+pub const CMDLINE_MAX: usize = 1024;
+
+/// Longest error display allowed.
+const MAX_TIMEOUT: u32 = 3600;
+
+/// How much the loader prints. Errors are always printed.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub enum LogLevel {
+    Quiet,
+    Info,
+    Debug,
+}
+
+/// A string of at most `N` UTF-8 bytes, stored inline.
+#[derive(Clone)]
+pub struct Text<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Text<N> {
+    pub const fn new() -> Self { Self { buf: [0; N], len: 0 } }
+
+    pub fn from_str(s: &str) -> Option<Self> {
+        let mut t = Self::new();
+        t.push_str(s).then_some(t)
+    }
+
+    /// Append `s`, or as much of it as fits; false if it did not all fit.
+    pub fn push_str(&mut self, s: &str) -> bool { s.chars().all(|c| self.push(c)) }
+
+    /// Append `c`, unless it does not fit.
+    pub fn push(&mut self, c: char) -> bool {
+        if self.len + c.len_utf8() > N {
+            return false;
+        }
+        self.len += c.encode_utf8(&mut self.buf[self.len..]).len();
+        true
+    }
+
+    pub fn as_str(&self) -> &str { str::from_utf8(&self.buf[..self.len]).unwrap_or("") }
+}
+
+impl<const N: usize> fmt::Debug for Text<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { fmt::Debug::fmt(self.as_str(), f) }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Absolute path on the ESP, with `\` separators, of the kernel booted
+    /// when neither slot can be.
+    pub kernel: Text<PATH_MAX>,
+    /// Used unless the loader was started with load options.
+    pub cmdline: Text<CMDLINE_MAX>,
+    /// GOP mode to switch to, if the display has it.
+    pub resolution: Option<(usize, usize)>,
+    /// Seconds an error stays on screen before the machine resets.
+    pub timeout: u32,
+    /// Slot booted when both have the same priority.
+    pub default_slot: Slot,
+    pub log: LogLevel,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            kernel: Text::from_str("\\EFI\\THATTE\\KERNEL.ELF").unwrap(),
+            cmdline: Text::new(),
+            resolution: None,
+            timeout: 5,
+            default_slot: Slot::A,
+            log: LogLevel::Info,
+        }
+    }
+}
+
+/// What is wrong with a line.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ConfigError {
+    /// Not `key = value`, an unterminated string, or not UTF-8.
+    Syntax,
+    UnknownKey,
+    /// The value does not fit the key.
+    BadValue,
+    /// A string longer than the setting allows.
+    TooLong,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Syntax => "not `key = value`",
+            Self::UnknownKey => "unknown key",
+            Self::BadValue => "bad value",
+            Self::TooLong => "value too long",
+        })
+    }
+}
+
+impl Config {
+    /// Read settings from `text`, calling `report` with the (1-based) line
+    /// number of each line that is skipped.
+    pub fn parse(text: &[u8], mut report: impl FnMut(usize, ConfigError)) -> Self {
+        let mut config = Self::default();
+        for (i, line) in text.split(|&b| b == b'\n').enumerate() {
+            if let Err(e) = str::from_utf8(line).map_err(|_| ConfigError::Syntax).and_then(|l| config.apply(l)) {
+                report(i + 1, e);
+            }
+        }
+        config
+    }
+
+    fn apply(&mut self, line: &str) -> Result<(), ConfigError> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        let (key, raw) = line.split_once('=').ok_or(ConfigError::Syntax)?;
+        let value = unquote(raw.trim())?;
+        let value = value.as_str();
+        match key.trim() {
+            "kernel" => self.kernel = path(value)?,
+            "cmdline" => self.cmdline = Text::from_str(value).ok_or(ConfigError::TooLong)?,
+            "resolution" => {
+                let (w, h) = value.split_once(['x', 'X']).ok_or(ConfigError::BadValue)?;
+                let (w, h) = (number(w)?, number(h)?);
+                if w == 0 || h == 0 {
+                    return Err(ConfigError::BadValue);
+                }
+                self.resolution = Some((w as usize, h as usize));
+            }
+            "timeout" => self.timeout = number(value).ok().filter(|&t| t <= MAX_TIMEOUT).ok_or(ConfigError::BadValue)?,
+            "default_slot" => {
+                self.default_slot = match value {
+                    "a" | "A" => Slot::A,
+                    "b" | "B" => Slot::B,
+                    _ => return Err(ConfigError::BadValue),
+                }
+            }
+            "log" => {
+                self.log = match value {
+                    "quiet" => LogLevel::Quiet,
+                    "info" => LogLevel::Info,
+                    "debug" => LogLevel::Debug,
+                    _ => return Err(ConfigError::BadValue),
+                }
+            }
+            _ => return Err(ConfigError::UnknownKey),
+        }
+        Ok(())
+    }
+}
+
+/// The value of `raw` (everything after `=`, trimmed): a quoted string
+/// followed by at most a comment, or a bare word up to any comment.
+fn unquote(raw: &str) -> Result<Text<CMDLINE_MAX>, ConfigError> {
+    let quote = match raw.chars().next() {
+        Some(q @ ('"' | '\'')) => q,
+        _ => {
+            let word = raw.split_once('#').map_or(raw, |(w, _)| w).trim_end();
+            return Text::from_str(word).ok_or(ConfigError::TooLong);
+        }
+    };
+    let mut out = Text::new();
+    let mut chars = raw[1..].char_indices();
+    let rest = loop {
+        let c = match chars.next().ok_or(ConfigError::Syntax)? {
+            (at, c) if c == quote => break &raw[1 + at + 1..],
+            (_, '\\') if quote == '"' => match chars.next() {
+                Some((_, c @ ('\\' | '"'))) => c,
+                _ => return Err(ConfigError::Syntax),
+            },
+            (_, c) => c,
+        };
+        if !out.push(c) {
+            return Err(ConfigError::TooLong);
+        }
+    };
+    let rest = rest.trim_start();
+    if rest.is_empty() || rest.starts_with('#') { Ok(out) } else { Err(ConfigError::Syntax) }
+}
+
+fn number(s: &str) -> Result<u32, ConfigError> { s.trim().parse().map_err(|_| ConfigError::BadValue) }
+
+/// An absolute ESP path, with `/` accepted for `\`.
+fn path(value: &str) -> Result<Text<PATH_MAX>, ConfigError> {
+    if !value.starts_with(['\\', '/']) || value.len() < 2 {
+        return Err(ConfigError::BadValue);
+    }
+    let mut t = Text::new();
+    for c in value.chars() {
+        if !t.push(if c == '/' { '\\' } else { c }) {
+            return Err(ConfigError::TooLong);
+        }
+    }
+    Ok(t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn parse(text: &str) -> (Config, Vec<(usize, ConfigError)>) {
+        let mut errors = Vec::new();
+        let c = Config::parse(text.as_bytes(), |line, e| errors.push((line, e)));
+        (c, errors)
+    }
+
+    #[test]
+    fn reads_toml_and_key_value() {
+        let (c, errors) = parse(
+            "# THATTE loader\n\
+             kernel = '\\EFI\\THATTE\\B\\KERNEL.ELF'\n\
+             cmdline = \"init=/sbin/init quote=\\\" # not a comment\"  # comment\n\
+             resolution = \"1280x800\"\r\n\
+             timeout = 0\n\
+             \n\
+             default_slot = \"b\"\n\
+             log = debug # bare word\n",
+        );
+        assert_eq!(errors, []);
+        assert_eq!(c.kernel.as_str(), "\\EFI\\THATTE\\B\\KERNEL.ELF");
+        assert_eq!(c.cmdline.as_str(), "init=/sbin/init quote=\" # not a comment");
+        assert_eq!((c.resolution, c.timeout, c.default_slot, c.log), (Some((1280, 800)), 0, Slot::B, LogLevel::Debug));
+
+        let (c, errors) = parse("kernel=/EFI/THATTE/K.ELF\ncmdline=a b\n");
+        assert_eq!(errors, []);
+        assert_eq!((c.kernel.as_str(), c.cmdline.as_str()), ("\\EFI\\THATTE\\K.ELF", "a b"));
+    }
+
+    #[test]
+    fn bad_lines_keep_defaults() {
+        let (c, errors) = parse(
+            "[loader]\n\
+             timeout = 99999\n\
+             resolution = 0x600\n\
+             kernel = KERNEL.ELF\n\
+             cmdline = \"unterminated\n\
+             log = loud\n\
+             colour = blue\n\
+             default_slot = \"a\" trailing\n",
+        );
+        let d = Config::default();
+        assert_eq!((c.kernel.as_str(), c.cmdline.as_str()), (d.kernel.as_str(), ""));
+        assert_eq!((c.resolution, c.timeout, c.default_slot, c.log), (None, 5, Slot::A, LogLevel::Info));
+        use ConfigError::*;
+        assert_eq!(errors, [(1, Syntax), (2, BadValue), (3, BadValue), (4, BadValue), (5, Syntax), (6, BadValue), (7, UnknownKey), (8, Syntax)]);
+
+        let long = std::format!("cmdline = {}\nkernel = /{}\n", "x".repeat(CMDLINE_MAX + 1), "k".repeat(PATH_MAX));
+        assert_eq!(parse(&long).1, [(1, TooLong), (2, TooLong)]);
+        assert_eq!(Config::parse(b"log = \xff\xfe\nlog = quiet", |_, _| ()).log, LogLevel::Quiet);
+    }
+
+    #[test]
+    fn shipped_config_is_clean() {
+        let (c, errors) = parse(include_str!("../../../configs/thatte.toml"));
+        assert_eq!(errors, []);
+        assert_eq!(c.kernel.as_str(), Config::default().kernel.as_str());
+    }
+}
@@ -0,0 +1,245 @@
+//! Just enough ELF64 to load a statically linked x86_64 kernel.
+//!
+//! The kernel runs on the firmware's identity mapping until it builds its
+//! own page tables, so every `PT_LOAD` segment must have equal virtual and
+//! physical addresses. Segments are checked against the file and against
+//! each other before anything is copied.
+
+use core::ops::Range;
+
+const MAGIC: [u8; 4] = *b"\x7fELF";
+const CLASS_64: u8 = 2;
+const LITTLE_ENDIAN: u8 = 1;
+const CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+pub const PT_LOAD: u32 = 1;
+pub const PF_X: u32 = 1;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+const HEADER_LEN: usize = 64;
+const PHDR_LEN: usize = 56;
+
+/// Why an image cannot be loaded.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ElfError {
+    /// The file ends inside the header or the program headers.
+    Truncated,
+    /// Not an ELF file.
+    BadMagic,
+    /// Not a little-endian ELF64 file of the current version.
+    BadClass,
+    /// Not an executable (`ET_EXEC`) for x86_64.
+    NotExecutable,
+    /// `e_phentsize` is not the ELF64 program header size.
+    BadHeaderSize,
+    /// Segment `index` reaches past the end of the file, or has more file
+    /// bytes than memory bytes.
+    BadSegment { index: usize },
+    /// Segment `index` is not identity-mapped (`p_vaddr != p_paddr`).
+    NotIdentity { index: usize },
+    /// Segment `index` wraps the address space, or ends in its last page.
+    AddressOverflow { index: usize },
+    /// Two segments share memory.
+    Overlap,
+    /// There are no `PT_LOAD` segments.
+    NoSegments,
+    /// The entry point is not in an executable segment.
+    BadEntry(u64),
+}
+
+/// One `PT_LOAD` segment.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Segment {
+    /// Load address (virtual and physical).
+    pub addr: u64,
+    /// Bytes in memory; those past `file.len()` are zero.
+    pub mem_size: u64,
+    /// Where the initialized bytes are in the file.
+    pub file: Range<usize>,
+    pub flags: u32,
+}
+
+impl Segment {
+    pub fn end(&self) -> u64 { self.addr + self.mem_size }
+
+    pub fn executable(&self) -> bool { self.flags & PF_X != 0 }
+}
+
+fn u16_at(b: &[u8], at: usize) -> u16 { u16::from_le_bytes([b[at], b[at + 1]]) }
+fn u32_at(b: &[u8], at: usize) -> u32 { u32::from_le_bytes(b[at..at + 4].try_into().unwrap()) }
+fn u64_at(b: &[u8], at: usize) -> u64 { u64::from_le_bytes(b[at..at + 8].try_into().unwrap()) }
+
+/// A validated kernel image.
+#[derive(Copy, Clone, Debug)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    phoff: usize,
+    phnum: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Check the header and every loadable segment.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_LEN { return Err(ElfError::Truncated); }
+        if data[..4] != MAGIC { return Err(ElfError::BadMagic); }
+        if (data[4], data[5], data[6]) != (CLASS_64, LITTLE_ENDIAN, CURRENT) { return Err(ElfError::BadClass); }
+        if (u16_at(data, 16), u16_at(data, 18)) != (ET_EXEC, EM_X86_64) { return Err(ElfError::NotExecutable); }
+        if u16_at(data, 54) as usize != PHDR_LEN { return Err(ElfError::BadHeaderSize); }
+        let phoff = usize::try_from(u64_at(data, 32)).map_err(|_| ElfError::Truncated)?;
+        let phnum = u16_at(data, 56) as usize;
+        let end = phoff.checked_add(phnum * PHDR_LEN).ok_or(ElfError::Truncated)?;
+        if end > data.len() { return Err(ElfError::Truncated); }
+        let elf = Self { data, entry: u64_at(data, 24), phoff, phnum };
+
+        let mut count = 0;
+        for (i, s) in elf.segments().enumerate() {
+            let s = s?;
+            count += 1;
+            if elf.segments().take(i).any(|t| t.is_ok_and(|t| t.addr < s.end() && s.addr < t.end())) {
+                return Err(ElfError::Overlap);
+            }
+        }
+        if count == 0 { return Err(ElfError::NoSegments); }
+        let entry_ok = elf.segments().flatten().any(|s| s.executable() && (s.addr..s.end()).contains(&elf.entry));
+        if !entry_ok { return Err(ElfError::BadEntry(elf.entry)); }
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> u64 { self.entry }
+
+    /// The `PT_LOAD` segments, in file order (with the index of their
+    /// program header on error).
+    pub fn segments(&self) -> impl Iterator<Item = Result<Segment, ElfError>> + '_ {
+        (0..self.phnum).filter_map(move |index| {
+            let h = &self.data[self.phoff + index * PHDR_LEN..][..PHDR_LEN];
+            (u32_at(h, 0) == PT_LOAD).then(|| self.segment(index, h))
+        })
+    }
+
+    fn segment(&self, index: usize, h: &[u8]) -> Result<Segment, ElfError> {
+        let (offset, vaddr, paddr, file_size, mem_size) = (u64_at(h, 8), u64_at(h, 16), u64_at(h, 24), u64_at(h, 32), u64_at(h, 40));
+        if vaddr != paddr { return Err(ElfError::NotIdentity { index }); }
+        // `span` rounds the end up to a page, so that has to fit too.
+        let end = paddr.checked_add(mem_size).and_then(|e| e.checked_next_multiple_of(PAGE_SIZE));
+        end.ok_or(ElfError::AddressOverflow { index })?;
+        let file_end = offset.checked_add(file_size).filter(|&e| e <= self.data.len() as u64 && file_size <= mem_size);
+        let file_end = file_end.ok_or(ElfError::BadSegment { index })?;
+        Ok(Segment { addr: paddr, mem_size, file: offset as usize..file_end as usize, flags: u32_at(h, 4) })
+    }
+
+    /// Page-aligned physical range covering every segment.
+    pub fn span(&self) -> Range<u64> {
+        let (lo, hi) = self.segments().flatten().fold((u64::MAX, 0), |(lo, hi), s| (lo.min(s.addr), hi.max(s.end())));
+        lo & !(PAGE_SIZE - 1)..hi.next_multiple_of(PAGE_SIZE)
+    }
+
+    /// Lay the image out in `memory`, which stands for `span()`: copy each
+    /// segment's file bytes and zero everything else.
+    pub fn load_into(&self, memory: &mut [u8]) {
+        let base = self.span().start;
+        memory.fill(0);
+        for s in self.segments().flatten() {
+            let at = (s.addr - base) as usize;
+            memory[at..at + s.file.len()].copy_from_slice(&self.data[s.file.clone()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    const PF_W: u32 = 2;
+
+    /// (type, flags, offset, vaddr, paddr, filesz, memsz)
+    type Phdr = (u32, u32, u64, u64, u64, u64, u64);
+
+    fn image(entry: u64, phdrs: &[Phdr], body: &[u8]) -> Vec<u8> {
+        let mut b = std::vec![0u8; HEADER_LEN];
+        b[..4].copy_from_slice(&MAGIC);
+        b[4..7].copy_from_slice(&[CLASS_64, LITTLE_ENDIAN, CURRENT]);
+        b[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        b[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        b[24..32].copy_from_slice(&entry.to_le_bytes());
+        b[32..40].copy_from_slice(&(HEADER_LEN as u64).to_le_bytes());
+        b[54..56].copy_from_slice(&(PHDR_LEN as u16).to_le_bytes());
+        b[56..58].copy_from_slice(&(phdrs.len() as u16).to_le_bytes());
+        for &(ty, flags, offset, vaddr, paddr, filesz, memsz) in phdrs {
+            let mut h = [0u8; PHDR_LEN];
+            h[0..4].copy_from_slice(&ty.to_le_bytes());
+            h[4..8].copy_from_slice(&flags.to_le_bytes());
+            for (at, v) in [(8, offset), (16, vaddr), (24, paddr), (32, filesz), (40, memsz)] {
+                h[at..at + 8].copy_from_slice(&v.to_le_bytes());
+            }
+            b.extend_from_slice(&h);
+        }
+        b.extend_from_slice(body);
+        b
+    }
+
+    const BODY: usize = HEADER_LEN + 3 * PHDR_LEN;
+
+    fn kernel() -> Vec<u8> {
+        let o = BODY as u64;
+        image(
+            0x100_0010,
+            &[
+                (PT_LOAD, PF_X, o, 0x100_0000, 0x100_0000, 32, 32),
+                (6, 0, 0, 0, 0, 0, 0),
+                (PT_LOAD, PF_W, o + 32, 0x100_2000, 0x100_2000, 8, 0x1800),
+            ],
+            &[0xcc; 40],
+        )
+    }
+
+    #[test]
+    fn loads_segments_and_zeroes_bss() {
+        let k = kernel();
+        let elf = Elf::parse(&k).unwrap();
+        assert_eq!(elf.entry(), 0x100_0010);
+        assert_eq!(elf.span(), 0x100_0000..0x100_4000);
+        let segs: Vec<_> = elf.segments().map(Result::unwrap).collect();
+        assert_eq!(segs.len(), 2);
+        assert_eq!((segs[1].file.clone(), segs[1].end()), (BODY + 32..BODY + 40, 0x100_3800));
+        let mut mem = std::vec![0xaa; 0x4000];
+        elf.load_into(&mut mem);
+        assert!(mem[..32].iter().all(|&b| b == 0xcc) && mem[32..0x2000].iter().all(|&b| b == 0));
+        assert!(mem[0x2000..0x2008].iter().all(|&b| b == 0xcc) && mem[0x2008..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn rejects_bad_images() {
+        let k = kernel();
+        let patched = |at: usize, bytes: &[u8]| {
+            let mut k = k.clone();
+            k[at..at + bytes.len()].copy_from_slice(bytes);
+            Elf::parse(&k).map(|e| e.entry())
+        };
+        assert_eq!(Elf::parse(&k[..40]).map(|e| e.entry()), Err(ElfError::Truncated));
+        assert_eq!(Elf::parse(&k[..BODY - 1]).map(|e| e.entry()), Err(ElfError::Truncated));
+        assert_eq!(patched(0, b"MZ"), Err(ElfError::BadMagic));
+        assert_eq!(patched(4, &[1]), Err(ElfError::BadClass));
+        assert_eq!(patched(16, &3u16.to_le_bytes()), Err(ElfError::NotExecutable));
+        assert_eq!(patched(24, &0x100_2000u64.to_le_bytes()), Err(ElfError::BadEntry(0x100_2000)));
+        let one = |h: Phdr| Elf::parse(&image(0x100_0000, &[h], &[0; 64])).map(|e| e.entry());
+        let at = HEADER_LEN as u64 + PHDR_LEN as u64;
+        assert_eq!(one((PT_LOAD, PF_X, at, 0x100_0000, 0x100_0000, 65, 65)), Err(ElfError::BadSegment { index: 0 }));
+        assert_eq!(one((PT_LOAD, PF_X, at, 0x100_0000, 0x100_0000, 16, 8)), Err(ElfError::BadSegment { index: 0 }));
+        assert_eq!(one((PT_LOAD, PF_X, at, 0xffff_8000_0000_0000, 0x100_0000, 8, 8)), Err(ElfError::NotIdentity { index: 0 }));
+        assert_eq!(one((PT_LOAD, PF_X, at, u64::MAX - 4, u64::MAX - 4, 0, 8)), Err(ElfError::AddressOverflow { index: 0 }));
+        let last_page = u64::MAX - (PAGE_SIZE - 1);
+        assert_eq!(one((PT_LOAD, PF_X, at, last_page, last_page, 0, 8)), Err(ElfError::AddressOverflow { index: 0 }));
+        assert_eq!(one((6, 0, 0, 0, 0, 0, 0)), Err(ElfError::NoSegments));
+        let two = image(
+            0x100_0000,
+            &[(PT_LOAD, PF_X, 0, 0x100_0000, 0x100_0000, 0, 0x2000), (PT_LOAD, PF_W, 0, 0x100_1000, 0x100_1000, 0, 8)],
+            &[],
+        );
+        assert_eq!(Elf::parse(&two).map(|e| e.entry()), Err(ElfError::Overlap));
+    }
+}
@@ -0,0 +1,139 @@
+//! What the loader leaves for the kernel besides the memory map, and the
+//! jump into it.
+
+use core::arch::asm;
+use core::str;
+
+use thatte_bootinfo::{BootInfo, RngSource, SEED_LEN};
+use uefi::prelude::*;
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::proto::rng::Rng;
+use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams};
+use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID};
+use uefi::CStr16;
+
+/// The ACPI RSDP from the configuration table (the ACPI 2.0 one if both
+/// are published), or 0.
+pub fn rsdp(st: &SystemTable<Boot>) -> u64 {
+    let find = |guid| st.config_table().iter().find(|e| e.guid == guid).map(|e| e.address as u64);
+    find(ACPI2_GUID).or_else(|| find(ACPI_GUID)).unwrap_or(0)
+}
+
+/// A seed for the kernel's random number generator, from the best source
+/// there is.
+pub fn rng_seed(bs: &BootServices, image: Handle) -> (RngSource, [u8; SEED_LEN]) {
+    let mut seed = [0; SEED_LEN];
+    if firmware_rng(bs, image, &mut seed).is_ok() {
+        return (RngSource::Firmware, seed);
+    }
+    if has_rdrand() && fill_rdrand(&mut seed) {
+        return (RngSource::Rdrand, seed);
+    }
+    // splitmix64 over timestamps taken a microsecond apart: the stall
+    // jitters by however long the firmware's timer takes.
+    let mut state = 0u64;
+    for c in seed.chunks_mut(8) {
+        bs.stall(1);
+        // SAFETY: rdtsc has no preconditions.
+        state = state.wrapping_add(unsafe { core::arch::x86_64::_rdtsc() }).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        c.copy_from_slice(&(z ^ (z >> 31)).to_le_bytes());
+    }
+    (RngSource::Timer, seed)
+}
+
+fn firmware_rng(bs: &BootServices, image: Handle, seed: &mut [u8]) -> uefi::Result {
+    let handle = bs.get_handle_for_protocol::<Rng>()?;
+    // SAFETY: the protocol is only used here, while boot services run.
+    let mut rng = unsafe {
+        bs.open_protocol::<Rng>(
+            OpenProtocolParams { handle, agent: image, controller: None },
+            OpenProtocolAttributes::GetProtocol,
+        )?
+    };
+    rng.get_rng(None, seed)
+}
+
+fn has_rdrand() -> bool {
+    core::arch::x86_64::__cpuid(1).ecx & (1 << 30) != 0
+}
+
+fn fill_rdrand(seed: &mut [u8]) -> bool {
+    for c in seed.chunks_mut(8) {
+        match rdrand64() {
+            Some(v) => c.copy_from_slice(&v.to_le_bytes()[..c.len()]),
+            None => return false,
+        }
+    }
+    true
+}
+
+/// One `rdrand` result, or `None` if the CPU had none ready after a few
+/// tries.
+fn rdrand64() -> Option<u64> {
+    (0..10).find_map(|_| {
+        let (value, ok): (u64, u8);
+        // SAFETY: only called once `has_rdrand` said the instruction exists.
+        unsafe { asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack)) };
+        (ok != 0).then_some(value)
+    })
+}
+
+/// The kernel command line: the load options this image was started with
+/// (the shell command line, or the boot entry's optional data), as UTF-8 in
+/// `buf`. Empty if there are none or they are not text.
+pub fn cmdline<'b>(bs: &BootServices, image: Handle, buf: &'b mut [u8]) -> &'b str {
+    let Ok(loaded) = bs.open_protocol_exclusive::<LoadedImage>(image) else { return "" };
+    match loaded.load_options_as_cstr16() {
+        Ok(options) => strip_image_name(utf8(options, buf)),
+        Err(_) => "",
+    }
+}
+
+/// The shell passes the command it ran as the first word; drop it.
+fn strip_image_name(options: &str) -> &str {
+    let options = options.trim();
+    let is_image = |word: &str| word.len() >= 4 && word.as_bytes()[word.len() - 4..].eq_ignore_ascii_case(b".efi");
+    match options.split_once(' ') {
+        Some((first, rest)) if is_image(first) => rest.trim_start(),
+        None if is_image(options) => "",
+        _ => options,
+    }
+}
+
+/// `s` as UTF-8 in `buf`, cut after the last character that fits.
+pub fn utf8<'b>(s: &CStr16, buf: &'b mut [u8]) -> &'b str {
+    let mut len = 0;
+    for &c in s.iter() {
+        let c = char::from(c);
+        if len + c.len_utf8() > buf.len() {
+            break;
+        }
+        len += c.encode_utf8(&mut buf[len..]).len();
+    }
+    str::from_utf8(&buf[..len]).unwrap_or("")
+}
+
+/// Jump to the kernel entry point with `info` in `rdi`, interrupts off.
+///
+/// # Safety
+/// Boot services must have been exited and `entry` must be the entry point
+/// of a loaded kernel.
+pub unsafe fn enter(entry: u64, info: *const BootInfo) -> ! {
+    asm!("cli", "cld", "jmp {}", in(reg) entry, in("rdi") info, options(noreturn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_the_shell_command() {
+        assert_eq!(strip_image_name("  fs0:\\EFI\\BOOT\\BOOTX64.EFI  log=debug quiet "), "log=debug quiet");
+        assert_eq!(strip_image_name("bootx64.efi"), "");
+        assert_eq!(strip_image_name("log=debug"), "log=debug");
+        assert_eq!(strip_image_name(""), "");
+    }
+}
@@ -1,101 +1,389 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+//! THATTE UEFI stage: paints the splash, loads the kernel ELF and any boot
+//! modules from the ESP it was started from, leaves boot services and jumps
+//! to the kernel with a `thatte_bootinfo::BootInfo`.
+//!
+//! The kernel comes from one of two A/B slots on the ESP, picked by the
+//! slot state in NVRAM (see `slots`), or from the configured path if
+//! neither slot can be booted. Settings come from
+//! `\EFI\THATTE\THATTE.TOML` (see `config`). If no kernel can be loaded,
+//! the error is printed and the machine resets after the configured timeout
+//! (5 seconds by default).
 
-use core::ptr;
+mod config;
+mod elf;
+mod handoff;
+mod slots;
+mod splash;
+
+use core::fmt::Write;
+use core::slice;
+
+use thatte_bootinfo::slot::{self, Slot, CMDLINE_KEY};
+use thatte_bootinfo::{Builder, BuildError, Framebuffer, Module, MODULE_NAME_LEN};
 use uefi::prelude::*;
-use uefi::proto::console::gop::{GraphicsOutput, PixelFormat};
+use uefi::proto::media::file::{Directory, File, FileAttribute, FileMode, RegularFile};
+use uefi::table::boot::{AllocateType, BootServices, MemoryType};
 use uefi::table::runtime::ResetType;
 use uefi::CStr16;
 
+use config::{Config, LogLevel, Text, CMDLINE_MAX, PATH_MAX};
+use elf::{Elf, ElfError, PAGE_SIZE};
+
+const CONFIG_PATH: &CStr16 = cstr16!("\\EFI\\THATTE\\THATTE.TOML");
+
+/// Booted if no slot can be and the configured path cannot be passed to
+/// the firmware.
+const KERNEL_PATH: &CStr16 = cstr16!("\\EFI\\THATTE\\KERNEL.ELF");
+
+/// Every file in this directory is loaded as a boot module.
+const MODULES_PATH: &CStr16 = cstr16!("\\EFI\\THATTE\\MODULES");
+
+const MAX_MODULES: usize = 16;
+
+/// Room on the command line for the slot argument.
+const SLOT_ARG_LEN: usize = 16;
+
+/// Spare memory map entries to allow for: allocations made between sizing
+/// the map and exiting boot services split entries.
+const MAP_SLACK: usize = 16;
+
+/// Why the kernel was not started.
+#[derive(Debug)]
+enum LoadError {
+    /// The ESP this image came from could not be opened.
+    FileSystem(Status),
+    /// The kernel file could not be opened or read.
+    Read(Status),
+    Elf(ElfError),
+    /// The firmware would not give us the kernel's physical range.
+    Placement { addr: u64, pages: usize, status: Status },
+    OutOfMemory(Status),
+    /// The boot information did not fit the area sized for it.
+    BootInfo(BuildError),
+}
+
+impl core::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::FileSystem(s) => write!(f, "boot volume not readable ({:?})", s),
+            Self::Read(s) => write!(f, "read failed ({:?})", s),
+            Self::Elf(e) => write!(f, "bad ELF image ({:?})", e),
+            Self::Placement { addr, pages, status } => write!(f, "{} pages at {:#x} unavailable ({:?})", pages, addr, status),
+            Self::OutOfMemory(s) => write!(f, "out of memory ({:?})", s),
+            Self::BootInfo(e) => write!(f, "boot info does not fit ({:?})", e),
+        }
+    }
+}
+
+/// Print a line on the firmware console if the configured verbosity is at
+/// least `$level`.
+macro_rules! say {
+    ($st:expr, $config:expr, $level:ident, $($arg:tt)*) => {
+        if $config.log >= LogLevel::$level {
+            let _ = writeln!($st.stdout(), $($arg)*);
+        }
+    };
+}
+
+/// A kernel laid out in memory.
+struct Kernel {
+    entry: u64,
+    start: u64,
+    end: u64,
+}
+
 #[entry]
-fn efi_main(_image: Handle, mut st: SystemTable<Boot>) -> Status {
+fn efi_main(image: Handle, mut st: SystemTable<Boot>) -> Status {
     let _ = st.stdout().reset(false);
-    let _ = st.stdout().output_string(cstr16!("THATTE: UEFI hello stage starting...\r\n"));
+    let config = load_config(&mut st, image);
+    say!(st, config, Info, "THATTE: UEFI loader starting");
+    say!(st, config, Debug, "THATTE: {:?}", config);
+    let fb = splash::show(st.boot_services(), image, config.resolution).unwrap_or_else(|e| {
+        say!(st, config, Info, "THATTE: no GOP splash ({:?})", e.status());
+        Framebuffer::NONE
+    });
+    match config.resolution {
+        Some((w, h)) if fb.base != 0 && (fb.width as usize, fb.height as usize) != (w, h) => {
+            say!(st, config, Info, "THATTE: no {}x{} mode; staying at {}x{}", w, h, fb.width, fb.height)
+        }
+        _ => {}
+    }
 
-    // GOP
-    let bt = st.boot_services();
-    let gop_ptr = match bt.locate_protocol::<GraphicsOutput>() {
-        Ok(p) => p,
-        Err(e) => { let _ = st.stdout().output_string(cstr16!("ERROR: GOP not available.\r\n")); return e.status(); }
+    let mut fallback = [0; PATH_MAX + 1];
+    let fallback = CStr16::from_str_with_buf(config.kernel.as_str(), &mut fallback).unwrap_or_else(|_| {
+        let _ = writeln!(st.stdout(), "THATTE: kernel path {:?} unusable; using {}", config.kernel, KERNEL_PATH);
+        KERNEL_PATH
+    });
+    let (kernel, slot) = match load_slot(&mut st, &config, image) {
+        Some((kernel, slot)) => (kernel, Some(slot)),
+        None => {
+            say!(st, config, Info, "THATTE: no bootable slot; trying {}", fallback);
+            match load_kernel(st.boot_services(), image, fallback) {
+                Ok(kernel) => (kernel, None),
+                Err(e) => fail(&mut st, &config, fallback, e),
+            }
+        }
     };
-    let gop = unsafe { &mut *gop_ptr.get() };
-    draw_scene(gop);
-
-    let _ = st.stdout().output_string(cstr16!("THATTE: drew frame. Warm reboot in 5s...\r\n"));
-    bt.stall(5_000_000);
-    st.runtime_services().reset(ResetType::Warm, Status::SUCCESS, None)
-}
-
-fn draw_scene(gop: &mut GraphicsOutput) {
-    let mode = gop.current_mode_info();
-    let (w, h) = mode.resolution();
-    let stride = mode.stride();
-    let fmt = mode.pixel_format();
-    let mut fb = gop.frame_buffer();
-    let base = fb.as_mut_ptr();
-
-    for y in 0..h {
-        for x in 0..w {
-            let fracx = (x as u32 * 255) / (w.max(1) as u32);
-            let fracy = (y as u32 * 255) / (h.max(1) as u32);
-            let r = (16 + (fracx / 3)) as u8;
-            let g = (32 + (fracy / 4)) as u8;
-            let b = 64u8;
-            unsafe { put_pixel(base, x as usize, y as usize, stride as usize, fmt, r, g, b); }
+    let path = match slot {
+        Some(slot) => slots::kernel_path(slot),
+        None => fallback,
+    };
+
+    let mut modules = [Module::new("", 0, 0); MAX_MODULES];
+    let mut count = 0;
+    if let Some(slot) = slot {
+        count += load_bootfs(&mut st, image, slot, &mut modules[0]) as usize;
+    }
+    count += load_modules(&mut st, &config, image, &mut modules[count..]);
+    let modules = &modules[..count];
+
+    // Load options (a shell command line) override the configured one.
+    let mut given = [0; CMDLINE_MAX];
+    let mut cmdline = Text::<{ CMDLINE_MAX + SLOT_ARG_LEN }>::new();
+    match handoff::cmdline(st.boot_services(), image, &mut given) {
+        "" => cmdline.push_str(config.cmdline.as_str()),
+        given => cmdline.push_str(given),
+    };
+    if let Some(slot) = slot {
+        let sep = if cmdline.as_str().is_empty() { "" } else { " " };
+        let _ = cmdline.push_str(sep) && cmdline.push_str(CMDLINE_KEY) && cmdline.push(slot.letter());
+    }
+    say!(st, config, Debug, "THATTE: cmdline {:?}", cmdline);
+
+    let mut info = match boot_info(st.boot_services(), &kernel, modules, cmdline.as_str()) {
+        Ok(info) => info,
+        Err(e) => fail(&mut st, &config, path, e),
+    };
+    info.framebuffer(fb);
+    info.rsdp(handoff::rsdp(&st));
+    let (source, seed) = handoff::rng_seed(st.boot_services(), image);
+    info.rng_seed(source, seed);
+    say!(
+        st,
+        config,
+        Info,
+        "THATTE: kernel at {:#x}..{:#x}, entry {:#x}, {} modules; exiting boot services",
+        kernel.start,
+        kernel.end,
+        kernel.entry,
+        modules.len()
+    );
+
+    let (rt, map) = st.exit_boot_services(MemoryType::LOADER_DATA);
+    info.system_table(rt.as_ptr() as u64);
+    // A map cut short only hides memory from the kernel; the area was sized
+    // with slack so it should not happen.
+    let _ = info.memory_map(map.entries().map(|d| (d.phys_start, d.page_count, d.ty.0)));
+    // SAFETY: boot services are gone and the kernel was loaded by
+    // `load_kernel`.
+    unsafe { handoff::enter(kernel.entry, info.finish()) }
+}
+
+/// Print why no kernel was started, then reset after the configured
+/// timeout.
+fn fail(st: &mut SystemTable<Boot>, config: &Config, path: &CStr16, e: LoadError) -> ! {
+    let _ = writeln!(st.stdout(), "ERROR: cannot start {}: {}", path, e);
+    let _ = writeln!(st.stdout(), "THATTE: warm reboot in {}s...", config.timeout);
+    st.boot_services().stall(config.timeout as usize * 1_000_000);
+    st.runtime_services().reset(ResetType::WARM, Status::SUCCESS, None)
+}
+
+/// Load the kernel of the best bootable slot, or of the other one if that
+/// fails. A slot that has a kernel file loses a try (unless it has already
+/// booted successfully) before the kernel is read, so one that keeps
+/// failing, here or later, is given up on.
+fn load_slot(st: &mut SystemTable<Boot>, config: &Config, image: Handle) -> Option<(Kernel, Slot)> {
+    let mut states = slots::read(st.runtime_services());
+    say!(st, config, Debug, "THATTE: slot a {:?}, slot b {:?}", states[0], states[1]);
+    for slot in slot::boot_order(&states, config.default_slot) {
+        let path = slots::kernel_path(slot);
+        if !exists(st.boot_services(), image, path) {
+            say!(st, config, Info, "THATTE: slot {}: no {}", slot.letter(), path);
+            continue;
+        }
+        let state = &mut states[slot.index()];
+        state.attempt();
+        if let Err(e) = slots::write(st.runtime_services(), slot, *state) {
+            let _ = writeln!(st.stdout(), "THATTE: slot {}: state not saved ({:?})", slot.letter(), e.status());
+        }
+        let good = if state.successful { "booted before" } else { "not yet booted" };
+        say!(st, config, Info, "THATTE: slot {}: priority {}, {} tries left, {}", slot.letter(), state.priority, state.tries, good);
+        match load_kernel(st.boot_services(), image, path) {
+            Ok(kernel) => return Some((kernel, slot)),
+            Err(e) => {
+                let _ = writeln!(st.stdout(), "ERROR: slot {}: cannot start {}: {}", slot.letter(), path, e);
+            }
+        }
+    }
+    None
+}
+
+/// Lay out everything but the memory map for the kernel, in pages with
+/// room for the map as it will be after ExitBootServices.
+fn boot_info(bs: &BootServices, kernel: &Kernel, modules: &[Module], cmdline: &str) -> Result<Builder<'static>, LoadError> {
+    let size = bs.memory_map_size();
+    let entries = size.map_size / size.entry_size;
+    let len = thatte_bootinfo::size_for(entries + MAP_SLACK, modules.len(), cmdline.len());
+    let pages = len.div_ceil(PAGE_SIZE as usize);
+    let area = bs.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages);
+    let area = area.map_err(|e| LoadError::OutOfMemory(e.status()))?;
+    // SAFETY: the firmware just gave us these pages, identity-mapped.
+    let area = unsafe { slice::from_raw_parts_mut(area as *mut u8, pages * PAGE_SIZE as usize) };
+    let mut info = Builder::new(area).map_err(LoadError::BootInfo)?;
+    info.kernel(kernel.start..kernel.end);
+    info.cmdline(cmdline).map_err(LoadError::BootInfo)?;
+    info.modules(modules).map_err(LoadError::BootInfo)?;
+    Ok(info)
+}
+
+/// The settings file, or the defaults if there is none. Problems with it
+/// are printed whatever the verbosity.
+fn load_config(st: &mut SystemTable<Boot>, image: Handle) -> Config {
+    let bs = st.boot_services();
+    let file = open_root(bs, image).and_then(|mut root| read_file(bs, &mut root, CONFIG_PATH));
+    let file = match file {
+        Ok(file) => file,
+        Err(LoadError::Read(Status::NOT_FOUND)) => return Config::default(),
+        Err(e) => {
+            let _ = writeln!(st.stdout(), "THATTE: {}: {}; using defaults", CONFIG_PATH, e);
+            return Config::default();
+        }
+    };
+    let config = if file.len() > config::MAX_SIZE {
+        let _ = writeln!(st.stdout(), "THATTE: {}: over {} bytes; using defaults", CONFIG_PATH, config::MAX_SIZE);
+        Config::default()
+    } else {
+        Config::parse(file, |line, e| {
+            let _ = writeln!(st.stdout(), "THATTE: {} line {}: {}; ignored", CONFIG_PATH, line, e);
+        })
+    };
+    free_file(st.boot_services(), file);
+    config
+}
+
+/// Read the kernel image from the boot volume and copy its segments into
+/// place.
+fn load_kernel(bs: &BootServices, image: Handle, path: &CStr16) -> Result<Kernel, LoadError> {
+    let file = read_file(bs, &mut open_root(bs, image)?, path)?;
+    let loaded = place(bs, file);
+    free_file(bs, file);
+    loaded
+}
+
+/// Read `slot`'s bootfs, if it has one, into `module`.
+fn load_bootfs(st: &mut SystemTable<Boot>, image: Handle, slot: Slot, module: &mut Module) -> bool {
+    let bs = st.boot_services();
+    let path = slots::bootfs_path(slot);
+    match open_root(bs, image).and_then(|mut root| read_file(bs, &mut root, path)) {
+        Ok(data) => {
+            *module = Module::new("bootfs", data.as_ptr() as u64, data.len() as u64);
+            true
+        }
+        Err(LoadError::Read(Status::NOT_FOUND)) => false,
+        Err(e) => {
+            let _ = writeln!(st.stdout(), "THATTE: skipping {}: {}", path, e);
+            false
         }
     }
-    // Draw block letters "THATTE"
-    let k = (h as usize / 16).max(8);
-    let spacing = k / 2;
-    let letter_w = 3 * k;
-    let letter_h = 5 * k;
-    let total_w = 6 * letter_w + 5 * spacing;
-    let start_x = (w as usize / 2).saturating_sub(total_w / 2);
-    let start_y = (h as usize / 2).saturating_sub(letter_h / 2);
-    let fg = (220u8, 230u8, 245u8);
-    let mut x = start_x;
-    draw_t(base, stride as usize, fmt, x, start_y, k, fg); x += letter_w + spacing;
-    draw_h(base, stride as usize, fmt, x, start_y, k, fg); x += letter_w + spacing;
-    draw_a(base, stride as usize, fmt, x, start_y, k, fg); x += letter_w + spacing;
-    draw_t(base, stride as usize, fmt, x, start_y, k, fg); x += letter_w + spacing;
-    draw_t(base, stride as usize, fmt, x, start_y, k, fg); x += letter_w + spacing;
-    draw_e(base, stride as usize, fmt, x, start_y, k, fg);
-}
-
-#[inline]
-unsafe fn put_pixel(base: *mut u8, x: usize, y: usize, stride: usize, fmt: PixelFormat, r: u8, g: u8, b: u8) {
-    let idx = ((y * stride) + x) * 4;
-    match fmt {
-        PixelFormat::Rgb => {
-            ptr::write(base.add(idx + 0), r);
-            ptr::write(base.add(idx + 1), g);
-            ptr::write(base.add(idx + 2), b);
-            ptr::write(base.add(idx + 3), 0);
+}
+
+/// Read the boot modules into `modules`, returning how many there are. A
+/// module that cannot be read is reported and left out.
+fn load_modules(st: &mut SystemTable<Boot>, config: &Config, image: Handle, modules: &mut [Module]) -> usize {
+    let Ok(mut root) = open_root(st.boot_services(), image) else { return 0 };
+    let dir = root.open(MODULES_PATH, FileMode::Read, FileAttribute::empty());
+    let Some(mut dir) = dir.ok().and_then(|d| d.into_directory()) else { return 0 };
+    #[repr(C, align(8))]
+    struct Entry([u8; 512]);
+    let mut entry = Entry([0; 512]);
+    let mut count = 0;
+    while count < modules.len() {
+        let file = match dir.read_entry(&mut entry.0) {
+            Ok(Some(file)) => file,
+            _ => break,
+        };
+        if !file.is_regular_file() {
+            continue;
         }
-        _ => {
-            ptr::write(base.add(idx + 0), b);
-            ptr::write(base.add(idx + 1), g);
-            ptr::write(base.add(idx + 2), r);
-            ptr::write(base.add(idx + 3), 0);
+        let mut name = [0; MODULE_NAME_LEN];
+        let name = handoff::utf8(file.file_name(), &mut name);
+        match read_file(st.boot_services(), &mut dir, file.file_name()) {
+            Ok(data) => {
+                say!(st, config, Debug, "THATTE: module {}: {} bytes at {:#x}", name, data.len(), data.as_ptr() as u64);
+                modules[count] = Module::new(name, data.as_ptr() as u64, data.len() as u64);
+                count += 1;
+            }
+            Err(e) => {
+                let _ = writeln!(st.stdout(), "THATTE: skipping module {}: {}", name, e);
+            }
         }
     }
+    count
 }
 
-fn draw_rect(base: *mut u8, stride: usize, fmt: PixelFormat, x: usize, y: usize, w: usize, h: usize, c: (u8,u8,u8)) {
-    for yy in y..y+h { for xx in x..x+w { unsafe { put_pixel(base, xx, yy, stride, fmt, c.0, c.1, c.2); } } }
+fn place(bs: &BootServices, file: &[u8]) -> Result<Kernel, LoadError> {
+    let elf = Elf::parse(file).map_err(LoadError::Elf)?;
+    let span = elf.span();
+    let pages = ((span.end - span.start) / PAGE_SIZE) as usize;
+    // Loader code, not data: firmware may map data pages no-execute.
+    bs.allocate_pages(AllocateType::Address(span.start), MemoryType::LOADER_CODE, pages)
+        .map_err(|e| LoadError::Placement { addr: span.start, pages, status: e.status() })?;
+    // SAFETY: the firmware just gave us these pages, identity-mapped.
+    let memory = unsafe { slice::from_raw_parts_mut(span.start as *mut u8, pages * PAGE_SIZE as usize) };
+    elf.load_into(memory);
+    Ok(Kernel { entry: elf.entry(), start: span.start, end: span.end })
 }
-fn draw_t(base:*mut u8,stride:usize,fmt:PixelFormat,x:usize,y:usize,k:usize,c:(u8,u8,u8)){
-    let w=3*k; let h=5*k; let t=k/3+1; draw_rect(base,stride,fmt,x,y,w,t,c); let vx=x+w/2-t/2; draw_rect(base,stride,fmt,vx,y,t,h,c);
+
+fn exists(bs: &BootServices, image: Handle, path: &CStr16) -> bool {
+    open_root(bs, image).is_ok_and(|mut root| root.open(path, FileMode::Read, FileAttribute::empty()).is_ok())
 }
-fn draw_h(base:*mut u8,stride:usize,fmt:PixelFormat,x:usize,y:usize,k:usize,c:(u8,u8,u8)){
-    let w=3*k; let h=5*k; let t=k/3+1; draw_rect(base,stride,fmt,x,y,t,h,c); draw_rect(base,stride,fmt,x+w-t,y,t,h,c); draw_rect(base,stride,fmt,x,y+2*k,w,t,c);
+
+/// The root directory of the volume this image was loaded from.
+fn open_root(bs: &BootServices, image: Handle) -> Result<Directory, LoadError> {
+    let mut fs = bs.get_image_file_system(image).map_err(|e| LoadError::FileSystem(e.status()))?;
+    fs.open_volume().map_err(|e| LoadError::FileSystem(e.status()))
 }
-fn draw_a(base:*mut u8,stride:usize,fmt:PixelFormat,x:usize,y:usize,k:usize,c:(u8,u8,u8)){
-    let w=3*k; let h=5*k; let t=k/3+1; draw_rect(base,stride,fmt,x,y+k,t,h-k,c); draw_rect(base,stride,fmt,x+w-t,y+k,t,h-k,c); draw_rect(base,stride,fmt,x+k/2,y,w-k,t,c); draw_rect(base,stride,fmt,x+t,y+2*k,w-2*t,t,c);
+
+/// Read a whole file into fresh `LOADER_DATA` pages.
+fn read_file(bs: &BootServices, dir: &mut Directory, path: &CStr16) -> Result<&'static mut [u8], LoadError> {
+    let read = |e: uefi::Error| LoadError::Read(e.status());
+    let handle = dir.open(path, FileMode::Read, FileAttribute::empty()).map_err(read)?;
+    let mut file = handle.into_regular_file().ok_or(LoadError::Read(Status::INVALID_PARAMETER))?;
+    file.set_position(RegularFile::END_OF_FILE).map_err(read)?;
+    let size = file.get_position().map_err(read)? as usize;
+    file.set_position(0).map_err(read)?;
+
+    let pages = size.div_ceil(PAGE_SIZE as usize).max(1);
+    let addr = bs.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages);
+    let addr = addr.map_err(|e| LoadError::OutOfMemory(e.status()))?;
+    // SAFETY: fresh, identity-mapped pages covering at least `size` bytes.
+    let buf = unsafe { slice::from_raw_parts_mut(addr as *mut u8, size) };
+    let mut done = 0;
+    while done < size {
+        let status = match file.read(&mut buf[done..]) {
+            Ok(0) => Status::END_OF_FILE,
+            Ok(n) => {
+                done += n;
+                continue;
+            }
+            Err(e) => e.status(),
+        };
+        free_file(bs, buf);
+        return Err(LoadError::Read(status));
+    }
+    Ok(buf)
 }
-fn draw_e(base:*mut u8,stride:usize,fmt:PixelFormat,x:usize,y:usize,k:usize,c:(u8,u8,u8)){
-    let w=3*k; let h=5*k; let t=k/3+1; draw_rect(base,stride,fmt,x,y,t,h,c); draw_rect(base,stride,fmt,x,y,w,t,c); draw_rect(base,stride,fmt,x,y+2*k,(w*4)/5,t,c); draw_rect(base,stride,fmt,x,y+h-t,w,t,c);
+
+/// Give back the pages of a file from `read_file`.
+fn free_file(bs: &BootServices, file: &mut [u8]) {
+    let pages = file.len().div_ceil(PAGE_SIZE as usize).max(1);
+    // SAFETY: `read_file` allocated exactly these pages, and the caller
+    // does not use `file` again.
+    let _ = unsafe { bs.free_pages(file.as_ptr() as u64, pages) };
 }
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! { loop {} }
@@ -0,0 +1,61 @@
+//! Where the A/B slots live on the ESP, and their state in NVRAM (see
+//! `thatte_bootinfo::slot`).
+
+use thatte_bootinfo::slot::{Slot, SlotState, ATTRIBUTES, STATE_LEN, VENDOR};
+use uefi::prelude::*;
+use uefi::table::runtime::{RuntimeServices, VariableAttributes, VariableVendor};
+use uefi::{CStr16, Guid};
+
+const VENDOR_ID: VariableVendor = VariableVendor(Guid::from_bytes(VENDOR));
+
+/// Name of `slot`'s variable (the same as `Slot::variable`).
+fn variable(slot: Slot) -> &'static CStr16 {
+    match slot {
+        Slot::A => cstr16!("ThatteSlotA"),
+        Slot::B => cstr16!("ThatteSlotB"),
+    }
+}
+
+pub fn kernel_path(slot: Slot) -> &'static CStr16 {
+    match slot {
+        Slot::A => cstr16!("\\EFI\\THATTE\\SLOT_A\\KERNEL.ELF"),
+        Slot::B => cstr16!("\\EFI\\THATTE\\SLOT_B\\KERNEL.ELF"),
+    }
+}
+
+/// The slot's boot file system image, passed to the kernel as the
+/// `bootfs` module if it is there.
+pub fn bootfs_path(slot: Slot) -> &'static CStr16 {
+    match slot {
+        Slot::A => cstr16!("\\EFI\\THATTE\\SLOT_A\\BOOTFS"),
+        Slot::B => cstr16!("\\EFI\\THATTE\\SLOT_B\\BOOTFS"),
+    }
+}
+
+/// The stored state of each slot; a missing or unreadable variable counts
+/// as a freshly installed slot.
+pub fn read(rt: &RuntimeServices) -> [SlotState; 2] {
+    Slot::ALL.map(|slot| {
+        let mut buf = [0; STATE_LEN];
+        let stored = rt.get_variable(variable(slot), &VENDOR_ID, &mut buf).ok();
+        stored.and_then(|(data, _)| SlotState::from_bytes(data)).unwrap_or(SlotState::NEW)
+    })
+}
+
+pub fn write(rt: &RuntimeServices, slot: Slot, state: SlotState) -> uefi::Result {
+    let attributes = VariableAttributes::from_bits_truncate(ATTRIBUTES);
+    rt.set_variable(variable(slot), &VENDOR_ID, attributes, &state.to_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_match_the_shared_ones() {
+        for slot in Slot::ALL {
+            assert_eq!(variable(slot).as_slice_with_nul().iter().map(|&c| u16::from(c)).collect::<std::vec::Vec<_>>(), slot.variable());
+        }
+        assert_eq!(VariableAttributes::from_bits_truncate(ATTRIBUTES).bits(), ATTRIBUTES);
+    }
+}
@@ -0,0 +1,140 @@
+//! The Day-0 splash: a gradient with "THATTE" in block letters, painted
+//! through GOP while the kernel loads.
+
+use core::ptr;
+use thatte_bootinfo::{self as bootinfo, Framebuffer};
+use uefi::proto::console::gop::{GraphicsOutput, PixelFormat};
+use uefi::table::boot::{BootServices, OpenProtocolAttributes, OpenProtocolParams};
+use uefi::{Handle, Result};
+
+/// Switch the first GOP device to the `want`ed resolution if it has it,
+/// paint the splash, and describe its frame buffer for the kernel.
+pub fn show(bs: &BootServices, image: Handle, want: Option<(usize, usize)>) -> Result<Framebuffer> {
+    let handle = bs.get_handle_for_protocol::<GraphicsOutput>()?;
+    // Shared access: an exclusive open would disconnect the firmware console.
+    // SAFETY: the firmware keeps the GOP alive while boot services run, and
+    // nothing else draws while we do.
+    let mut gop = unsafe {
+        bs.open_protocol::<GraphicsOutput>(
+            OpenProtocolParams { handle, agent: image, controller: None },
+            OpenProtocolAttributes::GetProtocol,
+        )?
+    };
+    if let Some(mode) = want.and_then(|want| gop.modes(bs).find(|m| m.info().resolution() == want)) {
+        // On failure the old mode stays, which is what the caller reports.
+        let _ = gop.set_mode(&mode);
+    }
+    draw_scene(&mut gop);
+    Ok(describe(&mut gop))
+}
+
+/// The current mode's frame buffer, or `Framebuffer::NONE` if it has none.
+fn describe(gop: &mut GraphicsOutput) -> Framebuffer {
+    let mode = gop.current_mode_info();
+    let format = match mode.pixel_format() {
+        PixelFormat::Rgb => bootinfo::PixelFormat::Rgb,
+        PixelFormat::Bgr => bootinfo::PixelFormat::Bgr,
+        PixelFormat::Bitmask => bootinfo::PixelFormat::Bitmask,
+        PixelFormat::BltOnly => return Framebuffer::NONE,
+    };
+    let (w, h) = mode.resolution();
+    let mut fb = gop.frame_buffer();
+    let mut info = Framebuffer::new(fb.as_mut_ptr() as u64, fb.size() as u64, (w as u32, h as u32), mode.stride() as u32, format);
+    if let Some(m) = mode.pixel_bitmask() {
+        (info.red_mask, info.green_mask, info.blue_mask, info.reserved_mask) = (m.red, m.green, m.blue, m.reserved);
+    }
+    info
+}
+
+fn draw_scene(gop: &mut GraphicsOutput) {
+    let mode = gop.current_mode_info();
+    let (w, h) = mode.resolution();
+    let mut fb = gop.frame_buffer();
+    let canvas = Canvas { base: fb.as_mut_ptr(), stride: mode.stride(), fmt: mode.pixel_format() };
+
+    for y in 0..h {
+        for x in 0..w {
+            let fracx = (x as u32 * 255) / (w.max(1) as u32);
+            let fracy = (y as u32 * 255) / (h.max(1) as u32);
+            let r = (16 + (fracx / 3)) as u8;
+            let g = (32 + (fracy / 4)) as u8;
+            canvas.put(x, y, (r, g, 64));
+        }
+    }
+    // Draw block letters "THATTE"
+    let k = (h / 16).max(8);
+    let spacing = k / 2;
+    let letter_w = 3 * k;
+    let letter_h = 5 * k;
+    let total_w = 6 * letter_w + 5 * spacing;
+    let start_x = (w / 2).saturating_sub(total_w / 2);
+    let start_y = (h / 2).saturating_sub(letter_h / 2);
+    let fg = (220u8, 230u8, 245u8);
+    let letters: [Letter; 6] = [draw_t, draw_h, draw_a, draw_t, draw_t, draw_e];
+    for (i, draw) in letters.iter().enumerate() {
+        draw(&canvas, start_x + i * (letter_w + spacing), start_y, k, fg);
+    }
+}
+
+type Rgb = (u8, u8, u8);
+
+/// Draws one letter at (x, y) with stroke unit `k`.
+type Letter = fn(&Canvas, usize, usize, usize, Rgb);
+
+/// The GOP frame buffer of the current mode (4 bytes per pixel).
+struct Canvas {
+    base: *mut u8,
+    stride: usize,
+    fmt: PixelFormat,
+}
+
+impl Canvas {
+    /// Pixels outside the current mode must not be written; every caller
+    /// stays inside the resolution it was given.
+    fn put(&self, x: usize, y: usize, (r, g, b): Rgb) {
+        let idx = ((y * self.stride) + x) * 4;
+        let px = match self.fmt {
+            PixelFormat::Rgb => [r, g, b, 0],
+            _ => [b, g, r, 0],
+        };
+        // SAFETY: (x, y) is inside the mode, so the 4 bytes are frame buffer.
+        unsafe { ptr::copy_nonoverlapping(px.as_ptr(), self.base.add(idx), 4) }
+    }
+
+    fn rect(&self, x: usize, y: usize, w: usize, h: usize, c: Rgb) {
+        for yy in y..y + h {
+            for xx in x..x + w {
+                self.put(xx, yy, c);
+            }
+        }
+    }
+}
+
+fn draw_t(cv: &Canvas, x: usize, y: usize, k: usize, c: Rgb) {
+    let (w, h, t) = (3 * k, 5 * k, k / 3 + 1);
+    cv.rect(x, y, w, t, c);
+    cv.rect(x + w / 2 - t / 2, y, t, h, c);
+}
+
+fn draw_h(cv: &Canvas, x: usize, y: usize, k: usize, c: Rgb) {
+    let (w, h, t) = (3 * k, 5 * k, k / 3 + 1);
+    cv.rect(x, y, t, h, c);
+    cv.rect(x + w - t, y, t, h, c);
+    cv.rect(x, y + 2 * k, w, t, c);
+}
+
+fn draw_a(cv: &Canvas, x: usize, y: usize, k: usize, c: Rgb) {
+    let (w, h, t) = (3 * k, 5 * k, k / 3 + 1);
+    cv.rect(x, y + k, t, h - k, c);
+    cv.rect(x + w - t, y + k, t, h - k, c);
+    cv.rect(x + k / 2, y, w - k, t, c);
+    cv.rect(x + t, y + 2 * k, w - 2 * t, t, c);
+}
+
+fn draw_e(cv: &Canvas, x: usize, y: usize, k: usize, c: Rgb) {
+    let (w, h, t) = (3 * k, 5 * k, k / 3 + 1);
+    cv.rect(x, y, t, h, c);
+    cv.rect(x, y, w, t, c);
+    cv.rect(x, y + 2 * k, (w * 4) / 5, t, c);
+    cv.rect(x, y + h - t, w, t, c);
+}
@@ -0,0 +1,625 @@
+//! The boot information the UEFI loader hands to the kernel.
+//!
+//! `thatte-boot-efi` builds a `BootInfo` in `LOADER_DATA` pages and passes
+//! its physical address in `rdi`; `thatte-mk` checks it with
+//! `BootInfo::from_addr` before reading anything else. The header is
+//! `repr(C)` with a fixed layout, and every pointer in it is a physical
+//! address the firmware's identity mapping covers. Variable-length parts
+//! (memory map, module list, command line) follow the header in the same
+//! area.
+//!
+//! The memory map is translated from UEFI memory types into `RegionKind`s,
+//! sorted and merged, so the kernel never deals with firmware descriptors.
+//! The kernel image is split out of the loader's memory as
+//! `RegionKind::Kernel`.
+//!
+//! `VERSION` changes on any incompatible change to the layout. Fields may
+//! be appended without a version change; `header_size` says how much of
+//! the header the loader wrote.
+//!
+//! `slot` holds the other thing the two sides share: the A/B slot state in
+//! UEFI variables.
+
+#![no_std]
+
+#[cfg(test)]
+extern crate std;
+
+pub mod slot;
+
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ops::Range;
+use core::{ptr, slice, str};
+
+/// First word of the header ("THATBOOT").
+pub const MAGIC: u64 = u64::from_le_bytes(*b"THATBOOT");
+
+/// Layout version; bumped on any incompatible change.
+pub const VERSION: u32 = 1;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Bytes of random seed the loader passes on.
+pub const SEED_LEN: usize = 32;
+
+/// Longest module name kept (UTF-8 bytes, NUL-padded).
+pub const MODULE_NAME_LEN: usize = 48;
+
+/// What a range of physical memory holds.
+#[repr(u32)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RegionKind {
+    /// Free for the kernel to use.
+    Usable = 1,
+    /// Firmware-reserved or of unknown type: never touch.
+    Reserved = 2,
+    /// ACPI tables; usable once they have been parsed.
+    AcpiReclaimable = 3,
+    /// ACPI non-volatile storage; must be preserved.
+    AcpiNvs = 4,
+    /// Memory-mapped I/O.
+    Mmio = 5,
+    /// UEFI runtime services code and data; must stay mapped for them.
+    Runtime = 6,
+    /// The kernel image.
+    Kernel = 7,
+    /// Loader memory holding the boot info, modules and the loader itself;
+    /// usable once the kernel is done with what is in it.
+    Bootloader = 8,
+    /// Memory with errors.
+    Unusable = 9,
+    /// Persistent (non-volatile) memory.
+    Persistent = 10,
+}
+
+impl RegionKind {
+    pub const fn from_raw(raw: u32) -> Option<Self> {
+        Some(match raw {
+            1 => Self::Usable,
+            2 => Self::Reserved,
+            3 => Self::AcpiReclaimable,
+            4 => Self::AcpiNvs,
+            5 => Self::Mmio,
+            6 => Self::Runtime,
+            7 => Self::Kernel,
+            8 => Self::Bootloader,
+            9 => Self::Unusable,
+            10 => Self::Persistent,
+            _ => return None,
+        })
+    }
+
+    /// The kind of memory a UEFI memory type (`EFI_MEMORY_TYPE`) describes
+    /// after ExitBootServices.
+    pub const fn from_uefi(ty: u32) -> Self {
+        match ty {
+            // Loader code and data.
+            1 | 2 => Self::Bootloader,
+            // Boot services code and data, conventional memory.
+            3 | 4 | 7 => Self::Usable,
+            5 | 6 => Self::Runtime,
+            8 => Self::Unusable,
+            9 => Self::AcpiReclaimable,
+            10 => Self::AcpiNvs,
+            // MMIO and MMIO port space.
+            11 | 12 => Self::Mmio,
+            14 => Self::Persistent,
+            // Reserved, PAL code, unaccepted, OEM and OS types.
+            _ => Self::Reserved,
+        }
+    }
+}
+
+/// A page-aligned run of physical memory.
+#[repr(C)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub pages: u64,
+    /// A `RegionKind`.
+    pub kind: u32,
+    pub _reserved: u32,
+}
+
+impl MemoryRegion {
+    pub const fn new(start: u64, pages: u64, kind: RegionKind) -> Self {
+        Self { start, pages, kind: kind as u32, _reserved: 0 }
+    }
+
+    pub const fn end(&self) -> u64 { self.start + self.pages * PAGE_SIZE }
+
+    /// The region's kind; kinds this crate does not know are `Reserved`.
+    pub const fn kind(&self) -> RegionKind {
+        match RegionKind::from_raw(self.kind) {
+            Some(k) => k,
+            None => RegionKind::Reserved,
+        }
+    }
+}
+
+/// How a frame buffer pixel is laid out (4 bytes each).
+#[repr(u32)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PixelFormat {
+    /// Red, green, blue, reserved: one byte each.
+    Rgb = 1,
+    /// Blue, green, red, reserved: one byte each.
+    Bgr = 2,
+    /// Channels given by the `*_mask` fields.
+    Bitmask = 3,
+}
+
+impl PixelFormat {
+    pub const fn from_raw(raw: u32) -> Option<Self> {
+        Some(match raw {
+            1 => Self::Rgb,
+            2 => Self::Bgr,
+            3 => Self::Bitmask,
+            _ => return None,
+        })
+    }
+}
+
+/// The linear frame buffer the loader left the display in.
+#[repr(C)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Framebuffer {
+    /// Physical address; 0 if there is no frame buffer.
+    pub base: u64,
+    /// Bytes.
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Pixels per scan line.
+    pub stride: u32,
+    /// A `PixelFormat`.
+    pub format: u32,
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+    pub reserved_mask: u32,
+}
+
+impl Framebuffer {
+    /// No frame buffer.
+    pub const NONE: Self = Self::new(0, 0, (0, 0), 0, PixelFormat::Bgr);
+
+    /// A frame buffer with one of the byte-per-channel formats; set the
+    /// masks after for `PixelFormat::Bitmask`.
+    pub const fn new(base: u64, size: u64, (width, height): (u32, u32), stride: u32, format: PixelFormat) -> Self {
+        let (red_mask, green_mask, blue_mask) = match format {
+            PixelFormat::Rgb => (0xff, 0xff00, 0xff_0000),
+            PixelFormat::Bgr | PixelFormat::Bitmask => (0xff_0000, 0xff00, 0xff),
+        };
+        let format = format as u32;
+        Self { base, size, width, height, stride, format, red_mask, green_mask, blue_mask, reserved_mask: 0xff00_0000 }
+    }
+
+    pub const fn pixel_format(&self) -> Option<PixelFormat> { PixelFormat::from_raw(self.format) }
+}
+
+/// Where the seed came from, best first.
+#[repr(u32)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RngSource {
+    /// No seed; the bytes are zero.
+    None = 0,
+    /// `EFI_RNG_PROTOCOL`.
+    Firmware = 1,
+    /// `rdrand`.
+    Rdrand = 2,
+    /// Mixed timestamp counter readings: not secret, only varied.
+    Timer = 3,
+}
+
+impl RngSource {
+    pub const fn from_raw(raw: u32) -> Option<Self> {
+        Some(match raw {
+            0 => Self::None,
+            1 => Self::Firmware,
+            2 => Self::Rdrand,
+            3 => Self::Timer,
+            _ => return None,
+        })
+    }
+}
+
+/// A file the loader read into memory for the kernel.
+#[repr(C)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Module {
+    /// Physical address, page-aligned.
+    pub base: u64,
+    /// Bytes.
+    pub len: u64,
+    name: [u8; MODULE_NAME_LEN],
+}
+
+impl Module {
+    /// `name` is cut to `MODULE_NAME_LEN` bytes at a character boundary.
+    pub fn new(name: &str, base: u64, len: u64) -> Self {
+        let mut cut = name.len().min(MODULE_NAME_LEN);
+        while !name.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        let mut bytes = [0; MODULE_NAME_LEN];
+        bytes[..cut].copy_from_slice(&name.as_bytes()[..cut]);
+        Self { base, len, name: bytes }
+    }
+
+    /// The name, or `""` if it is not UTF-8.
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(MODULE_NAME_LEN);
+        str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+}
+
+/// `len` values of `T` at physical address `addr`. Only `Builder` makes
+/// them, so one inside a `BootInfo` always points into the same area.
+#[repr(C)]
+pub struct Slice<T> {
+    addr: u64,
+    len: u64,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Slice<T> {
+    const EMPTY: Self = Self { addr: 0, len: 0, _marker: PhantomData };
+
+    fn get(&self) -> &[T] {
+        if self.len == 0 {
+            return &[];
+        }
+        // SAFETY: see the type: the values were written with the header and
+        // live as long as it does.
+        unsafe { slice::from_raw_parts(self.addr as *const T, self.len as usize) }
+    }
+}
+
+/// The header at the address passed to the kernel.
+#[repr(C)]
+pub struct BootInfo {
+    pub magic: u64,
+    pub version: u32,
+    /// Bytes of header the loader wrote.
+    pub header_size: u32,
+    /// Physical range of the kernel image.
+    pub kernel_start: u64,
+    pub kernel_end: u64,
+    /// The UEFI system table (for runtime services), or 0.
+    pub system_table: u64,
+    /// The ACPI RSDP, or 0 if the firmware published none.
+    pub rsdp: u64,
+    pub framebuffer: Framebuffer,
+    /// An `RngSource`.
+    pub rng_source: u32,
+    pub _reserved: u32,
+    pub rng_seed: [u8; SEED_LEN],
+    memory_map: Slice<MemoryRegion>,
+    modules: Slice<Module>,
+    cmdline: Slice<u8>,
+}
+
+const _: () = assert!(size_of::<BootInfo>() == 184);
+
+/// Why an address does not hold boot information this kernel can use.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum InfoError {
+    /// Null or not 8-byte aligned.
+    BadAddress(u64),
+    BadMagic,
+    /// A layout version other than `VERSION`.
+    Version(u32),
+    /// `header_size` is smaller than this version's header.
+    Truncated,
+    /// The command line is not UTF-8.
+    BadCmdline,
+}
+
+impl BootInfo {
+    /// Check and borrow the boot information at `addr`.
+    ///
+    /// # Safety
+    /// If `addr` is non-null and aligned, it must be readable for a header,
+    /// and if that header has the right magic and version, it and the
+    /// memory map, modules and command line it points at must have been
+    /// written by `Builder`, be mapped at their physical addresses and stay
+    /// unchanged for `'a`.
+    pub unsafe fn from_addr<'a>(addr: u64) -> Result<&'a Self, InfoError> {
+        if addr == 0 || !addr.is_multiple_of(align_of::<Self>() as u64) {
+            return Err(InfoError::BadAddress(addr));
+        }
+        let info = &*(addr as *const Self);
+        if info.magic != MAGIC {
+            return Err(InfoError::BadMagic);
+        }
+        if info.version != VERSION {
+            return Err(InfoError::Version(info.version));
+        }
+        if (info.header_size as usize) < size_of::<Self>() {
+            return Err(InfoError::Truncated);
+        }
+        str::from_utf8(info.cmdline.get()).map_err(|_| InfoError::BadCmdline)?;
+        Ok(info)
+    }
+
+    /// Physical memory, sorted by address, with no two adjacent regions of
+    /// the same kind.
+    pub fn memory_map(&self) -> &[MemoryRegion] { self.memory_map.get() }
+
+    pub fn modules(&self) -> &[Module] { self.modules.get() }
+
+    pub fn cmdline(&self) -> &str { str::from_utf8(self.cmdline.get()).unwrap_or("") }
+
+    pub fn framebuffer(&self) -> Option<&Framebuffer> { (self.framebuffer.base != 0).then_some(&self.framebuffer) }
+
+    pub fn rsdp(&self) -> Option<u64> { (self.rsdp != 0).then_some(self.rsdp) }
+
+    pub fn rng_source(&self) -> RngSource { RngSource::from_raw(self.rng_source).unwrap_or(RngSource::None) }
+
+    /// Total bytes of `RegionKind::Usable` memory.
+    pub fn usable_bytes(&self) -> u64 {
+        self.memory_map().iter().filter(|r| r.kind() == RegionKind::Usable).map(|r| r.pages * PAGE_SIZE).sum()
+    }
+}
+
+/// Bytes of area `Builder` needs for a map of up to `regions` UEFI
+/// descriptors, `modules` modules and a command line of `cmdline` bytes.
+pub const fn size_for(regions: usize, modules: usize, cmdline: usize) -> usize {
+    // Each region may be split in three around the kernel image; each part
+    // is padded to 8 bytes.
+    size_of::<BootInfo>() + (regions + 2) * size_of::<MemoryRegion>() + modules * size_of::<Module>() + cmdline + 8
+}
+
+/// Why `Builder` could not add something.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum BuildError {
+    /// The area is not 8-byte aligned.
+    Misaligned,
+    /// The area is too small.
+    Full,
+}
+
+/// Lays out a `BootInfo` in an area the kernel will find at the same
+/// (identity-mapped) address.
+pub struct Builder<'a> {
+    area: &'a mut [u8],
+    used: usize,
+    info: BootInfo,
+}
+
+impl<'a> Builder<'a> {
+    pub fn new(area: &'a mut [u8]) -> Result<Self, BuildError> {
+        if !area.as_ptr().cast::<BootInfo>().is_aligned() {
+            return Err(BuildError::Misaligned);
+        }
+        if area.len() < size_of::<BootInfo>() {
+            return Err(BuildError::Full);
+        }
+        let info = BootInfo {
+            magic: MAGIC,
+            version: VERSION,
+            header_size: size_of::<BootInfo>() as u32,
+            kernel_start: 0,
+            kernel_end: 0,
+            system_table: 0,
+            rsdp: 0,
+            framebuffer: Framebuffer::NONE,
+            rng_source: RngSource::None as u32,
+            _reserved: 0,
+            rng_seed: [0; SEED_LEN],
+            memory_map: Slice::EMPTY,
+            modules: Slice::EMPTY,
+            cmdline: Slice::EMPTY,
+        };
+        Ok(Self { area, used: size_of::<BootInfo>(), info })
+    }
+
+    /// Physical range of the kernel image; set before `memory_map`.
+    pub fn kernel(&mut self, range: Range<u64>) {
+        self.info.kernel_start = range.start;
+        self.info.kernel_end = range.end;
+    }
+
+    pub fn system_table(&mut self, addr: u64) { self.info.system_table = addr; }
+
+    pub fn rsdp(&mut self, addr: u64) { self.info.rsdp = addr; }
+
+    pub fn framebuffer(&mut self, fb: Framebuffer) { self.info.framebuffer = fb; }
+
+    pub fn rng_seed(&mut self, source: RngSource, seed: [u8; SEED_LEN]) {
+        self.info.rng_source = source as u32;
+        self.info.rng_seed = seed;
+    }
+
+    pub fn cmdline(&mut self, cmdline: &str) -> Result<(), BuildError> {
+        let (room, at) = self.room::<u8>();
+        let dst = room.get_mut(..cmdline.len()).ok_or(BuildError::Full)?;
+        dst.copy_from_slice(cmdline.as_bytes());
+        self.info.cmdline = self.commit(at, cmdline.len());
+        Ok(())
+    }
+
+    pub fn modules(&mut self, modules: &[Module]) -> Result<(), BuildError> {
+        let (room, at) = self.room::<Module>();
+        let dst = room.get_mut(..modules.len()).ok_or(BuildError::Full)?;
+        dst.copy_from_slice(modules);
+        self.info.modules = self.commit(at, modules.len());
+        Ok(())
+    }
+
+    /// Translate UEFI memory descriptors, given as (physical start, pages,
+    /// UEFI memory type), into the memory map. If they do not all fit, the
+    /// map keeps those that did and `Full` is returned; leaving memory out
+    /// only hides it from the kernel.
+    pub fn memory_map(&mut self, descriptors: impl IntoIterator<Item = (u64, u64, u32)>) -> Result<(), BuildError> {
+        let kernel = self.info.kernel_start..self.info.kernel_end;
+        let (room, at) = self.room::<MemoryRegion>();
+        let mut n = 0;
+        let mut full = false;
+        let mut push = |start: u64, end: u64, kind: RegionKind| {
+            if start >= end {
+                return;
+            }
+            match room.get_mut(n) {
+                Some(r) => {
+                    *r = MemoryRegion::new(start, (end - start) / PAGE_SIZE, kind);
+                    n += 1;
+                }
+                None => full = true,
+            }
+        };
+        for (start, pages, ty) in descriptors {
+            let end = start.saturating_add(pages.saturating_mul(PAGE_SIZE));
+            let kind = RegionKind::from_uefi(ty);
+            push(start, end.min(kernel.start), kind);
+            push(start.max(kernel.start), end.min(kernel.end), RegionKind::Kernel);
+            push(start.max(kernel.end), end, kind);
+        }
+
+        let regions = &mut room[..n];
+        regions.sort_unstable_by_key(|r| r.start);
+        let mut len: usize = 0;
+        for i in 0..regions.len() {
+            let r = regions[i];
+            match len.checked_sub(1).map(|l| &mut regions[l]) {
+                Some(last) if last.end() == r.start && last.kind == r.kind => last.pages += r.pages,
+                _ => {
+                    regions[len] = r;
+                    len += 1;
+                }
+            }
+        }
+        self.info.memory_map = self.commit(at, len);
+        if full { Err(BuildError::Full) } else { Ok(()) }
+    }
+
+    /// Write the header and return it.
+    pub fn finish(self) -> &'a BootInfo {
+        let Self { area, info, .. } = self;
+        let header = area.as_mut_ptr().cast::<BootInfo>();
+        // SAFETY: `new` checked the area is aligned and big enough, and the
+        // header's slices point into the rest of it.
+        unsafe {
+            ptr::write(header, info);
+            &*header
+        }
+    }
+
+    /// The free part of the area as `T`s, and its offset. Only used for
+    /// plain-data types that any bytes are valid for.
+    fn room<T>(&mut self) -> (&mut [T], usize) {
+        let at = self.used.next_multiple_of(align_of::<T>().max(8)).min(self.area.len());
+        let rest = &mut self.area[at..];
+        let len = rest.len() / size_of::<T>();
+        // SAFETY: `at` is aligned for `T` (the area is 8-byte aligned), the
+        // slice stays inside the area, and any bytes are a valid `T`.
+        (unsafe { slice::from_raw_parts_mut(rest.as_mut_ptr().cast::<T>(), len) }, at)
+    }
+
+    /// Claim `len` `T`s at offset `at`.
+    fn commit<T>(&mut self, at: usize, len: usize) -> Slice<T> {
+        self.used = at + len * size_of::<T>();
+        let addr = if len == 0 { 0 } else { self.area.as_ptr() as u64 + at as u64 };
+        Slice { addr, len: len as u64, _marker: PhantomData }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// An 8-byte aligned area of `len` bytes.
+    fn area(len: usize) -> Vec<u64> { std::vec![0; len.div_ceil(8)] }
+
+    fn bytes(a: &mut [u64]) -> &mut [u8] {
+        // SAFETY: reinterpreting initialized u64s as bytes.
+        unsafe { slice::from_raw_parts_mut(a.as_mut_ptr().cast::<u8>(), a.len() * 8) }
+    }
+
+    const CONVENTIONAL: u32 = 7;
+    const LOADER_CODE: u32 = 1;
+    const LOADER_DATA: u32 = 2;
+    const BOOT_DATA: u32 = 4;
+    const ACPI_NVS: u32 = 10;
+
+    #[test]
+    fn builds_and_reads_back() {
+        let mut a = area(size_for(8, 2, 32));
+        let mut b = Builder::new(bytes(&mut a)).unwrap();
+        b.kernel(0x100_0000..0x102_6000);
+        b.rsdp(0xe_0000);
+        b.framebuffer(Framebuffer::new(0x8000_0000, 800 * 600 * 4, (800, 600), 800, PixelFormat::Bgr));
+        b.rng_seed(RngSource::Rdrand, [7; SEED_LEN]);
+        b.cmdline("log=debug init=/bin/sh").unwrap();
+        b.modules(&[Module::new("bootfs", 0x200_0000, 12345), Module::new(&"ä".repeat(40), 0x300_0000, 1)]).unwrap();
+        b.memory_map([
+            (0x10_0000, 0xf00, CONVENTIONAL),
+            (0x0, 0xa0, CONVENTIONAL),
+            (0x100_0000, 0x40, LOADER_CODE),
+            (0x104_0000, 0x10, LOADER_DATA),
+            (0x105_0000, 0x100, BOOT_DATA),
+            (0x7f00_0000, 0x10, ACPI_NVS),
+            (0xfee0_0000, 1, 0x8000_0001),
+        ])
+        .unwrap();
+        let addr = b.finish() as *const BootInfo as u64;
+
+        let info = unsafe { BootInfo::from_addr(addr) }.unwrap();
+        assert_eq!((info.kernel_start, info.kernel_end, info.rsdp()), (0x100_0000, 0x102_6000, Some(0xe_0000)));
+        assert_eq!(info.framebuffer().map(|f| (f.width, f.pixel_format())), Some((800, Some(PixelFormat::Bgr))));
+        assert_eq!((info.rng_source(), info.rng_seed), (RngSource::Rdrand, [7; SEED_LEN]));
+        assert_eq!(info.cmdline(), "log=debug init=/bin/sh");
+        let names: Vec<_> = info.modules().iter().map(|m| (m.name(), m.base)).collect();
+        assert_eq!(names, [("bootfs", 0x200_0000), (&*"ä".repeat(24), 0x300_0000)]);
+
+        let map: Vec<_> = info.memory_map().iter().map(|r| (r.start, r.end(), r.kind())).collect();
+        assert_eq!(
+            map,
+            [
+                (0x0, 0xa_0000, RegionKind::Usable),
+                (0x10_0000, 0x100_0000, RegionKind::Usable),
+                (0x100_0000, 0x102_6000, RegionKind::Kernel),
+                (0x102_6000, 0x105_0000, RegionKind::Bootloader),
+                (0x105_0000, 0x115_0000, RegionKind::Usable),
+                (0x7f00_0000, 0x7f01_0000, RegionKind::AcpiNvs),
+                (0xfee0_0000, 0xfee0_1000, RegionKind::Reserved),
+            ]
+        );
+        assert_eq!(info.usable_bytes(), 0xa_0000 + 0xf0_0000 + 0x10_0000);
+    }
+
+    #[test]
+    fn rejects_bad_headers_and_small_areas() {
+        let mut a = area(size_for(1, 0, 0));
+        let addr = Builder::new(bytes(&mut a)).unwrap().finish() as *const BootInfo as u64;
+        let info = unsafe { BootInfo::from_addr(addr) }.unwrap();
+        assert!(info.memory_map().is_empty() && info.modules().is_empty() && info.cmdline().is_empty());
+        assert_eq!((info.framebuffer(), info.rsdp(), info.rng_source()), (None, None, RngSource::None));
+
+        assert_eq!(unsafe { BootInfo::from_addr(0) }.err(), Some(InfoError::BadAddress(0)));
+        assert_eq!(unsafe { BootInfo::from_addr(addr + 4) }.err(), Some(InfoError::BadAddress(addr + 4)));
+        a[1] = 2;
+        assert_eq!(unsafe { BootInfo::from_addr(addr) }.err(), Some(InfoError::Version(2)));
+        a[1] = VERSION as u64 | 64 << 32;
+        assert_eq!(unsafe { BootInfo::from_addr(addr) }.err(), Some(InfoError::Truncated));
+        a[0] = 0;
+        assert_eq!(unsafe { BootInfo::from_addr(addr) }.err(), Some(InfoError::BadMagic));
+
+        let mut a = area(size_for(1, 0, 0));
+        assert_eq!(Builder::new(&mut bytes(&mut a)[1..]).err(), Some(BuildError::Misaligned));
+        assert_eq!(Builder::new(&mut bytes(&mut a)[..64]).err(), Some(BuildError::Full));
+        let mut b = Builder::new(bytes(&mut a)).unwrap();
+        assert_eq!(b.cmdline(&"x".repeat(200)), Err(BuildError::Full));
+        // Three separate regions where only three fit: the first is split
+        // around the kernel, so one is dropped.
+        b.kernel(0x1000..0x2000);
+        let full = b.memory_map([(0, 3, CONVENTIONAL), (0x10_0000, 1, ACPI_NVS)]);
+        assert_eq!(full, Err(BuildError::Full));
+        let info = b.finish();
+        let kinds: Vec<_> = info.memory_map().iter().map(|r| r.kind()).collect();
+        assert_eq!(kinds, [RegionKind::Usable, RegionKind::Kernel, RegionKind::Usable]);
+    }
+}
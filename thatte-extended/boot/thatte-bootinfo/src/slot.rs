@@ -0,0 +1,197 @@
+//! A/B boot slots: their state, kept in UEFI variables that the loader and
+//! the kernel share, and the rule for picking one.
+//!
+//! Each slot is a directory on the ESP (`\EFI\THATTE\SLOT_A`,
+//! `\EFI\THATTE\SLOT_B`) holding a kernel and an optional bootfs. Its
+//! variable (`ThatteSlotA`, `ThatteSlotB` under `VENDOR`, non-volatile and
+//! visible at runtime) holds a `SlotState`: a priority (0 = never boot),
+//! the tries it has left, and whether it has booted successfully.
+//!
+//! The loader boots the bootable slot with the highest priority, taking a
+//! try before it loads anything unless the slot already succeeded, and
+//! tells the kernel which slot it came from on the command line
+//! (`thatte.slot=a`). The kernel marks that slot successful once it is up.
+//! A slot that never gets marked runs out of tries and the other one takes
+//! over. Whoever installs a new image into a slot writes `SlotState::NEW`
+//! (or another priority) for it.
+
+/// Vendor GUID of the slot variables, in the firmware's byte order
+/// (`4ad62b87-0e6f-4c1b-9f3d-7a52c8e10b64`).
+pub const VENDOR: [u8; 16] = [0x87, 0x2b, 0xd6, 0x4a, 0x6f, 0x0e, 0x1b, 0x4c, 0x9f, 0x3d, 0x7a, 0x52, 0xc8, 0xe1, 0x0b, 0x64];
+
+/// Variable attributes: non-volatile, boot services and runtime access.
+pub const ATTRIBUTES: u32 = 0x7;
+
+/// Bytes in a stored `SlotState`.
+pub const STATE_LEN: usize = 4;
+
+/// Layout of the stored state; bumped on any incompatible change.
+const FORMAT: u8 = 1;
+
+pub const MAX_PRIORITY: u8 = 15;
+
+/// Tries a freshly installed slot gets.
+pub const TRIES: u8 = 3;
+
+/// What the loader adds to the kernel command line, followed by the slot
+/// letter.
+pub const CMDLINE_KEY: &str = "thatte.slot=";
+
+const fn variable_name(letter: u8) -> [u16; 12] {
+    let prefix = b"ThatteSlot";
+    let mut name = [0; 12];
+    let mut i = 0;
+    while i < prefix.len() {
+        name[i] = prefix[i] as u16;
+        i += 1;
+    }
+    name[10] = letter as u16;
+    name
+}
+
+const NAME_A: [u16; 12] = variable_name(b'A');
+const NAME_B: [u16; 12] = variable_name(b'B');
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub const ALL: [Self; 2] = [Self::A, Self::B];
+
+    pub const fn other(self) -> Self {
+        match self {
+            Self::A => Self::B,
+            Self::B => Self::A,
+        }
+    }
+
+    pub const fn index(self) -> usize { self as usize }
+
+    /// `'a'` or `'b'`, as on the command line.
+    pub const fn letter(self) -> char {
+        match self {
+            Self::A => 'a',
+            Self::B => 'b',
+        }
+    }
+
+    /// Name of the slot's variable, NUL-terminated UCS-2.
+    pub const fn variable(self) -> &'static [u16; 12] {
+        match self {
+            Self::A => &NAME_A,
+            Self::B => &NAME_B,
+        }
+    }
+
+    /// The slot a command line says the kernel was booted from.
+    pub fn from_cmdline(cmdline: &str) -> Option<Self> {
+        cmdline.split_whitespace().find_map(|arg| match arg.strip_prefix(CMDLINE_KEY)? {
+            "a" => Some(Self::A),
+            "b" => Some(Self::B),
+            _ => None,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct SlotState {
+    /// 0 to `MAX_PRIORITY`; 0 means never boot.
+    pub priority: u8,
+    /// Boot attempts left before the slot is given up on, unless it
+    /// succeeds first.
+    pub tries: u8,
+    pub successful: bool,
+}
+
+impl SlotState {
+    /// A slot just installed (and what a missing variable stands for).
+    pub const NEW: Self = Self { priority: MAX_PRIORITY, tries: TRIES, successful: false };
+
+    pub const fn bootable(&self) -> bool { self.priority > 0 && (self.successful || self.tries > 0) }
+
+    /// Account for a boot attempt: a slot that has not succeeded yet loses
+    /// a try.
+    pub fn attempt(&mut self) {
+        if !self.successful {
+            self.tries = self.tries.saturating_sub(1);
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; STATE_LEN] { [FORMAT, self.priority, self.tries, self.successful as u8] }
+
+    /// Decode a stored state; `None` if it is not one this crate wrote.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match *bytes {
+            [FORMAT, priority, tries, successful @ (0 | 1)] if priority <= MAX_PRIORITY => {
+                Some(Self { priority, tries, successful: successful == 1 })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The bootable slots, best first: higher priority wins, and `preferred`
+/// wins a tie.
+pub fn boot_order(states: &[SlotState; 2], preferred: Slot) -> impl Iterator<Item = Slot> {
+    let states = *states;
+    let other = preferred.other();
+    let order = if states[other.index()].priority > states[preferred.index()].priority { [other, preferred] } else { [preferred, other] };
+    order.into_iter().filter(move |s| states[s.index()].bootable())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn order(a: SlotState, b: SlotState, preferred: Slot) -> Vec<Slot> { boot_order(&[a, b], preferred).collect() }
+
+    #[test]
+    fn unmarked_slot_runs_out_of_tries_and_rolls_back() {
+        let good = SlotState { priority: 10, tries: 0, successful: true };
+        let mut new = SlotState::NEW;
+        let mut booted = Vec::new();
+        for _ in 0..5 {
+            let slot = order(good, new, Slot::A)[0];
+            if slot == Slot::B {
+                new.attempt();
+            }
+            booted.push((slot, new.tries));
+        }
+        assert_eq!(booted, [(Slot::B, 2), (Slot::B, 1), (Slot::B, 0), (Slot::A, 0), (Slot::A, 0)]);
+
+        // Marked on its second try, it stays bootable without using more.
+        let mut new = SlotState::NEW;
+        new.attempt();
+        new.successful = true;
+        new.attempt();
+        assert_eq!((new.tries, order(good, new, Slot::A)), (2, [Slot::B, Slot::A].to_vec()));
+    }
+
+    #[test]
+    fn ties_go_to_the_preferred_slot() {
+        let n = SlotState::NEW;
+        assert_eq!(order(n, n, Slot::B), [Slot::B, Slot::A]);
+        assert_eq!(order(n, n, Slot::A), [Slot::A, Slot::B]);
+        let off = SlotState { priority: 0, ..n };
+        assert_eq!(order(n, off, Slot::B), [Slot::A]);
+        assert_eq!(order(SlotState { tries: 0, ..n }, off, Slot::A), []);
+    }
+
+    #[test]
+    fn encodes_state_names_and_cmdline() {
+        let s = SlotState { priority: 7, tries: 2, successful: true };
+        assert_eq!(SlotState::from_bytes(&s.to_bytes()), Some(s));
+        assert_eq!(SlotState::from_bytes(&[2, 7, 2, 1]), None);
+        assert_eq!(SlotState::from_bytes(&[FORMAT, 16, 2, 1]), None);
+        assert_eq!(SlotState::from_bytes(&[FORMAT, 7, 2]), None);
+
+        let name: Vec<u16> = "ThatteSlotB\0".encode_utf16().collect();
+        assert_eq!(Slot::B.variable()[..], name[..]);
+        assert_eq!(Slot::from_cmdline("quiet thatte.slot=b log=debug"), Some(Slot::B));
+        assert_eq!(Slot::from_cmdline("thatte.slot=c thatte.slot"), None);
+    }
+}
@@ -14,7 +14,7 @@
         pkgs = import nixpkgs { inherit system overlays; };
         rustToolchain = pkgs.rust-bin.nightly.latest.default.override {
           extensions = [ "rust-src" "rustfmt" ];
-          targets = [ "x86_64-unknown-uefi" "x86_64-unknown-linux-musl" ];
+          targets = [ "x86_64-unknown-uefi" "x86_64-unknown-none" "x86_64-unknown-linux-musl" ];
         };
       in {
         devShells.default = pkgs.mkShell {
@@ -0,0 +1,19 @@
+//! Compile `idl/kernel.idl` into `$OUT_DIR/kernel_idl.rs` (included by `msg`),
+//! and link the kernel image with `kernel.ld` on bare-metal targets.
+
+use std::path::PathBuf;
+
+fn main() {
+    let src = "idl/kernel.idl";
+    println!("cargo:rerun-if-changed={}", src);
+    let text = std::fs::read_to_string(src).unwrap_or_else(|e| panic!("reading {}: {}", src, e));
+    let code = thatte_idl::compile(&text).unwrap_or_else(|e| panic!("{}: {}", src, e));
+    let out = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("kernel_idl.rs");
+    std::fs::write(&out, code).unwrap_or_else(|e| panic!("writing {}: {}", out.display(), e));
+
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("none") {
+        let script = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("kernel.ld");
+        println!("cargo:rerun-if-changed=kernel.ld");
+        println!("cargo:rustc-link-arg-bin=thatte-kernel=-T{}", script.display());
+    }
+}
@@ -0,0 +1,175 @@
+//! The global descriptor table and the task state segment.
+//!
+//! Long mode ignores segment bases and limits, so the GDT only needs flat
+//! code and data descriptors for each ring, plus the TSS, which supplies the
+//! ring 0 stack for traps from user mode and the IST stacks for faults that
+//! cannot trust the current one.
+//!
+//! The order is fixed by `sysret`, which loads the user `ss` from STAR + 8
+//! and the user `cs` from STAR + 16: user data must directly precede user
+//! code.
+
+use core::arch::asm;
+
+pub const KERNEL_CODE: u16 = 0x08;
+pub const KERNEL_DATA: u16 = 0x10;
+pub const USER_DATA: u16 = 0x18 | 3;
+pub const USER_CODE: u16 = 0x20 | 3;
+pub const TSS: u16 = 0x28;
+
+/// IST slot (1-based) holding the double fault stack.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+
+const PRESENT: u8 = 0x80;
+const SEGMENT: u8 = 0x10;
+const EXECUTABLE: u8 = 0x08;
+const READ_WRITE: u8 = 0x02;
+const TSS_AVAILABLE: u8 = 0x09;
+const GRANULAR: u8 = 0x8;
+const LONG: u8 = 0x2;
+const DEFAULT_32: u8 = 0x4;
+
+/// A flat 4 GiB code or data descriptor.
+const fn segment(access: u8, flags: u8) -> u64 { 0xffff | 0xf << 48 | (access as u64) << 40 | (flags as u64) << 52 }
+
+const fn code(dpl: u8) -> u64 { segment(PRESENT | dpl << 5 | SEGMENT | EXECUTABLE | READ_WRITE, GRANULAR | LONG) }
+
+const fn data(dpl: u8) -> u64 { segment(PRESENT | dpl << 5 | SEGMENT | READ_WRITE, GRANULAR | DEFAULT_32) }
+
+/// The 64-bit task state segment.
+#[repr(C, packed(4))]
+pub struct Tss {
+    _reserved0: u32,
+    /// Stack pointers loaded on a trap from ring 1..3 into ring 0..2.
+    rsp: [u64; 3],
+    _reserved1: u64,
+    ist: [u64; 7],
+    _reserved2: u64,
+    _reserved3: u16,
+    iomap_base: u16,
+}
+
+pub const TSS_SIZE: usize = 104;
+
+const _: () = assert!(core::mem::size_of::<Tss>() == TSS_SIZE);
+
+impl Default for Tss {
+    fn default() -> Self { Self::new() }
+}
+
+impl Tss {
+    /// No stacks, and no I/O permission bitmap (the base points past the
+    /// end, so every port is denied to user mode).
+    pub const fn new() -> Self {
+        Self { _reserved0: 0, rsp: [0; 3], _reserved1: 0, ist: [0; 7], _reserved2: 0, _reserved3: 0, iomap_base: TSS_SIZE as u16 }
+    }
+
+    /// Stack top for traps from user mode.
+    pub fn set_kernel_stack(&mut self, top: u64) { self.rsp[0] = top }
+
+    /// Stack top for gates that name IST slot `index` (1..=7).
+    pub fn set_ist(&mut self, index: u8, top: u64) {
+        assert!((1..=7).contains(&index), "IST slots are 1..=7");
+        self.ist[index as usize - 1] = top;
+    }
+
+    pub fn kernel_stack(&self) -> u64 { self.rsp[0] }
+
+    pub fn ist(&self, index: u8) -> u64 { self.ist[index as usize - 1] }
+}
+
+/// The two descriptor words of a TSS at `base`.
+const fn tss_descriptor(base: u64) -> [u64; 2] {
+    let limit = TSS_SIZE as u64 - 1;
+    let low = (limit & 0xffff)
+        | (base & 0xff_ffff) << 16
+        | ((PRESENT | TSS_AVAILABLE) as u64) << 40
+        | (limit >> 16 & 0xf) << 48
+        | (base >> 24 & 0xff) << 56;
+    [low, base >> 32]
+}
+
+#[repr(C, align(16))]
+pub struct Gdt([u64; 7]);
+
+impl Gdt {
+    /// A table with only the null descriptor, for statics.
+    pub const fn empty() -> Self { Self([0; 7]) }
+
+    /// The table for one CPU, whose TSS lives at `tss`.
+    pub fn new(tss: &Tss) -> Self {
+        let [tss_low, tss_high] = tss_descriptor(tss as *const Tss as u64);
+        Self([0, code(0), data(0), data(3), code(3), tss_low, tss_high])
+    }
+
+    pub fn entries(&self) -> &[u64; 7] { &self.0 }
+
+    /// Load the table, reload every segment register from it, and load the
+    /// task register.
+    ///
+    /// # Safety
+    /// Ring 0 only. The TSS this table was built with must stay put for as
+    /// long as the table is loaded.
+    pub unsafe fn load(&'static self) {
+        let pointer = DescriptorPointer { limit: core::mem::size_of::<Self>() as u16 - 1, base: self as *const Self as u64 };
+        asm!("lgdt [{}]", in(reg) &pointer, options(readonly, nostack, preserves_flags));
+        // A far return is the only way to reload `cs` in long mode.
+        asm!(
+            "push {code}",
+            "lea {tmp}, [rip + 2f]",
+            "push {tmp}",
+            "retfq",
+            "2:",
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "mov ss, {data:x}",
+            "mov fs, {null:x}",
+            "mov gs, {null:x}",
+            code = in(reg) KERNEL_CODE as u64,
+            data = in(reg) KERNEL_DATA as u64,
+            null = in(reg) 0u64,
+            tmp = out(reg) _,
+            options(preserves_flags),
+        );
+        asm!("ltr {:x}", in(reg) TSS, options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Operand of `lgdt` and `lidt`.
+#[repr(C, packed)]
+pub(crate) struct DescriptorPointer {
+    pub(crate) limit: u16,
+    pub(crate) base: u64,
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptors_match_the_manual() {
+        assert_eq!(code(0), 0x00af_9a00_0000_ffff);
+        assert_eq!(data(0), 0x00cf_9200_0000_ffff);
+        assert_eq!(code(3), 0x00af_fa00_0000_ffff);
+        assert_eq!(data(3), 0x00cf_f200_0000_ffff);
+        let [low, high] = tss_descriptor(0xffff_8000_1234_5678);
+        assert_eq!((low, high), (0x1200_8934_5678_0067, 0xffff_8000));
+        // sysret: STAR[63:48] = KERNEL_DATA gives user ss and cs.
+        assert_eq!((KERNEL_DATA + 8) | 3, USER_DATA);
+        assert_eq!((KERNEL_DATA + 16) | 3, USER_CODE);
+    }
+
+    #[test]
+    fn gdt_points_at_its_tss() {
+        let mut tss = Tss::new();
+        tss.set_kernel_stack(0x8000);
+        tss.set_ist(DOUBLE_FAULT_IST, 0x9000);
+        assert_eq!((tss.kernel_stack(), tss.ist(1)), (0x8000, 0x9000));
+        let gdt = Gdt::new(&tss);
+        let e = gdt.entries();
+        assert_eq!(e[KERNEL_CODE as usize / 8], code(0));
+        assert_eq!(e[(USER_CODE & !3) as usize / 8], code(3));
+        let i = TSS as usize / 8;
+        assert_eq!([e[i], e[i + 1]], tss_descriptor(&tss as *const Tss as u64));
+    }
+}
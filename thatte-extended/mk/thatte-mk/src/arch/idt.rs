@@ -0,0 +1,243 @@
+//! The interrupt descriptor table and the frame the trap stubs save.
+//!
+//! Every vector enters through a small stub that pushes a uniform frame (a
+//! zero where the CPU pushes no error code, then the vector number and the
+//! general registers) and calls one Rust handler with a `TrapFrame`. The
+//! stubs are part of the kernel binary; this module only describes what
+//! they build and where the IDT sends each vector.
+
+use core::arch::asm;
+use core::fmt;
+
+use super::gdt::{DescriptorPointer, DOUBLE_FAULT_IST, KERNEL_CODE};
+
+pub const VECTORS: usize = 256;
+
+/// Vectors `0..EXCEPTIONS` are CPU exceptions; the rest are interrupts.
+pub const EXCEPTIONS: u8 = 32;
+
+pub const BREAKPOINT: u8 = 3;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const PAGE_FAULT: u8 = 14;
+
+/// Mnemonic and name of each exception vector.
+const NAMES: [(&str, &str); EXCEPTIONS as usize] = [
+    ("#DE", "divide error"),
+    ("#DB", "debug"),
+    ("NMI", "non-maskable interrupt"),
+    ("#BP", "breakpoint"),
+    ("#OF", "overflow"),
+    ("#BR", "bound range exceeded"),
+    ("#UD", "invalid opcode"),
+    ("#NM", "device not available"),
+    ("#DF", "double fault"),
+    ("#CSO", "coprocessor segment overrun"),
+    ("#TS", "invalid TSS"),
+    ("#NP", "segment not present"),
+    ("#SS", "stack-segment fault"),
+    ("#GP", "general protection fault"),
+    ("#PF", "page fault"),
+    ("#15", "reserved"),
+    ("#MF", "x87 floating-point error"),
+    ("#AC", "alignment check"),
+    ("#MC", "machine check"),
+    ("#XM", "SIMD floating-point error"),
+    ("#VE", "virtualization exception"),
+    ("#CP", "control protection"),
+    ("#22", "reserved"),
+    ("#23", "reserved"),
+    ("#24", "reserved"),
+    ("#25", "reserved"),
+    ("#26", "reserved"),
+    ("#27", "reserved"),
+    ("#HV", "hypervisor injection"),
+    ("#VC", "VMM communication"),
+    ("#SX", "security exception"),
+    ("#31", "reserved"),
+];
+
+/// Exceptions for which the CPU pushes an error code.
+pub const fn has_error_code(vector: u8) -> bool { matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30) }
+
+/// Mnemonic and name of an exception vector.
+pub fn exception_name(vector: u8) -> Option<(&'static str, &'static str)> { NAMES.get(vector as usize).copied() }
+
+/// One 16-byte IDT entry.
+#[repr(C)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Gate {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    attributes: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    _reserved: u32,
+}
+
+const _: () = assert!(core::mem::size_of::<Gate>() == 16);
+
+impl Gate {
+    /// A not-present gate: using it raises #GP (then #DF from an exception).
+    pub const MISSING: Self = Self { offset_low: 0, selector: 0, ist: 0, attributes: 0, offset_mid: 0, offset_high: 0, _reserved: 0 };
+
+    /// An interrupt gate (interrupts disabled on entry) to `handler` in the
+    /// kernel code segment, on IST stack `ist` (0 for none), reachable by
+    /// `int n` from privilege level `dpl` and below.
+    pub const fn interrupt(handler: u64, ist: u8, dpl: u8) -> Self {
+        Self {
+            offset_low: handler as u16,
+            selector: KERNEL_CODE,
+            ist: ist & 7,
+            attributes: 0x80 | (dpl & 3) << 5 | 0xe,
+            offset_mid: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            _reserved: 0,
+        }
+    }
+
+    pub const fn handler(&self) -> u64 { self.offset_low as u64 | (self.offset_mid as u64) << 16 | (self.offset_high as u64) << 32 }
+
+    pub const fn is_present(&self) -> bool { self.attributes & 0x80 != 0 }
+}
+
+#[repr(C, align(16))]
+pub struct Idt([Gate; VECTORS]);
+
+impl Default for Idt {
+    fn default() -> Self { Self::new() }
+}
+
+impl Idt {
+    pub const fn new() -> Self { Self([Gate::MISSING; VECTORS]) }
+
+    /// Route every vector to its stub, the stubs being `stride` bytes apart
+    /// from `stubs`. The double fault gets its own stack, and only the
+    /// breakpoint can be raised from user mode.
+    pub fn fill(&mut self, stubs: u64, stride: u64) {
+        for (v, gate) in self.0.iter_mut().enumerate() {
+            let ist = if v == DOUBLE_FAULT as usize { DOUBLE_FAULT_IST } else { 0 };
+            let dpl = if v == BREAKPOINT as usize { 3 } else { 0 };
+            *gate = Gate::interrupt(stubs + v as u64 * stride, ist, dpl);
+        }
+    }
+
+    pub fn gate(&self, vector: u8) -> &Gate { &self.0[vector as usize] }
+
+    /// Load this table.
+    ///
+    /// # Safety
+    /// Ring 0 only; every present gate must lead to a handler that can take
+    /// its vector.
+    pub unsafe fn load(&'static self) {
+        let pointer = DescriptorPointer { limit: core::mem::size_of::<Self>() as u16 - 1, base: self as *const Self as u64 };
+        asm!("lidt [{}]", in(reg) &pointer, options(readonly, nostack, preserves_flags));
+    }
+}
+
+/// What a trap stub saves, lowest address first.
+#[repr(C)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct TrapFrame {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub vector: u64,
+    /// The CPU's error code, or 0 for vectors without one.
+    pub error: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    pub fn is_exception(&self) -> bool { self.vector < EXCEPTIONS as u64 }
+
+    pub fn from_user(&self) -> bool { self.cs & 3 != 0 }
+}
+
+/// Page fault error code bits, as printed.
+const PAGE_FAULT_BITS: [(u64, &str); 6] =
+    [(1 << 0, "present"), (1 << 1, "write"), (1 << 2, "user"), (1 << 3, "reserved-bit"), (1 << 4, "fetch"), (1 << 5, "pkey")];
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match exception_name(self.vector as u8).filter(|_| self.is_exception()) {
+            Some((mnemonic, name)) => write!(f, "{} {} (vector {}", mnemonic, name, self.vector)?,
+            None => write!(f, "interrupt (vector {}", self.vector)?,
+        }
+        if self.is_exception() && has_error_code(self.vector as u8) {
+            write!(f, ", error {:#x}", self.error)?;
+            if self.vector == PAGE_FAULT as u64 {
+                for (bit, name) in PAGE_FAULT_BITS {
+                    if self.error & bit != 0 { write!(f, " {}", name)?; }
+                }
+            }
+        }
+        writeln!(f, ") in {} mode", if self.from_user() { "user" } else { "kernel" })?;
+        writeln!(f, "  rip {:#018x}  cs {:#06x}  rflags {:#010x}", self.rip, self.cs, self.rflags)?;
+        writeln!(f, "  rsp {:#018x}  ss {:#06x}", self.rsp, self.ss)?;
+        let regs = [
+            ("rax", self.rax), ("rbx", self.rbx), ("rcx", self.rcx), ("rdx", self.rdx),
+            ("rsi", self.rsi), ("rdi", self.rdi), ("rbp", self.rbp), ("r8", self.r8),
+            ("r9", self.r9), ("r10", self.r10), ("r11", self.r11), ("r12", self.r12),
+            ("r13", self.r13), ("r14", self.r14), ("r15", self.r15),
+        ];
+        for row in regs.chunks(4) {
+            for (name, value) in row {
+                write!(f, "  {:>3} {:#018x}", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn gates_encode_handler_and_stack() {
+        let g = Gate::interrupt(0xffff_8000_dead_beef, 1, 0);
+        assert_eq!(g.handler(), 0xffff_8000_dead_beef);
+        // SAFETY: `Gate` is 16 bytes of plain integers.
+        let words: [u64; 2] = unsafe { core::mem::transmute(g) };
+        assert_eq!(words, [0xdead_8e01_0008_beef, 0xffff_8000]);
+        assert!(!Gate::MISSING.is_present());
+
+        let mut idt = Idt::new();
+        idt.fill(0x10_0000, 16);
+        assert_eq!(idt.gate(255).handler(), 0x10_0000 + 255 * 16);
+        assert_eq!((idt.gate(DOUBLE_FAULT).ist, idt.gate(PAGE_FAULT).ist), (DOUBLE_FAULT_IST, 0));
+        assert_eq!((idt.gate(BREAKPOINT).attributes, idt.gate(PAGE_FAULT).attributes), (0xee, 0x8e));
+    }
+
+    #[test]
+    fn faults_print_readably() {
+        let f = TrapFrame { vector: 14, error: 0x6, rip: 0x10_2000, cs: 0x23, rax: 0xabc, ..TrapFrame::default() };
+        let s = f.to_string();
+        assert!(s.starts_with("#PF page fault (vector 14, error 0x6 write user) in user mode\n"), "{}", s);
+        assert!(s.contains("rip 0x0000000000102000") && s.contains("rax 0x0000000000000abc"), "{}", s);
+        let bp = TrapFrame { vector: 3, cs: 0x08, ..f };
+        assert!(bp.to_string().starts_with("#BP breakpoint (vector 3) in kernel mode"));
+        assert!(TrapFrame { vector: 48, ..f }.to_string().starts_with("interrupt (vector 48) in user mode"));
+        assert_eq!((0..EXCEPTIONS).filter(|&v| has_error_code(v)).count(), 10);
+        assert_eq!(std::mem::size_of::<TrapFrame>(), 22 * 8);
+    }
+}
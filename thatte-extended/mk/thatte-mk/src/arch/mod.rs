@@ -0,0 +1,88 @@
+//! x86_64 CPU setup: descriptor tables, the trap frame, the serial console,
+//! and the few privileged instructions the kernel needs.
+//!
+//! The table layouts are plain data and are tested on the host; only the
+//! `unsafe` functions that load them or touch ports must run in ring 0.
+
+pub mod gdt;
+pub mod idt;
+pub mod serial;
+
+use core::arch::asm;
+
+/// Write a byte to an I/O port.
+///
+/// # Safety
+/// Port writes can reprogram any device; the caller owns `port`.
+pub unsafe fn outb(port: u16, value: u8) { asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags)) }
+
+/// Read a byte from an I/O port.
+///
+/// # Safety
+/// Port reads can have side effects; the caller owns `port`.
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", in("dx") port, out("al") value, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// The faulting address of the last page fault.
+///
+/// # Safety
+/// Ring 0 only.
+pub unsafe fn cr2() -> u64 {
+    let value: u64;
+    asm!("mov {}, cr2", out(reg) value, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// Physical address of the current PML4, with CR3's flag bits cleared.
+///
+/// # Safety
+/// Ring 0 only.
+pub unsafe fn cr3() -> u64 {
+    let value: u64;
+    asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags));
+    value & 0x000f_ffff_ffff_f000
+}
+
+/// Disable interrupts.
+///
+/// # Safety
+/// Ring 0 only.
+pub unsafe fn cli() { asm!("cli", options(nomem, nostack)) }
+
+/// Enable interrupts and wait for one. `sti` holds off interrupts for one
+/// more instruction, so one arriving in between still wakes the `hlt`.
+///
+/// # Safety
+/// Ring 0 only, with an IDT loaded.
+pub unsafe fn wait_for_interrupt() { asm!("sti", "hlt", options(nomem, nostack)) }
+
+/// Stop this CPU for good.
+pub fn halt() -> ! {
+    loop {
+        // SAFETY: parking the CPU has no effect on memory.
+        unsafe { asm!("cli", "hlt", options(nomem, nostack)) }
+    }
+}
+
+/// Remap the legacy 8259 PICs to vectors 0x20..0x30 and mask every line.
+///
+/// Firmware leaves them wherever it liked, possibly on top of exception
+/// vectors; remapping first means a spurious IRQ 7 or 15 (which masking
+/// does not stop) lands on a vector the kernel treats as an interrupt.
+///
+/// # Safety
+/// Ring 0 only; the PICs must not be in use.
+pub unsafe fn disable_pic() {
+    const PIC1: u16 = 0x20;
+    const PIC2: u16 = 0xa0;
+    for (port, icw) in [(PIC1, [0x11, 0x20, 0x04, 0x01]), (PIC2, [0x11, 0x28, 0x02, 0x01])] {
+        outb(port, icw[0]);
+        for w in &icw[1..] {
+            outb(port + 1, *w);
+        }
+        outb(port + 1, 0xff);
+    }
+}
@@ -0,0 +1,66 @@
+//! 16550 UART console (COM1 on PCs and in QEMU's `-serial stdio`).
+
+use core::fmt;
+
+use super::{inb, outb};
+
+pub const COM1: u16 = 0x3f8;
+
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+/// Line status: the transmit holding register is empty.
+const THR_EMPTY: u8 = 1 << 5;
+/// Line control: the data port holds the divisor latch.
+const DLAB: u8 = 1 << 7;
+
+pub struct Serial {
+    base: u16,
+}
+
+impl Serial {
+    /// The UART at I/O port `base`.
+    ///
+    /// # Safety
+    /// Nothing else may drive the UART at `base`.
+    pub const unsafe fn new(base: u16) -> Self { Self { base } }
+
+    /// Program 115200 baud, 8N1, FIFOs on, no interrupts.
+    pub fn init(&mut self) {
+        // SAFETY: `new` gave us the UART.
+        unsafe {
+            outb(self.base + INTERRUPT_ENABLE, 0);
+            outb(self.base + LINE_CONTROL, DLAB);
+            outb(self.base + DATA, 1);
+            outb(self.base + INTERRUPT_ENABLE, 0);
+            outb(self.base + LINE_CONTROL, 0x03);
+            outb(self.base + FIFO_CONTROL, 0xc7);
+            outb(self.base + MODEM_CONTROL, 0x03);
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        // SAFETY: `new` gave us the UART.
+        unsafe {
+            while inb(self.base + LINE_STATUS) & THR_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            outb(self.base + DATA, byte);
+        }
+    }
+}
+
+impl fmt::Write for Serial {
+    /// Writes `s`, turning `\n` into `\r\n` for terminals.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            if b == b'\n' { self.write_byte(b'\r'); }
+            self.write_byte(b);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+//! Just enough of the UEFI runtime services to update the A/B slot
+//! variables. The loader never calls SetVirtualAddressMap, so the
+//! firmware's runtime code and data are still at their physical addresses,
+//! which the identity mapping covers.
+
+use thatte_bootinfo::slot::{Slot, SlotState, ATTRIBUTES, STATE_LEN, VENDOR};
+
+const SYSTEM_TABLE_SIGNATURE: u64 = u64::from_le_bytes(*b"IBI SYST");
+const RUNTIME_SERVICES_SIGNATURE: u64 = u64::from_le_bytes(*b"RUNTSERV");
+
+/// An EFI status other than success.
+pub type Status = usize;
+
+#[repr(C)]
+struct TableHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    crc32: u32,
+    reserved: u32,
+}
+
+#[repr(C)]
+struct SystemTable {
+    header: TableHeader,
+    /// Firmware vendor and revision, console handles and protocols.
+    _firmware_and_consoles: [u64; 8],
+    runtime_services: *const RuntimeServices,
+}
+
+type GetVariable = unsafe extern "efiapi" fn(*const u16, *const Guid, *mut u32, *mut usize, *mut u8) -> Status;
+type SetVariable = unsafe extern "efiapi" fn(*const u16, *const Guid, u32, usize, *const u8) -> Status;
+
+#[repr(C)]
+struct RuntimeServices {
+    header: TableHeader,
+    /// Time services, SetVirtualAddressMap and ConvertPointer.
+    _time_and_mapping: [usize; 6],
+    get_variable: GetVariable,
+    _get_next_variable_name: usize,
+    set_variable: SetVariable,
+}
+
+#[repr(C, align(8))]
+struct Guid([u8; 16]);
+
+const SLOT_VENDOR: Guid = Guid(VENDOR);
+
+pub struct Runtime(&'static RuntimeServices);
+
+impl Runtime {
+    /// The runtime services of `system_table`, if it looks like a UEFI
+    /// system table.
+    ///
+    /// # Safety
+    /// A non-zero `system_table` must be the one the loader passed, with
+    /// the firmware's runtime memory still mapped at its physical
+    /// addresses, and nothing else may call the firmware while the result
+    /// is in use.
+    pub unsafe fn new(system_table: u64) -> Option<Self> {
+        if system_table == 0 {
+            return None;
+        }
+        let st = &*(system_table as *const SystemTable);
+        if st.header.signature != SYSTEM_TABLE_SIGNATURE || st.runtime_services.is_null() {
+            return None;
+        }
+        let rt = &*st.runtime_services;
+        (rt.header.signature == RUNTIME_SERVICES_SIGNATURE).then_some(Self(rt))
+    }
+
+    /// Record that `slot` booted; `Ok(false)` if it already had.
+    pub fn mark_successful(&self, slot: Slot) -> Result<bool, Status> {
+        let name = slot.variable().as_ptr();
+        let mut buf = [0; STATE_LEN];
+        let (mut attributes, mut size) = (0, buf.len());
+        // SAFETY: see `new`; the name is NUL-terminated and every pointer
+        // is valid for the sizes given.
+        let status = unsafe { (self.0.get_variable)(name, &SLOT_VENDOR, &mut attributes, &mut size, buf.as_mut_ptr()) };
+        // A slot without a valid state boots as new; record it as such.
+        let mut state = if status == 0 { SlotState::from_bytes(&buf[..size]) } else { None }.unwrap_or(SlotState::NEW);
+        if state.successful {
+            return Ok(false);
+        }
+        state.successful = true;
+        let data = state.to_bytes();
+        // SAFETY: as above.
+        let status = unsafe { (self.0.set_variable)(name, &SLOT_VENDOR, ATTRIBUTES, data.len(), data.as_ptr()) };
+        if status == 0 { Ok(true) } else { Err(status) }
+    }
+}
@@ -0,0 +1,244 @@
+//! The thatte-mk kernel image.
+//!
+//! Built for `x86_64-unknown-none` and linked at a fixed physical address
+//! by `kernel.ld`; `make kernel` produces it and `make esp` installs it as
+//! `\EFI\THATTE\KERNEL.ELF`. The loader enters `_start` in long mode with
+//! the firmware's identity mapping and interrupts disabled, passing the
+//! address of a `thatte_bootinfo::BootInfo` in `rdi`.
+//!
+//! Phase 1 brings the CPU under the kernel's control (its own stack, GDT,
+//! TSS and IDT), reports what the loader found and its own progress on
+//! COM1, hands the usable memory to the frame allocator and the heap, marks
+//! the A/B slot it was booted from as good, and idles. Faults print the
+//! trap frame and stop the machine.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+mod efi;
+mod trap;
+
+use core::fmt::Write;
+
+use thatte_bootinfo::slot::Slot;
+use thatte_bootinfo::{BootInfo, RegionKind};
+use thatte_mk::arch::gdt::{Gdt, Tss, DOUBLE_FAULT_IST};
+use thatte_mk::arch::idt::Idt;
+use thatte_mk::arch::serial::{Serial, COM1};
+use thatte_mk::arch::{self, halt};
+use thatte_mk::cspace::{CSpaces, SlotRef};
+use thatte_mk::frame::{FrameAllocator, MemKind, MemRegion, FRAME_SIZE};
+use thatte_mk::heap::{FrameBacking, HEAP};
+use thatte_mk::paging::{AddressSpace, DirectMap};
+use thatte_mk::sync::SpinLock;
+use thatte_mk::Cap;
+
+const STACK_SIZE: usize = 64 * 1024;
+
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+static mut BOOT_STACK: Stack = Stack([0; STACK_SIZE]);
+static mut DOUBLE_FAULT_STACK: Stack = Stack([0; STACK_SIZE]);
+
+/// The boot CPU's descriptor tables: written once during startup, then
+/// only read by the CPU.
+struct Tables {
+    tss: Tss,
+    gdt: Gdt,
+    idt: Idt,
+}
+
+static mut TABLES: Tables = Tables { tss: Tss::new(), gdt: Gdt::empty(), idt: Idt::new() };
+
+/// Frame bitmap words: enough for the first 4 GiB, all of which the
+/// firmware's identity mapping covers.
+const FRAME_WORDS: usize = 16 * 1024;
+/// Real-mode leftovers the firmware may still point at; never handed out.
+const LOW_MEMORY: u64 = 1 << 20;
+
+static FRAMES: SpinLock<FrameAllocator<FRAME_WORDS>> = SpinLock::new(FrameAllocator::new());
+
+// SAFETY: the identity mapping covers every frame `FRAMES` manages, and
+// nothing frees frames the heap took.
+static mut HEAP_BACKING: FrameBacking<'static, FRAME_WORDS> = unsafe { FrameBacking::new(&FRAMES, 0) };
+
+// SAFETY: the kernel owns COM1.
+static CONSOLE: SpinLock<Serial> = SpinLock::new(unsafe { Serial::new(COM1) });
+
+/// Print to the serial console.
+macro_rules! println {
+    ($($arg:tt)*) => { $crate::print(format_args!("{}\n", format_args!($($arg)*))) };
+}
+pub(crate) use println;
+
+pub(crate) fn print(args: core::fmt::Arguments<'_>) {
+    match CONSOLE.try_lock() {
+        Some(mut c) => {
+            let _ = c.write_fmt(args);
+        }
+        // Only a fault in the middle of printing gets here; break the lock
+        // rather than hang with the report unsent.
+        // SAFETY: the holder was interrupted and is not coming back.
+        None => {
+            let _ = unsafe { Serial::new(COM1) }.write_fmt(args);
+        }
+    }
+}
+
+core::arch::global_asm!(
+    ".pushsection .text.entry, \"ax\"",
+    ".global _start",
+    "_start:",
+    "    cli",
+    "    cld",
+    "    lea rsp, [rip + {stack} + {size}]",
+    "    xor ebp, ebp",
+    "    call {main}",
+    "    ud2",
+    ".popsection",
+    stack = sym BOOT_STACK,
+    size = const STACK_SIZE,
+    main = sym kernel_main,
+);
+
+fn stack_top(stack: *const Stack) -> u64 { stack as u64 + STACK_SIZE as u64 }
+
+extern "C" fn kernel_main(boot_info: u64) -> ! {
+    CONSOLE.lock().init();
+    println!("thatte-mk {}: hello, kernel (boot info at {:#x})", env!("CARGO_PKG_VERSION"), boot_info);
+    // SAFETY: the loader left the info and everything it points at in
+    // loader memory, identity-mapped, and nothing reuses that yet.
+    let info = match unsafe { BootInfo::from_addr(boot_info) } {
+        Ok(info) => {
+            report(info);
+            Some(info)
+        }
+        Err(e) => {
+            println!("no usable boot info ({:?}); carrying on without it", e);
+            None
+        }
+    };
+
+    // SAFETY: single CPU, interrupts off, and nothing else has touched the
+    // tables; once loaded they are only read again by the CPU.
+    unsafe {
+        let tables: *mut Tables = &raw mut TABLES;
+        let t = &mut *tables;
+        t.tss.set_kernel_stack(stack_top(&raw const BOOT_STACK));
+        t.tss.set_ist(DOUBLE_FAULT_IST, stack_top(&raw const DOUBLE_FAULT_STACK));
+        t.gdt = Gdt::new(&t.tss);
+        t.idt.fill(trap::stubs(), trap::STUB_SIZE);
+        let t = &*tables;
+        t.gdt.load();
+        t.idt.load();
+        arch::disable_pic();
+    }
+    println!("gdt, tss and idt loaded; legacy PIC masked");
+
+    // Prove the trap path end to end: #BP is reported and execution resumes.
+    // SAFETY: the IDT routes vector 3 to a handler that returns.
+    unsafe { core::arch::asm!("int3") };
+
+    if let Some(info) = info {
+        init_memory(info);
+        mark_slot(info);
+    }
+
+    println!("entering idle loop");
+    let mut wakeups: u64 = 0;
+    loop {
+        // SAFETY: the IDT is loaded and every vector has a handler.
+        unsafe { arch::wait_for_interrupt() };
+        wakeups += 1;
+        if wakeups.is_power_of_two() { println!("idle: {} wakeups", wakeups); }
+    }
+}
+
+/// Print what the loader handed over.
+fn report(info: &BootInfo) {
+    let map = info.memory_map();
+    let kernel = map.iter().filter(|r| r.kind() == RegionKind::Kernel).map(|r| r.pages).sum::<u64>();
+    println!(
+        "memory: {} MiB usable in {} regions; kernel {:#x}..{:#x} ({} pages)",
+        info.usable_bytes() >> 20,
+        map.len(),
+        info.kernel_start,
+        info.kernel_end,
+        kernel
+    );
+    match info.framebuffer() {
+        Some(fb) => println!("framebuffer: {}x{} {:?}, stride {}, at {:#x}", fb.width, fb.height, fb.pixel_format(), fb.stride, fb.base),
+        None => println!("framebuffer: none"),
+    }
+    match info.rsdp() {
+        Some(rsdp) => println!("acpi: rsdp at {:#x}", rsdp),
+        None => println!("acpi: no rsdp"),
+    }
+    println!("rng seed: {:?}", info.rng_source());
+    println!("cmdline: {:?}", info.cmdline());
+    for m in info.modules() {
+        println!("module {}: {} bytes at {:#x}", m.name(), m.len, m.base);
+    }
+}
+
+/// Give the usable memory to `FRAMES` and let the heap grow from it.
+fn init_memory(info: &BootInfo) {
+    let mut frames = FRAMES.lock();
+    let map = info.memory_map().iter().map(MemRegion::from);
+    for r in map.clone().filter(|r| r.kind == MemKind::Usable) {
+        frames.manage(r.base, r.len);
+    }
+    for r in map.filter(|r| r.kind != MemKind::Usable) {
+        frames.withhold(r.base, r.len);
+    }
+    frames.withhold(0, LOW_MEMORY);
+    // The page tables we run on are boot services data, which the map calls
+    // usable.
+    // SAFETY: ring 0, and the identity mapping covers the tables CR3 leads
+    // to; they are only read here.
+    let (live, mem) = unsafe { (AddressSpace::from_root(arch::cr3()), DirectMap::new(0)) };
+    let mut tables = 0;
+    live.tables(&mem, |t| {
+        frames.withhold(t, FRAME_SIZE);
+        tables += 1;
+    });
+    let stats = frames.stats();
+    drop(frames);
+    println!("frames: {} MiB free; {} page-table frames kept for the firmware mapping", (stats.free as u64 * FRAME_SIZE) >> 20, tables);
+
+    let backing: *mut FrameBacking<'static, FRAME_WORDS> = &raw mut HEAP_BACKING;
+    // SAFETY: set once, before anything allocates, and never touched again.
+    HEAP.init(unsafe { &mut *backing });
+    // Prove the heap end to end: a capability space grows its table on it.
+    let mut cs = CSpaces::<1, 64>::new();
+    let ok = cs.insert_root(SlotRef::new(0, 63), Cap::new([0; 16])).is_ok();
+    let stats = HEAP.stats();
+    println!("heap: {} KiB from frames, cspace table {}", stats.managed_bytes >> 10, if ok { "grown" } else { "NOT grown" });
+}
+
+/// Tell the loader the slot we came from works, so it keeps booting it.
+fn mark_slot(info: &BootInfo) {
+    let Some(slot) = Slot::from_cmdline(info.cmdline()) else { return };
+    // SAFETY: the system table came from the loader, runtime services are
+    // still identity-mapped, and nothing else calls the firmware.
+    let Some(rt) = (unsafe { efi::Runtime::new(info.system_table) }) else {
+        println!("slot {}: no UEFI runtime services; not marked", slot.letter());
+        return;
+    };
+    match rt.mark_successful(slot) {
+        Ok(true) => println!("slot {}: marked successful", slot.letter()),
+        Ok(false) => println!("slot {}: already marked successful", slot.letter()),
+        Err(status) => println!("slot {}: not marked (EFI status {:#x})", slot.letter(), status),
+    }
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    // SAFETY: we are about to stop; nothing may run after this.
+    unsafe { arch::cli() };
+    println!("kernel panic: {}", info);
+    halt()
+}
@@ -0,0 +1,99 @@
+//! Trap entry stubs and the common handler.
+//!
+//! Stub `n` sits at `trap_stubs + n * STUB_SIZE`. It pushes a zero in place
+//! of the error code where the CPU pushes none, then `n`, and jumps to
+//! `trap_common`, which saves the general registers into a `TrapFrame` and
+//! calls `trap`. The CPU aligns the stack to 16 bytes before pushing its
+//! five words, and the stub adds 17 more, so the call sees an aligned stack.
+
+use thatte_mk::arch::idt::{has_error_code, TrapFrame, BREAKPOINT, EXCEPTIONS, PAGE_FAULT};
+use thatte_mk::arch::{self, halt};
+
+use crate::println;
+
+/// Bytes between consecutive stubs.
+pub const STUB_SIZE: u64 = 16;
+
+// The list of vectors with error codes must agree with `has_error_code`
+// (checked in `stubs`).
+core::arch::global_asm!(
+    ".pushsection .text.trap, \"ax\"",
+    ".balign 16",
+    ".global trap_stubs",
+    "trap_stubs:",
+    ".set vector, 0",
+    ".rept 256",
+    "    .balign 16",
+    "    .if (vector == 8) || (vector >= 10 && vector <= 14) || (vector == 17) || (vector == 21) || (vector == 29) || (vector == 30)",
+    "    .else",
+    "    push 0",
+    "    .endif",
+    "    push vector",
+    "    jmp trap_common",
+    "    .set vector, vector + 1",
+    ".endr",
+    "trap_common:",
+    "    push r15",
+    "    push r14",
+    "    push r13",
+    "    push r12",
+    "    push r11",
+    "    push r10",
+    "    push r9",
+    "    push r8",
+    "    push rbp",
+    "    push rdi",
+    "    push rsi",
+    "    push rdx",
+    "    push rcx",
+    "    push rbx",
+    "    push rax",
+    "    mov rdi, rsp",
+    "    cld",
+    "    call {trap}",
+    "    pop rax",
+    "    pop rbx",
+    "    pop rcx",
+    "    pop rdx",
+    "    pop rsi",
+    "    pop rdi",
+    "    pop rbp",
+    "    pop r8",
+    "    pop r9",
+    "    pop r10",
+    "    pop r11",
+    "    pop r12",
+    "    pop r13",
+    "    pop r14",
+    "    pop r15",
+    "    add rsp, 16",
+    "    iretq",
+    ".popsection",
+    trap = sym trap,
+);
+
+extern "C" {
+    static trap_stubs: [u8; 256 * STUB_SIZE as usize];
+}
+
+/// Address of stub 0.
+pub fn stubs() -> u64 {
+    debug_assert_eq!((0..EXCEPTIONS).filter(|&v| has_error_code(v)).count(), 10);
+    (&raw const trap_stubs) as u64
+}
+
+extern "C" fn trap(frame: &mut TrapFrame) {
+    match frame.vector as u8 {
+        BREAKPOINT => println!("trap: {}", frame),
+        v if v < EXCEPTIONS => {
+            println!("\nFATAL: {}", frame);
+            if v == PAGE_FAULT {
+                // SAFETY: we are in ring 0.
+                println!("  cr2 {:#018x}", unsafe { arch::cr2() });
+            }
+            halt()
+        }
+        // Nothing unmasks an interrupt source yet, so these are spurious.
+        v => println!("spurious interrupt on vector {}", v),
+    }
+}
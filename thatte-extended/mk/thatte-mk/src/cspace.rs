@@ -0,0 +1,438 @@
+//! Capability spaces: per-task slot tables plus the derivation tree (CDT)
+//! recording which capability was derived from which. A table lives on the
+//! heap and grows, up to a fixed size, as slots further in are used.
+//!
+//! Every occupied slot is a node in the tree. Copying or minting a cap makes
+//! the new slot a child of the source; `revoke` tears down the whole subtree
+//! below a slot, `delete` removes a single slot and promotes its children to
+//! its parent so nothing derived from it escapes revocation higher up.
+
+use crate::mint::{MintAuthority, MintError};
+use crate::{Cap, Rights};
+use alloc::vec::Vec;
+
+/// Address of a slot: a task's cspace index plus a slot index inside it.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct SlotRef {
+    pub space: u16,
+    pub slot: u16,
+}
+
+impl SlotRef {
+    pub const fn new(space: u16, slot: u16) -> Self { Self { space, slot } }
+}
+
+/// Errors returned by capability-space operations.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum CapError {
+    /// The space index is out of range.
+    BadSpace,
+    /// The slot index is out of range.
+    BadSlot,
+    /// The addressed slot holds no capability.
+    Empty,
+    /// The destination slot is already occupied.
+    Occupied,
+    /// The capability to derive from is forged or was not minted.
+    Cap(MintError),
+    /// The destination slot table could not grow.
+    NoMemory,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Entry {
+    cap: Cap,
+    parent: Option<SlotRef>,
+    first_child: Option<SlotRef>,
+    prev_sibling: Option<SlotRef>,
+    next_sibling: Option<SlotRef>,
+}
+
+impl Entry {
+    const fn root(cap: Cap) -> Self {
+        Self { cap, parent: None, first_child: None, prev_sibling: None, next_sibling: None }
+    }
+}
+
+/// The capability spaces of `TASKS` tasks with up to `SLOTS` slots each.
+///
+/// The derivation tree spans spaces: a cap copied into another task's space
+/// is still revoked together with its ancestor.
+pub struct CSpaces<const TASKS: usize, const SLOTS: usize> {
+    /// Each table is only as long as its highest slot used so far.
+    slots: [Vec<Option<Entry>>; TASKS],
+}
+
+impl<const TASKS: usize, const SLOTS: usize> Default for CSpaces<TASKS, SLOTS> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<const TASKS: usize, const SLOTS: usize> CSpaces<TASKS, SLOTS> {
+    /// Empty spaces; nothing is allocated until a slot is filled.
+    pub const fn new() -> Self { Self { slots: [const { Vec::new() }; TASKS] } }
+
+    fn check(&self, at: SlotRef) -> Result<(), CapError> {
+        if at.space as usize >= TASKS { return Err(CapError::BadSpace); }
+        if at.slot as usize >= SLOTS { return Err(CapError::BadSlot); }
+        Ok(())
+    }
+
+    fn entry(&self, at: SlotRef) -> Result<&Entry, CapError> {
+        self.check(at)?;
+        self.slots[at.space as usize].get(at.slot as usize).and_then(Option::as_ref).ok_or(CapError::Empty)
+    }
+
+    // Only called on refs that were validated when they were linked in.
+    fn node(&mut self, at: SlotRef) -> &mut Entry {
+        self.slots[at.space as usize][at.slot as usize].as_mut().expect("dangling CDT link")
+    }
+
+    fn vacant(&self, at: SlotRef) -> Result<(), CapError> {
+        self.check(at)?;
+        match self.slots[at.space as usize].get(at.slot as usize) {
+            Some(Some(_)) => Err(CapError::Occupied),
+            _ => Ok(()),
+        }
+    }
+
+    /// Store `e` in the vacant slot `at`, growing its table to reach it.
+    fn put(&mut self, at: SlotRef, e: Entry) -> Result<(), CapError> {
+        let table = &mut self.slots[at.space as usize];
+        let len = at.slot as usize + 1;
+        if table.len() < len {
+            table.try_reserve(len - table.len()).map_err(|_| CapError::NoMemory)?;
+            table.resize(len, None);
+        }
+        table[at.slot as usize] = Some(e);
+        Ok(())
+    }
+
+    // Only called on occupied slots.
+    fn clear(&mut self, at: SlotRef) { self.slots[at.space as usize][at.slot as usize] = None; }
+
+    /// Capability stored at `at`.
+    pub fn lookup(&self, at: SlotRef) -> Result<Cap, CapError> { self.entry(at).map(|e| e.cap) }
+
+    /// The slot `at` was derived from, or `None` for a root capability.
+    pub fn parent(&self, at: SlotRef) -> Result<Option<SlotRef>, CapError> { self.entry(at).map(|e| e.parent) }
+
+    /// Immediate children of `at` in derivation order (most recent first).
+    pub fn children(&self, at: SlotRef) -> Result<Children<'_, TASKS, SLOTS>, CapError> {
+        let next = self.entry(at)?.first_child;
+        Ok(Children { cs: self, next })
+    }
+
+    /// True if `at` lies strictly below `ancestor` in the derivation tree.
+    pub fn is_descendant(&self, at: SlotRef, ancestor: SlotRef) -> Result<bool, CapError> {
+        self.entry(ancestor)?;
+        let mut cur = self.entry(at)?.parent;
+        while let Some(p) = cur {
+            if p == ancestor { return Ok(true); }
+            cur = self.entry(p)?.parent;
+        }
+        Ok(false)
+    }
+
+    /// First occupied slot in `space` holding exactly `cap`.
+    pub fn find(&self, space: u16, cap: &Cap) -> Option<SlotRef> {
+        let slots = self.slots.get(space as usize)?;
+        slots.iter().position(|e| matches!(e, Some(e) if e.cap == *cap)).map(|i| SlotRef::new(space, i as u16))
+    }
+
+    /// First empty slot in `space`, which may be just past the end of its
+    /// table.
+    pub fn free_slot(&self, space: u16) -> Option<SlotRef> {
+        let slots = self.slots.get(space as usize)?;
+        let i = slots.iter().position(|e| e.is_none()).unwrap_or(slots.len());
+        (i < SLOTS).then(|| SlotRef::new(space, i as u16))
+    }
+
+    /// Install an original capability (a new tree root) at `at`.
+    pub fn insert_root(&mut self, at: SlotRef, cap: Cap) -> Result<(), CapError> {
+        self.vacant(at)?;
+        self.put(at, Entry::root(cap))
+    }
+
+    /// Copy the capability at `src` into `dst` as a child of `src`.
+    pub fn copy(&mut self, src: SlotRef, dst: SlotRef) -> Result<(), CapError> {
+        let cap = self.lookup(src)?;
+        self.mint(src, dst, cap)
+    }
+
+    /// Store `cap` at `dst` as a child of `src` (a derived capability with its
+    /// own bytes, e.g. a badged or attenuated version of the source).
+    pub fn mint(&mut self, src: SlotRef, dst: SlotRef, cap: Cap) -> Result<(), CapError> {
+        self.entry(src)?;
+        self.vacant(dst)?;
+        self.put(dst, Entry::root(cap))?;
+        self.link_child(src, dst);
+        Ok(())
+    }
+
+    /// Copy the capability at `src` into `dst` restricted to `mask`: the child
+    /// never holds rights its parent lacks. The child is re-minted by `mint`
+    /// (the MAC covers the rights), so it verifies like its parent.
+    pub fn derive(&mut self, mint: &MintAuthority, src: SlotRef, dst: SlotRef, mask: Rights) -> Result<(), CapError> {
+        let cap = mint.attenuate(&self.lookup(src)?, mask).map_err(CapError::Cap)?;
+        self.mint(src, dst, cap)
+    }
+
+    /// Move the capability at `src` to `dst`, keeping its place in the tree.
+    pub fn move_cap(&mut self, src: SlotRef, dst: SlotRef) -> Result<(), CapError> {
+        let e = *self.entry(src)?;
+        self.vacant(dst)?;
+        self.put(dst, e)?;
+        self.clear(src);
+        match e.prev_sibling {
+            Some(p) => self.node(p).next_sibling = Some(dst),
+            None => if let Some(p) = e.parent { self.node(p).first_child = Some(dst); },
+        }
+        if let Some(n) = e.next_sibling { self.node(n).prev_sibling = Some(dst); }
+        let mut c = e.first_child;
+        while let Some(ch) = c {
+            let n = self.node(ch);
+            n.parent = Some(dst);
+            c = n.next_sibling;
+        }
+        Ok(())
+    }
+
+    /// Remove the capability at `at`. Its children are promoted to its parent
+    /// (or become roots) so they stay reachable by an ancestor's revoke.
+    pub fn delete(&mut self, at: SlotRef) -> Result<Cap, CapError> {
+        let e = *self.entry(at)?;
+        self.unlink(at);
+        let mut c = e.first_child;
+        while let Some(ch) = c {
+            let next = self.node(ch).next_sibling;
+            let n = self.node(ch);
+            n.parent = None;
+            n.prev_sibling = None;
+            n.next_sibling = None;
+            if let Some(p) = e.parent { self.link_child(p, ch); }
+            c = next;
+        }
+        self.clear(at);
+        Ok(e.cap)
+    }
+
+    /// Delete every capability derived (transitively) from `at`, keeping `at`
+    /// itself. Returns the number of slots cleared.
+    pub fn revoke(&mut self, at: SlotRef) -> Result<usize, CapError> {
+        self.entry(at)?;
+        let mut n = 0;
+        while let Some(mut leaf) = self.node(at).first_child {
+            while let Some(c) = self.node(leaf).first_child { leaf = c; }
+            self.unlink(leaf);
+            self.clear(leaf);
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// Number of occupied slots in `space`.
+    pub fn occupied(&self, space: u16) -> usize {
+        self.slots.get(space as usize).map_or(0, |s| s.iter().filter(|e| e.is_some()).count())
+    }
+
+    fn link_child(&mut self, parent: SlotRef, child: SlotRef) {
+        let head = self.node(parent).first_child;
+        {
+            let c = self.node(child);
+            c.parent = Some(parent);
+            c.prev_sibling = None;
+            c.next_sibling = head;
+        }
+        if let Some(h) = head { self.node(h).prev_sibling = Some(child); }
+        self.node(parent).first_child = Some(child);
+    }
+
+    fn unlink(&mut self, at: SlotRef) {
+        let e = *self.node(at);
+        match e.prev_sibling {
+            Some(p) => self.node(p).next_sibling = e.next_sibling,
+            None => if let Some(p) = e.parent { self.node(p).first_child = e.next_sibling; },
+        }
+        if let Some(n) = e.next_sibling { self.node(n).prev_sibling = e.prev_sibling; }
+        let n = self.node(at);
+        n.parent = None;
+        n.prev_sibling = None;
+        n.next_sibling = None;
+    }
+}
+
+/// Iterator over the immediate children of a slot.
+pub struct Children<'a, const TASKS: usize, const SLOTS: usize> {
+    cs: &'a CSpaces<TASKS, SLOTS>,
+    next: Option<SlotRef>,
+}
+
+impl<const TASKS: usize, const SLOTS: usize> Iterator for Children<'_, TASKS, SLOTS> {
+    type Item = SlotRef;
+    fn next(&mut self) -> Option<SlotRef> {
+        let cur = self.next?;
+        self.next = self.cs.entry(cur).ok().and_then(|e| e.next_sibling);
+        Some(cur)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::HashMap;
+    use std::vec::Vec;
+
+    const T: usize = 3;
+    const S: usize = 6;
+    type Cs = CSpaces<T, S>;
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Root(SlotRef, u8),
+        Copy(SlotRef, SlotRef),
+        Move(SlotRef, SlotRef),
+        Delete(SlotRef),
+        Revoke(SlotRef),
+    }
+
+    fn slot() -> impl Strategy<Value = SlotRef> {
+        (0..T as u16, 0..S as u16).prop_map(|(a, b)| SlotRef::new(a, b))
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (slot(), any::<u8>()).prop_map(|(s, b)| Op::Root(s, b)),
+            (slot(), slot()).prop_map(|(a, b)| Op::Copy(a, b)),
+            (slot(), slot()).prop_map(|(a, b)| Op::Move(a, b)),
+            slot().prop_map(Op::Delete),
+            slot().prop_map(Op::Revoke),
+        ]
+    }
+
+    /// Reference model: slot -> (cap, parent).
+    type Model = HashMap<SlotRef, (Cap, Option<SlotRef>)>;
+
+    fn model_is_below(m: &Model, mut at: SlotRef, anc: SlotRef) -> bool {
+        while let Some(&(_, Some(p))) = m.get(&at) {
+            if p == anc { return true; }
+            at = p;
+        }
+        false
+    }
+
+    fn apply_model(m: &mut Model, op: &Op) {
+        match *op {
+            Op::Root(s, b) => { m.entry(s).or_insert((Cap::new([b; 16]), None)); }
+            Op::Copy(a, b) => {
+                if let Some(&(cap, _)) = m.get(&a) { m.entry(b).or_insert((cap, Some(a))); }
+            }
+            Op::Move(a, b) => {
+                if m.contains_key(&a) && !m.contains_key(&b) {
+                    let e = m.remove(&a).unwrap();
+                    m.insert(b, e);
+                    for v in m.values_mut() { if v.1 == Some(a) { v.1 = Some(b); } }
+                }
+            }
+            Op::Delete(a) => {
+                if let Some((_, p)) = m.remove(&a) {
+                    for v in m.values_mut() { if v.1 == Some(a) { v.1 = p; } }
+                }
+            }
+            Op::Revoke(a) => {
+                if m.contains_key(&a) {
+                    let doomed: Vec<_> = m.keys().copied().filter(|&k| model_is_below(m, k, a)).collect();
+                    for k in doomed { m.remove(&k); }
+                }
+            }
+        }
+    }
+
+    fn apply(cs: &mut Cs, op: &Op) {
+        let _ = match *op {
+            Op::Root(s, b) => cs.insert_root(s, Cap::new([b; 16])),
+            Op::Copy(a, b) => cs.copy(a, b),
+            Op::Move(a, b) => cs.move_cap(a, b),
+            Op::Delete(a) => cs.delete(a).map(|_| ()),
+            Op::Revoke(a) => cs.revoke(a).map(|_| ()),
+        };
+    }
+
+    #[test]
+    fn revoke_clears_subtree_across_spaces() {
+        let mut cs = Cs::new();
+        let root = SlotRef::new(0, 0);
+        cs.insert_root(root, Cap::new([1; 16])).unwrap();
+        cs.copy(root, SlotRef::new(1, 0)).unwrap();
+        cs.copy(SlotRef::new(1, 0), SlotRef::new(2, 3)).unwrap();
+        cs.copy(root, SlotRef::new(0, 1)).unwrap();
+        assert!(cs.is_descendant(SlotRef::new(2, 3), root).unwrap());
+        assert_eq!(cs.revoke(root).unwrap(), 3);
+        assert_eq!(cs.lookup(SlotRef::new(2, 3)), Err(CapError::Empty));
+        assert_eq!(cs.lookup(root), Ok(Cap::new([1; 16])));
+        assert_eq!(cs.children(root).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn derive_attenuates_and_re_mints() {
+        let auth = MintAuthority::new([5; 16]);
+        let mut cs = Cs::new();
+        let root = SlotRef::new(0, 0);
+        cs.insert_root(root, auth.mint(7, 2, Rights::READ | Rights::CALL)).unwrap();
+        cs.derive(&auth, root, SlotRef::new(1, 0), Rights::CALL | Rights::GRANT).unwrap();
+        let child = cs.lookup(SlotRef::new(1, 0)).unwrap();
+        assert_eq!(child.rights(), Rights::CALL);
+        assert_eq!(auth.verify_current(&child, 2).map(|m| (m.object, m.rights)), Ok((7, Rights::CALL)));
+        assert_eq!(cs.parent(SlotRef::new(1, 0)), Ok(Some(root)));
+
+        // A cap the authority never minted cannot be derived from.
+        cs.insert_root(SlotRef::new(2, 0), Cap::new([1; 16]).with_rights(Rights::ALL)).unwrap();
+        assert_eq!(cs.derive(&auth, SlotRef::new(2, 0), SlotRef::new(2, 1), Rights::READ), Err(CapError::Cap(MintError::Forged)));
+        assert_eq!(cs.lookup(SlotRef::new(2, 1)), Err(CapError::Empty));
+    }
+
+    #[test]
+    fn tables_grow_on_demand() {
+        let mut cs = Cs::new();
+        assert_eq!(cs.free_slot(0), Some(SlotRef::new(0, 0)));
+        cs.insert_root(SlotRef::new(0, 3), Cap::new([1; 16])).unwrap();
+        assert_eq!(cs.slots[0].len(), 4);
+        assert!(cs.slots[1].is_empty());
+        assert_eq!(cs.free_slot(0), Some(SlotRef::new(0, 0)));
+        for i in [0, 1, 2, 4, 5] {
+            cs.copy(SlotRef::new(0, 3), SlotRef::new(0, i)).unwrap();
+        }
+        assert_eq!(cs.free_slot(0), None);
+        assert_eq!(cs.occupied(0), S);
+        assert_eq!(cs.insert_root(SlotRef::new(0, S as u16), Cap::new([2; 16])), Err(CapError::BadSlot));
+        assert_eq!(cs.revoke(SlotRef::new(0, 3)), Ok(5));
+        assert_eq!(cs.free_slot(0), Some(SlotRef::new(0, 0)));
+    }
+
+    proptest! {
+        #[test]
+        fn cdt_matches_model(ops in proptest::collection::vec(op(), 1..64)) {
+            let mut cs = Cs::new();
+            let mut m = Model::new();
+            for op in &ops {
+                apply(&mut cs, op);
+                apply_model(&mut m, op);
+                for sp in 0..T as u16 {
+                    for sl in 0..S as u16 {
+                        let at = SlotRef::new(sp, sl);
+                        match m.get(&at) {
+                            Some(&(cap, parent)) => {
+                                prop_assert_eq!(cs.lookup(at), Ok(cap));
+                                prop_assert_eq!(cs.parent(at), Ok(parent));
+                                for ch in cs.children(at).unwrap() {
+                                    prop_assert_eq!(m.get(&ch).and_then(|e| e.1), Some(at));
+                                }
+                            }
+                            None => prop_assert_eq!(cs.lookup(at), Err(CapError::Empty)),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
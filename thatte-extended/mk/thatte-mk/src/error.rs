@@ -0,0 +1,115 @@
+//! Why a message, header or field failed to decode.
+//!
+//! Every decoder in this crate reports a `ParseError`, so the kernel and
+//! services can log and count rejections by reason (`code` is a dense index
+//! for counter arrays).
+
+/// A decoding failure.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ParseError {
+    /// The buffer ends before the data it must hold.
+    Truncated { needed: usize, got: usize },
+    /// The buffer continues past the end of the message.
+    TrailingBytes { expected: usize, got: usize },
+    /// The buffer does not start with `MAGIC`.
+    BadMagic([u8; 4]),
+    /// A protocol version this build does not speak.
+    Version(u16),
+    /// The header was corrupted (stored vs. computed CRC-32C).
+    Checksum { stored: u32, computed: u32 },
+    /// Undefined flag bits, or flags that contradict the rest of the header
+    /// or the decoder used.
+    BadFlags(u16),
+    /// `ty` is not a known `MsgType`.
+    UnknownType(u16),
+    /// A reply where a request was expected, or the other way round.
+    WrongDirection(u16),
+    /// A length (declared, or of the buffer itself) larger than a message
+    /// can be.
+    LengthOverflow(u32),
+    /// The declared payload length does not match the body of its type.
+    LengthMismatch { declared: u32, expected: usize },
+    /// More transfer entries than `msg::MAX_CAPS`.
+    TooManyCaps(u16),
+    /// The transfer-section size does not match the body of its type.
+    CapCountMismatch { declared: u16, expected: usize },
+    /// Transfer entry `index` has an unknown mode or nonzero reserved byte.
+    BadCapEntry { index: usize },
+    /// The body field at byte `offset` holds a non-canonical value.
+    BadField { offset: usize },
+}
+
+impl ParseError {
+    /// Number of distinct `code` values.
+    pub const COUNT: usize = 14;
+
+    /// Stable index of the variant, in `0..COUNT`.
+    pub const fn code(&self) -> usize {
+        match self {
+            Self::Truncated { .. } => 0,
+            Self::TrailingBytes { .. } => 1,
+            Self::BadMagic(_) => 2,
+            Self::Version(_) => 3,
+            Self::Checksum { .. } => 4,
+            Self::BadFlags(_) => 5,
+            Self::UnknownType(_) => 6,
+            Self::WrongDirection(_) => 7,
+            Self::LengthOverflow(_) => 8,
+            Self::LengthMismatch { .. } => 9,
+            Self::TooManyCaps(_) => 10,
+            Self::CapCountMismatch { .. } => 11,
+            Self::BadCapEntry { .. } => 12,
+            Self::BadField { .. } => 13,
+        }
+    }
+}
+
+impl core::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {
+            Self::Truncated { needed, got } => write!(f, "truncated: need {} bytes, got {}", needed, got),
+            Self::TrailingBytes { expected, got } => write!(f, "trailing bytes: expected {}, got {}", expected, got),
+            Self::BadMagic(m) => write!(f, "bad magic {:02x?}", m),
+            Self::Version(v) => write!(f, "unsupported version {}", v),
+            Self::Checksum { stored, computed } => write!(f, "checksum {:#010x} != {:#010x}", stored, computed),
+            Self::BadFlags(b) => write!(f, "bad flags {:#06x}", b),
+            Self::UnknownType(t) => write!(f, "unknown message type {:#06x}", t),
+            Self::WrongDirection(t) => write!(f, "unexpected direction for type {:#06x}", t),
+            Self::LengthOverflow(l) => write!(f, "length {} too large", l),
+            Self::LengthMismatch { declared, expected } => write!(f, "length {} != {}", declared, expected),
+            Self::TooManyCaps(n) => write!(f, "{} caps attached", n),
+            Self::CapCountMismatch { declared, expected } => write!(f, "{} caps != {}", declared, expected),
+            Self::BadCapEntry { index } => write!(f, "bad cap entry {}", index),
+            Self::BadField { offset } => write!(f, "bad field at body offset {}", offset),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_dense() {
+        let all = [
+            ParseError::Truncated { needed: 1, got: 0 },
+            ParseError::TrailingBytes { expected: 0, got: 1 },
+            ParseError::BadMagic([0; 4]),
+            ParseError::Version(0),
+            ParseError::Checksum { stored: 0, computed: 1 },
+            ParseError::BadFlags(0),
+            ParseError::UnknownType(0),
+            ParseError::WrongDirection(0),
+            ParseError::LengthOverflow(0),
+            ParseError::LengthMismatch { declared: 0, expected: 1 },
+            ParseError::TooManyCaps(0),
+            ParseError::CapCountMismatch { declared: 0, expected: 1 },
+            ParseError::BadCapEntry { index: 0 },
+            ParseError::BadField { offset: 0 },
+        ];
+        assert_eq!(all.len(), ParseError::COUNT);
+        for (i, e) in all.iter().enumerate() {
+            assert_eq!(e.code(), i);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! `IpcHeader.flags`: how a message is to be delivered and how its payload
+//! is laid out.
+//!
+//! Every bit has one meaning for every service; `parse_header` refuses
+//! undefined bits and combinations that contradict the rest of the header,
+//! and the IPC path enforces the per-operation rules below.
+
+/// Flags carried in every `IpcHeader`.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub struct MsgFlags(u16);
+
+impl MsgFlags {
+    /// The sender waits for an answer. Set on every `call` and on no other
+    /// operation, so a receiver can trust it to mean a reply cap came along.
+    pub const REPLY_EXPECTED: Self = Self(1 << 0);
+    /// Fail with `WouldBlock` instead of blocking the sender when the
+    /// endpoint queue is full. Only meaningful for send and call.
+    pub const NON_BLOCKING: Self = Self(1 << 1);
+    /// The message has a capability-transfer section. Must be set exactly
+    /// when `caps` is nonzero; sending it needs GRANT on the endpoint cap.
+    pub const GRANT_CAPS: Self = Self(1 << 2);
+    /// The payload lives in a shared-memory region: the inline body is a
+    /// `msg::ShmPayload` descriptor and the region's cap is the first
+    /// transferred cap. Requires `GRANT_CAPS`.
+    pub const SHM_PAYLOAD: Self = Self(1 << 3);
+    /// The reply reports a failure: the body is a `msg::ErrorBody` instead of
+    /// the method's reply. Only valid on replies.
+    pub const ERROR: Self = Self(1 << 4);
+
+    pub const NONE: Self = Self(0);
+    pub const ALL: Self = Self(0x1f);
+
+    /// Build from raw bits; `None` if any undefined bit is set.
+    pub const fn from_bits(bits: u16) -> Option<Self> {
+        if bits & !Self::ALL.0 != 0 { None } else { Some(Self(bits)) }
+    }
+    pub const fn bits(self) -> u16 { self.0 }
+    pub const fn is_empty(self) -> bool { self.0 == 0 }
+    pub const fn contains(self, other: Self) -> bool { self.0 & other.0 == other.0 }
+    pub const fn union(self, other: Self) -> Self { Self(self.0 | other.0) }
+    pub const fn intersection(self, other: Self) -> Self { Self(self.0 & other.0) }
+    /// Flags in `self` that are not in `other`.
+    pub const fn difference(self, other: Self) -> Self { Self(self.0 & !other.0) }
+}
+
+impl core::ops::BitOr for MsgFlags {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self { self.union(rhs) }
+}
+
+impl core::ops::BitAnd for MsgFlags {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self { self.intersection(rhs) }
+}
+
+impl core::fmt::Debug for MsgFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        const NAMES: [(MsgFlags, char); 5] = [
+            (MsgFlags::REPLY_EXPECTED, 'r'), (MsgFlags::NON_BLOCKING, 'n'), (MsgFlags::GRANT_CAPS, 'g'),
+            (MsgFlags::SHM_PAYLOAD, 's'), (MsgFlags::ERROR, 'e'),
+        ];
+        write!(f, "MsgFlags(")?;
+        for (m, c) in NAMES {
+            let c = if self.contains(m) { c } else { '-' };
+            write!(f, "{}", c)?;
+        }
+        write!(f, ")")
+    }
+}
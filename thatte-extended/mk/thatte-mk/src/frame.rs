@@ -0,0 +1,372 @@
+//! Physical frame allocator.
+//!
+//! A bitmap over the first `WORDS * 64` 4 KiB frames of physical memory,
+//! seeded from the boot memory map. Frames outside usable regions, or inside
+//! a reserved range, are never handed out. 2 MiB allocations take 512
+//! consecutive free frames on a 2 MiB boundary.
+//!
+//! Two bitmaps are kept: which frames are RAM the allocator manages, and
+//! which of those are in use. Freeing checks both, so double frees and
+//! frees of memory the allocator never owned are reported instead of
+//! corrupting the pool.
+
+pub const FRAME_SIZE: u64 = 4096;
+pub const LARGE_FRAME_SIZE: u64 = 2 << 20;
+
+const FRAMES_PER_LARGE: usize = (LARGE_FRAME_SIZE / FRAME_SIZE) as usize;
+const WORDS_PER_LARGE: usize = FRAMES_PER_LARGE / 64;
+
+pub type PhysAddr = u64;
+
+/// What a range of physical memory holds, as reported by the firmware.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MemKind {
+    /// Free RAM.
+    Usable,
+    /// Anything the kernel must not allocate from.
+    Reserved,
+}
+
+/// One entry of the boot memory map.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct MemRegion {
+    pub base: PhysAddr,
+    pub len: u64,
+    pub kind: MemKind,
+}
+
+impl From<&thatte_bootinfo::MemoryRegion> for MemRegion {
+    /// Only `Usable` memory is free at boot: loader memory holds the boot
+    /// info and modules until the kernel is done with them.
+    fn from(r: &thatte_bootinfo::MemoryRegion) -> Self {
+        let kind = if r.kind() == thatte_bootinfo::RegionKind::Usable { MemKind::Usable } else { MemKind::Reserved };
+        Self { base: r.start, len: r.pages * FRAME_SIZE, kind }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum FrameSize {
+    Size4K,
+    Size2M,
+}
+
+impl FrameSize {
+    pub const fn bytes(self) -> u64 {
+        match self {
+            Self::Size4K => FRAME_SIZE,
+            Self::Size2M => LARGE_FRAME_SIZE,
+        }
+    }
+
+    const fn frames(self) -> usize { (self.bytes() / FRAME_SIZE) as usize }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum FrameError {
+    /// The address is not aligned to the frame size.
+    Misaligned,
+    /// Some frame in the range is not RAM this allocator manages.
+    NotManaged,
+    /// Some frame in the range is already free.
+    DoubleFree,
+}
+
+/// Frame counts (multiply by `FRAME_SIZE` for bytes).
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct FrameStats {
+    /// Frames the allocator manages.
+    pub total: usize,
+    pub free: usize,
+    pub used: usize,
+}
+
+/// Bitmap allocator over `WORDS * 64` frames starting at physical 0.
+pub struct FrameAllocator<const WORDS: usize> {
+    managed: [u64; WORDS],
+    used: [u64; WORDS],
+    /// Word to start the next 4 KiB search at.
+    hint: usize,
+}
+
+impl<const WORDS: usize> Default for FrameAllocator<WORDS> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<const WORDS: usize> FrameAllocator<WORDS> {
+    /// Highest address (exclusive) the allocator can track.
+    pub const LIMIT: PhysAddr = (WORDS * 64) as u64 * FRAME_SIZE;
+
+    /// An allocator that manages nothing yet.
+    pub const fn new() -> Self { Self { managed: [0; WORDS], used: [0; WORDS], hint: 0 } }
+
+    /// Build from a boot memory map, then withhold each `reserved` range (the
+    /// kernel image, boot modules, ...). Usable regions are shrunk inward to
+    /// whole frames and reserved ones grown outward; `Reserved` entries win
+    /// over overlapping `Usable` ones.
+    pub fn from_map(map: &[MemRegion], reserved: &[(PhysAddr, u64)]) -> Self {
+        let mut a = Self::new();
+        for r in map.iter().filter(|r| r.kind == MemKind::Usable) {
+            a.manage(r.base, r.len);
+        }
+        let withheld = map.iter().filter(|r| r.kind != MemKind::Usable).map(|r| (r.base, r.len));
+        for (base, len) in withheld.chain(reserved.iter().copied()) {
+            a.withhold(base, len);
+        }
+        a
+    }
+
+    /// Hand the whole frames in `len` bytes at `base` to the allocator.
+    /// With `withhold`, this does what `from_map` does in place, for an
+    /// allocator that lives in a static.
+    pub fn manage(&mut self, base: PhysAddr, len: u64) {
+        self.set_range(base.div_ceil(FRAME_SIZE), base.saturating_add(len) / FRAME_SIZE, true);
+    }
+
+    /// Take back every frame that overlaps `len` bytes at `base`.
+    pub fn withhold(&mut self, base: PhysAddr, len: u64) {
+        self.set_range(base / FRAME_SIZE, base.saturating_add(len).div_ceil(FRAME_SIZE), false);
+    }
+
+    /// Mark frames `start..end` (clamped to the bitmap) as managed or not.
+    fn set_range(&mut self, start: u64, end: u64, managed: bool) {
+        let limit = (WORDS * 64) as u64;
+        for f in start.min(limit)..end.min(limit) {
+            let (w, b) = (f as usize / 64, f % 64);
+            if managed { self.managed[w] |= 1 << b } else { self.managed[w] &= !(1 << b) }
+        }
+    }
+
+    /// Bits of word `w` that are managed and free.
+    fn free_bits(&self, w: usize) -> u64 { self.managed[w] & !self.used[w] }
+
+    /// Allocate one frame of `size`; returns its physical address.
+    pub fn alloc(&mut self, size: FrameSize) -> Option<PhysAddr> {
+        match size {
+            FrameSize::Size4K => {
+                let w = (0..WORDS).map(|i| (self.hint + i) % WORDS).find(|&w| self.free_bits(w) != 0)?;
+                let b = self.free_bits(w).trailing_zeros() as usize;
+                self.used[w] |= 1 << b;
+                self.hint = w;
+                Some((w * 64 + b) as u64 * FRAME_SIZE)
+            }
+            FrameSize::Size2M => {
+                let first = (0..WORDS / WORDS_PER_LARGE)
+                    .map(|l| l * WORDS_PER_LARGE)
+                    .find(|&w| (w..w + WORDS_PER_LARGE).all(|i| self.free_bits(i) == u64::MAX))?;
+                self.used[first..first + WORDS_PER_LARGE].fill(u64::MAX);
+                Some(first as u64 * 64 * FRAME_SIZE)
+            }
+        }
+    }
+
+    /// Allocate `count` consecutive 4 KiB frames; returns the address of the
+    /// first. Each is freed on its own as a `Size4K` frame.
+    pub fn alloc_run(&mut self, count: usize) -> Option<PhysAddr> {
+        if count == 0 { return None; }
+        let mut run = 0;
+        let last = (0..WORDS * 64).find(|&f| {
+            run = if self.free_bits(f / 64) >> (f % 64) & 1 == 1 { run + 1 } else { 0 };
+            run == count
+        })?;
+        let first = last + 1 - count;
+        for f in first..=last {
+            self.used[f / 64] |= 1 << (f % 64);
+        }
+        Some(first as u64 * FRAME_SIZE)
+    }
+
+    /// Return a frame of `size` at `addr`. Nothing changes on error.
+    pub fn free(&mut self, addr: PhysAddr, size: FrameSize) -> Result<(), FrameError> {
+        if !addr.is_multiple_of(size.bytes()) { return Err(FrameError::Misaligned); }
+        if addr.saturating_add(size.bytes()) > Self::LIMIT { return Err(FrameError::NotManaged); }
+        let first = (addr / FRAME_SIZE) as usize;
+        let frames = first..first + size.frames();
+        let bit = |f: usize, map: &[u64; WORDS]| map[f / 64] >> (f % 64) & 1 == 1;
+        if !frames.clone().all(|f| bit(f, &self.managed)) { return Err(FrameError::NotManaged); }
+        if !frames.clone().all(|f| bit(f, &self.used)) { return Err(FrameError::DoubleFree); }
+        for f in frames {
+            self.used[f / 64] &= !(1 << (f % 64));
+        }
+        self.hint = self.hint.min(first / 64);
+        Ok(())
+    }
+
+    /// Whether the frame containing `addr` is managed and allocated.
+    pub fn is_used(&self, addr: PhysAddr) -> bool {
+        let f = (addr / FRAME_SIZE) as usize;
+        f < WORDS * 64 && (self.managed[f / 64] & self.used[f / 64]) >> (f % 64) & 1 == 1
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        let count = |m: &dyn Fn(usize) -> u64| (0..WORDS).map(|w| m(w).count_ones() as usize).sum::<usize>();
+        let total = count(&|w| self.managed[w]);
+        let used = count(&|w| self.managed[w] & self.used[w]);
+        FrameStats { total, free: total - used, used }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    /// 16 MiB worth of frames.
+    type Frames = FrameAllocator<64>;
+
+    const MIB: u64 = 1 << 20;
+
+    fn usable(base: u64, len: u64) -> MemRegion { MemRegion { base, len, kind: MemKind::Usable } }
+
+    #[test]
+    fn map_and_reserved_ranges_are_honored() {
+        let map = [
+            usable(0x1000 + 12, 3 * FRAME_SIZE),
+            usable(4 * MIB, 8 * MIB),
+            MemRegion { base: 6 * MIB, len: 100, kind: MemKind::Reserved },
+            usable(15 * MIB, 4 * MIB),
+        ];
+        let a = Frames::from_map(&map, &[(8 * MIB, MIB)]);
+        // 2 partial-page frames, 8 MiB less 1 + 1 MiB, and 1 MiB below the limit.
+        let expect = 2 + (8 * MIB / FRAME_SIZE) as usize - 1 - (MIB / FRAME_SIZE) as usize + (MIB / FRAME_SIZE) as usize;
+        assert_eq!(a.stats(), FrameStats { total: expect, free: expect, used: 0 });
+
+        let mut a = a;
+        let mut seen = std::vec::Vec::new();
+        while let Some(f) = a.alloc(FrameSize::Size4K) {
+            assert!(!(6 * MIB..6 * MIB + FRAME_SIZE).contains(&f) && !(8 * MIB..9 * MIB).contains(&f));
+            seen.push(f);
+        }
+        assert_eq!((seen[0], seen.len()), (0x2000, expect));
+        assert_eq!(a.stats().free, 0);
+        assert_eq!(a.free(6 * MIB, FrameSize::Size4K), Err(FrameError::NotManaged));
+        assert_eq!(a.free(0x2001, FrameSize::Size4K), Err(FrameError::Misaligned));
+    }
+
+    #[test]
+    fn large_frames_need_an_aligned_free_run() {
+        let mut a = Frames::from_map(&[usable(MIB, 5 * MIB)], &[]);
+        assert_eq!(a.alloc(FrameSize::Size2M), Some(2 * MIB));
+        assert_eq!(a.alloc(FrameSize::Size2M), Some(4 * MIB));
+        assert_eq!(a.alloc(FrameSize::Size2M), None);
+        assert_eq!(a.free(4 * MIB + FRAME_SIZE, FrameSize::Size4K), Ok(()));
+        assert_eq!(a.free(4 * MIB, FrameSize::Size2M), Err(FrameError::DoubleFree));
+        assert!(a.is_used(4 * MIB));
+        assert_eq!(a.alloc(FrameSize::Size4K), Some(MIB));
+        assert_eq!(a.free(2 * MIB, FrameSize::Size2M), Ok(()));
+        assert_eq!(a.alloc(FrameSize::Size2M), Some(2 * MIB));
+
+        // Runs of small frames only need to be adjacent.
+        assert_eq!(a.alloc_run(256), None);
+        assert_eq!(a.alloc_run(255), Some(MIB + FRAME_SIZE));
+        assert_eq!(a.alloc_run(1), Some(4 * MIB + FRAME_SIZE));
+        assert_eq!(a.free(2 * MIB - FRAME_SIZE, FrameSize::Size4K), Ok(()));
+    }
+
+    #[test]
+    fn boot_regions_convert() {
+        use thatte_bootinfo::{MemoryRegion, RegionKind};
+        let boot = [
+            MemoryRegion::new(MIB, 256, RegionKind::Usable),
+            MemoryRegion::new(2 * MIB, 16, RegionKind::Kernel),
+            MemoryRegion::new(3 * MIB, 16, RegionKind::Bootloader),
+        ];
+        let map: std::vec::Vec<MemRegion> = boot.iter().map(MemRegion::from).collect();
+        assert_eq!(map[0], usable(MIB, MIB));
+        assert_eq!(map[1], MemRegion { base: 2 * MIB, len: 16 * FRAME_SIZE, kind: MemKind::Reserved });
+        assert_eq!(Frames::from_map(&map, &[]).stats().total, 256);
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Alloc(bool),
+        /// Free the nth live allocation (mod count).
+        Free(usize),
+        /// Free an arbitrary page-aligned address.
+        FreeAt(u64, bool),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            3 => any::<bool>().prop_map(Op::Alloc),
+            2 => any::<usize>().prop_map(Op::Free),
+            1 => (0..(Frames::LIMIT / FRAME_SIZE), any::<bool>()).prop_map(|(f, l)| Op::FreeAt(f * FRAME_SIZE, l)),
+        ]
+    }
+
+    fn size(large: bool) -> FrameSize { if large { FrameSize::Size2M } else { FrameSize::Size4K } }
+
+    proptest! {
+        // Allocations never overlap each other or reserved memory, frees of
+        // anything not currently allocated fail, and stats stay exact.
+        #[test]
+        fn random_alloc_free(
+            holes in proptest::collection::vec((0..16 * MIB, 0..MIB), 0..4),
+            ops in proptest::collection::vec(op(), 1..200),
+        ) {
+            let mut a = Frames::from_map(&[usable(0, 16 * MIB)], &holes);
+            let total = a.stats().total;
+            let in_hole = |f: u64| holes.iter().any(|&(b, l)| f + FRAME_SIZE > b && f < b + l);
+            // Live allocations: frame address -> size.
+            let mut live: BTreeMap<u64, FrameSize> = BTreeMap::new();
+            let owner = |live: &BTreeMap<u64, FrameSize>, f: u64| {
+                live.range(..=f).next_back().filter(|(&b, s)| f < b + s.bytes()).map(|(&b, &s)| (b, s))
+            };
+            for op in ops {
+                match op {
+                    Op::Alloc(large) => {
+                        if let Some(f) = a.alloc(size(large)) {
+                            prop_assert_eq!(f % size(large).bytes(), 0);
+                            for p in (f..f + size(large).bytes()).step_by(FRAME_SIZE as usize) {
+                                prop_assert!(owner(&live, p).is_none(), "overlap at {:#x}", p);
+                                prop_assert!(!in_hole(p), "reserved frame {:#x}", p);
+                            }
+                            live.insert(f, size(large));
+                        }
+                    }
+                    Op::Free(n) => {
+                        if live.is_empty() { continue; }
+                        let (&f, &s) = live.iter().nth(n % live.len()).unwrap();
+                        prop_assert_eq!(a.free(f, s), Ok(()));
+                        live.remove(&f);
+                        prop_assert_eq!(a.free(f, s), Err(FrameError::DoubleFree));
+                    }
+                    Op::FreeAt(f, large) => {
+                        let s = size(large);
+                        let exact = live.get(&f) == Some(&s);
+                        let r = a.free(f, s);
+                        if exact {
+                            prop_assert_eq!(r, Ok(()));
+                            live.remove(&f);
+                        } else if !large {
+                            // A 4K piece of a live 2M frame may be freed on its own.
+                            match owner(&live, f) {
+                                Some((b, FrameSize::Size2M)) => {
+                                    prop_assert_eq!(r, Ok(()));
+                                    live.remove(&b);
+                                    for p in (b..b + LARGE_FRAME_SIZE).step_by(FRAME_SIZE as usize).filter(|&p| p != f) {
+                                        live.insert(p, FrameSize::Size4K);
+                                    }
+                                }
+                                _ => prop_assert!(r.is_err()),
+                            }
+                        } else if r.is_ok() {
+                            // Only possible if every 4K frame of it was live.
+                            for p in (f..f + LARGE_FRAME_SIZE).step_by(FRAME_SIZE as usize) {
+                                let o = owner(&live, p);
+                                prop_assert!(o.is_some());
+                                let (b, os) = o.unwrap();
+                                live.remove(&b);
+                                for q in (b..b + os.bytes()).step_by(FRAME_SIZE as usize).filter(|q| !(f..f + LARGE_FRAME_SIZE).contains(q)) {
+                                    live.insert(q, FrameSize::Size4K);
+                                }
+                            }
+                        }
+                    }
+                }
+                let used: u64 = live.values().map(|s| s.bytes() / FRAME_SIZE).sum();
+                prop_assert_eq!(a.stats(), FrameStats { total, free: total - used as usize, used: used as usize });
+            }
+        }
+    }
+}
@@ -0,0 +1,527 @@
+//! Kernel heap (`alloc` feature).
+//!
+//! Requests of up to 2 KiB (size and alignment) are served from per-class
+//! slabs: 4 KiB pages cut into equal power-of-two slots, so every slot is
+//! aligned to its size. Anything larger or more aligned goes to a first-fit,
+//! address-ordered free list that coalesces on free. Slab pages are taken
+//! from that list and kept once carved.
+//!
+//! When both run dry, `GlobalHeap` asks its `Backing` for more memory;
+//! `FrameBacking` takes it from the frame allocator through the direct map.
+//! With poisoning on, fresh blocks are filled with `POISON_ALLOC`, freed
+//! ones with `POISON_FREE`, and a slab slot that was written after being
+//! freed is counted in `HeapStats::poison_faults` when it is handed out
+//! again.
+//!
+//! The capability-space slot tables (`cspace`) are what allocate. The kernel
+//! image builds with this feature and seeds `HEAP` with a `FrameBacking`
+//! over the frames the boot memory map leaves usable.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+
+use crate::frame::{FrameAllocator, FrameSize, FRAME_SIZE, LARGE_FRAME_SIZE};
+use crate::sync::SpinLock;
+
+/// Slot sizes of the slab classes.
+pub const CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const POISON_ALLOC: u8 = 0xaa;
+pub const POISON_FREE: u8 = 0xdd;
+
+const SLAB_PAGE: usize = 4096;
+/// Free-list granule: blocks are multiples of this, and big enough for a `Hole`.
+const GRANULE: usize = 16;
+/// Bytes at the start of a free slab slot holding the link.
+const LINK: usize = core::mem::size_of::<usize>();
+
+struct Slot {
+    next: *mut Slot,
+}
+
+struct Hole {
+    size: usize,
+    next: *mut Hole,
+}
+
+const fn round_up(v: usize, align: usize) -> usize { (v + align - 1) & !(align - 1) }
+
+/// Slab class for `layout`, if it is small enough for one.
+fn class_of(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(CLASSES[0]).next_power_of_two();
+    CLASSES.iter().position(|&c| c == size)
+}
+
+/// Free-list block size for a `layout` that has no slab class.
+fn block_size(layout: Layout) -> usize { round_up(layout.size().max(GRANULE), GRANULE) }
+
+/// Allocation and leak accounting.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct HeapStats {
+    /// Allocations not yet freed.
+    pub live: usize,
+    /// Bytes requested by live allocations.
+    pub live_bytes: usize,
+    pub peak_bytes: usize,
+    /// Live allocations per slab class (`CLASSES` order).
+    pub live_per_class: [usize; CLASSES.len()],
+    /// Bytes handed to the heap so far.
+    pub managed_bytes: usize,
+    /// Bytes on the free list.
+    pub free_bytes: usize,
+    /// Pages carved into slabs.
+    pub slab_pages: usize,
+    /// Slab slots found modified while free.
+    pub poison_faults: usize,
+}
+
+/// The allocator proper, without locking or growth.
+pub struct Heap {
+    slabs: [*mut Slot; CLASSES.len()],
+    /// Sorted by address, never adjacent (adjacent holes are merged).
+    holes: *mut Hole,
+    poison: bool,
+    stats: HeapStats,
+}
+
+// SAFETY: the heap owns the memory its pointers refer to.
+unsafe impl Send for Heap {}
+
+impl Heap {
+    pub const fn new(poison: bool) -> Self {
+        let stats = HeapStats {
+            live: 0,
+            live_bytes: 0,
+            peak_bytes: 0,
+            live_per_class: [0; CLASSES.len()],
+            managed_bytes: 0,
+            free_bytes: 0,
+            slab_pages: 0,
+            poison_faults: 0,
+        };
+        Self { slabs: [ptr::null_mut(); CLASSES.len()], holes: ptr::null_mut(), poison, stats }
+    }
+
+    /// Give the heap `len` bytes at `start`.
+    ///
+    /// # Safety
+    /// The memory must be writable, unused by anything else, and stay valid
+    /// for as long as the heap is used.
+    pub unsafe fn add_region(&mut self, start: *mut u8, len: usize) {
+        let base = round_up(start as usize, GRANULE);
+        let Some(len) = (start as usize + len).checked_sub(base) else { return };
+        let len = len & !(GRANULE - 1);
+        if len == 0 { return; }
+        self.stats.managed_bytes += len;
+        self.list_free(base, len);
+    }
+
+    pub fn stats(&self) -> HeapStats { self.stats }
+
+    pub fn alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let p = match class_of(layout) {
+            Some(c) => {
+                let p = self.slab_alloc(c)?;
+                self.stats.live_per_class[c] += 1;
+                p
+            }
+            None => self.list_alloc(block_size(layout), layout.align().max(GRANULE))?,
+        };
+        if self.poison {
+            // SAFETY: `p` is a fresh block of at least `layout.size()` bytes.
+            unsafe { ptr::write_bytes(p.as_ptr(), POISON_ALLOC, layout.size()) };
+        }
+        let s = &mut self.stats;
+        s.live += 1;
+        s.live_bytes += layout.size();
+        s.peak_bytes = s.peak_bytes.max(s.live_bytes);
+        Some(p)
+    }
+
+    /// # Safety
+    /// `ptr` must come from `alloc` on this heap with the same `layout`, and
+    /// not have been freed since.
+    pub unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.stats.live -= 1;
+        self.stats.live_bytes -= layout.size();
+        match class_of(layout) {
+            Some(c) => {
+                self.stats.live_per_class[c] -= 1;
+                self.slab_free(c, ptr.as_ptr());
+            }
+            None => self.list_free(ptr.as_ptr() as usize, block_size(layout)),
+        }
+    }
+
+    fn slab_alloc(&mut self, c: usize) -> Option<NonNull<u8>> {
+        let size = CLASSES[c];
+        if self.slabs[c].is_null() {
+            let page = self.list_alloc(SLAB_PAGE, SLAB_PAGE)?.as_ptr();
+            self.stats.slab_pages += 1;
+            for i in (0..SLAB_PAGE / size).rev() {
+                // SAFETY: slot `i` lies inside the page we just took.
+                unsafe { self.slab_free(c, page.add(i * size)) };
+            }
+        }
+        let slot = self.slabs[c];
+        // SAFETY: free slots hold a valid link and are `size` bytes long.
+        unsafe {
+            self.slabs[c] = (*slot).next;
+            let body = core::slice::from_raw_parts(slot.cast::<u8>().add(LINK), size - LINK);
+            if self.poison && body.iter().any(|&b| b != POISON_FREE) {
+                self.stats.poison_faults += 1;
+            }
+        }
+        NonNull::new(slot.cast())
+    }
+
+    unsafe fn slab_free(&mut self, c: usize, p: *mut u8) {
+        if self.poison {
+            ptr::write_bytes(p, POISON_FREE, CLASSES[c]);
+        }
+        let slot = p.cast::<Slot>();
+        slot.write(Slot { next: self.slabs[c] });
+        self.slabs[c] = slot;
+    }
+
+    /// First fit for `size` bytes (a multiple of `GRANULE`) at `align`.
+    fn list_alloc(&mut self, size: usize, align: usize) -> Option<NonNull<u8>> {
+        let mut link: *mut *mut Hole = &mut self.holes;
+        // SAFETY: every hole is a valid, exclusively owned free block.
+        unsafe {
+            while !(*link).is_null() {
+                let hole = *link;
+                let start = hole as usize;
+                let end = start + (*hole).size;
+                let at = round_up(start, align);
+                if at.checked_add(size).is_some_and(|stop| stop <= end) {
+                    // Both leftovers are multiples of GRANULE, so either
+                    // empty or big enough to stay on the list.
+                    let stop = at + size;
+                    let mut rest = (*hole).next;
+                    if stop < end {
+                        let back = stop as *mut Hole;
+                        back.write(Hole { size: end - stop, next: rest });
+                        rest = back;
+                    }
+                    if at > start {
+                        hole.write(Hole { size: at - start, next: rest });
+                        rest = hole;
+                    }
+                    *link = rest;
+                    self.stats.free_bytes -= size;
+                    return NonNull::new(at as *mut u8);
+                }
+                link = &mut (*hole).next;
+            }
+        }
+        None
+    }
+
+    /// Return `size` bytes at `addr` to the free list, merging neighbours.
+    unsafe fn list_free(&mut self, addr: usize, size: usize) {
+        if self.poison {
+            ptr::write_bytes(addr as *mut u8, POISON_FREE, size);
+        }
+        self.stats.free_bytes += size;
+        let mut before: *mut Hole = ptr::null_mut();
+        let mut after = self.holes;
+        while !after.is_null() && (after as usize) < addr {
+            before = after;
+            after = (*after).next;
+        }
+        debug_assert!(after.is_null() || addr + size <= after as usize, "heap block {:#x} freed twice", addr);
+        debug_assert!(before.is_null() || before as usize + (*before).size <= addr, "heap block {:#x} freed twice", addr);
+        let (mut size, mut next) = (size, after);
+        if !after.is_null() && addr + size == after as usize {
+            size += (*after).size;
+            next = (*after).next;
+        }
+        if !before.is_null() && before as usize + (*before).size == addr {
+            (*before).size += size;
+            (*before).next = next;
+        } else {
+            let hole = addr as *mut Hole;
+            hole.write(Hole { size, next });
+            if before.is_null() { self.holes = hole } else { (*before).next = hole }
+        }
+    }
+}
+
+/// Where the heap gets more memory when it runs dry.
+pub trait Backing {
+    /// Hand over at least `min` bytes of fresh, writable, page-aligned
+    /// memory that stays valid forever.
+    fn grow(&mut self, min: usize) -> Option<(NonNull<u8>, usize)>;
+}
+
+/// Grows the heap with frames reached through the direct map at `direct_map`.
+/// Uses 2 MiB frames while they last, then runs of adjacent 4 KiB frames;
+/// a request for more than 2 MiB always takes such a run.
+pub struct FrameBacking<'a, const WORDS: usize> {
+    frames: &'a SpinLock<FrameAllocator<WORDS>>,
+    direct_map: usize,
+}
+
+impl<'a, const WORDS: usize> FrameBacking<'a, WORDS> {
+    /// # Safety
+    /// Every frame `frames` hands out must be mapped writable at
+    /// `direct_map + pa`, and frames given to the heap must never be freed.
+    pub const unsafe fn new(frames: &'a SpinLock<FrameAllocator<WORDS>>, direct_map: usize) -> Self {
+        Self { frames, direct_map }
+    }
+}
+
+impl<const WORDS: usize> Backing for FrameBacking<'_, WORDS> {
+    fn grow(&mut self, min: usize) -> Option<(NonNull<u8>, usize)> {
+        let mut frames = self.frames.lock();
+        let large = if min as u64 <= LARGE_FRAME_SIZE { frames.alloc(FrameSize::Size2M) } else { None };
+        let (pa, len) = match large {
+            Some(pa) => (pa, LARGE_FRAME_SIZE),
+            None => {
+                let count = (min as u64).div_ceil(FRAME_SIZE).max(1);
+                (frames.alloc_run(count as usize)?, count * FRAME_SIZE)
+            }
+        };
+        NonNull::new((self.direct_map + pa as usize) as *mut u8).map(|p| (p, len as usize))
+    }
+}
+
+struct Global {
+    heap: Heap,
+    backing: Option<&'static mut (dyn Backing + Send)>,
+}
+
+/// A locked `Heap` that grows through its `Backing`, usable as the
+/// `#[global_allocator]`.
+pub struct GlobalHeap {
+    inner: SpinLock<Global>,
+}
+
+impl Default for GlobalHeap {
+    fn default() -> Self { Self::new() }
+}
+
+impl GlobalHeap {
+    /// Poisoning follows `debug_assertions`.
+    pub const fn new() -> Self {
+        Self { inner: SpinLock::new(Global { heap: Heap::new(cfg!(debug_assertions)), backing: None }) }
+    }
+
+    /// Set where memory comes from once the heap is exhausted.
+    pub fn init(&self, backing: &'static mut (dyn Backing + Send)) { self.inner.lock().backing = Some(backing) }
+
+    /// See `Heap::add_region`.
+    ///
+    /// # Safety
+    /// As for `Heap::add_region`.
+    pub unsafe fn add_region(&self, start: *mut u8, len: usize) { self.inner.lock().heap.add_region(start, len) }
+
+    pub fn stats(&self) -> HeapStats { self.inner.lock().heap.stats() }
+}
+
+unsafe impl GlobalAlloc for GlobalHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut g = self.inner.lock();
+        if let Some(p) = g.heap.alloc(layout) { return p.as_ptr(); }
+        // Enough for the block, or a fresh slab page. Backing memory is
+        // page-aligned, so only a larger alignment needs slack.
+        let need = round_up(layout.size().max(1), SLAB_PAGE) + layout.align().saturating_sub(SLAB_PAGE);
+        let Some((p, len)) = g.backing.as_deref_mut().and_then(|b| b.grow(need)) else { return ptr::null_mut() };
+        g.heap.add_region(p.as_ptr(), len);
+        g.heap.alloc(layout).map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(p) = NonNull::new(ptr) {
+            self.inner.lock().heap.dealloc(p, layout);
+        }
+    }
+}
+
+/// The kernel's allocator. Hosted builds keep the system allocator.
+#[cfg(not(feature = "std"))]
+#[global_allocator]
+pub static HEAP: GlobalHeap = GlobalHeap::new();
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::frame::{MemKind, MemRegion};
+    use proptest::prelude::*;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    /// Page-aligned scratch memory from the system allocator.
+    struct Arena {
+        ptr: *mut u8,
+        layout: Layout,
+    }
+
+    impl Arena {
+        fn new(len: usize) -> Self {
+            let layout = Layout::from_size_align(len, 1 << 21).unwrap();
+            // SAFETY: nonzero size.
+            let ptr = unsafe { std::alloc::alloc(layout) };
+            assert!(!ptr.is_null());
+            Self { ptr, layout }
+        }
+
+        fn heap(&self) -> Heap {
+            let mut h = Heap::new(true);
+            // SAFETY: the arena outlives every heap built on it in these tests.
+            unsafe { h.add_region(self.ptr, self.layout.size()) };
+            h
+        }
+    }
+
+    impl Drop for Arena {
+        // SAFETY: allocated in `new` with this layout.
+        fn drop(&mut self) { unsafe { std::alloc::dealloc(self.ptr, self.layout) } }
+    }
+
+    fn layout(size: usize, align: usize) -> Layout { Layout::from_size_align(size, align).unwrap() }
+
+    /// Every managed byte is either free, in a slab page, or live in a
+    /// free-list block.
+    fn settled(h: &Heap) -> bool {
+        let s = h.stats();
+        s.live == 0 && s.live_bytes == 0 && s.free_bytes + s.slab_pages * SLAB_PAGE == s.managed_bytes
+    }
+
+    #[test]
+    fn slabs_and_list_share_the_region() {
+        let arena = Arena::new(1 << 20);
+        let mut h = arena.heap();
+        let small = h.alloc(layout(24, 8)).unwrap();
+        let small2 = h.alloc(layout(24, 8)).unwrap();
+        assert_eq!(small2.as_ptr() as usize - small.as_ptr() as usize, 32);
+        let big = h.alloc(layout(10_000, 64)).unwrap();
+        let aligned = h.alloc(layout(100, 8192)).unwrap();
+        assert_eq!(aligned.as_ptr() as usize % 8192, 0);
+        // SAFETY: fresh 10_000-byte block.
+        assert!(unsafe { core::slice::from_raw_parts(big.as_ptr(), 10_000) }.iter().all(|&b| b == POISON_ALLOC));
+        let s = h.stats();
+        assert_eq!((s.live, s.live_bytes, s.slab_pages, s.live_per_class[1]), (4, 10_148, 1, 2));
+        // SAFETY: each pointer freed once with its own layout.
+        unsafe {
+            h.dealloc(small, layout(24, 8));
+            h.dealloc(aligned, layout(100, 8192));
+            h.dealloc(big, layout(10_000, 64));
+            h.dealloc(small2, layout(24, 8));
+        }
+        assert!(settled(&h));
+        assert_eq!(h.stats().peak_bytes, 10_148);
+        assert!(h.alloc(layout(2 << 20, 8)).is_none());
+    }
+
+    #[test]
+    fn use_after_free_is_caught_by_poison() {
+        let arena = Arena::new(1 << 16);
+        let mut h = arena.heap();
+        let p = h.alloc(layout(64, 8)).unwrap();
+        // SAFETY: freed once, then scribbled on deliberately (inside the arena).
+        unsafe {
+            h.dealloc(p, layout(64, 8));
+            *p.as_ptr().add(40) = 1;
+        }
+        assert_eq!(h.alloc(layout(64, 8)), Some(p));
+        assert_eq!(h.stats().poison_faults, 1);
+    }
+
+    #[test]
+    fn global_heap_grows_from_frames() {
+        const WORDS: usize = 64;
+        let arena = Arena::new(8 << 20);
+        let map = [MemRegion { base: 0, len: 8 << 20, kind: MemKind::Usable }];
+        let frames: &'static _ = Box::leak(Box::new(SpinLock::new(FrameAllocator::<WORDS>::from_map(&map, &[]))));
+        // SAFETY: the arena is the "physical memory" of `frames`, and outlives the heap.
+        let backing = Box::leak(Box::new(unsafe { FrameBacking::new(frames, arena.ptr as usize) }));
+        let heap = GlobalHeap::new();
+        // SAFETY: nothing allocated yet.
+        assert!(unsafe { heap.alloc(layout(8, 8)) }.is_null());
+        heap.init(backing);
+        let ptrs: Vec<_> = (1..=6).map(|i| (unsafe { heap.alloc(layout(i * 300_000, 16)) }, i * 300_000)).collect();
+        assert!(ptrs.iter().all(|&(p, _)| !p.is_null()));
+        assert_eq!(frames.lock().stats().used, 4 * 512);
+        // SAFETY: more than a 2 MiB frame can hold.
+        assert!(unsafe { heap.alloc(layout(3 << 20, 16)) }.is_null());
+        for (p, size) in ptrs {
+            // SAFETY: allocated above with this layout.
+            unsafe { heap.dealloc(p, layout(size, 16)) };
+        }
+        assert_eq!((heap.stats().live, heap.stats().free_bytes), (0, 8 << 20));
+    }
+
+    #[test]
+    fn global_heap_falls_back_to_small_frames() {
+        const WORDS: usize = 16;
+        let arena = Arena::new(4 << 20);
+        // One 2 MiB frame, then 16 small ones.
+        let map = [MemRegion { base: 0, len: (2 << 20) + 16 * FRAME_SIZE, kind: MemKind::Usable }];
+        let frames: &'static _ = Box::leak(Box::new(SpinLock::new(FrameAllocator::<WORDS>::from_map(&map, &[]))));
+        // SAFETY: the arena is the "physical memory" of `frames`, and outlives the heap.
+        let backing = Box::leak(Box::new(unsafe { FrameBacking::new(frames, arena.ptr as usize) }));
+        let heap = GlobalHeap::new();
+        heap.init(backing);
+        // SAFETY: each pointer is freed once below with its own layout.
+        unsafe {
+            let whole = heap.alloc(layout(2 << 20, 16));
+            assert!(!whole.is_null());
+            assert_eq!(frames.lock().stats().used, 512);
+            let run = heap.alloc(layout(40_000, 16));
+            let small = heap.alloc(layout(100, 8));
+            assert!(!run.is_null() && !small.is_null());
+            assert_eq!(frames.lock().stats().used, 512 + 10 + 1);
+            // Five frames left, one short.
+            assert!(heap.alloc(layout(6 * SLAB_PAGE, 16)).is_null());
+            heap.dealloc(small, layout(100, 8));
+            heap.dealloc(run, layout(40_000, 16));
+            heap.dealloc(whole, layout(2 << 20, 16));
+        }
+        let s = heap.stats();
+        assert_eq!((s.live, s.managed_bytes, s.free_bytes + s.slab_pages * SLAB_PAGE), (0, (2 << 20) + 11 * SLAB_PAGE, s.managed_bytes));
+    }
+
+    proptest! {
+        // Random allocate/free never hands out overlapping or misaligned
+        // blocks, keeps their contents intact, and gives everything back.
+        #[test]
+        fn random_alloc_free(
+            ops in proptest::collection::vec((1usize..6000, 0u32..14, any::<bool>(), any::<usize>()), 1..300),
+        ) {
+            let arena = Arena::new(1 << 21);
+            let mut h = arena.heap();
+            let mut live: Vec<(NonNull<u8>, Layout, u8)> = Vec::new();
+            for (i, (size, align, alloc, pick)) in ops.into_iter().enumerate() {
+                if alloc || live.is_empty() {
+                    let l = layout(size, 1 << align);
+                    let Some(p) = h.alloc(l) else { continue };
+                    let addr = p.as_ptr() as usize;
+                    prop_assert_eq!(addr % l.align(), 0);
+                    for (q, ql, _) in &live {
+                        let qa = q.as_ptr() as usize;
+                        prop_assert!(addr + l.size() <= qa || qa + ql.size() <= addr, "overlap at {:#x}", addr);
+                    }
+                    // SAFETY: fresh block of `size` bytes.
+                    unsafe { ptr::write_bytes(p.as_ptr(), i as u8, size) };
+                    live.push((p, l, i as u8));
+                } else {
+                    let (p, l, tag) = live.swap_remove(pick % live.len());
+                    // SAFETY: live block of `l.size()` bytes, freed once.
+                    unsafe {
+                        let body = core::slice::from_raw_parts(p.as_ptr(), l.size());
+                        prop_assert!(body.iter().all(|&b| b == tag));
+                        h.dealloc(p, l);
+                    }
+                }
+                prop_assert_eq!(h.stats().live, live.len());
+            }
+            for (p, l, _) in live {
+                // SAFETY: still live.
+                unsafe { h.dealloc(p, l) };
+            }
+            prop_assert!(settled(&h));
+            prop_assert_eq!(h.stats().poison_faults, 0);
+        }
+    }
+}
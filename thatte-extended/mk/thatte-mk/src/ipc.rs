@@ -0,0 +1,827 @@
+//! IPC endpoints: bounded message queues with blocking and non-blocking
+//! send/recv, synchronous `call` with one-shot reply capabilities, and
+//! `reply` / `reply_recv` for servers.
+//!
+//! A message is routed by the endpoint capability in its header's `dst`.
+//! Each thread owns the cspace with its own index; caps listed in a
+//! message's transfer section are moved or copied from the sender's cspace
+//! into free slots of the receiver's at delivery time, which requires GRANT
+//! on the endpoint cap. Replies may carry caps back to the caller too, if
+//! the caller's endpoint cap had GRANT: only then is the reply cap minted
+//! with it.
+//!
+//! Header flags are enforced here: `call` messages and only they carry
+//! `REPLY_EXPECTED`, `NON_BLOCKING` turns a would-be block into `WouldBlock`,
+//! `GRANT_CAPS` needs GRANT, `SHM_PAYLOAD` needs a well-formed descriptor and
+//! `ERROR` is only accepted from `reply`.
+//! Threads are plain indices: an operation that cannot complete parks the
+//! thread (its `ThreadState` stops being `Ready`), and whoever schedules
+//! threads — the kernel, or a host test — resumes it once it is `Ready`
+//! again and collects anything delivered meanwhile with `take`. A wait with
+//! a timeout ends early through `cancel` (see `time::Timeouts`).
+
+use crate::cspace::{CSpaces, CapError, SlotRef};
+use crate::mint::{object_id, MintAuthority, MintError, ObjectKind};
+use crate::msg::{cap_entry_range, framed, CapTransfer, ShmPayload, TransferMode, MAX_CAPS};
+use crate::{authorize, parse_header, Cap, IpcDenied, IpcHeader, MissingRights, MsgFlags, ParseError, Rights};
+
+pub type ThreadId = u16;
+
+/// Largest message an endpoint carries inline.
+pub const MSG_MAX: usize = 128;
+
+/// An owned, length-checked message buffer.
+#[derive(Copy, Clone)]
+pub struct Message {
+    buf: [u8; MSG_MAX],
+    len: u16,
+}
+
+impl Message {
+    /// Copy `bytes` into a message; `None` if longer than `MSG_MAX`.
+    pub fn new(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() > MSG_MAX { return None; }
+        let mut buf = [0u8; MSG_MAX];
+        buf[..bytes.len()].copy_from_slice(bytes);
+        Some(Self { buf, len: bytes.len() as u16 })
+    }
+    pub fn bytes(&self) -> &[u8] { &self.buf[..self.len as usize] }
+    pub fn header(&self) -> Result<IpcHeader, ParseError> { parse_header(self.bytes()) }
+}
+
+impl PartialEq for Message {
+    fn eq(&self, other: &Self) -> bool { self.bytes() == other.bytes() }
+}
+impl Eq for Message {}
+
+impl core::fmt::Debug for Message {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Message({} bytes)", self.len)
+    }
+}
+
+/// A message handed to a thread, with the kernel-attested sender and, for
+/// calls, the one-shot capability to answer it.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Delivery {
+    pub msg: Message,
+    pub sender: ThreadId,
+    pub reply: Option<Cap>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ThreadState {
+    Ready,
+    /// Waiting for queue space on an endpoint (index).
+    SendBlocked(u16),
+    /// Waiting for a message on an endpoint (index).
+    RecvBlocked(u16),
+    /// Waiting for the reply to a call.
+    ReplyBlocked,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum IpcError {
+    /// Thread id out of range.
+    BadThread,
+    /// The thread is blocked and cannot issue operations.
+    NotReady,
+    /// The thread has a delivered message it has not collected with `take`.
+    Unclaimed,
+    /// The message is too long or does not parse.
+    Malformed(ParseError),
+    /// A slot named in the transfer section is invalid or empty.
+    Slot(CapError),
+    /// The header's flags do not fit the operation.
+    BadFlags,
+    /// The capability is forged or stale.
+    Cap(MintError),
+    /// The capability names an object of the wrong kind.
+    WrongObject,
+    /// The endpoint does not exist.
+    NoEndpoint,
+    /// The capability lacks rights for the operation.
+    Rights(MissingRights),
+    /// The message type is unknown or not allowed by `dst`.
+    Denied(IpcDenied),
+    /// A non-blocking operation could not complete immediately.
+    WouldBlock,
+    /// The reply target is not waiting for this reply.
+    NotWaiting,
+    /// No free endpoint slots.
+    Exhausted,
+}
+
+/// Outcome of a send or call.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Sent {
+    /// Handed straight to a waiting receiver.
+    Delivered(ThreadId),
+    /// Placed in the endpoint's queue.
+    Queued,
+    /// The queue was full; the sender is now `SendBlocked`.
+    Blocked,
+}
+
+/// Outcome of a receive.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Received {
+    Msg(Delivery),
+    /// Nothing queued; the receiver is now `RecvBlocked`.
+    Blocked,
+}
+
+/// Fixed-capacity FIFO.
+#[derive(Copy, Clone)]
+struct Ring<T: Copy, const N: usize> {
+    items: [Option<T>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> Ring<T, N> {
+    const fn new() -> Self { Self { items: [None; N], head: 0, len: 0 } }
+
+    fn push(&mut self, t: T) -> Result<(), T> {
+        if self.len == N { return Err(t); }
+        self.items[(self.head + self.len) % N] = Some(t);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        if self.len == 0 { return None; }
+        let t = self.items[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        t
+    }
+
+    /// Remove the first item matching `f`, keeping the others in order.
+    fn remove(&mut self, f: impl Fn(&T) -> bool) -> Option<T> {
+        let i = (0..self.len).find(|&i| self.items[(self.head + i) % N].as_ref().is_some_and(&f))?;
+        let t = self.items[(self.head + i) % N].take();
+        for j in i..self.len - 1 {
+            self.items[(self.head + j) % N] = self.items[(self.head + j + 1) % N].take();
+        }
+        self.len -= 1;
+        t
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Queued {
+    msg: Message,
+    sender: ThreadId,
+    reply: Option<Cap>,
+}
+
+impl Queued {
+    fn delivery(self) -> Delivery { Delivery { msg: self.msg, sender: self.sender, reply: self.reply } }
+}
+
+#[derive(Copy, Clone)]
+struct Endpoint<const DEPTH: usize, const THREADS: usize> {
+    live: bool,
+    generation: u32,
+    queue: Ring<Queued, DEPTH>,
+    senders: Ring<ThreadId, THREADS>,
+    receivers: Ring<ThreadId, THREADS>,
+}
+
+impl<const DEPTH: usize, const THREADS: usize> Endpoint<DEPTH, THREADS> {
+    const fn new() -> Self {
+        Self { live: false, generation: 0, queue: Ring::new(), senders: Ring::new(), receivers: Ring::new() }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Tcb {
+    state: ThreadState,
+    inbox: Option<Delivery>,
+    /// Message of a `SendBlocked` thread.
+    pending: Option<Queued>,
+    /// Generation of this thread's reply object; bumped on every reply so
+    /// each reply cap works once.
+    reply_gen: u32,
+    /// Endpoint of this thread's latest call, and the thread that received
+    /// it (until it replies).
+    call_ep: u16,
+    callee: Option<ThreadId>,
+}
+
+impl Tcb {
+    const fn new() -> Self {
+        Self { state: ThreadState::Ready, inbox: None, pending: None, reply_gen: 0, call_ep: 0, callee: None }
+    }
+}
+
+/// The IPC subsystem: `THREADS` threads (each with a cspace of `SLOTS`
+/// slots) and `EPS` endpoints, each with a queue of `DEPTH` messages.
+pub struct Ipc<const THREADS: usize, const EPS: usize, const DEPTH: usize, const SLOTS: usize> {
+    mint: MintAuthority,
+    threads: [Tcb; THREADS],
+    endpoints: [Endpoint<DEPTH, THREADS>; EPS],
+    cspaces: CSpaces<THREADS, SLOTS>,
+}
+
+impl<const THREADS: usize, const EPS: usize, const DEPTH: usize, const SLOTS: usize> Ipc<THREADS, EPS, DEPTH, SLOTS> {
+    /// `secret` keys the authority that mints endpoint and reply caps.
+    pub const fn new(secret: [u8; 16]) -> Self {
+        Self {
+            mint: MintAuthority::new(secret),
+            threads: [Tcb::new(); THREADS],
+            endpoints: [Endpoint::new(); EPS],
+            cspaces: CSpaces::new(),
+        }
+    }
+
+    /// Thread cspaces (space index = thread id).
+    pub fn cspaces(&self) -> &CSpaces<THREADS, SLOTS> { &self.cspaces }
+    pub fn cspaces_mut(&mut self) -> &mut CSpaces<THREADS, SLOTS> { &mut self.cspaces }
+
+    /// Create an endpoint and return a cap to it with all rights.
+    pub fn create_endpoint(&mut self) -> Result<Cap, IpcError> {
+        let i = self.endpoints.iter().position(|e| !e.live).ok_or(IpcError::Exhausted)?;
+        let e = &mut self.endpoints[i];
+        e.live = true;
+        Ok(self.mint.mint(object_id(ObjectKind::Endpoint, i as u32), e.generation, Rights::ALL))
+    }
+
+    /// Re-mint `cap` with only the rights in `mask`.
+    pub fn attenuate(&self, cap: &Cap, mask: Rights) -> Result<Cap, IpcError> {
+        self.mint.attenuate(cap, mask).map_err(IpcError::Cap)
+    }
+
+    /// Derive a copy of `tid`'s cap in slot `src` into its slot `dst`,
+    /// restricted to `mask` (`Sysno::CapDerive`).
+    pub fn derive(&mut self, tid: ThreadId, src: u16, dst: u16, mask: Rights) -> Result<(), IpcError> {
+        self.cspaces.derive(&self.mint, SlotRef::new(tid, src), SlotRef::new(tid, dst), mask).map_err(IpcError::Slot)
+    }
+
+    pub fn state(&self, tid: ThreadId) -> Option<ThreadState> { self.threads.get(tid as usize).map(|t| t.state) }
+
+    /// The thread a blocked caller is waiting on, to lend it its priority:
+    /// the server handling its call or, while the call is still queued, a
+    /// thread serving another call from the same endpoint.
+    pub fn donee(&self, tid: ThreadId) -> Option<ThreadId> {
+        let t = self.threads.get(tid as usize)?;
+        let ep = match t.state {
+            ThreadState::ReplyBlocked => t.callee.map_or(Ok(t.call_ep), Err),
+            ThreadState::SendBlocked(ep) if t.pending.is_some_and(|p| p.reply.is_some()) => Ok(ep),
+            _ => return None,
+        };
+        match ep {
+            Err(callee) => Some(callee),
+            Ok(ep) => self
+                .threads
+                .iter()
+                .find(|c| c.state == ThreadState::ReplyBlocked && c.call_ep == ep && c.callee.is_some())
+                .and_then(|c| c.callee),
+        }
+    }
+
+    /// Collect a message delivered while the thread was blocked.
+    pub fn take(&mut self, tid: ThreadId) -> Option<Delivery> { self.threads.get_mut(tid as usize)?.inbox.take() }
+
+    fn tcb(&mut self, tid: ThreadId) -> Result<&mut Tcb, IpcError> {
+        let t = self.threads.get_mut(tid as usize).ok_or(IpcError::BadThread)?;
+        if t.state != ThreadState::Ready { return Err(IpcError::NotReady); }
+        Ok(t)
+    }
+
+    fn ready(&mut self, tid: ThreadId) -> Result<(), IpcError> {
+        if self.tcb(tid)?.inbox.is_some() { return Err(IpcError::Unclaimed); }
+        Ok(())
+    }
+
+    /// Resolve an endpoint cap, requiring `need`.
+    fn endpoint(&self, cap: &Cap, need: Rights) -> Result<usize, IpcError> {
+        let m = self.mint.verify(cap).map_err(IpcError::Cap)?;
+        let i = m.index_of(ObjectKind::Endpoint).filter(|&i| i < EPS).ok_or(IpcError::WrongObject)?;
+        let e = &self.endpoints[i];
+        if !e.live { return Err(IpcError::NoEndpoint); }
+        self.mint.verify_current(cap, e.generation).map_err(IpcError::Cap)?;
+        cap.require(need).map_err(IpcError::Rights)?;
+        Ok(i)
+    }
+
+    /// Parse and check a message `tid` is sending: sizes add up, the
+    /// transfer section names occupied slots of `tid`'s cspace, and an
+    /// `SHM_PAYLOAD` body is a descriptor.
+    fn checked(&self, tid: ThreadId, bytes: &[u8]) -> Result<(Message, IpcHeader), IpcError> {
+        let msg = Message::new(bytes).ok_or(IpcError::Malformed(ParseError::LengthOverflow(bytes.len() as u32)))?;
+        let h = msg.header().map_err(IpcError::Malformed)?;
+        framed(&h, bytes).map_err(IpcError::Malformed)?;
+        if h.flags.contains(MsgFlags::SHM_PAYLOAD) && h.len as usize != ShmPayload::LEN {
+            return Err(IpcError::Malformed(ParseError::LengthMismatch { declared: h.len, expected: ShmPayload::LEN }));
+        }
+        for i in 0..h.caps as usize {
+            let t = CapTransfer::read(bytes, i).map_err(IpcError::Malformed)?;
+            self.cspaces.lookup(SlotRef::new(tid, t.slot)).map_err(IpcError::Slot)?;
+        }
+        Ok((msg, h))
+    }
+
+    /// Check a send (`call` false) or call and resolve its endpoint.
+    fn outgoing(&self, tid: ThreadId, bytes: &[u8], call: bool) -> Result<(Message, usize, IpcHeader), IpcError> {
+        let (msg, h) = self.checked(tid, bytes)?;
+        if h.flags.contains(MsgFlags::REPLY_EXPECTED) != call || h.flags.contains(MsgFlags::ERROR) {
+            return Err(IpcError::BadFlags);
+        }
+        let need = if call { Rights::CALL } else { Rights::WRITE };
+        let need = if h.flags.contains(MsgFlags::GRANT_CAPS) { need | Rights::GRANT } else { need };
+        let ep = self.endpoint(&h.dst, need)?;
+        authorize(&h).map_err(IpcError::Denied)?;
+        Ok((msg, ep, h))
+    }
+
+    /// Move or copy the caps listed in `msg` from `from`'s cspace into free
+    /// slots of `to`'s, rewriting each entry with the receiving slot (or
+    /// `CapTransfer::NO_SLOT` if the cap is gone or `to` has no room).
+    fn transfer(&mut self, from: ThreadId, to: ThreadId, msg: &mut Message) {
+        let Ok(h) = msg.header() else { return };
+        for i in 0..(h.caps as usize).min(MAX_CAPS) {
+            let Ok(t) = CapTransfer::read(msg.bytes(), i) else { continue };
+            let src = SlotRef::new(from, t.slot);
+            let landed = self.cspaces.free_slot(to).filter(|&dst| {
+                match t.mode {
+                    TransferMode::Copy => self.cspaces.copy(src, dst),
+                    TransferMode::Move => self.cspaces.move_cap(src, dst),
+                }
+                .is_ok()
+            });
+            let slot = landed.map_or(CapTransfer::NO_SLOT, |d| d.slot);
+            msg.buf[cap_entry_range(i)].copy_from_slice(&CapTransfer { slot, mode: t.mode }.to_bytes());
+        }
+    }
+
+    /// Hand `q` to thread `to`, performing its cap transfer.
+    fn deliver(&mut self, to: ThreadId, mut q: Queued) -> Delivery {
+        if q.reply.is_some() {
+            self.threads[q.sender as usize].callee = Some(to);
+        }
+        self.transfer(q.sender, to, &mut q.msg);
+        q.delivery()
+    }
+
+    /// State of a sender once its message has left it.
+    fn sent(q: &Queued) -> ThreadState {
+        if q.reply.is_some() { ThreadState::ReplyBlocked } else { ThreadState::Ready }
+    }
+
+    fn enqueue(&mut self, tid: ThreadId, ep: usize, q: Queued, blocking: bool) -> Result<Sent, IpcError> {
+        let e = &mut self.endpoints[ep];
+        if let Some(r) = e.receivers.pop() {
+            let d = self.deliver(r, q);
+            let rt = &mut self.threads[r as usize];
+            rt.inbox = Some(d);
+            rt.state = ThreadState::Ready;
+            self.threads[tid as usize].state = Self::sent(&q);
+            return Ok(Sent::Delivered(r));
+        }
+        let e = &mut self.endpoints[ep];
+        if e.queue.push(q).is_ok() {
+            self.threads[tid as usize].state = Self::sent(&q);
+            return Ok(Sent::Queued);
+        }
+        if !blocking { return Err(IpcError::WouldBlock); }
+        // Every thread waits on at most one queue, so this cannot overflow.
+        let _ = e.senders.push(tid);
+        let t = &mut self.threads[tid as usize];
+        t.pending = Some(q);
+        t.state = ThreadState::SendBlocked(ep as u16);
+        Ok(Sent::Blocked)
+    }
+
+    /// One-way send to the endpoint named by the header's `dst` (needs
+    /// WRITE). Blocks only if `blocking` and the message is not `NON_BLOCKING`.
+    pub fn send(&mut self, tid: ThreadId, bytes: &[u8], blocking: bool) -> Result<Sent, IpcError> {
+        self.ready(tid)?;
+        let (msg, ep, h) = self.outgoing(tid, bytes, false)?;
+        let blocking = blocking && !h.flags.contains(MsgFlags::NON_BLOCKING);
+        self.enqueue(tid, ep, Queued { msg, sender: tid, reply: None }, blocking)
+    }
+
+    /// Send a `REPLY_EXPECTED` message and wait for a reply (needs CALL). The
+    /// receiver gets a one-shot reply cap, carrying GRANT if the endpoint cap
+    /// did; the caller stays blocked until it is used. A `NON_BLOCKING` call
+    /// fails instead of waiting for queue space.
+    pub fn call(&mut self, tid: ThreadId, bytes: &[u8]) -> Result<Sent, IpcError> {
+        self.ready(tid)?;
+        let (msg, ep, h) = self.outgoing(tid, bytes, true)?;
+        let gen = self.threads[tid as usize].reply_gen;
+        let rights = Rights::REPLY | h.dst.rights().intersection(Rights::GRANT);
+        let reply = self.mint.mint(object_id(ObjectKind::Reply, tid as u32), gen, rights);
+        self.threads[tid as usize].call_ep = ep as u16;
+        self.enqueue(tid, ep, Queued { msg, sender: tid, reply: Some(reply) }, !h.flags.contains(MsgFlags::NON_BLOCKING))
+    }
+
+    /// Move the first blocked sender's message into the space just freed in
+    /// `ep`'s queue.
+    fn refill(&mut self, ep: usize) {
+        let e = &mut self.endpoints[ep];
+        if let Some(s) = e.senders.pop() {
+            let st = &mut self.threads[s as usize];
+            if let Some(p) = st.pending.take() {
+                let _ = e.queue.push(p);
+                st.state = Self::sent(&p);
+            }
+        }
+    }
+
+    /// Receive from `ep` (needs READ). A message delivered while the thread
+    /// was blocked is returned first.
+    pub fn recv(&mut self, tid: ThreadId, ep: &Cap, blocking: bool) -> Result<Received, IpcError> {
+        if let Some(d) = self.tcb(tid)?.inbox.take() { return Ok(Received::Msg(d)); }
+        let i = self.endpoint(ep, Rights::READ)?;
+        if let Some(q) = self.endpoints[i].queue.pop() {
+            self.refill(i);
+            return Ok(Received::Msg(self.deliver(tid, q)));
+        }
+        if !blocking { return Err(IpcError::WouldBlock); }
+        let _ = self.endpoints[i].receivers.push(tid);
+        self.threads[tid as usize].state = ThreadState::RecvBlocked(i as u16);
+        Ok(Received::Blocked)
+    }
+
+    /// Answer a call through its reply cap, which is spent afterwards. The
+    /// reply may be an `ERROR` response and may transfer caps to the caller
+    /// if the reply cap has GRANT.
+    pub fn reply(&mut self, tid: ThreadId, reply: &Cap, bytes: &[u8]) -> Result<(), IpcError> {
+        self.ready(tid)?;
+        let (mut msg, h) = self.checked(tid, bytes)?;
+        if h.flags.contains(MsgFlags::REPLY_EXPECTED) { return Err(IpcError::BadFlags); }
+        let m = self.mint.verify(reply).map_err(IpcError::Cap)?;
+        let caller = m.index_of(ObjectKind::Reply).filter(|&i| i < THREADS).ok_or(IpcError::WrongObject)?;
+        let need = if h.flags.contains(MsgFlags::GRANT_CAPS) { Rights::REPLY | Rights::GRANT } else { Rights::REPLY };
+        reply.require(need).map_err(IpcError::Rights)?;
+        let c = &mut self.threads[caller];
+        self.mint.verify_current(reply, c.reply_gen).map_err(IpcError::Cap)?;
+        if c.state != ThreadState::ReplyBlocked { return Err(IpcError::NotWaiting); }
+        c.reply_gen = c.reply_gen.wrapping_add(1);
+        c.callee = None;
+        self.transfer(tid, caller as ThreadId, &mut msg);
+        let c = &mut self.threads[caller];
+        c.inbox = Some(Delivery { msg, sender: tid, reply: None });
+        c.state = ThreadState::Ready;
+        Ok(())
+    }
+
+    /// End `tid`'s wait because its timeout expired, returning the state it
+    /// was blocked in (`None` if it was not blocked, e.g. the operation
+    /// completed first). A call not yet received is withdrawn from the
+    /// queue, and the reply cap of one already received stops working; caps
+    /// in a withdrawn message stay with the sender.
+    pub fn cancel(&mut self, tid: ThreadId) -> Option<ThreadState> {
+        let t = self.threads.get(tid as usize)?;
+        let state = t.state;
+        match state {
+            ThreadState::Ready => return None,
+            ThreadState::SendBlocked(ep) => {
+                self.endpoints[ep as usize].senders.remove(|&s| s == tid);
+            }
+            ThreadState::RecvBlocked(ep) => {
+                self.endpoints[ep as usize].receivers.remove(|&r| r == tid);
+            }
+            ThreadState::ReplyBlocked => {
+                let ep = t.call_ep as usize;
+                if t.callee.is_none() && self.endpoints[ep].queue.remove(|q| q.sender == tid).is_some() {
+                    self.refill(ep);
+                }
+            }
+        }
+        let t = &mut self.threads[tid as usize];
+        if state == ThreadState::ReplyBlocked { t.reply_gen = t.reply_gen.wrapping_add(1); }
+        t.pending = None;
+        t.callee = None;
+        t.state = ThreadState::Ready;
+        Some(state)
+    }
+
+    /// Reply, then block receiving on `ep` — a server's steady-state loop.
+    pub fn reply_recv(&mut self, tid: ThreadId, reply: &Cap, bytes: &[u8], ep: &Cap) -> Result<Received, IpcError> {
+        self.reply(tid, reply, bytes)?;
+        self.recv(tid, ep, true)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::cspace::SlotRef;
+    use crate::msg::{encode_error, encode_shm, error_of, shm_payload, ErrorBody, MapShared, Ping, PingReply, Reply, Request};
+    use crate::MsgType;
+    use proptest::prelude::*;
+    use std::vec::Vec;
+
+    type Sys = Ipc<4, 2, 2, 4>;
+
+    fn ping_with(dst: Cap, seq: u64, flags: MsgFlags) -> Vec<u8> {
+        let mut buf = [0u8; MSG_MAX];
+        let n = Request::Ping(Ping { seq }).encode(Cap::nil(), dst, flags, &mut buf).unwrap();
+        buf[..n].to_vec()
+    }
+
+    fn ping(dst: Cap, seq: u64) -> Vec<u8> { ping_with(dst, seq, MsgFlags::NONE) }
+    fn call_ping(dst: Cap, seq: u64) -> Vec<u8> { ping_with(dst, seq, MsgFlags::REPLY_EXPECTED) }
+
+    fn seq_of(d: &Delivery) -> u64 {
+        match Request::decode(d.msg.bytes()).unwrap().1 {
+            Request::Ping(p) => p.seq,
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn call_and_reply_recv() {
+        let mut s = Sys::new([3; 16]);
+        let ep = s.create_endpoint().unwrap();
+        assert_eq!(s.recv(0, &ep, true), Ok(Received::Blocked));
+        assert_eq!(s.call(1, &call_ping(ep, 5)), Ok(Sent::Delivered(0)));
+        assert_eq!(s.state(1), Some(ThreadState::ReplyBlocked));
+        let d = s.take(0).unwrap();
+        assert_eq!((seq_of(&d), d.sender), (5, 1));
+        let rc = d.reply.unwrap();
+
+        let mut buf = [0u8; MSG_MAX];
+        let n = Reply::Ping(PingReply { seq: 5 }).encode(Cap::nil(), Cap::nil(), MsgFlags::NONE, &mut buf).unwrap();
+        assert_eq!(s.reply_recv(0, &rc, &buf[..n], &ep), Ok(Received::Blocked));
+        assert_eq!(s.state(1), Some(ThreadState::Ready));
+        let r = s.take(1).unwrap();
+        assert_eq!(Reply::decode(r.msg.bytes()).unwrap().1, Reply::Ping(PingReply { seq: 5 }));
+        // Reply caps are one-shot.
+        assert_eq!(s.reply(2, &rc, &buf[..n]), Err(IpcError::Cap(MintError::Stale { current: 1, presented: 0 })));
+    }
+
+    #[test]
+    fn callers_lend_to_their_server() {
+        let mut s = Sys::new([3; 16]);
+        let ep = s.create_endpoint().unwrap();
+        let mut buf = [0u8; MSG_MAX];
+        let n = Reply::Ping(PingReply { seq: 0 }).encode(Cap::nil(), Cap::nil(), MsgFlags::NONE, &mut buf).unwrap();
+        assert_eq!(s.recv(0, &ep, true), Ok(Received::Blocked));
+        assert_eq!(s.call(1, &call_ping(ep, 1)), Ok(Sent::Delivered(0)));
+        assert_eq!(s.call(2, &call_ping(ep, 2)), Ok(Sent::Queued));
+        // The queued caller lends to whoever serves the endpoint now.
+        assert_eq!((s.donee(0), s.donee(1), s.donee(2)), (None, Some(0), Some(0)));
+        let rc = s.take(0).unwrap().reply.unwrap();
+        s.reply(0, &rc, &buf[..n]).unwrap();
+        assert_eq!((s.donee(1), s.donee(2)), (None, None));
+        let Ok(Received::Msg(d)) = s.recv(3, &ep, true) else { panic!() };
+        assert_eq!((d.sender, s.donee(2)), (2, Some(3)));
+    }
+
+    #[test]
+    fn cancel_ends_waits() {
+        let mut s = Sys::new([3; 16]);
+        let ep = s.create_endpoint().unwrap();
+        assert_eq!(s.call(1, &call_ping(ep, 1)), Ok(Sent::Queued));
+        assert_eq!(s.call(2, &call_ping(ep, 2)), Ok(Sent::Queued));
+        assert_eq!(s.send(3, &ping(ep, 3), true), Ok(Sent::Blocked));
+        // Withdrawing a queued call makes room for the blocked sender.
+        assert_eq!(s.cancel(1), Some(ThreadState::ReplyBlocked));
+        assert_eq!((s.state(1), s.state(3)), (Some(ThreadState::Ready), Some(ThreadState::Ready)));
+        let Ok(Received::Msg(d)) = s.recv(0, &ep, false) else { panic!() };
+        assert_eq!(seq_of(&d), 2);
+        // A received call's reply cap dies with the wait.
+        assert_eq!(s.cancel(2), Some(ThreadState::ReplyBlocked));
+        assert_eq!(s.cancel(2), None);
+        let mut buf = [0u8; MSG_MAX];
+        let n = Reply::Ping(PingReply { seq: 2 }).encode(Cap::nil(), Cap::nil(), MsgFlags::NONE, &mut buf).unwrap();
+        assert_eq!(s.reply(0, &d.reply.unwrap(), &buf[..n]), Err(IpcError::Cap(MintError::Stale { current: 1, presented: 0 })));
+        let Ok(Received::Msg(d)) = s.recv(0, &ep, false) else { panic!() };
+        assert_eq!(seq_of(&d), 3);
+        // A cancelled receiver no longer takes messages.
+        assert_eq!(s.recv(0, &ep, true), Ok(Received::Blocked));
+        assert_eq!(s.cancel(0), Some(ThreadState::RecvBlocked(0)));
+        assert_eq!(s.send(3, &ping(ep, 4), false), Ok(Sent::Queued));
+    }
+
+    #[test]
+    fn rights_are_enforced() {
+        let mut s = Sys::new([3; 16]);
+        let ep = s.create_endpoint().unwrap();
+        let call_only = s.attenuate(&ep, Rights::CALL).unwrap();
+        assert_eq!(s.send(1, &ping(call_only, 1), false), Err(IpcError::Rights(MissingRights(Rights::WRITE))));
+        assert_eq!(s.recv(0, &call_only, false), Err(IpcError::Rights(MissingRights(Rights::READ))));
+        assert_eq!(s.call(1, &call_ping(call_only, 1)), Ok(Sent::Queued));
+        let read_only = s.attenuate(&ep, Rights::READ).unwrap();
+        assert!(matches!(s.recv(0, &read_only, false), Ok(Received::Msg(_))));
+        let mut forged = *ep.bytes();
+        forged[2] ^= 1;
+        assert_eq!(s.recv(0, &Cap::new(forged), false), Err(IpcError::Cap(MintError::Forged)));
+
+        // A cap derived in a cspace still verifies, with only the rights kept.
+        s.cspaces_mut().insert_root(SlotRef::new(0, 0), ep).unwrap();
+        assert_eq!(s.derive(0, 0, 1, Rights::READ), Ok(()));
+        let derived = s.cspaces().lookup(SlotRef::new(0, 1)).unwrap();
+        assert_eq!(s.send(2, &ping(ep, 5), false), Ok(Sent::Queued));
+        assert!(matches!(s.recv(0, &derived, false), Ok(Received::Msg(_))));
+        assert_eq!(s.send(0, &ping(derived, 5), false), Err(IpcError::Rights(MissingRights(Rights::WRITE))));
+    }
+
+    #[test]
+    fn full_queue_blocks_sender_until_recv() {
+        let mut s = Sys::new([3; 16]);
+        let ep = s.create_endpoint().unwrap();
+        assert_eq!(s.send(1, &ping(ep, 1), true), Ok(Sent::Queued));
+        assert_eq!(s.send(1, &ping(ep, 2), true), Ok(Sent::Queued));
+        assert_eq!(s.send(2, &ping(ep, 3), false), Err(IpcError::WouldBlock));
+        assert_eq!(s.send(2, &ping(ep, 3), true), Ok(Sent::Blocked));
+        assert_eq!(s.send(2, &ping(ep, 4), true), Err(IpcError::NotReady));
+        let Ok(Received::Msg(d)) = s.recv(0, &ep, false) else { panic!() };
+        assert_eq!(seq_of(&d), 1);
+        assert_eq!(s.state(2), Some(ThreadState::Ready));
+        let got: Vec<u64> = (0..2).map(|_| match s.recv(0, &ep, false) {
+            Ok(Received::Msg(d)) => seq_of(&d),
+            r => panic!("{:?}", r),
+        }).collect();
+        assert_eq!(got, [2, 3]);
+        assert_eq!(s.recv(0, &ep, false), Err(IpcError::WouldBlock));
+    }
+
+    fn map_shared(dst: Cap, region: CapTransfer) -> Vec<u8> {
+        let mut buf = [0u8; MSG_MAX];
+        let req = Request::MapShared(MapShared { region, offset: 0, len: 4096, writable: false });
+        let n = req.encode(Cap::nil(), dst, MsgFlags::REPLY_EXPECTED, &mut buf).unwrap();
+        buf[..n].to_vec()
+    }
+
+    fn region_of(d: &Delivery) -> CapTransfer {
+        match Request::decode(d.msg.bytes()).unwrap().1 {
+            Request::MapShared(m) => m.region,
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn caps_move_and_copy_into_receiver() {
+        let mut s = Sys::new([3; 16]);
+        let ep = s.create_endpoint().unwrap();
+        let region = Cap::new([9; 16]);
+        let (at, other) = (SlotRef::new(1, 2), SlotRef::new(1, 3));
+        s.cspaces_mut().insert_root(at, region).unwrap();
+        s.cspaces_mut().insert_root(other, Cap::new([8; 16])).unwrap();
+        s.cspaces_mut().insert_root(SlotRef::new(0, 0), ep).unwrap();
+
+        let no_grant = s.attenuate(&ep, Rights::CALL | Rights::MAP).unwrap();
+        assert_eq!(
+            s.call(1, &map_shared(no_grant, CapTransfer::copy(2))),
+            Err(IpcError::Rights(MissingRights(Rights::GRANT)))
+        );
+        assert_eq!(s.call(1, &map_shared(ep, CapTransfer::copy(0))), Err(IpcError::Slot(CapError::Empty)));
+
+        // Copy: the receiver's cap is a CDT child of the sender's.
+        assert_eq!(s.call(1, &map_shared(ep, CapTransfer::copy(2))), Ok(Sent::Queued));
+        let Ok(Received::Msg(d)) = s.recv(0, &ep, false) else { panic!() };
+        let landed = region_of(&d);
+        assert_eq!(landed, CapTransfer::copy(1));
+        let got = SlotRef::new(0, landed.slot);
+        assert_eq!(s.cspaces().lookup(got), Ok(region));
+        assert_eq!(s.cspaces().parent(got), Ok(Some(at)));
+        let mut buf = [0u8; MSG_MAX];
+        let n = encode_error(MsgType::MapShared, Cap::nil(), Cap::nil(), &ErrorBody { code: 1 }, &mut buf).unwrap();
+        s.reply(0, &d.reply.unwrap(), &buf[..n]).unwrap();
+        s.take(1).unwrap();
+
+        // Move: the sender's slot is emptied.
+        assert_eq!(s.recv(0, &ep, true), Ok(Received::Blocked));
+        assert_eq!(s.call(1, &map_shared(ep, CapTransfer::moved(3))), Ok(Sent::Delivered(0)));
+        assert_eq!(region_of(&s.take(0).unwrap()), CapTransfer::moved(2));
+        assert_eq!(s.cspaces().lookup(other), Err(CapError::Empty));
+        assert_eq!(s.cspaces().lookup(SlotRef::new(0, 2)), Ok(Cap::new([8; 16])));
+    }
+
+    #[test]
+    fn replies_need_grant_to_transfer_caps() {
+        let mut s = Sys::new([3; 16]);
+        let ep = s.create_endpoint().unwrap();
+        let region = Cap::new([9; 16]);
+        s.cspaces_mut().insert_root(SlotRef::new(0, 0), region).unwrap();
+        let mut with_cap = [0u8; MSG_MAX];
+        let p = ShmPayload { offset: 0, len: 4096 };
+        let n = encode_shm(MsgType::Ping as u16, Cap::nil(), Cap::nil(), MsgFlags::NONE, &[CapTransfer::copy(0)], &p, &mut with_cap).unwrap();
+        let with_cap = &with_cap[..n];
+
+        // Called without GRANT: the reply cap lacks it too.
+        let call_only = s.attenuate(&ep, Rights::CALL).unwrap();
+        assert_eq!(s.call(1, &call_ping(call_only, 1)), Ok(Sent::Queued));
+        let Ok(Received::Msg(d)) = s.recv(0, &ep, false) else { panic!() };
+        let reply = d.reply.unwrap();
+        assert_eq!(s.reply(0, &reply, with_cap), Err(IpcError::Rights(MissingRights(Rights::GRANT))));
+        assert_eq!(s.cspaces().occupied(1), 0);
+        let mut buf = [0u8; MSG_MAX];
+        let plain = Reply::Ping(PingReply { seq: 1 }).encode(Cap::nil(), Cap::nil(), MsgFlags::NONE, &mut buf).unwrap();
+        assert_eq!(s.reply(0, &reply, &buf[..plain]), Ok(()));
+        s.take(1).unwrap();
+
+        // Called with GRANT: the reply may hand the cap back.
+        assert_eq!(s.call(1, &call_ping(ep, 2)), Ok(Sent::Queued));
+        let Ok(Received::Msg(d)) = s.recv(0, &ep, false) else { panic!() };
+        assert_eq!(s.reply(0, &d.reply.unwrap(), with_cap), Ok(()));
+        assert_eq!(s.cspaces().lookup(SlotRef::new(1, 0)), Ok(region));
+    }
+
+    #[test]
+    fn flags_are_enforced() {
+        let mut s = Sys::new([3; 16]);
+        let ep = s.create_endpoint().unwrap();
+        assert_eq!(s.send(1, &call_ping(ep, 1), false), Err(IpcError::BadFlags));
+        assert_eq!(s.call(1, &ping(ep, 1)), Err(IpcError::BadFlags));
+        let mut buf = [0u8; MSG_MAX];
+        let n = encode_error(MsgType::Ping, Cap::nil(), ep, &ErrorBody { code: 7 }, &mut buf).unwrap();
+        assert_eq!(s.send(1, &buf[..n], false), Err(IpcError::BadFlags));
+
+        // NON_BLOCKING overrides a blocking send and applies to calls.
+        s.send(1, &ping(ep, 1), true).unwrap();
+        s.send(1, &ping(ep, 2), true).unwrap();
+        assert_eq!(s.send(2, &ping_with(ep, 3, MsgFlags::NON_BLOCKING), true), Err(IpcError::WouldBlock));
+        let nb_call = ping_with(ep, 3, MsgFlags::NON_BLOCKING | MsgFlags::REPLY_EXPECTED);
+        assert_eq!(s.call(2, &nb_call), Err(IpcError::WouldBlock));
+        assert_eq!(s.state(2), Some(ThreadState::Ready));
+        for _ in 0..2 {
+            assert!(matches!(s.recv(0, &ep, false), Ok(Received::Msg(_))));
+        }
+
+        // An error reply reaches the caller as-is.
+        assert_eq!(s.call(2, &nb_call), Ok(Sent::Queued));
+        let Ok(Received::Msg(d)) = s.recv(0, &ep, false) else { panic!() };
+        let n = encode_error(MsgType::Ping, Cap::nil(), Cap::nil(), &ErrorBody { code: 7 }, &mut buf).unwrap();
+        s.reply(0, &d.reply.unwrap(), &buf[..n]).unwrap();
+        assert_eq!(error_of(s.take(2).unwrap().msg.bytes()), Ok((MsgType::Ping, ErrorBody { code: 7 })));
+    }
+
+    #[test]
+    fn shm_payload_carries_region() {
+        let mut s = Sys::new([3; 16]);
+        let ep = s.create_endpoint().unwrap();
+        s.cspaces_mut().insert_root(SlotRef::new(1, 0), Cap::new([9; 16])).unwrap();
+        let mut buf = [0u8; MSG_MAX];
+        let p = ShmPayload { offset: 64, len: 1 << 20 };
+        let n = encode_shm(MsgType::Ping as u16, Cap::nil(), ep, MsgFlags::NONE, &[CapTransfer::copy(0)], &p, &mut buf).unwrap();
+        assert_eq!(s.send(1, &buf[..n - 1], false), Err(IpcError::Malformed(ParseError::Truncated { needed: n, got: n - 1 })));
+        assert_eq!(s.send(1, &buf[..n], false), Ok(Sent::Queued));
+        let Ok(Received::Msg(d)) = s.recv(0, &ep, false) else { panic!() };
+        let (_, region, p2) = shm_payload(d.msg.bytes()).unwrap();
+        assert_eq!((region, p2), (CapTransfer::copy(0), p));
+        assert_eq!(s.cspaces().lookup(SlotRef::new(0, 0)), Ok(Cap::new([9; 16])));
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Send(ThreadId, usize, bool),
+        Recv(ThreadId, usize, bool),
+        Take(ThreadId),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (0..4u16, 0..2usize, any::<bool>()).prop_map(|(t, e, b)| Op::Send(t, e, b)),
+            (0..4u16, 0..2usize, any::<bool>()).prop_map(|(t, e, b)| Op::Recv(t, e, b)),
+            (0..4u16).prop_map(Op::Take),
+        ]
+    }
+
+    proptest! {
+        // Messages are never lost, duplicated or reordered per endpoint.
+        #[test]
+        fn endpoints_are_fifo(ops in proptest::collection::vec(op(), 1..80)) {
+            let mut s = Sys::new([1; 16]);
+            let eps = [s.create_endpoint().unwrap(), s.create_endpoint().unwrap()];
+            let mut next = 0u64;
+            let mut sent: [Vec<u64>; 2] = Default::default();
+            let mut got: [Vec<u64>; 2] = Default::default();
+            // A direct hand-off counts as received when it happens, even if
+            // the receiver only collects it later.
+            let mut inboxed = [false; 4];
+            for op in ops {
+                match op {
+                    Op::Send(t, e, b) => {
+                        match s.send(t, &ping(eps[e], next), b) {
+                            Ok(Sent::Delivered(r)) => {
+                                sent[e].push(next);
+                                got[e].push(next);
+                                inboxed[r as usize] = true;
+                            }
+                            Ok(_) => sent[e].push(next),
+                            Err(_) => {}
+                        }
+                        next += 1;
+                    }
+                    Op::Recv(t, e, b) => {
+                        if let Ok(Received::Msg(d)) = s.recv(t, &eps[e], b) {
+                            if !core::mem::take(&mut inboxed[t as usize]) { got[e].push(seq_of(&d)); }
+                        }
+                    }
+                    Op::Take(t) => {
+                        if s.take(t).is_some() { inboxed[t as usize] = false; }
+                    }
+                }
+            }
+            for e in 0..2 {
+                prop_assert!(sent[e].starts_with(&got[e]));
+            }
+        }
+    }
+}
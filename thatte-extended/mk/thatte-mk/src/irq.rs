@@ -0,0 +1,443 @@
+//! Notifications and interrupt routing to user-mode drivers.
+//!
+//! A notification is a 64-bit signal word: `signal` ORs bits into it, and
+//! `wait` takes and clears the whole word, blocking while it is zero. At
+//! most one thread waits on a notification at a time.
+//!
+//! An IRQ handler cap stands for one interrupt line. The kernel `claim`s a
+//! line for a driver and hands it the cap; the driver binds the line to a
+//! bit of a notification and waits on that. Each line moves through
+//!
+//! ```text
+//!   Free --claim--> Claimed --bind--> Armed --interrupt--> Pending
+//!                      ^                ^                     |
+//!                      |                +--------ack----------+
+//!                      +----unbind (from Armed or Pending)----+
+//! ```
+//!
+//! and `release` returns it to `Free` from anywhere, staling every cap to
+//! it. The line is unmasked exactly while `Armed`: an interrupt masks it
+//! and signals the notification, and it stays masked until the driver has
+//! serviced the device and acks, so a level-triggered line cannot storm.
+//! Interrupts on a line that is not armed are counted as spurious.
+//!
+//! The controller sits behind `IrqChip`, so all of this runs on the host.
+
+use crate::ipc::ThreadId;
+use crate::mint::{object_id, MintAuthority, MintError, ObjectKind};
+use crate::{Cap, MissingRights, Rights};
+
+/// The interrupt controller (I/O APIC and local APIC, or a fake).
+pub trait IrqChip {
+    fn mask(&mut self, line: usize);
+    fn unmask(&mut self, line: usize);
+    /// Signal end of interrupt for `line`.
+    fn eoi(&mut self, line: usize);
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum IrqError {
+    /// The capability is forged or stale.
+    Cap(MintError),
+    /// The capability names an object of the wrong kind.
+    WrongObject,
+    /// The capability lacks rights for the operation.
+    Rights(MissingRights),
+    /// No such interrupt line.
+    BadLine,
+    /// The line already has a handler.
+    Claimed,
+    /// Notification bit out of range.
+    BadBit,
+    /// The line is not bound to a notification.
+    NotBound,
+    /// The line is already bound.
+    AlreadyBound,
+    /// No interrupt is waiting for an ack.
+    NotPending,
+    /// Another thread is waiting on the notification.
+    Busy,
+    /// A non-blocking wait found no bits set.
+    WouldBlock,
+    /// No free notification slots.
+    Exhausted,
+}
+
+/// Where an interrupt line is in its life cycle (see the module docs).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum LineState {
+    Free,
+    Claimed,
+    Armed,
+    Pending,
+}
+
+/// A thread woken by a signal, and the word it receives.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Woken {
+    pub tid: ThreadId,
+    pub bits: u64,
+}
+
+/// Outcome of a wait.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Waited {
+    Bits(u64),
+    /// The word was zero; the thread now waits for a `Woken`.
+    Blocked,
+}
+
+#[derive(Copy, Clone)]
+struct Notification {
+    live: bool,
+    word: u64,
+    waiter: Option<ThreadId>,
+}
+
+#[derive(Copy, Clone)]
+struct Handler {
+    state: LineState,
+    generation: u32,
+    /// Notification index and the bit the line sets in it.
+    target: Option<(usize, u64)>,
+}
+
+/// `LINES` interrupt lines and `NOTIFS` notifications.
+pub struct Irqs<const LINES: usize, const NOTIFS: usize> {
+    mint: MintAuthority,
+    lines: [Handler; LINES],
+    notifications: [Notification; NOTIFS],
+    spurious: u64,
+}
+
+impl<const LINES: usize, const NOTIFS: usize> Irqs<LINES, NOTIFS> {
+    /// `secret` keys the authority that mints notification and handler caps.
+    pub const fn new(secret: [u8; 16]) -> Self {
+        Self {
+            mint: MintAuthority::new(secret),
+            lines: [Handler { state: LineState::Free, generation: 0, target: None }; LINES],
+            notifications: [Notification { live: false, word: 0, waiter: None }; NOTIFS],
+            spurious: 0,
+        }
+    }
+
+    /// Create a notification and return a cap to it (READ to wait, WRITE to
+    /// signal or bind a line to it, GRANT to pass it on).
+    pub fn create_notification(&mut self) -> Result<Cap, IrqError> {
+        let i = self.notifications.iter().position(|n| !n.live).ok_or(IrqError::Exhausted)?;
+        self.notifications[i].live = true;
+        Ok(self.mint.mint(object_id(ObjectKind::Notification, i as u32), 0, Rights::READ | Rights::WRITE | Rights::GRANT))
+    }
+
+    /// Re-mint `cap` with only the rights in `mask`.
+    pub fn attenuate(&self, cap: &Cap, mask: Rights) -> Result<Cap, IrqError> {
+        self.mint.attenuate(cap, mask).map_err(IrqError::Cap)
+    }
+
+    /// Resolve a notification cap, requiring `need`.
+    fn notification(&self, cap: &Cap, need: Rights) -> Result<usize, IrqError> {
+        let m = self.mint.verify(cap).map_err(IrqError::Cap)?;
+        let i = m.index_of(ObjectKind::Notification).filter(|&i| i < NOTIFS).ok_or(IrqError::WrongObject)?;
+        if !self.notifications[i].live { return Err(IrqError::WrongObject); }
+        cap.require(need).map_err(IrqError::Rights)?;
+        Ok(i)
+    }
+
+    /// Resolve a handler cap, requiring `need`.
+    fn handler(&self, cap: &Cap, need: Rights) -> Result<usize, IrqError> {
+        let m = self.mint.verify(cap).map_err(IrqError::Cap)?;
+        let line = m.index_of(ObjectKind::IrqHandler).filter(|&l| l < LINES).ok_or(IrqError::WrongObject)?;
+        self.mint.verify_current(cap, self.lines[line].generation).map_err(IrqError::Cap)?;
+        cap.require(need).map_err(IrqError::Rights)?;
+        Ok(line)
+    }
+
+    fn raise(&mut self, i: usize, bits: u64) -> Option<Woken> {
+        let n = &mut self.notifications[i];
+        n.word |= bits;
+        let tid = n.waiter.take()?;
+        Some(Woken { tid, bits: core::mem::take(&mut n.word) })
+    }
+
+    /// OR `bits` into the notification (needs WRITE), waking its waiter.
+    pub fn signal(&mut self, cap: &Cap, bits: u64) -> Result<Option<Woken>, IrqError> {
+        let i = self.notification(cap, Rights::WRITE)?;
+        Ok(if bits == 0 { None } else { self.raise(i, bits) })
+    }
+
+    /// Take and clear the notification's word (needs READ), or wait for a
+    /// signal if it is zero and `blocking`.
+    pub fn wait(&mut self, tid: ThreadId, cap: &Cap, blocking: bool) -> Result<Waited, IrqError> {
+        let i = self.notification(cap, Rights::READ)?;
+        let n = &mut self.notifications[i];
+        if n.word != 0 { return Ok(Waited::Bits(core::mem::take(&mut n.word))); }
+        if !blocking { return Err(IrqError::WouldBlock); }
+        if n.waiter.is_some_and(|w| w != tid) { return Err(IrqError::Busy); }
+        n.waiter = Some(tid);
+        Ok(Waited::Blocked)
+    }
+
+    /// Stop `tid` waiting (its timeout expired); false if it was not.
+    pub fn cancel(&mut self, tid: ThreadId) -> bool {
+        let n = self.notifications.iter_mut().find(|n| n.waiter == Some(tid));
+        n.map(|n| n.waiter = None).is_some()
+    }
+
+    /// Give `line` a handler and return the cap to it (WRITE to bind, ack
+    /// and unbind, GRANT to pass it on). Only the kernel calls this, for the
+    /// driver the boot policy assigns the line to; the line stays masked.
+    pub fn claim(&mut self, line: usize, chip: &mut impl IrqChip) -> Result<Cap, IrqError> {
+        let h = self.lines.get_mut(line).ok_or(IrqError::BadLine)?;
+        if h.state != LineState::Free { return Err(IrqError::Claimed); }
+        h.state = LineState::Claimed;
+        chip.mask(line);
+        Ok(self.mint.mint(object_id(ObjectKind::IrqHandler, line as u32), h.generation, Rights::WRITE | Rights::GRANT))
+    }
+
+    /// Deliver the line's interrupts as `bit` of the notification (needs
+    /// WRITE on both caps), and unmask it.
+    pub fn bind(&mut self, handler: &Cap, notification: &Cap, bit: u32, chip: &mut impl IrqChip) -> Result<(), IrqError> {
+        let line = self.handler(handler, Rights::WRITE)?;
+        let n = self.notification(notification, Rights::WRITE)?;
+        if bit >= u64::BITS { return Err(IrqError::BadBit); }
+        let h = &mut self.lines[line];
+        if h.state != LineState::Claimed { return Err(IrqError::AlreadyBound); }
+        *h = Handler { state: LineState::Armed, target: Some((n, 1 << bit)), ..*h };
+        chip.unmask(line);
+        Ok(())
+    }
+
+    /// Detach the line from its notification and mask it.
+    pub fn unbind(&mut self, handler: &Cap, chip: &mut impl IrqChip) -> Result<(), IrqError> {
+        let line = self.handler(handler, Rights::WRITE)?;
+        let h = &mut self.lines[line];
+        if h.target.is_none() { return Err(IrqError::NotBound); }
+        *h = Handler { state: LineState::Claimed, target: None, ..*h };
+        chip.mask(line);
+        Ok(())
+    }
+
+    /// The driver has serviced the device: unmask the line for the next
+    /// interrupt.
+    pub fn ack(&mut self, handler: &Cap, chip: &mut impl IrqChip) -> Result<(), IrqError> {
+        let line = self.handler(handler, Rights::WRITE)?;
+        let h = &mut self.lines[line];
+        match h.state {
+            LineState::Pending => {
+                h.state = LineState::Armed;
+                chip.unmask(line);
+                Ok(())
+            }
+            LineState::Armed => Err(IrqError::NotPending),
+            _ => Err(IrqError::NotBound),
+        }
+    }
+
+    /// Free the line (needs WRITE); every cap to it goes stale.
+    pub fn release(&mut self, handler: &Cap, chip: &mut impl IrqChip) -> Result<(), IrqError> {
+        let line = self.handler(handler, Rights::WRITE)?;
+        let h = &mut self.lines[line];
+        *h = Handler { state: LineState::Free, generation: h.generation.wrapping_add(1), target: None };
+        chip.mask(line);
+        Ok(())
+    }
+
+    /// Entry from the interrupt vector for `line`: mask it, then
+    /// acknowledge it at the controller, so it cannot fire again before the
+    /// driver acks; if the line is armed, signal its notification and
+    /// return the thread to wake. A line out of range is only counted as
+    /// spurious; the controller is not touched.
+    pub fn interrupt(&mut self, line: usize, chip: &mut impl IrqChip) -> Option<Woken> {
+        let Some(h) = self.lines.get_mut(line) else {
+            self.spurious += 1;
+            return None;
+        };
+        chip.mask(line);
+        chip.eoi(line);
+        match (h.state, h.target) {
+            (LineState::Armed, Some((n, bit))) => {
+                h.state = LineState::Pending;
+                self.raise(n, bit)
+            }
+            _ => {
+                self.spurious += 1;
+                None
+            }
+        }
+    }
+
+    pub fn state(&self, line: usize) -> Option<LineState> { self.lines.get(line).map(|h| h.state) }
+
+    /// Interrupts that arrived on lines not armed for them.
+    pub fn spurious(&self) -> u64 { self.spurious }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::vec::Vec;
+
+    type Sys = Irqs<4, 2>;
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum Call {
+        Mask(usize),
+        Unmask(usize),
+        Eoi(usize),
+    }
+
+    /// Records mask state and every call in order; every line starts
+    /// masked.
+    #[derive(Debug, Default)]
+    struct Chip {
+        unmasked: u64,
+        calls: Vec<Call>,
+    }
+
+    impl Chip {
+        fn eois(&self) -> usize { self.calls.iter().filter(|c| matches!(c, Call::Eoi(_))).count() }
+    }
+
+    impl IrqChip for Chip {
+        fn mask(&mut self, line: usize) {
+            self.unmasked &= !(1 << line);
+            self.calls.push(Call::Mask(line));
+        }
+        fn unmask(&mut self, line: usize) {
+            self.unmasked |= 1 << line;
+            self.calls.push(Call::Unmask(line));
+        }
+        fn eoi(&mut self, line: usize) { self.calls.push(Call::Eoi(line)); }
+    }
+
+    #[test]
+    fn driver_waits_and_acks() {
+        let mut s = Sys::new([9; 16]);
+        let mut chip = Chip::default();
+        let n = s.create_notification().unwrap();
+        let h = s.claim(2, &mut chip).unwrap();
+        assert_eq!(s.claim(2, &mut chip), Err(IrqError::Claimed));
+        assert_eq!(s.claim(4, &mut chip), Err(IrqError::BadLine));
+        assert_eq!(s.ack(&h, &mut chip), Err(IrqError::NotBound));
+        assert_eq!(s.bind(&h, &n, 64, &mut chip), Err(IrqError::BadBit));
+        let wait_only = s.attenuate(&n, Rights::READ).unwrap();
+        assert_eq!(s.bind(&h, &wait_only, 3, &mut chip), Err(IrqError::Rights(MissingRights(Rights::WRITE))));
+        assert_eq!(s.bind(&n, &n, 3, &mut chip), Err(IrqError::WrongObject));
+        s.bind(&h, &n, 3, &mut chip).unwrap();
+        assert_eq!((s.state(2), chip.unmasked), (Some(LineState::Armed), 1 << 2));
+
+        // The driver waits; the interrupt wakes it and masks the line.
+        assert_eq!(s.wait(7, &wait_only, true), Ok(Waited::Blocked));
+        assert_eq!(s.wait(8, &n, true), Err(IrqError::Busy));
+        assert_eq!(s.interrupt(2, &mut chip), Some(Woken { tid: 7, bits: 1 << 3 }));
+        assert_eq!((s.state(2), chip.unmasked), (Some(LineState::Pending), 0));
+        assert_eq!(chip.calls[chip.calls.len() - 2..], [Call::Mask(2), Call::Eoi(2)]);
+        // Nothing more is delivered until the ack.
+        assert_eq!(s.interrupt(2, &mut chip), None);
+        assert_eq!(s.spurious(), 1);
+        s.ack(&h, &mut chip).unwrap();
+        assert_eq!(s.ack(&h, &mut chip), Err(IrqError::NotPending));
+        assert_eq!(chip.unmasked, 1 << 2);
+
+        // Signals accumulate while nobody waits.
+        assert_eq!(s.interrupt(2, &mut chip), None);
+        assert_eq!(s.signal(&n, 1), Ok(None));
+        assert_eq!(s.wait(7, &n, false), Ok(Waited::Bits(1 << 3 | 1)));
+        assert_eq!(s.wait(7, &n, false), Err(IrqError::WouldBlock));
+        assert_eq!(s.wait(7, &n, true), Ok(Waited::Blocked));
+        assert!(s.cancel(7) && !s.cancel(7));
+        assert_eq!(chip.eois(), 3);
+        // A line that does not exist is counted, not acknowledged.
+        let calls = chip.calls.len();
+        assert_eq!(s.interrupt(4, &mut chip), None);
+        assert_eq!((chip.calls.len(), s.spurious()), (calls, 2));
+
+        s.unbind(&h, &mut chip).unwrap();
+        assert_eq!((s.state(2), chip.unmasked), (Some(LineState::Claimed), 0));
+        s.release(&h, &mut chip).unwrap();
+        assert_eq!(s.ack(&h, &mut chip), Err(IrqError::Cap(MintError::Stale { current: 1, presented: 0 })));
+        assert!(s.claim(2, &mut chip).is_ok());
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Claim(usize),
+        Bind(usize, u32),
+        Unbind(usize),
+        Ack(usize),
+        Release(usize),
+        Interrupt(usize),
+        Wait,
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (0..4usize).prop_map(Op::Claim),
+            (0..4usize, 0..4u32).prop_map(|(l, b)| Op::Bind(l, b)),
+            (0..4usize).prop_map(Op::Unbind),
+            (0..4usize).prop_map(Op::Ack),
+            (0..4usize).prop_map(Op::Release),
+            (0..5usize).prop_map(Op::Interrupt),
+            Just(Op::Wait),
+        ]
+    }
+
+    proptest! {
+        // Any sequence of driver operations and interrupts, against a model
+        // of the signal word: a line is unmasked exactly while armed, every
+        // interrupt on a real line masks it and then gets an EOI (one out of
+        // range touches nothing), and interrupts on armed lines (only) reach
+        // the driver, coalescing while it is not waiting.
+        #[test]
+        fn line_state_machine(ops in proptest::collection::vec(op(), 1..100)) {
+            let mut s = Sys::new([9; 16]);
+            let mut chip = Chip::default();
+            let n = s.create_notification().unwrap();
+            let mut caps: [Option<Cap>; 4] = [None; 4];
+            let mut bits = [0u64; 4];
+            let (mut word, mut waiting, mut interrupts, mut spurious) = (0u64, false, 0, 0);
+            for op in ops {
+                match op {
+                    Op::Claim(l) => if let Ok(c) = s.claim(l, &mut chip) { caps[l] = Some(c); },
+                    Op::Bind(l, b) => if let Some(c) = caps[l] {
+                        if s.bind(&c, &n, b, &mut chip).is_ok() { bits[l] = 1 << b; }
+                    },
+                    Op::Unbind(l) => if let Some(c) = caps[l] { let _ = s.unbind(&c, &mut chip); },
+                    Op::Ack(l) => if let Some(c) = caps[l] { let _ = s.ack(&c, &mut chip); },
+                    Op::Release(l) => if let Some(c) = caps[l].take() { s.release(&c, &mut chip).unwrap(); },
+                    Op::Interrupt(l) => {
+                        let armed = s.state(l) == Some(LineState::Armed);
+                        let calls = chip.calls.len();
+                        let woken = s.interrupt(l, &mut chip);
+                        if l < 4 {
+                            interrupts += 1;
+                            prop_assert_eq!(&chip.calls[calls..], &[Call::Mask(l), Call::Eoi(l)]);
+                        } else {
+                            prop_assert_eq!(chip.calls.len(), calls);
+                        }
+                        if !armed {
+                            spurious += 1;
+                            prop_assert_eq!(woken, None);
+                        } else {
+                            word |= bits[l];
+                            if core::mem::take(&mut waiting) {
+                                prop_assert_eq!(woken, Some(Woken { tid: 0, bits: core::mem::take(&mut word) }));
+                            } else {
+                                prop_assert_eq!(woken, None);
+                            }
+                        }
+                    }
+                    Op::Wait => {
+                        let expect = if word != 0 { Waited::Bits(core::mem::take(&mut word)) } else { Waited::Blocked };
+                        waiting = expect == Waited::Blocked;
+                        prop_assert_eq!(s.wait(0, &n, true), Ok(expect));
+                    }
+                }
+                let armed: Vec<_> = (0..4).filter(|&l| s.state(l) == Some(LineState::Armed)).collect();
+                prop_assert_eq!(chip.unmasked, armed.iter().map(|l| 1u64 << l).sum::<u64>());
+            }
+            prop_assert_eq!((chip.eois(), s.spurious()), (interrupts, spurious));
+        }
+    }
+}
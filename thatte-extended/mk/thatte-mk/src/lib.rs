@@ -1,10 +1,64 @@
 #![cfg_attr(feature = "no_std", no_std)]
-//! THATTE microkernel skeleton: capability ids and typed IPC model (stubs).
+//! THATTE microkernel: the kernel's object model and mechanisms as a `no_std`
+//! library, plus the bootable kernel image built on it.
 //!
-//! This crate is currently a library that builds in `no_std` mode by default,
-//! with some `std` tests for message encoding.
+//! - Capabilities: `Cap` and `Rights`, keyed minting (`mint`) and capability
+//!   spaces with a derivation tree (`cspace`).
+//! - IPC: the wire format (`msg`, `flags`, CRC-32C in `crc`), endpoints with
+//!   call/reply and capability transfer (`ipc`), shared-memory regions
+//!   (`shm`), notifications and IRQ routing (`irq`).
+//! - Threads and time: TCBs and the three-class scheduler with priority
+//!   inheritance (`sched`), clocks, timer wheel and IPC timeouts (`time`).
+//! - The system call ABI and its dispatcher (`syscall`).
+//! - Memory: the physical frame allocator (`frame`), four-level page tables
+//!   (`paging`) and, behind the `alloc` feature, the kernel heap (`heap`)
+//!   that the capability-space tables grow on.
+//! - x86_64 setup (`arch`): GDT/TSS, IDT and trap frames, the serial console.
+//!
+//! The `kernel` feature builds `thatte-kernel` (`src/bin/thatte-kernel`),
+//! which the UEFI loader starts: it takes over the CPU with its own GDT, TSS
+//! and IDT, reports the boot info on COM1, sets up its frame allocator and
+//! heap, marks its A/B slot as good and idles. The scheduler, IPC and time
+//! code are not wired into it yet. Hosted builds (`std`) add the scheduler
+//! simulator (`sim`) and the tests.
+
+#[cfg(feature = "std")]
+extern crate std;
+extern crate alloc;
+
+#[cfg(target_arch = "x86_64")]
+pub mod arch;
+pub mod crc;
+pub mod cspace;
+pub mod error;
+pub mod flags;
+pub mod frame;
+#[cfg(feature = "alloc")]
+pub mod heap;
+pub mod ipc;
+pub mod irq;
+pub mod mint;
+pub mod msg;
+pub mod paging;
+pub mod rights;
+pub mod sched;
+pub mod shm;
+#[cfg(feature = "std")]
+pub mod sim;
+pub mod sync;
+pub mod syscall;
+pub mod time;
+
+pub use error::ParseError;
+pub use flags::MsgFlags;
+pub use msg::MsgType;
+pub use rights::Rights;
 
-/// A 128-bit capability id (opaque).
+/// A 128-bit capability id.
+///
+/// The first two bytes carry the `Rights` mask (little-endian); the rest is
+/// opaque to everything but the object's owner. Kernel-issued caps are
+/// produced by a `mint::MintAuthority`, which authenticates all 16 bytes.
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub struct Cap([u8; 16]);
 
@@ -12,56 +66,179 @@ impl Cap {
     pub const fn new(bytes: [u8; 16]) -> Self { Self(bytes) }
     pub fn nil() -> Self { Self([0u8; 16]) }
     pub fn bytes(&self) -> &[u8; 16] { &self.0 }
+
+    /// Rights this capability grants.
+    pub fn rights(&self) -> Rights { Rights::from_bits_truncate(u16::from_le_bytes([self.0[0], self.0[1]])) }
+
+    /// Same capability with its rights replaced by `rights`, which may add
+    /// some; outside the crate only `attenuate` changes rights.
+    pub(crate) fn with_rights(mut self, rights: Rights) -> Self {
+        self.0[0..2].copy_from_slice(&rights.bits().to_le_bytes());
+        self
+    }
+
+    /// Same capability restricted to `mask`. Rights can only be removed.
+    pub fn attenuate(self, mask: Rights) -> Self { self.with_rights(self.rights() & mask) }
+
+    /// Succeeds if every right in `needed` is present; otherwise reports the
+    /// missing ones.
+    pub fn require(&self, needed: Rights) -> Result<(), MissingRights> {
+        let missing = needed.difference(self.rights());
+        if missing.is_empty() { Ok(()) } else { Err(MissingRights(missing)) }
+    }
 }
 
-/// A tiny typed IPC message enum (to be codegen'd from IDL later).
-#[repr(u16)]
+/// Rights that an operation needed but the capability did not carry.
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
-pub enum MsgType {
-    Ping = 1,
-    GetTime = 2,
-    MapShared = 3,
-}
+pub struct MissingRights(pub Rights);
+
+/// Size of a serialized `IpcHeader`.
+pub const HEADER_LEN: usize = 52;
+
+/// First four bytes of every header ("THMK").
+pub const MAGIC: [u8; 4] = *b"THMK";
 
-/// IPC header (fixed size) followed by payload depending on `MsgType`.
+/// Wire protocol version written by `serialize_header`.
+pub const VERSION: u16 = 1;
+
+/// Size of the unversioned header (`ty, flags, src, dst, len`, no magic,
+/// caps or checksum) still accepted by `parse_header_compat`.
+pub const LEGACY_HEADER_LEN: usize = 40;
+
+/// IPC header (fixed size), then `caps` capability-transfer entries (see
+/// `msg::CapTransfer`), then `len` bytes of payload depending on `MsgType`
+/// (see `msg` for the typed bodies).
+///
+/// On the wire (little-endian): magic, version, `ty`, `flags`, `caps`,
+/// `len`, `src`, `dst`, then a CRC-32C of the preceding 48 bytes. The
+/// checksum covers the header only; the kernel rewrites the transfer section
+/// in flight.
 #[repr(C)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Eq, PartialEq)]
 pub struct IpcHeader {
     pub ty: u16,
-    pub flags: u16,
+    pub flags: MsgFlags,
     pub src: Cap,
     pub dst: Cap,
     pub len: u32,
+    /// Number of entries in the capability-transfer section.
+    pub caps: u16,
 }
 
 impl core::fmt::Debug for IpcHeader {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "IpcHeader {{ ty: {}, flags: {}, len: {} }}", self.ty, self.flags, self.len)
+        write!(f, "IpcHeader {{ ty: {}, flags: {:?}, len: {}, caps: {} }}", self.ty, self.flags, self.len, self.caps)
+    }
+}
+
+impl IpcHeader {
+    /// Whether `flags` agree with the rest of the header (see `MsgFlags`).
+    pub(crate) fn flags_consistent(&self) -> bool {
+        let f = self.flags;
+        let reply = self.ty & msg::REPLY_BIT != 0;
+        f.contains(MsgFlags::GRANT_CAPS) == (self.caps != 0)
+            && (!f.contains(MsgFlags::SHM_PAYLOAD) || f.contains(MsgFlags::GRANT_CAPS))
+            && (!f.contains(MsgFlags::ERROR) || reply)
+            && (!f.contains(MsgFlags::REPLY_EXPECTED) || !reply)
     }
 }
 
 /// Serialize a header to bytes (little-endian).
-pub fn serialize_header(h: &IpcHeader) -> [u8; 40] {
-    let mut out = [0u8; 40];
-    out[0..2].copy_from_slice(&(h.ty).to_le_bytes());
-    out[2..4].copy_from_slice(&(h.flags).to_le_bytes());
-    out[4..20].copy_from_slice(h.src.bytes());
-    out[20..36].copy_from_slice(h.dst.bytes());
-    out[36..40].copy_from_slice(&(h.len).to_le_bytes());
+pub fn serialize_header(h: &IpcHeader) -> [u8; HEADER_LEN] {
+    let mut out = [0u8; HEADER_LEN];
+    out[0..4].copy_from_slice(&MAGIC);
+    out[4..6].copy_from_slice(&VERSION.to_le_bytes());
+    out[6..8].copy_from_slice(&(h.ty).to_le_bytes());
+    out[8..10].copy_from_slice(&(h.flags.bits()).to_le_bytes());
+    out[10..12].copy_from_slice(&(h.caps).to_le_bytes());
+    out[12..16].copy_from_slice(&(h.len).to_le_bytes());
+    out[16..32].copy_from_slice(h.src.bytes());
+    out[32..48].copy_from_slice(h.dst.bytes());
+    seal(&mut out);
     out
 }
 
-/// Parse a header from bytes (little-endian).
-pub fn parse_header(buf: &[u8]) -> Option<IpcHeader> {
-    if buf.len() < 40 { return None; }
+/// Write the checksum of `out[..48]` into `out[48..52]`.
+fn seal(out: &mut [u8; HEADER_LEN]) {
+    let crc = crc::crc32c(&out[..HEADER_LEN - 4]);
+    out[HEADER_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Why the IPC path refused to deliver a message.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum IpcDenied {
+    /// `ty` is not a known `MsgType`.
+    UnknownType(u16),
+    /// The destination capability lacks rights for this message type.
+    Rights(MissingRights),
+}
+
+/// Check that `h.dst` authorizes sending a message of type `h.ty`.
+pub fn authorize(h: &IpcHeader) -> Result<MsgType, IpcDenied> {
+    let ty = MsgType::try_from(h.ty).map_err(|_| IpcDenied::UnknownType(h.ty))?;
+    h.dst.require(ty.required_rights()).map_err(IpcDenied::Rights)?;
+    Ok(ty)
+}
+
+fn cap_at(buf: &[u8], at: usize) -> Cap {
+    let mut c = [0u8; 16];
+    c.copy_from_slice(&buf[at..at + 16]);
+    Cap::new(c)
+}
+
+fn checked(h: IpcHeader, raw_flags: u16) -> Result<IpcHeader, ParseError> {
+    if h.flags_consistent() { Ok(h) } else { Err(ParseError::BadFlags(raw_flags)) }
+}
+
+/// Parse a header from bytes (little-endian). The magic, version and checksum
+/// must match; undefined flag bits, or flags that contradict the rest of the
+/// header, are rejected.
+pub fn parse_header(buf: &[u8]) -> Result<IpcHeader, ParseError> {
+    let got = buf.len();
+    let buf: &[u8; HEADER_LEN] = buf.get(..HEADER_LEN).ok_or(ParseError::Truncated { needed: HEADER_LEN, got })?.try_into().unwrap();
+    let magic = [buf[0], buf[1], buf[2], buf[3]];
+    if magic != MAGIC { return Err(ParseError::BadMagic(magic)); }
+    let version = u16::from_le_bytes([buf[4], buf[5]]);
+    if version != VERSION { return Err(ParseError::Version(version)); }
+    let stored = u32::from_le_bytes([buf[48], buf[49], buf[50], buf[51]]);
+    let computed = crc::crc32c(&buf[..HEADER_LEN - 4]);
+    if stored != computed { return Err(ParseError::Checksum { stored, computed }); }
+    let ty = u16::from_le_bytes([buf[6], buf[7]]);
+    let raw_flags = u16::from_le_bytes([buf[8], buf[9]]);
+    let flags = MsgFlags::from_bits(raw_flags).ok_or(ParseError::BadFlags(raw_flags))?;
+    let caps = u16::from_le_bytes([buf[10], buf[11]]);
+    let len = u32::from_le_bytes([buf[12], buf[13], buf[14], buf[15]]);
+    checked(IpcHeader { ty, flags, src: cap_at(buf, 16), dst: cap_at(buf, 32), len, caps }, raw_flags)
+}
+
+/// Parse a header in either the current layout or the legacy 40-byte one
+/// (which has no magic, version, checksum or transfer section). Returns the
+/// header and its encoded size, i.e. where the rest of the message starts.
+///
+/// A legacy header cannot be mistaken for a current one: `MAGIC` read as a
+/// legacy `flags` field has undefined bits set.
+pub fn parse_header_compat(buf: &[u8]) -> Result<(IpcHeader, usize), ParseError> {
+    if buf.starts_with(&MAGIC) { return parse_header(buf).map(|h| (h, HEADER_LEN)); }
+    if buf.len() < LEGACY_HEADER_LEN { return Err(ParseError::Truncated { needed: LEGACY_HEADER_LEN, got: buf.len() }); }
     let ty = u16::from_le_bytes([buf[0], buf[1]]);
-    let flags = u16::from_le_bytes([buf[2], buf[3]]);
-    let mut src = [0u8; 16];
-    src.copy_from_slice(&buf[4..20]);
-    let mut dst = [0u8; 16];
-    dst.copy_from_slice(&buf[20..36]);
+    let raw_flags = u16::from_le_bytes([buf[2], buf[3]]);
+    let flags = MsgFlags::from_bits(raw_flags).ok_or(ParseError::BadFlags(raw_flags))?;
     let len = u32::from_le_bytes([buf[36], buf[37], buf[38], buf[39]]);
-    Some(IpcHeader { ty, flags, src: Cap::new(src), dst: Cap::new(dst), len })
+    let h = IpcHeader { ty, flags, src: cap_at(buf, 4), dst: cap_at(buf, 20), len, caps: 0 };
+    Ok((checked(h, raw_flags)?, LEGACY_HEADER_LEN))
+}
+
+/// Rewrite a message with a legacy header into the current layout in `out`,
+/// so the typed decoders in `msg` can read it. Returns the new length; an
+/// `out` too small for it is reported as `Truncated`.
+pub fn upgrade_legacy(buf: &[u8], out: &mut [u8]) -> Result<usize, ParseError> {
+    let (h, at) = parse_header_compat(buf)?;
+    let body = &buf[at..];
+    let n = HEADER_LEN + body.len();
+    if out.len() < n { return Err(ParseError::Truncated { needed: n, got: out.len() }); }
+    out[..HEADER_LEN].copy_from_slice(&serialize_header(&h));
+    out[HEADER_LEN..n].copy_from_slice(body);
+    Ok(n)
 }
 
 #[cfg(all(test, feature = "std"))]
@@ -71,13 +248,108 @@ mod tests {
 
     proptest! {
         #[test]
-        fn header_roundtrip(ty in 0u16..=u16::MAX, flags in 0u16..=u16::MAX, len in 0u32..=u32::MAX) {
-            let h = IpcHeader { ty, flags, src: Cap::nil(), dst: Cap::nil(), len };
+        fn header_roundtrip(ty in 0u16..=u16::MAX, flags in 0u16..=0x1f, len in 0u32..=u32::MAX, caps in 0u16..=u16::MAX) {
+            let h = IpcHeader { ty, flags: MsgFlags::from_bits(flags).unwrap(), src: Cap::nil(), dst: Cap::nil(), len, caps };
             let b = serialize_header(&h);
-            let h2 = parse_header(&b).unwrap();
+            let Ok(h2) = parse_header(&b) else {
+                prop_assert!(!h.flags_consistent());
+                return Ok(());
+            };
             prop_assert_eq!(h.ty, h2.ty);
             prop_assert_eq!(h.flags, h2.flags);
             prop_assert_eq!(h.len, h2.len);
+            prop_assert_eq!(h.caps, h2.caps);
+        }
+
+        #[test]
+        fn attenuate_never_adds_rights(bits in 0u16..=u16::MAX, mask in 0u16..=u16::MAX) {
+            let c = Cap::nil().with_rights(Rights::from_bits_truncate(bits));
+            let a = c.attenuate(Rights::from_bits_truncate(mask));
+            prop_assert!(c.rights().contains(a.rights()));
+            prop_assert_eq!(a.attenuate(Rights::ALL), a);
+        }
+
+        #[test]
+        fn undefined_flag_bits_are_rejected(bits in 0x20u16..=u16::MAX) {
+            let h = IpcHeader { ty: 1, flags: MsgFlags::NONE, src: Cap::nil(), dst: Cap::nil(), len: 0, caps: 0 };
+            let mut b = serialize_header(&h);
+            b[8..10].copy_from_slice(&bits.to_le_bytes());
+            seal(&mut b);
+            prop_assert_eq!(parse_header(&b), Err(ParseError::BadFlags(bits)));
         }
+
+        #[test]
+        fn corruption_is_detected(byte in 0..HEADER_LEN, bit in 0..8u8) {
+            let h = IpcHeader { ty: 1, flags: MsgFlags::NONE, src: Cap::new([5; 16]), dst: Cap::new([6; 16]), len: 9, caps: 0 };
+            let mut b = serialize_header(&h);
+            b[byte] ^= 1 << bit;
+            let e = parse_header(&b).unwrap_err();
+            let detected = matches!(e, ParseError::BadMagic(_) | ParseError::Version(_) | ParseError::Checksum { .. });
+            prop_assert!(detected, "{:?}", e);
+        }
+
+        #[test]
+        fn legacy_headers_still_decode(ty in any::<u16>(), len in any::<u32>(), src in any::<[u8; 16]>(), dst in any::<[u8; 16]>()) {
+            let mut old = [0u8; LEGACY_HEADER_LEN + 3];
+            old[0..2].copy_from_slice(&ty.to_le_bytes());
+            old[4..20].copy_from_slice(&src);
+            old[20..36].copy_from_slice(&dst);
+            old[36..40].copy_from_slice(&len.to_le_bytes());
+            old[40..].copy_from_slice(b"abc");
+            let (h, at) = parse_header_compat(&old).unwrap();
+            prop_assert_eq!((h.ty, h.flags, h.len, h.caps, at), (ty, MsgFlags::NONE, len, 0, LEGACY_HEADER_LEN));
+            prop_assert_eq!((h.src, h.dst), (Cap::new(src), Cap::new(dst)));
+
+            let mut out = [0u8; HEADER_LEN + 3];
+            prop_assert_eq!(upgrade_legacy(&old, &mut out), Ok(out.len()));
+            prop_assert_eq!(parse_header_compat(&out).map(|(h2, at)| (h2.ty, h2.len, at)), Ok((ty, len, HEADER_LEN)));
+            prop_assert_eq!(&out[HEADER_LEN..], b"abc");
+        }
+    }
+
+    #[test]
+    fn header_errors_are_typed() {
+        let h = IpcHeader { ty: 1, flags: MsgFlags::NONE, src: Cap::nil(), dst: Cap::nil(), len: 0, caps: 0 };
+        let b = serialize_header(&h);
+        assert_eq!(parse_header(&b[..HEADER_LEN - 1]), Err(ParseError::Truncated { needed: HEADER_LEN, got: HEADER_LEN - 1 }));
+        let mut bad = b;
+        bad[0] = b'X';
+        assert_eq!(parse_header(&bad), Err(ParseError::BadMagic(*b"XHMK")));
+        let mut v2 = b;
+        v2[4] = 2;
+        seal(&mut v2);
+        assert_eq!(parse_header(&v2), Err(ParseError::Version(2)));
+        let mut flipped = b;
+        flipped[20] ^= 0x80;
+        assert!(matches!(parse_header(&flipped), Err(ParseError::Checksum { .. })));
+        // The legacy layout cannot claim undefined flags either.
+        assert_eq!(parse_header_compat(&[0xff; LEGACY_HEADER_LEN]), Err(ParseError::BadFlags(0xffff)));
+    }
+
+    #[test]
+    fn flags_must_match_header() {
+        let h = IpcHeader { ty: 1, flags: MsgFlags::NONE, src: Cap::nil(), dst: Cap::nil(), len: 0, caps: 0 };
+        let parses = |h: IpcHeader| parse_header(&serialize_header(&h)).is_ok();
+        assert!(parses(h));
+        assert!(!parses(IpcHeader { caps: 1, ..h }));
+        assert!(parses(IpcHeader { caps: 1, flags: MsgFlags::GRANT_CAPS, ..h }));
+        assert!(!parses(IpcHeader { flags: MsgFlags::GRANT_CAPS, ..h }));
+        assert!(!parses(IpcHeader { flags: MsgFlags::SHM_PAYLOAD, ..h }));
+        assert!(parses(IpcHeader { caps: 1, flags: MsgFlags::SHM_PAYLOAD | MsgFlags::GRANT_CAPS, ..h }));
+        assert!(!parses(IpcHeader { flags: MsgFlags::ERROR, ..h }));
+        assert!(parses(IpcHeader { ty: 1 | msg::REPLY_BIT, flags: MsgFlags::ERROR, ..h }));
+        assert!(!parses(IpcHeader { ty: 1 | msg::REPLY_BIT, flags: MsgFlags::REPLY_EXPECTED, ..h }));
+    }
+
+    #[test]
+    fn authorize_checks_dst_rights() {
+        let full = Cap::new([7; 16]).with_rights(Rights::ALL);
+        let h = IpcHeader { ty: MsgType::MapShared as u16, flags: MsgFlags::NONE, src: Cap::nil(), dst: full, len: 0, caps: 0 };
+        assert_eq!(authorize(&h), Ok(MsgType::MapShared));
+        let call_only = IpcHeader { dst: full.attenuate(Rights::CALL), ..h };
+        assert_eq!(authorize(&call_only), Err(IpcDenied::Rights(MissingRights(Rights::MAP))));
+        let ping = IpcHeader { ty: MsgType::Ping as u16, ..call_only };
+        assert_eq!(authorize(&ping), Ok(MsgType::Ping));
+        assert_eq!(authorize(&IpcHeader { ty: 99, ..h }), Err(IpcDenied::UnknownType(99)));
     }
 }
@@ -0,0 +1,415 @@
+//! Typed message bodies for every `MsgType`, plus whole-message codecs:
+//! `IpcHeader`, then the capability-transfer section, then a fixed-size
+//! little-endian body.
+//!
+//! `MsgType`, the body structs, `Request`/`Reply` and the per-interface
+//! client stubs and server traits are generated from `idl/kernel.idl` by
+//! `thatte-idl` at build time.
+//!
+//! IDL fields of type `cap` are not serialized in the body. They become
+//! `CapTransfer` entries naming a slot in the sender's cspace; the kernel
+//! moves or copies those caps into the receiver's cspace on delivery and
+//! rewrites each entry with the slot the cap landed in.
+//!
+//! Replies reuse the request's `MsgType` with `REPLY_BIT` set in `ty`.
+//! Decoders require `len` and `caps` to match the body and the buffer to end
+//! exactly after the body.
+//!
+//! Two flags swap the typed body for a fixed one: a `MsgFlags::SHM_PAYLOAD`
+//! message carries a `ShmPayload` descriptor (see `encode_shm`), and a
+//! `MsgFlags::ERROR` reply carries an `ErrorBody` (see `encode_error`). The
+//! typed `Request`/`Reply` decoders reject both.
+
+use crate::{parse_header, serialize_header, Cap, IpcHeader, MsgFlags, ParseError, Rights, HEADER_LEN};
+
+/// Set in `IpcHeader.ty` for replies.
+pub const REPLY_BIT: u16 = 0x8000;
+
+/// Most capabilities a single message can carry.
+pub const MAX_CAPS: usize = 4;
+
+/// Size of one capability-transfer entry.
+pub const CAP_ENTRY_LEN: usize = 4;
+
+/// Whether a transferred cap leaves the sender's cspace.
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TransferMode {
+    /// The receiver gets a derived copy (a CDT child of the sender's slot).
+    Copy = 0,
+    /// The cap moves out of the sender's slot.
+    Move = 1,
+}
+
+/// One entry of the capability-transfer section: a slot in the local
+/// cspace (the sender's when sending, the receiver's once delivered).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct CapTransfer {
+    pub slot: u16,
+    pub mode: TransferMode,
+}
+
+impl CapTransfer {
+    /// Slot value of an entry whose cap could not be delivered.
+    pub const NO_SLOT: u16 = u16::MAX;
+
+    pub const fn copy(slot: u16) -> Self { Self { slot, mode: TransferMode::Copy } }
+    pub const fn moved(slot: u16) -> Self { Self { slot, mode: TransferMode::Move } }
+
+    /// True if the entry names a slot (delivery did not fail).
+    pub fn is_present(&self) -> bool { self.slot != Self::NO_SLOT }
+
+    pub fn to_bytes(self) -> [u8; CAP_ENTRY_LEN] {
+        let s = self.slot.to_le_bytes();
+        [s[0], s[1], self.mode as u8, 0]
+    }
+
+    /// Read entry `index` of the transfer section of the message in `msg`.
+    pub fn read(msg: &[u8], index: usize) -> Result<Self, ParseError> {
+        let r = cap_entry_range(index);
+        let b = msg.get(r.clone()).ok_or(ParseError::Truncated { needed: r.end, got: msg.len() })?;
+        let mode = match b[2] {
+            0 => TransferMode::Copy,
+            1 => TransferMode::Move,
+            _ => return Err(ParseError::BadCapEntry { index }),
+        };
+        if b[3] != 0 { return Err(ParseError::BadCapEntry { index }); }
+        Ok(Self { slot: u16::from_le_bytes([b[0], b[1]]), mode })
+    }
+}
+
+/// A fixed-size message body with a fixed number of attached caps.
+pub trait Body: Sized {
+    const TY: MsgType;
+    const LEN: usize;
+    const CAPS: usize;
+    /// Write exactly `LEN` bytes to the front of `out` and `CAPS` entries to
+    /// `caps`.
+    fn encode(&self, out: &mut [u8], caps: &mut [CapTransfer]);
+    /// Decode from exactly `LEN` bytes and `CAPS` entries.
+    fn decode(buf: &[u8], caps: &[CapTransfer]) -> Result<Self, ParseError>;
+}
+
+/// Entry `i` of a body's decoded transfer entries.
+fn nth_cap(caps: &[CapTransfer], i: usize) -> Result<CapTransfer, ParseError> {
+    caps.get(i).copied().ok_or(ParseError::CapCountMismatch { declared: caps.len() as u16, expected: i + 1 })
+}
+
+/// A field type that can appear in a message body.
+pub trait Wire: Sized {
+    const SIZE: usize;
+    fn put(&self, out: &mut [u8], at: usize);
+    fn get(buf: &[u8], at: usize) -> Result<Self, ParseError>;
+}
+
+fn field(buf: &[u8], at: usize, size: usize) -> Result<&[u8], ParseError> {
+    buf.get(at..at + size).ok_or(ParseError::Truncated { needed: at + size, got: buf.len() })
+}
+
+macro_rules! wire_int {
+    ($($t:ty),*) => {$(
+        impl Wire for $t {
+            const SIZE: usize = core::mem::size_of::<$t>();
+            fn put(&self, out: &mut [u8], at: usize) { out[at..at + Self::SIZE].copy_from_slice(&self.to_le_bytes()); }
+            fn get(buf: &[u8], at: usize) -> Result<Self, ParseError> {
+                Ok(<$t>::from_le_bytes(field(buf, at, Self::SIZE)?.try_into().unwrap()))
+            }
+        }
+    )*};
+}
+wire_int!(u8, u16, u32, u64, i64);
+
+/// Booleans are one byte, and only 0 or 1 decode.
+impl Wire for bool {
+    const SIZE: usize = 1;
+    fn put(&self, out: &mut [u8], at: usize) { out[at] = *self as u8; }
+    fn get(buf: &[u8], at: usize) -> Result<Self, ParseError> {
+        match field(buf, at, 1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(ParseError::BadField { offset: at }),
+        }
+    }
+}
+
+/// Byte range of the `i`th capability-transfer entry in a message.
+pub fn cap_entry_range(i: usize) -> core::ops::Range<usize> {
+    let at = HEADER_LEN + i * CAP_ENTRY_LEN;
+    at..at + CAP_ENTRY_LEN
+}
+
+/// Flags that select a non-typed body.
+const UNTYPED: MsgFlags = MsgFlags::SHM_PAYLOAD.union(MsgFlags::ERROR);
+
+/// Write the header and `caps` entries, returning where the body starts.
+/// `GRANT_CAPS` is set when `caps` is nonempty; `None` if `out` cannot hold
+/// `len` more bytes or the flags do not fit the header.
+fn encode_head(h: IpcHeader, caps: &[CapTransfer], out: &mut [u8]) -> Option<usize> {
+    let start = HEADER_LEN + caps.len() * CAP_ENTRY_LEN;
+    let grant = if caps.is_empty() { MsgFlags::NONE } else { MsgFlags::GRANT_CAPS };
+    let h = IpcHeader { flags: h.flags | grant, caps: caps.len() as u16, ..h };
+    if out.len() < start + h.len as usize || !h.flags_consistent() { return None; }
+    out[..HEADER_LEN].copy_from_slice(&serialize_header(&h));
+    for (i, c) in caps.iter().enumerate() {
+        out[cap_entry_range(i)].copy_from_slice(&c.to_bytes());
+    }
+    Some(start)
+}
+
+/// Encode header + caps + body into `out`, returning the total length written.
+fn encode_msg<B: Body>(b: &B, ty: u16, src: Cap, dst: Cap, flags: MsgFlags, out: &mut [u8]) -> Option<usize> {
+    let start = HEADER_LEN + B::CAPS * CAP_ENTRY_LEN;
+    if !flags.intersection(UNTYPED).is_empty() || out.len() < start + B::LEN { return None; }
+    let mut caps = [CapTransfer::copy(CapTransfer::NO_SLOT); MAX_CAPS];
+    b.encode(&mut out[start..start + B::LEN], &mut caps[..B::CAPS]);
+    let h = IpcHeader { ty, flags, src, dst, len: B::LEN as u32, caps: 0 };
+    encode_head(h, &caps[..B::CAPS], out)?;
+    Some(start + B::LEN)
+}
+
+/// Check that `buf` holds exactly the message `h` describes; returns where
+/// the body starts.
+pub fn framed(h: &IpcHeader, buf: &[u8]) -> Result<usize, ParseError> {
+    if h.caps as usize > MAX_CAPS { return Err(ParseError::TooManyCaps(h.caps)); }
+    let start = HEADER_LEN + h.caps as usize * CAP_ENTRY_LEN;
+    let end = start.checked_add(h.len as usize).ok_or(ParseError::LengthOverflow(h.len))?;
+    if buf.len() < end { return Err(ParseError::Truncated { needed: end, got: buf.len() }); }
+    if buf.len() > end { return Err(ParseError::TrailingBytes { expected: end, got: buf.len() }); }
+    Ok(start)
+}
+
+/// Check that `h` declares exactly `caps` entries and a `len`-byte body.
+fn expect(h: &IpcHeader, caps: usize, len: usize) -> Result<(), ParseError> {
+    if h.caps as usize > MAX_CAPS { return Err(ParseError::TooManyCaps(h.caps)); }
+    if h.caps as usize != caps { return Err(ParseError::CapCountMismatch { declared: h.caps, expected: caps }); }
+    if h.len as usize != len { return Err(ParseError::LengthMismatch { declared: h.len, expected: len }); }
+    Ok(())
+}
+
+/// Body of a complete message whose header declares `B`'s size and caps.
+fn body_of<B: Body>(h: &IpcHeader, buf: &[u8]) -> Result<B, ParseError> {
+    if !h.flags.intersection(UNTYPED).is_empty() { return Err(ParseError::BadFlags(h.flags.bits())); }
+    expect(h, B::CAPS, B::LEN)?;
+    let start = framed(h, buf)?;
+    let mut caps = [CapTransfer::copy(CapTransfer::NO_SLOT); MAX_CAPS];
+    for (i, c) in caps[..B::CAPS].iter_mut().enumerate() {
+        *c = CapTransfer::read(buf, i)?;
+    }
+    B::decode(&buf[start..], &caps[..B::CAPS])
+}
+
+/// Inline body of a `MsgFlags::SHM_PAYLOAD` message: where the real
+/// payload sits in the region whose cap is the message's first transfer
+/// entry.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct ShmPayload {
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl ShmPayload {
+    pub const LEN: usize = 16;
+}
+
+/// Encode a message of type `ty` whose payload is in shared memory. `caps`
+/// starts with the region's cap and may list further caps to transfer.
+pub fn encode_shm(ty: u16, src: Cap, dst: Cap, flags: MsgFlags, caps: &[CapTransfer], p: &ShmPayload, out: &mut [u8]) -> Option<usize> {
+    if caps.is_empty() || caps.len() > MAX_CAPS || flags.contains(MsgFlags::ERROR) { return None; }
+    let h = IpcHeader { ty, flags: flags | MsgFlags::SHM_PAYLOAD, src, dst, len: ShmPayload::LEN as u32, caps: 0 };
+    let start = encode_head(h, caps, out)?;
+    p.offset.put(out, start);
+    p.len.put(out, start + 8);
+    Some(start + ShmPayload::LEN)
+}
+
+/// Parse a `MsgFlags::SHM_PAYLOAD` message into its header, region entry and
+/// descriptor.
+pub fn shm_payload(buf: &[u8]) -> Result<(IpcHeader, CapTransfer, ShmPayload), ParseError> {
+    let h = parse_header(buf)?;
+    if !h.flags.contains(MsgFlags::SHM_PAYLOAD) { return Err(ParseError::BadFlags(h.flags.bits())); }
+    if h.len as usize != ShmPayload::LEN {
+        return Err(ParseError::LengthMismatch { declared: h.len, expected: ShmPayload::LEN });
+    }
+    let start = framed(&h, buf)?;
+    let region = CapTransfer::read(buf, 0)?;
+    Ok((h, region, ShmPayload { offset: Wire::get(buf, start)?, len: Wire::get(buf, start + 8)? }))
+}
+
+/// Body of a `MsgFlags::ERROR` reply. Codes are per-method; 0 is reserved.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct ErrorBody {
+    pub code: u32,
+}
+
+impl ErrorBody {
+    pub const LEN: usize = 4;
+}
+
+/// Encode an error reply to a `ty` request.
+pub fn encode_error(ty: MsgType, src: Cap, dst: Cap, e: &ErrorBody, out: &mut [u8]) -> Option<usize> {
+    let h = IpcHeader { ty: ty as u16 | REPLY_BIT, flags: MsgFlags::ERROR, src, dst, len: ErrorBody::LEN as u32, caps: 0 };
+    let start = encode_head(h, &[], out)?;
+    e.code.put(out, start);
+    Some(start + ErrorBody::LEN)
+}
+
+/// Parse a `MsgFlags::ERROR` reply into the request type it answers and its
+/// body.
+pub fn error_of(buf: &[u8]) -> Result<(MsgType, ErrorBody), ParseError> {
+    let h = parse_header(buf)?;
+    if !h.flags.contains(MsgFlags::ERROR) { return Err(ParseError::BadFlags(h.flags.bits())); }
+    expect(&h, 0, ErrorBody::LEN)?;
+    let start = framed(&h, buf)?;
+    Ok((MsgType::try_from(h.ty & !REPLY_BIT)?, ErrorBody { code: Wire::get(buf, start)? }))
+}
+
+/// Carries an encoded request to a server and returns the encoded reply.
+pub trait Transport {
+    /// Deliver `msg` and write the reply into `reply`, returning its length.
+    fn call(&mut self, msg: &[u8], reply: &mut [u8]) -> Option<usize>;
+}
+
+/// Encode `req`, send it over `t`, and decode the reply. An error reply
+/// decodes as `None`.
+pub fn call<T: Transport + ?Sized>(t: &mut T, src: Cap, dst: Cap, req: &Request) -> Option<Reply> {
+    let mut buf = [0u8; MAX_MSG_LEN];
+    let n = req.encode(src, dst, MsgFlags::REPLY_EXPECTED, &mut buf)?;
+    let mut rbuf = [0u8; MAX_MSG_LEN];
+    let m = t.call(&buf[..n], &mut rbuf)?;
+    Reply::decode(rbuf.get(..m)?).ok().map(|(_, r)| r)
+}
+
+include!(concat!(env!("OUT_DIR"), "/kernel_idl.rs"));
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn cap() -> impl Strategy<Value = Cap> { any::<[u8; 16]>().prop_map(Cap::new) }
+
+    fn transfer() -> impl Strategy<Value = CapTransfer> {
+        (any::<u16>(), any::<bool>()).prop_map(|(s, m)| if m { CapTransfer::moved(s) } else { CapTransfer::copy(s) })
+    }
+
+    fn request() -> impl Strategy<Value = Request> {
+        prop_oneof![
+            any::<u64>().prop_map(|seq| Request::Ping(Ping { seq })),
+            Just(Request::GetTime(GetTime)),
+            (transfer(), any::<u64>(), any::<u64>(), any::<bool>()).prop_map(|(region, offset, len, writable)| {
+                Request::MapShared(MapShared { region, offset, len, writable })
+            }),
+        ]
+    }
+
+    fn reply() -> impl Strategy<Value = Reply> {
+        prop_oneof![
+            any::<u64>().prop_map(|seq| Reply::Ping(PingReply { seq })),
+            (any::<u64>(), any::<u64>()).prop_map(|(monotonic_ns, wall_ns)| Reply::GetTime(GetTimeReply { monotonic_ns, wall_ns })),
+            (any::<u64>(), any::<u64>()).prop_map(|(addr, len)| Reply::MapShared(MapSharedReply { addr, len })),
+        ]
+    }
+
+    /// Flags a typed message may be encoded with.
+    fn flags(reply: bool) -> impl Strategy<Value = MsgFlags> {
+        let bits = if reply { MsgFlags::NON_BLOCKING } else { MsgFlags::NON_BLOCKING | MsgFlags::REPLY_EXPECTED };
+        (0..=bits.bits()).prop_filter_map("undefined", move |b| MsgFlags::from_bits(b & bits.bits()))
+    }
+
+    proptest! {
+        #[test]
+        fn request_roundtrip(r in request(), src in cap(), dst in cap(), flags in flags(false)) {
+            let mut buf = [0u8; 128];
+            let n = r.encode(src, dst, flags, &mut buf).unwrap();
+            let (h, r2) = Request::decode(&buf[..n]).unwrap();
+            prop_assert_eq!(r, r2);
+            prop_assert_eq!((h.src, h.dst, h.flags.difference(MsgFlags::GRANT_CAPS)), (src, dst, flags));
+            prop_assert_eq!(h.flags.contains(MsgFlags::GRANT_CAPS), h.caps != 0);
+            prop_assert_eq!(Request::decode(&buf[..n + 1]).unwrap_err(), ParseError::TrailingBytes { expected: n, got: n + 1 });
+            prop_assert_eq!(Request::decode(&buf[..n - 1]).unwrap_err(), ParseError::Truncated { needed: n, got: n - 1 });
+            prop_assert_eq!(Reply::decode(&buf[..n]).unwrap_err(), ParseError::WrongDirection(h.ty));
+        }
+
+        #[test]
+        fn reply_roundtrip(r in reply(), src in cap(), dst in cap(), flags in flags(true)) {
+            let mut buf = [0u8; 128];
+            let n = r.encode(src, dst, flags, &mut buf).unwrap();
+            let (h, r2) = Reply::decode(&buf[..n]).unwrap();
+            prop_assert_eq!(r, r2);
+            prop_assert_eq!(h.ty, r.ty() as u16 | REPLY_BIT);
+            prop_assert!(Reply::decode(&buf[..n + 1]).is_err());
+            prop_assert_eq!(Request::decode(&buf[..n]).unwrap_err(), ParseError::WrongDirection(h.ty));
+            prop_assert!(r.encode(src, dst, MsgFlags::REPLY_EXPECTED, &mut buf).is_none());
+        }
+
+        #[test]
+        fn shm_and_error_roundtrip(ty in 1u16..0x8000, region in transfer(), offset in any::<u64>(), len in any::<u64>(), code in any::<u32>()) {
+            let mut buf = [0u8; 128];
+            let p = ShmPayload { offset, len };
+            let n = encode_shm(ty, Cap::nil(), Cap::nil(), MsgFlags::REPLY_EXPECTED, &[region], &p, &mut buf).unwrap();
+            let (h, r, p2) = shm_payload(&buf[..n]).unwrap();
+            prop_assert_eq!((r, p2, h.caps), (region, p, 1));
+            prop_assert_eq!(h.flags, MsgFlags::REPLY_EXPECTED | MsgFlags::SHM_PAYLOAD | MsgFlags::GRANT_CAPS);
+            prop_assert!(Request::decode(&buf[..n]).is_err());
+
+            let n = encode_error(MsgType::Ping, Cap::nil(), Cap::nil(), &ErrorBody { code }, &mut buf).unwrap();
+            prop_assert_eq!(error_of(&buf[..n]), Ok((MsgType::Ping, ErrorBody { code })));
+            prop_assert_eq!(Reply::decode(&buf[..n]).unwrap_err(), ParseError::BadFlags(MsgFlags::ERROR.bits()));
+            prop_assert!(shm_payload(&buf[..n]).is_err());
+        }
+
+        #[test]
+        fn len_must_match_body(r in request(), len in any::<u32>()) {
+            let mut buf = [0u8; 128];
+            let n = r.encode(Cap::nil(), Cap::nil(), MsgFlags::NONE, &mut buf).unwrap();
+            let (h, _) = Request::decode(&buf[..n]).unwrap();
+            prop_assume!(len != h.len);
+            buf[..HEADER_LEN].copy_from_slice(&serialize_header(&IpcHeader { len, ..h }));
+            prop_assert_eq!(Request::decode(&buf[..n]).unwrap_err(), ParseError::LengthMismatch { declared: len, expected: h.len as usize });
+        }
+    }
+
+    struct Loopback<S>(S);
+
+    impl<S: KernelServer> Transport for Loopback<S> {
+        fn call(&mut self, msg: &[u8], reply: &mut [u8]) -> Option<usize> {
+            let (h, req) = Request::decode(msg).ok()?;
+            if !h.flags.contains(MsgFlags::REPLY_EXPECTED) { return None; }
+            dispatch_kernel(&mut self.0, req)?.encode(h.dst, h.src, MsgFlags::NONE, reply)
+        }
+    }
+
+    struct Fixed;
+
+    impl KernelServer for Fixed {
+        fn ping(&mut self, req: Ping) -> PingReply { PingReply { seq: req.seq } }
+        fn get_time(&mut self, _: GetTime) -> GetTimeReply { GetTimeReply { monotonic_ns: 42, wall_ns: 43 } }
+        fn map_shared(&mut self, req: MapShared) -> MapSharedReply { MapSharedReply { addr: 0x1000 + req.offset, len: req.len } }
+    }
+
+    #[test]
+    fn client_stub_reaches_server() {
+        let mut c = KernelClient::new(Loopback(Fixed), Cap::nil(), Cap::nil());
+        assert_eq!(c.ping(Ping { seq: 7 }), Some(PingReply { seq: 7 }));
+        assert_eq!(c.get_time(GetTime), Some(GetTimeReply { monotonic_ns: 42, wall_ns: 43 }));
+        let m = MapShared { region: CapTransfer::copy(3), offset: 8, len: 4096, writable: false };
+        assert_eq!(c.map_shared(m), Some(MapSharedReply { addr: 0x1008, len: 4096 }));
+        assert_eq!(MsgType::MapShared.required_rights(), Rights::CALL | Rights::MAP);
+    }
+
+    #[test]
+    fn rejects_non_canonical_fields() {
+        let mut buf = [0u8; 128];
+        let m = Request::MapShared(MapShared { region: CapTransfer::moved(1), offset: 0, len: 4096, writable: true });
+        let n = m.encode(Cap::nil(), Cap::nil(), MsgFlags::NONE, &mut buf).unwrap();
+        assert_eq!(CapTransfer::read(&buf, 0), Ok(CapTransfer::moved(1)));
+        buf[n - 1] = 2;
+        assert_eq!(Request::decode(&buf[..n]), Err(ParseError::BadField { offset: 16 }));
+        buf[n - 1] = 1;
+        buf[cap_entry_range(0).start + 2] = 7;
+        assert_eq!(Request::decode(&buf[..n]), Err(ParseError::BadCapEntry { index: 0 }));
+        let h = IpcHeader { ty: 0x7777, flags: MsgFlags::NONE, src: Cap::nil(), dst: Cap::nil(), len: 0, caps: 0 };
+        assert_eq!(Request::decode(&serialize_header(&h)), Err(ParseError::UnknownType(0x7777)));
+        assert_eq!(MsgType::try_from(0x7777), Err(ParseError::UnknownType(0x7777)));
+    }
+}
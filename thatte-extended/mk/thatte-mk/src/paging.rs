@@ -0,0 +1,530 @@
+//! x86_64 four-level page tables.
+//!
+//! An `AddressSpace` is a PML4 root plus the tables hanging off it. All table
+//! accesses go through a `PhysMem`, so the same code edits real tables through
+//! the kernel's direct map and simulated RAM in host tests. Table frames come
+//! from a `FrameSource`; the frames being mapped belong to the caller and are
+//! never allocated or freed here.
+//!
+//! Intermediate entries are always present, writable and user-accessible;
+//! the leaf decides the effective permissions. Tables left empty by `unmap`
+//! are freed straight away, so a huge page can later take their place.
+//! Nothing here flushes the TLB: after `unmap` on live tables the caller must
+//! `invlpg` (or reload CR3).
+
+use crate::frame::{FrameAllocator, FrameSize, PhysAddr};
+
+pub type VirtAddr = u64;
+
+const PRESENT: u64 = 1 << 0;
+const HUGE: u64 = 1 << 7;
+const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+const ENTRIES: u64 = 512;
+const TABLE_LEVELS: usize = 4;
+
+/// Word-granular access to physical memory.
+pub trait PhysMem {
+    fn read(&self, pa: PhysAddr) -> u64;
+    fn write(&mut self, pa: PhysAddr, value: u64);
+
+    /// Zero the 4 KiB frame at `pa`.
+    fn zero_frame(&mut self, pa: PhysAddr) {
+        for i in 0..ENTRIES {
+            self.write(pa + i * 8, 0);
+        }
+    }
+}
+
+/// Where page-table frames come from.
+pub trait FrameSource {
+    fn alloc_table(&mut self) -> Option<PhysAddr>;
+    fn free_table(&mut self, pa: PhysAddr);
+}
+
+impl<const WORDS: usize> FrameSource for FrameAllocator<WORDS> {
+    fn alloc_table(&mut self) -> Option<PhysAddr> { self.alloc(FrameSize::Size4K) }
+
+    fn free_table(&mut self, pa: PhysAddr) {
+        let freed = self.free(pa, FrameSize::Size4K);
+        debug_assert_eq!(freed, Ok(()), "page table frame {:#x}", pa);
+    }
+}
+
+/// Physical memory reached through a linear mapping at `base` (the kernel's
+/// direct map, or identity with `base == 0`).
+pub struct DirectMap {
+    base: usize,
+}
+
+impl DirectMap {
+    /// # Safety
+    /// Every physical address later passed to `read`/`write` must be mapped
+    /// at `base + pa`, and nothing else may alias the tables being edited.
+    pub const unsafe fn new(base: usize) -> Self { Self { base } }
+}
+
+impl PhysMem for DirectMap {
+    fn read(&self, pa: PhysAddr) -> u64 {
+        // SAFETY: guaranteed by the contract of `DirectMap::new`.
+        unsafe { core::ptr::read_volatile((self.base + pa as usize) as *const u64) }
+    }
+
+    fn write(&mut self, pa: PhysAddr, value: u64) {
+        // SAFETY: guaranteed by the contract of `DirectMap::new`.
+        unsafe { core::ptr::write_volatile((self.base + pa as usize) as *mut u64, value) }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+impl PageSize {
+    pub const fn bytes(self) -> u64 { 1 << (12 + 9 * self.level()) }
+
+    /// Table level holding the leaf entry (0 = PT, 1 = PD, 2 = PDPT).
+    const fn level(self) -> usize {
+        match self {
+            Self::Size4K => 0,
+            Self::Size2M => 1,
+            Self::Size1G => 2,
+        }
+    }
+
+    const fn at_level(level: usize) -> Self {
+        match level {
+            0 => Self::Size4K,
+            1 => Self::Size2M,
+            _ => Self::Size1G,
+        }
+    }
+}
+
+/// Leaf permission and caching bits, in their page-table-entry positions.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub struct PageFlags(u64);
+
+impl PageFlags {
+    pub const WRITABLE: Self = Self(1 << 1);
+    /// Accessible from ring 3.
+    pub const USER: Self = Self(1 << 2);
+    pub const WRITE_THROUGH: Self = Self(1 << 3);
+    pub const NO_CACHE: Self = Self(1 << 4);
+    /// Survives CR3 reloads (kernel mappings only).
+    pub const GLOBAL: Self = Self(1 << 8);
+    pub const NO_EXECUTE: Self = Self(1 << 63);
+
+    pub const NONE: Self = Self(0);
+    pub const ALL: Self = Self(0x11e | 1 << 63);
+
+    /// Build from raw bits; undefined bits are dropped.
+    pub const fn from_bits_truncate(bits: u64) -> Self { Self(bits & Self::ALL.0) }
+    pub const fn bits(self) -> u64 { self.0 }
+    pub const fn contains(self, other: Self) -> bool { self.0 & other.0 == other.0 }
+    pub const fn union(self, other: Self) -> Self { Self(self.0 | other.0) }
+    pub const fn intersection(self, other: Self) -> Self { Self(self.0 & other.0) }
+    pub const fn difference(self, other: Self) -> Self { Self(self.0 & !other.0) }
+}
+
+impl core::ops::BitOr for PageFlags {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self { self.union(rhs) }
+}
+
+impl core::ops::BitAnd for PageFlags {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self { self.intersection(rhs) }
+}
+
+impl core::fmt::Debug for PageFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        const NAMES: [(PageFlags, char); 6] = [
+            (PageFlags::WRITABLE, 'w'), (PageFlags::USER, 'u'), (PageFlags::WRITE_THROUGH, 't'),
+            (PageFlags::NO_CACHE, 'c'), (PageFlags::GLOBAL, 'g'), (PageFlags::NO_EXECUTE, 'n'),
+        ];
+        write!(f, "PageFlags(")?;
+        for (p, c) in NAMES {
+            let c = if self.contains(p) { c } else { '-' };
+            write!(f, "{}", c)?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PagingError {
+    /// The virtual or physical address is not aligned to the page size.
+    Misaligned,
+    /// The virtual address is not canonical (bits 63..47 differ).
+    NonCanonical,
+    /// The physical address does not fit in 52 bits.
+    BadPhysAddr,
+    /// Part of the range is already mapped, or covered by a huge page.
+    AlreadyMapped,
+    /// Nothing is mapped at the address.
+    NotMapped,
+    /// No frame was left for a page table.
+    OutOfFrames,
+}
+
+/// Where a virtual address leads.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Translation {
+    /// Physical address of the byte, page offset included.
+    pub phys: PhysAddr,
+    pub size: PageSize,
+    pub flags: PageFlags,
+}
+
+const fn canonical(va: VirtAddr) -> bool { ((va as i64) << 16 >> 16) as u64 == va }
+
+/// Address of the entry for `va` in the table at `table`, `level` levels
+/// above the PT.
+const fn slot(table: PhysAddr, va: VirtAddr, level: usize) -> PhysAddr {
+    table + ((va >> (12 + 9 * level)) & (ENTRIES - 1)) * 8
+}
+
+/// A PML4 and the tables under it.
+pub struct AddressSpace {
+    root: PhysAddr,
+}
+
+impl AddressSpace {
+    /// Allocate an empty PML4.
+    pub fn new(mem: &mut impl PhysMem, frames: &mut impl FrameSource) -> Result<Self, PagingError> {
+        let root = frames.alloc_table().ok_or(PagingError::OutOfFrames)?;
+        mem.zero_frame(root);
+        Ok(Self { root })
+    }
+
+    /// The address space whose PML4 is at `root`, such as the one CR3
+    /// points at.
+    ///
+    /// # Safety
+    /// `root` must be a PML4 that nothing else changes while this is in use.
+    pub const unsafe fn from_root(root: PhysAddr) -> Self { Self { root } }
+
+    /// Physical address of the PML4, for CR3.
+    pub const fn root(&self) -> PhysAddr { self.root }
+
+    /// Map `va` to `pa` as one page of `size`, creating tables as needed.
+    pub fn map(
+        &mut self,
+        mem: &mut impl PhysMem,
+        frames: &mut impl FrameSource,
+        va: VirtAddr,
+        pa: PhysAddr,
+        size: PageSize,
+        flags: PageFlags,
+    ) -> Result<(), PagingError> {
+        if !canonical(va) { return Err(PagingError::NonCanonical); }
+        if pa >> 52 != 0 { return Err(PagingError::BadPhysAddr); }
+        if !va.is_multiple_of(size.bytes()) || !pa.is_multiple_of(size.bytes()) {
+            return Err(PagingError::Misaligned);
+        }
+        let mut table = self.root;
+        for level in (size.level() + 1..TABLE_LEVELS).rev() {
+            let slot = slot(table, va, level);
+            let e = mem.read(slot);
+            if e & PRESENT == 0 {
+                let Some(t) = frames.alloc_table() else {
+                    self.prune(mem, frames, va);
+                    return Err(PagingError::OutOfFrames);
+                };
+                mem.zero_frame(t);
+                mem.write(slot, t | PRESENT | PageFlags::WRITABLE.0 | PageFlags::USER.0);
+                table = t;
+            } else if e & HUGE != 0 {
+                return Err(PagingError::AlreadyMapped);
+            } else {
+                table = e & ADDR_MASK;
+            }
+        }
+        let slot = slot(table, va, size.level());
+        // A present entry here is either a page or a (non-empty) table.
+        if mem.read(slot) & PRESENT != 0 { return Err(PagingError::AlreadyMapped); }
+        let huge = if size == PageSize::Size4K { 0 } else { HUGE };
+        mem.write(slot, pa | PRESENT | huge | flags.0);
+        Ok(())
+    }
+
+    /// Walk to the leaf entry covering `va`: its slot address and level.
+    fn leaf(&self, mem: &impl PhysMem, va: VirtAddr) -> Option<(PhysAddr, usize)> {
+        if !canonical(va) { return None; }
+        let mut table = self.root;
+        for level in (0..TABLE_LEVELS).rev() {
+            let slot = slot(table, va, level);
+            let e = mem.read(slot);
+            if e & PRESENT == 0 { return None; }
+            if level == 0 || (level < 3 && e & HUGE != 0) { return Some((slot, level)); }
+            table = e & ADDR_MASK;
+        }
+        None
+    }
+
+    /// Where `va` leads, if anywhere.
+    pub fn translate(&self, mem: &impl PhysMem, va: VirtAddr) -> Option<Translation> {
+        let (slot, level) = self.leaf(mem, va)?;
+        let e = mem.read(slot);
+        let size = PageSize::at_level(level);
+        let base = e & ADDR_MASK & !(size.bytes() - 1);
+        Some(Translation { phys: base | (va & (size.bytes() - 1)), size, flags: PageFlags::from_bits_truncate(e) })
+    }
+
+    /// Remove the page starting at `va`, returning what it mapped. Tables
+    /// left empty are freed.
+    pub fn unmap(
+        &mut self,
+        mem: &mut impl PhysMem,
+        frames: &mut impl FrameSource,
+        va: VirtAddr,
+    ) -> Result<(PhysAddr, PageSize), PagingError> {
+        if !canonical(va) { return Err(PagingError::NonCanonical); }
+        let (slot, level) = self.leaf(mem, va).ok_or(PagingError::NotMapped)?;
+        let size = PageSize::at_level(level);
+        if !va.is_multiple_of(size.bytes()) { return Err(PagingError::Misaligned); }
+        let pa = mem.read(slot) & ADDR_MASK;
+        mem.write(slot, 0);
+        self.prune(mem, frames, va);
+        Ok((pa, size))
+    }
+
+    /// Free the empty tables on the walk to `va`, bottom-up. The PML4 stays.
+    fn prune(&mut self, mem: &mut impl PhysMem, frames: &mut impl FrameSource, va: VirtAddr) {
+        let mut path = [0; TABLE_LEVELS - 1];
+        let mut depth = 0;
+        let mut table = self.root;
+        for level in (1..TABLE_LEVELS).rev() {
+            let slot = slot(table, va, level);
+            let e = mem.read(slot);
+            if e & PRESENT == 0 || (level < 3 && e & HUGE != 0) { break; }
+            path[depth] = slot;
+            depth += 1;
+            table = e & ADDR_MASK;
+        }
+        for &slot in path[..depth].iter().rev() {
+            let t = mem.read(slot) & ADDR_MASK;
+            if (0..ENTRIES).any(|i| mem.read(t + i * 8) != 0) { break; }
+            frames.free_table(t);
+            mem.write(slot, 0);
+        }
+    }
+
+    /// Call `f` with every table, children before their parent and the
+    /// PML4 last.
+    pub fn tables(&self, mem: &impl PhysMem, mut f: impl FnMut(PhysAddr)) {
+        fn visit(mem: &impl PhysMem, f: &mut impl FnMut(PhysAddr), table: PhysAddr, level: usize) {
+            if level > 0 {
+                for i in 0..ENTRIES {
+                    let e = mem.read(table + i * 8);
+                    if e & PRESENT != 0 && (level == 3 || e & HUGE == 0) {
+                        visit(mem, f, e & ADDR_MASK, level - 1);
+                    }
+                }
+            }
+            f(table);
+        }
+        visit(mem, &mut f, self.root, TABLE_LEVELS - 1)
+    }
+
+    /// Free every table, the PML4 included; returns how many. Mapped frames
+    /// are left to their owners.
+    pub fn teardown(self, mem: &mut impl PhysMem, frames: &mut impl FrameSource) -> usize {
+        let mut n = 0;
+        self.tables(mem, |t| {
+            frames.free_table(t);
+            n += 1;
+        });
+        n
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::frame::{MemKind, MemRegion};
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+    use std::vec::Vec;
+
+    /// 16 MiB of frames for page tables.
+    type Frames = FrameAllocator<64>;
+
+    /// Simulated RAM backing the frames of `Frames`.
+    struct SimRam(Vec<u64>);
+
+    impl SimRam {
+        fn new() -> Self { Self(std::vec![0xdead_beef_dead_beef; (Frames::LIMIT / 8) as usize]) }
+    }
+
+    impl PhysMem for SimRam {
+        fn read(&self, pa: PhysAddr) -> u64 {
+            assert_eq!(pa % 8, 0);
+            self.0[(pa / 8) as usize]
+        }
+
+        fn write(&mut self, pa: PhysAddr, value: u64) {
+            assert_eq!(pa % 8, 0);
+            self.0[(pa / 8) as usize] = value;
+        }
+    }
+
+    fn setup() -> (SimRam, Frames) {
+        let map = [MemRegion { base: 0x1000, len: Frames::LIMIT - 0x1000, kind: MemKind::Usable }];
+        (SimRam::new(), Frames::from_map(&map, &[]))
+    }
+
+    const GIB: u64 = 1 << 30;
+    const KERNEL: VirtAddr = 0xffff_8000_0000_0000;
+
+    #[test]
+    fn map_translate_unmap() {
+        let (mut mem, mut frames) = setup();
+        let mut s = AddressSpace::new(&mut mem, &mut frames).unwrap();
+        let rw = PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+        s.map(&mut mem, &mut frames, 0x40_1000, 0x7000, PageSize::Size4K, rw | PageFlags::USER).unwrap();
+        s.map(&mut mem, &mut frames, KERNEL + 2 * GIB, 3 * GIB, PageSize::Size1G, PageFlags::GLOBAL).unwrap();
+        s.map(&mut mem, &mut frames, 0x60_0000, 0x20_0000, PageSize::Size2M, rw).unwrap();
+        // PML4 + {PDPT, PD, PT} low + PDPT high.
+        assert_eq!(frames.stats().used, 5);
+
+        let t = s.translate(&mem, 0x40_1abc).unwrap();
+        assert_eq!(t, Translation { phys: 0x7abc, size: PageSize::Size4K, flags: rw | PageFlags::USER });
+        assert_eq!(s.translate(&mem, KERNEL + 2 * GIB + 0x1234_5678).unwrap().phys, 3 * GIB + 0x1234_5678);
+        assert_eq!(s.translate(&mem, 0x60_0000 + 0x1f_ffff).unwrap().phys, 0x3f_ffff);
+        assert_eq!(s.translate(&mem, 0x40_2000), None);
+        assert_eq!(s.translate(&mem, 0x8000_0000_0000), None);
+
+        let m = |s: &mut AddressSpace, mem: &mut SimRam, frames: &mut Frames, va, pa, size| {
+            s.map(mem, frames, va, pa, size, PageFlags::NONE)
+        };
+        assert_eq!(m(&mut s, &mut mem, &mut frames, 0x8000_0000_0000, 0, PageSize::Size4K), Err(PagingError::NonCanonical));
+        assert_eq!(m(&mut s, &mut mem, &mut frames, 0x40_1800, 0, PageSize::Size4K), Err(PagingError::Misaligned));
+        assert_eq!(m(&mut s, &mut mem, &mut frames, 0x20_0000, 0x1000, PageSize::Size2M), Err(PagingError::Misaligned));
+        assert_eq!(m(&mut s, &mut mem, &mut frames, 0, 1 << 52, PageSize::Size4K), Err(PagingError::BadPhysAddr));
+        assert_eq!(m(&mut s, &mut mem, &mut frames, 0x40_0000, 0, PageSize::Size2M), Err(PagingError::AlreadyMapped));
+        assert_eq!(m(&mut s, &mut mem, &mut frames, 0x61_0000, 0, PageSize::Size4K), Err(PagingError::AlreadyMapped));
+        assert_eq!(m(&mut s, &mut mem, &mut frames, KERNEL + 2 * GIB, 0, PageSize::Size2M), Err(PagingError::AlreadyMapped));
+
+        assert_eq!(s.unmap(&mut mem, &mut frames, 0x60_1000), Err(PagingError::Misaligned));
+        assert_eq!(s.unmap(&mut mem, &mut frames, 0x40_1000), Ok((0x7000, PageSize::Size4K)));
+        assert_eq!(s.unmap(&mut mem, &mut frames, 0x40_1000), Err(PagingError::NotMapped));
+        // The emptied PT is gone, so a 2M page fits where it was.
+        assert_eq!(frames.stats().used, 4);
+        m(&mut s, &mut mem, &mut frames, 0x40_0000, 0, PageSize::Size2M).unwrap();
+        let mut tables = Vec::new();
+        unsafe { AddressSpace::from_root(s.root()) }.tables(&mem, |t| tables.push(t));
+        assert_eq!(tables.len(), 4);
+        assert_eq!(tables.last(), Some(&s.root()));
+        assert!(tables.iter().all(|&t| frames.is_used(t)));
+        assert_eq!(s.teardown(&mut mem, &mut frames), 4);
+        assert_eq!(frames.stats().used, 0);
+    }
+
+    #[test]
+    fn running_out_of_frames_leaves_no_tables_behind() {
+        let mut mem = SimRam::new();
+        let map = [MemRegion { base: 0, len: 3 * 4096, kind: MemKind::Usable }];
+        let mut frames = Frames::from_map(&map, &[]);
+        let mut s = AddressSpace::new(&mut mem, &mut frames).unwrap();
+        assert_eq!(s.map(&mut mem, &mut frames, 0, 0, PageSize::Size4K, PageFlags::NONE), Err(PagingError::OutOfFrames));
+        assert_eq!(frames.stats().used, 1);
+        s.map(&mut mem, &mut frames, 0, 0, PageSize::Size1G, PageFlags::NONE).unwrap();
+        assert_eq!(s.teardown(&mut mem, &mut frames), 2);
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Map { va: VirtAddr, pa: PhysAddr, size: PageSize, flags: PageFlags },
+        /// Unmap the nth live mapping (mod count).
+        Unmap(usize),
+        /// Unmap whatever is at an arbitrary address.
+        UnmapAt(VirtAddr),
+    }
+
+    fn size() -> impl Strategy<Value = PageSize> {
+        prop_oneof![4 => Just(PageSize::Size4K), 2 => Just(PageSize::Size2M), 1 => Just(PageSize::Size1G)]
+    }
+
+    /// Addresses on a small grid so that mappings collide often.
+    fn addr() -> impl Strategy<Value = VirtAddr> {
+        (any::<bool>(), 0..3u64, 0..3u64, 0..3u64)
+            .prop_map(|(high, g, m, k)| if high { KERNEL } else { 0 } + g * GIB + m * (2 << 20) + k * 4096)
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            3 => (addr(), 0..(1u64 << 40), size(), any::<u64>()).prop_map(|(va, pa, size, f)| {
+                let mask = !(size.bytes() - 1);
+                Op::Map { va: va & mask, pa: pa & mask, size, flags: PageFlags::from_bits_truncate(f) }
+            }),
+            1 => any::<usize>().prop_map(Op::Unmap),
+            1 => addr().prop_map(Op::UnmapAt),
+        ]
+    }
+
+    proptest! {
+        // The tables agree with a simple model of the mapped ranges, and
+        // teardown returns every frame they used.
+        #[test]
+        fn tables_match_model(ops in proptest::collection::vec(op(), 1..60), clear in any::<bool>()) {
+            let (mut mem, mut frames) = setup();
+            let mut s = AddressSpace::new(&mut mem, &mut frames).unwrap();
+            // va -> (pa, size, flags)
+            let mut model: BTreeMap<VirtAddr, (PhysAddr, PageSize, PageFlags)> = BTreeMap::new();
+            let covering = |model: &BTreeMap<VirtAddr, (PhysAddr, PageSize, PageFlags)>, va: VirtAddr| {
+                model.range(..=va).next_back().filter(|(&v, m)| va < v + m.1.bytes()).map(|(&v, &m)| (v, m))
+            };
+            for op in ops {
+                match op {
+                    Op::Map { va, pa, size, flags } => {
+                        let overlaps = model.range(..va + size.bytes()).any(|(&v, m)| v + m.1.bytes() > va);
+                        let r = s.map(&mut mem, &mut frames, va, pa, size, flags);
+                        if overlaps {
+                            prop_assert_eq!(r, Err(PagingError::AlreadyMapped));
+                        } else {
+                            prop_assert_eq!(r, Ok(()));
+                            model.insert(va, (pa, size, flags));
+                        }
+                    }
+                    Op::Unmap(n) => {
+                        if model.is_empty() { continue; }
+                        let (&va, &(pa, size, _)) = model.iter().nth(n % model.len()).unwrap();
+                        prop_assert_eq!(s.unmap(&mut mem, &mut frames, va), Ok((pa, size)));
+                        model.remove(&va);
+                    }
+                    Op::UnmapAt(va) => {
+                        let r = s.unmap(&mut mem, &mut frames, va);
+                        match covering(&model, va) {
+                            Some((v, (pa, size, _))) if v == va => {
+                                prop_assert_eq!(r, Ok((pa, size)));
+                                model.remove(&va);
+                            }
+                            Some(_) => prop_assert_eq!(r, Err(PagingError::Misaligned)),
+                            None => prop_assert_eq!(r, Err(PagingError::NotMapped)),
+                        }
+                    }
+                }
+                for k in 0..27u64 {
+                    for high in [0, KERNEL] {
+                        let va = high + (k / 9) * GIB + (k / 3 % 3) * (2 << 20) + (k % 3) * 4096 + 0x123;
+                        let want = covering(&model, va).map(|(v, (pa, size, flags))| Translation { phys: pa + (va - v), size, flags });
+                        prop_assert_eq!(s.translate(&mem, va), want);
+                    }
+                }
+            }
+            if clear {
+                for (va, (pa, size, _)) in std::mem::take(&mut model) {
+                    prop_assert_eq!(s.unmap(&mut mem, &mut frames, va), Ok((pa, size)));
+                }
+                prop_assert_eq!(frames.stats().used, 1);
+            }
+            let used = frames.stats().used;
+            prop_assert_eq!(s.teardown(&mut mem, &mut frames), used);
+            prop_assert_eq!(frames.stats().used, 0);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! Capability rights: a small bitmask carried by every `Cap`.
+//!
+//! Rights can only ever be removed from a capability (see `Cap::attenuate`),
+//! so a holder can hand out a weaker handle to the same object but never a
+//! stronger one.
+
+/// Set of operations a capability authorizes.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub struct Rights(u16);
+
+impl Rights {
+    /// Read object state / receive from an endpoint.
+    pub const READ: Self = Self(1 << 0);
+    /// Modify object state / send to an endpoint.
+    pub const WRITE: Self = Self(1 << 1);
+    /// Pass capabilities along with a message.
+    pub const GRANT: Self = Self(1 << 2);
+    /// Map a memory object into an address space.
+    pub const MAP: Self = Self(1 << 3);
+    /// Make a synchronous call (send + wait for reply).
+    pub const CALL: Self = Self(1 << 4);
+    /// Answer a call through a reply capability.
+    pub const REPLY: Self = Self(1 << 5);
+
+    pub const NONE: Self = Self(0);
+    pub const ALL: Self = Self(0x3f);
+
+    /// Build from raw bits; undefined bits are dropped.
+    pub const fn from_bits_truncate(bits: u16) -> Self { Self(bits & Self::ALL.0) }
+    pub const fn bits(self) -> u16 { self.0 }
+    pub const fn is_empty(self) -> bool { self.0 == 0 }
+    pub const fn contains(self, other: Self) -> bool { self.0 & other.0 == other.0 }
+    pub const fn union(self, other: Self) -> Self { Self(self.0 | other.0) }
+    pub const fn intersection(self, other: Self) -> Self { Self(self.0 & other.0) }
+    /// Rights in `self` that are not in `other`.
+    pub const fn difference(self, other: Self) -> Self { Self(self.0 & !other.0) }
+}
+
+impl core::ops::BitOr for Rights {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self { self.union(rhs) }
+}
+
+impl core::ops::BitAnd for Rights {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self { self.intersection(rhs) }
+}
+
+impl core::fmt::Debug for Rights {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        const NAMES: [(Rights, char); 6] = [
+            (Rights::READ, 'r'), (Rights::WRITE, 'w'), (Rights::GRANT, 'g'),
+            (Rights::MAP, 'm'), (Rights::CALL, 'c'), (Rights::REPLY, 'y'),
+        ];
+        write!(f, "Rights(")?;
+        for (r, c) in NAMES {
+            let c = if self.contains(r) { c } else { '-' };
+            write!(f, "{}", c)?;
+        }
+        write!(f, ")")
+    }
+}
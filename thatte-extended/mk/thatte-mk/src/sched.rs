@@ -0,0 +1,735 @@
+//! Thread control blocks and the scheduler.
+//!
+//! Three classes, strictly ordered on each CPU:
+//!
+//! - `RealTime(prio)`: higher priority always preempts lower; equal
+//!   priorities round-robin with `RT_SLICE`.
+//! - `Normal(nice)`: CFS-like. Runtime is charged as virtual time scaled by
+//!   the nice weight, the thread with the least virtual time runs next, and
+//!   slices divide `SCHED_LATENCY` by weight.
+//! - `Batch(nice)`: FIFO round-robin with long `BATCH_SLICE`s, run only when
+//!   nothing else is queued, and never preempting on wakeup.
+//!
+//! A preempted RT or batch thread keeps its place at the head of the queue
+//! until its slice is used up.
+//!
+//! Each CPU has its own run queue (the `Ready` threads whose `cpu` is that
+//! CPU). Wakeups pick a CPU within the thread's affinity, RT threads going
+//! to the CPU running the least important work; an idle CPU steals queued
+//! work, and a thread preempted off a CPU is pushed to an idle one.
+//!
+//! Priority inheritance: a thread can lend its priority to the thread it
+//! waits on (`lend`, or `inherit` to follow blocked IPC calls). A thread runs
+//! at the most urgent of its own policy and those of every thread whose
+//! chain of loans leads to it, so an RT client calling a batch server gets
+//! served at RT priority until the reply.
+//!
+//! The scheduler never touches hardware. It is told the time on every call
+//! and records the CPUs that must call `schedule` in a set the caller drains
+//! with `take_resched` (and turns into IPIs).
+
+use crate::ipc::{Ipc, ThreadId};
+
+pub type CpuId = usize;
+/// Time in nanoseconds.
+pub type Nanos = u64;
+
+/// Round-robin slice between RT threads of equal priority.
+pub const RT_SLICE: Nanos = 10_000_000;
+/// Period in which every runnable `Normal` thread should run once.
+pub const SCHED_LATENCY: Nanos = 6_000_000;
+/// Shortest `Normal` slice, however many threads share the CPU.
+pub const MIN_GRANULARITY: Nanos = 750_000;
+/// Virtual-time lead a woken `Normal` thread needs to preempt another.
+pub const WAKEUP_GRANULARITY: Nanos = 1_000_000;
+pub const BATCH_SLICE: Nanos = 20_000_000;
+/// Virtual time a `Normal` thread may gain by sleeping.
+pub const SLEEPER_CREDIT: Nanos = SCHED_LATENCY / 2;
+pub const MAX_RT_PRIO: u8 = 99;
+pub const NICE_0_WEIGHT: u64 = 1024;
+
+/// Scheduling weight of nice -20..=19 (each step is ~1.25x).
+const NICE_WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916,
+    9548, 7620, 6100, 4904, 3906, 3121, 2501, 1991, 1586, 1277,
+    1024, 820, 655, 526, 423, 335, 272, 215, 172, 137,
+    110, 87, 70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Policy {
+    /// Priority 0..=`MAX_RT_PRIO`, higher is more urgent.
+    RealTime(u8),
+    /// Nice -20..=19, lower gets more CPU.
+    Normal(i8),
+    /// Nice -20..=19 among batch threads.
+    Batch(i8),
+}
+
+impl Policy {
+    pub const fn is_valid(self) -> bool {
+        match self {
+            Self::RealTime(p) => p <= MAX_RT_PRIO,
+            Self::Normal(n) | Self::Batch(n) => -20 <= n && n <= 19,
+        }
+    }
+
+    /// CFS weight (`NICE_0_WEIGHT` for RT).
+    pub const fn weight(self) -> u64 {
+        match self {
+            Self::RealTime(_) => NICE_0_WEIGHT,
+            Self::Normal(n) | Self::Batch(n) => NICE_WEIGHTS[(n + 20) as usize],
+        }
+    }
+
+    /// Importance across classes: batch < normal < RT by priority.
+    pub const fn rank(self) -> u32 {
+        match self {
+            Self::Batch(_) => 1,
+            Self::Normal(_) => 2,
+            Self::RealTime(p) => 3 + p as u32,
+        }
+    }
+
+    /// Whether `self` is more urgent than `other`: by `rank`, then by lower
+    /// nice within the normal or batch class.
+    pub const fn outranks(self, other: Self) -> bool {
+        match (self, other) {
+            (Self::Normal(a), Self::Normal(b)) | (Self::Batch(a), Self::Batch(b)) => a < b,
+            _ => self.rank() > other.rank(),
+        }
+    }
+}
+
+/// Set of CPUs (at most 64).
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Default, Debug)]
+pub struct CpuSet(u64);
+
+impl CpuSet {
+    pub const NONE: Self = Self(0);
+    pub const ALL: Self = Self(u64::MAX);
+
+    pub const fn from_bits(bits: u64) -> Self { Self(bits) }
+    pub const fn single(cpu: CpuId) -> Self { Self(1 << cpu) }
+    /// CPUs `0..n`.
+    pub const fn first(n: usize) -> Self { Self(if n >= 64 { u64::MAX } else { (1 << n) - 1 }) }
+    pub const fn bits(self) -> u64 { self.0 }
+    pub const fn is_empty(self) -> bool { self.0 == 0 }
+    pub const fn contains(self, cpu: CpuId) -> bool { cpu < 64 && self.0 >> cpu & 1 == 1 }
+    pub const fn union(self, other: Self) -> Self { Self(self.0 | other.0) }
+    pub const fn intersection(self, other: Self) -> Self { Self(self.0 & other.0) }
+    pub const fn without(self, cpu: CpuId) -> Self { Self(self.0 & !(1 << cpu)) }
+
+    pub fn iter(self) -> impl Iterator<Item = CpuId> { (0..64).filter(move |&c| self.contains(c)) }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RunState {
+    /// The TCB is unused.
+    Free,
+    /// Queued on `cpu`.
+    Ready,
+    /// Running on `cpu`.
+    Running,
+    Blocked,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SchedError {
+    /// Thread id out of range or not in use.
+    BadThread,
+    /// Priority or nice value out of range.
+    BadPolicy,
+    /// The affinity names no CPU that exists.
+    BadAffinity,
+    /// The thread is not in a state the operation applies to.
+    BadState,
+    /// No free TCBs.
+    Exhausted,
+}
+
+/// A thread control block.
+#[derive(Copy, Clone, Debug)]
+pub struct Tcb {
+    state: RunState,
+    /// Policy as set by `spawn` / `set_policy`.
+    base: Policy,
+    /// Effective policy: `base` raised by loans.
+    policy: Policy,
+    /// Thread this one lends its priority to.
+    donee: Option<ThreadId>,
+    affinity: CpuSet,
+    /// Run queue the thread is on, or the CPU it last ran on.
+    cpu: CpuId,
+    /// `Normal` only: weighted runtime, in `cpu`'s virtual clock.
+    vruntime: u64,
+    /// Left of the current slice.
+    slice_left: Nanos,
+    /// Queue order: lower goes first.
+    seq: i64,
+    runtime: Nanos,
+}
+
+impl Tcb {
+    const FREE: Self = Self {
+        state: RunState::Free,
+        base: Policy::Normal(0),
+        policy: Policy::Normal(0),
+        donee: None,
+        affinity: CpuSet::NONE,
+        cpu: 0,
+        vruntime: 0,
+        slice_left: 0,
+        seq: 0,
+        runtime: 0,
+    };
+
+    pub const fn state(&self) -> RunState { self.state }
+    /// Effective policy, including inherited priority.
+    pub const fn policy(&self) -> Policy { self.policy }
+    pub const fn base_policy(&self) -> Policy { self.base }
+    pub const fn donee(&self) -> Option<ThreadId> { self.donee }
+    pub const fn affinity(&self) -> CpuSet { self.affinity }
+    pub const fn cpu(&self) -> CpuId { self.cpu }
+    pub const fn vruntime(&self) -> u64 { self.vruntime }
+    /// Total CPU time consumed.
+    pub const fn runtime(&self) -> Nanos { self.runtime }
+}
+
+#[derive(Copy, Clone)]
+struct Cpu {
+    current: Option<ThreadId>,
+    /// When `current` was last charged.
+    since: Nanos,
+    /// Time `current` has run since it was dispatched.
+    ran: Nanos,
+    /// Monotonic floor of the `Normal` vruntimes on this CPU.
+    min_vruntime: u64,
+}
+
+/// `THREADS` TCBs scheduled on `CPUS` CPUs.
+pub struct Scheduler<const THREADS: usize, const CPUS: usize> {
+    tcbs: [Tcb; THREADS],
+    cpus: [Cpu; CPUS],
+    resched: CpuSet,
+    /// Next queue position at the tail / head.
+    back: i64,
+    front: i64,
+}
+
+impl<const THREADS: usize, const CPUS: usize> Default for Scheduler<THREADS, CPUS> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<const THREADS: usize, const CPUS: usize> Scheduler<THREADS, CPUS> {
+    const ONLINE: CpuSet = CpuSet::first(CPUS);
+
+    pub const fn new() -> Self {
+        assert!(CPUS <= 64 && THREADS <= ThreadId::MAX as usize);
+        Self {
+            tcbs: [Tcb::FREE; THREADS],
+            cpus: [Cpu { current: None, since: 0, ran: 0, min_vruntime: 0 }; CPUS],
+            resched: CpuSet::NONE,
+            back: 0,
+            front: 0,
+        }
+    }
+
+    /// The TCB of a thread in use.
+    pub fn tcb(&self, tid: ThreadId) -> Option<&Tcb> {
+        self.tcbs.get(tid as usize).filter(|t| t.state != RunState::Free)
+    }
+
+    pub fn current(&self, cpu: CpuId) -> Option<ThreadId> { self.cpus.get(cpu)?.current }
+
+    /// When the running thread's slice ends: program `cpu`'s timer for it
+    /// (and call `tick`) after every call that may change it.
+    pub fn deadline(&self, cpu: CpuId) -> Option<Nanos> {
+        let c = self.cpus.get(cpu)?;
+        c.current.map(|t| c.since + self.tcbs[t as usize].slice_left)
+    }
+
+    /// Threads queued on `cpu` (not counting the running one).
+    pub fn queued(&self, cpu: CpuId) -> usize { self.ready_on(cpu).count() }
+
+    /// CPUs that must call `schedule`; clears the set.
+    pub fn take_resched(&mut self) -> CpuSet { core::mem::take(&mut self.resched) }
+
+    fn live(&self, tid: ThreadId) -> Result<usize, SchedError> {
+        self.tcb(tid).map(|_| tid as usize).ok_or(SchedError::BadThread)
+    }
+
+    fn ready_on(&self, cpu: CpuId) -> impl Iterator<Item = ThreadId> + '_ {
+        (0..THREADS as ThreadId).filter(move |&t| {
+            let t = &self.tcbs[t as usize];
+            t.state == RunState::Ready && t.cpu == cpu
+        })
+    }
+
+    /// Create a thread and make it runnable.
+    pub fn spawn(&mut self, policy: Policy, affinity: CpuSet, now: Nanos) -> Result<ThreadId, SchedError> {
+        if !policy.is_valid() { return Err(SchedError::BadPolicy); }
+        let affinity = affinity.intersection(Self::ONLINE);
+        if affinity.is_empty() { return Err(SchedError::BadAffinity); }
+        let tid = self.tcbs.iter().position(|t| t.state == RunState::Free).ok_or(SchedError::Exhausted)?;
+        let cpu = affinity.iter().next().unwrap_or(0);
+        let vruntime = self.cpus[cpu].min_vruntime;
+        self.tcbs[tid] = Tcb { state: RunState::Blocked, base: policy, policy, affinity, cpu, vruntime, ..Tcb::FREE };
+        self.wake(tid as ThreadId, now)?;
+        Ok(tid as ThreadId)
+    }
+
+    /// Remove a thread for good; its CPU reschedules if it was running.
+    pub fn exit(&mut self, tid: ThreadId, now: Nanos) -> Result<(), SchedError> {
+        let t = self.live(tid)?;
+        self.stop(t, now);
+        self.tcbs[t] = Tcb::FREE;
+        for tcb in self.tcbs.iter_mut().filter(|u| u.donee == Some(tid)) {
+            tcb.donee = None;
+        }
+        self.reinherit(now);
+        Ok(())
+    }
+
+    /// Take a runnable thread off the CPU until `wake`.
+    pub fn block(&mut self, tid: ThreadId, now: Nanos) -> Result<(), SchedError> {
+        let t = self.live(tid)?;
+        if self.tcbs[t].state == RunState::Blocked { return Err(SchedError::BadState); }
+        self.stop(t, now);
+        self.tcbs[t].state = RunState::Blocked;
+        Ok(())
+    }
+
+    /// Charge and unseat `t` if it is running.
+    fn stop(&mut self, t: usize, now: Nanos) {
+        if self.tcbs[t].state == RunState::Running {
+            let cpu = self.tcbs[t].cpu;
+            self.charge(cpu, now);
+            self.cpus[cpu].current = None;
+            self.resched = self.resched.union(CpuSet::single(cpu));
+        }
+    }
+
+    /// Make a blocked thread runnable, marking its CPU for rescheduling if
+    /// it should preempt what runs there.
+    pub fn wake(&mut self, tid: ThreadId, now: Nanos) -> Result<(), SchedError> {
+        let t = self.live(tid)?;
+        if self.tcbs[t].state != RunState::Blocked { return Err(SchedError::BadState); }
+        let cpu = self.select_cpu(t);
+        self.migrate(t, cpu, now);
+        let tcb = &mut self.tcbs[t];
+        if let Policy::Normal(_) = tcb.policy {
+            tcb.vruntime = tcb.vruntime.max(self.cpus[cpu].min_vruntime.saturating_sub(SLEEPER_CREDIT));
+        }
+        self.enqueue(t, cpu, false);
+        self.kick_if_preempts(cpu, t, now);
+        Ok(())
+    }
+
+    /// Change a thread's own policy (loans to it still apply).
+    pub fn set_policy(&mut self, tid: ThreadId, policy: Policy, now: Nanos) -> Result<(), SchedError> {
+        let t = self.live(tid)?;
+        if !policy.is_valid() { return Err(SchedError::BadPolicy); }
+        self.tcbs[t].base = policy;
+        self.reinherit(now);
+        Ok(())
+    }
+
+    /// Lend `tid`'s priority to `to` (or stop lending, with `None`) while
+    /// it waits on it.
+    pub fn lend(&mut self, tid: ThreadId, to: Option<ThreadId>, now: Nanos) -> Result<(), SchedError> {
+        let t = self.live(tid)?;
+        if let Some(to) = to { self.live(to)?; }
+        self.tcbs[t].donee = to;
+        self.reinherit(now);
+        Ok(())
+    }
+
+    /// Make every thread lend to the thread its blocked IPC call waits on
+    /// (`Ipc::donee`), and nothing else. Call after each IPC operation.
+    pub fn inherit<const EPS: usize, const DEPTH: usize, const SLOTS: usize>(
+        &mut self,
+        ipc: &Ipc<THREADS, EPS, DEPTH, SLOTS>,
+        now: Nanos,
+    ) {
+        for t in 0..THREADS as ThreadId {
+            let donee = ipc.donee(t).filter(|&d| self.tcb(d).is_some());
+            if self.tcb(t).is_some() { self.tcbs[t as usize].donee = donee; }
+        }
+        self.reinherit(now);
+    }
+
+    /// Recompute effective policies from the loans, and apply the changes.
+    fn reinherit(&mut self, now: Nanos) {
+        let mut eff: [Policy; THREADS] = core::array::from_fn(|t| self.tcbs[t].base);
+        for u in self.tcbs.iter().filter(|u| u.state != RunState::Free) {
+            // Loans can form a cycle (a deadlock); stop after one lap.
+            let mut at = u.donee;
+            for _ in 0..THREADS {
+                let Some(d) = at else { break };
+                let d = d as usize;
+                if u.base.outranks(eff[d]) { eff[d] = u.base; }
+                at = self.tcbs[d].donee;
+            }
+        }
+        for (t, &policy) in eff.iter().enumerate() {
+            if self.tcbs[t].state != RunState::Free && self.tcbs[t].policy != policy {
+                self.apply(t, policy, now);
+            }
+        }
+    }
+
+    /// Switch `t` to effective `policy`, rescheduling as needed.
+    fn apply(&mut self, t: usize, policy: Policy, now: Nanos) {
+        let (state, cpu) = (self.tcbs[t].state, self.tcbs[t].cpu);
+        if state == RunState::Running { self.charge(cpu, now); }
+        let tcb = &mut self.tcbs[t];
+        if !matches!(tcb.policy, Policy::Normal(_)) {
+            tcb.vruntime = self.cpus[cpu].min_vruntime;
+        }
+        tcb.policy = policy;
+        match state {
+            RunState::Running => self.resched = self.resched.union(CpuSet::single(cpu)),
+            RunState::Ready => {
+                self.push(t, now);
+                if self.tcbs[t].cpu == cpu { self.kick_if_preempts(cpu, t, now); }
+            }
+            _ => {}
+        }
+    }
+
+    /// Restrict a thread to `affinity`, moving it off a CPU it may no longer use.
+    pub fn set_affinity(&mut self, tid: ThreadId, affinity: CpuSet, now: Nanos) -> Result<(), SchedError> {
+        let t = self.live(tid)?;
+        let affinity = affinity.intersection(Self::ONLINE);
+        if affinity.is_empty() { return Err(SchedError::BadAffinity); }
+        self.tcbs[t].affinity = affinity;
+        let (state, cpu) = (self.tcbs[t].state, self.tcbs[t].cpu);
+        if affinity.contains(cpu) { return Ok(()); }
+        match state {
+            RunState::Running => self.resched = self.resched.union(CpuSet::single(cpu)),
+            RunState::Ready => {
+                let to = self.select_cpu(t);
+                self.migrate(t, to, now);
+                self.kick_if_preempts(to, t, now);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Charge the running thread and flag the CPU once its slice is used up.
+    /// Call from the timer interrupt.
+    pub fn tick(&mut self, cpu: CpuId, now: Nanos) {
+        self.charge(cpu, now);
+        let expired = match self.cpus[cpu].current {
+            Some(t) => self.tcbs[t as usize].slice_left == 0,
+            None => self.ready_on(cpu).next().is_some(),
+        };
+        if expired {
+            self.resched = self.resched.union(CpuSet::single(cpu));
+        }
+    }
+
+    /// Put the running thread back on the queue and pick the next one to
+    /// run on `cpu`, with its slice. `None` means idle.
+    pub fn schedule(&mut self, cpu: CpuId, now: Nanos) -> Option<(ThreadId, Nanos)> {
+        self.resched = self.resched.without(cpu);
+        self.charge(cpu, now);
+        let prev = self.cpus[cpu].current.take().map(|p| p as usize);
+        if let Some(p) = prev {
+            let tcb = &mut self.tcbs[p];
+            // Preempted RT and batch threads keep their turn; expired ones
+            // go last.
+            let front = !matches!(tcb.policy, Policy::Normal(_)) && tcb.slice_left > 0;
+            self.enqueue(p, cpu, front);
+        }
+        let next = self.pick(cpu).or_else(|| self.steal(cpu, now));
+        if let Some(p) = prev.filter(|&p| Some(p) != next) {
+            self.push(p, now);
+        }
+        let t = next?;
+        let slice = self.slice(t, cpu);
+        let tcb = &mut self.tcbs[t];
+        tcb.state = RunState::Running;
+        tcb.slice_left = slice;
+        self.cpus[cpu].current = Some(t as ThreadId);
+        self.cpus[cpu].ran = 0;
+        self.update_min_vruntime(cpu);
+        Some((t as ThreadId, slice))
+    }
+
+    fn enqueue(&mut self, t: usize, cpu: CpuId, front: bool) {
+        let seq = if front {
+            self.front -= 1;
+            self.front
+        } else {
+            self.back += 1;
+            self.back
+        };
+        let tcb = &mut self.tcbs[t];
+        tcb.state = RunState::Ready;
+        tcb.cpu = cpu;
+        tcb.seq = seq;
+    }
+
+    /// Move `t`'s queue to `to`, carrying its vruntime over to `to`'s clock.
+    fn migrate(&mut self, t: usize, to: CpuId, now: Nanos) {
+        let from = self.tcbs[t].cpu;
+        if from != to {
+            self.charge(from, now);
+            self.charge(to, now);
+            let tcb = &mut self.tcbs[t];
+            let lag = tcb.vruntime.saturating_sub(self.cpus[from].min_vruntime);
+            tcb.vruntime = self.cpus[to].min_vruntime + lag;
+            tcb.cpu = to;
+        }
+    }
+
+    /// Account time since the last charge to `cpu`'s running thread.
+    fn charge(&mut self, cpu: CpuId, now: Nanos) {
+        let c = &mut self.cpus[cpu];
+        let delta = now.saturating_sub(c.since);
+        c.since = now;
+        let Some(t) = c.current else { return };
+        c.ran += delta;
+        let tcb = &mut self.tcbs[t as usize];
+        tcb.runtime += delta;
+        tcb.slice_left = tcb.slice_left.saturating_sub(delta);
+        if let Policy::Normal(_) = tcb.policy {
+            tcb.vruntime += delta * NICE_0_WEIGHT / tcb.policy.weight();
+        }
+        self.update_min_vruntime(cpu);
+    }
+
+    fn update_min_vruntime(&mut self, cpu: CpuId) {
+        let running = self.cpus[cpu].current.map(|t| t as ThreadId);
+        let min = self
+            .ready_on(cpu)
+            .chain(running)
+            .map(|t| &self.tcbs[t as usize])
+            .filter(|t| matches!(t.policy, Policy::Normal(_)))
+            .map(|t| t.vruntime)
+            .min();
+        if let Some(min) = min {
+            let c = &mut self.cpus[cpu];
+            c.min_vruntime = c.min_vruntime.max(min);
+        }
+    }
+
+    /// Queue order: class rank first, then vruntime (`Normal`), then FIFO.
+    fn key(&self, t: usize) -> (core::cmp::Reverse<u32>, u64, i64) {
+        let tcb = &self.tcbs[t];
+        let vruntime = if let Policy::Normal(_) = tcb.policy { tcb.vruntime } else { 0 };
+        (core::cmp::Reverse(tcb.policy.rank()), vruntime, tcb.seq)
+    }
+
+    fn pick(&self, cpu: CpuId) -> Option<usize> {
+        self.ready_on(cpu)
+            .map(|t| t as usize)
+            .filter(|&t| self.tcbs[t].affinity.contains(cpu))
+            .min_by_key(|&t| self.key(t))
+    }
+
+    /// Take the most important thread queued elsewhere that may run here.
+    fn steal(&mut self, cpu: CpuId, now: Nanos) -> Option<usize> {
+        let t = (0..THREADS)
+            .filter(|&t| {
+                let tcb = &self.tcbs[t];
+                tcb.state == RunState::Ready && tcb.cpu != cpu && tcb.affinity.contains(cpu)
+            })
+            .min_by_key(|&t| (core::cmp::Reverse(self.tcbs[t].policy.rank()), self.tcbs[t].seq))?;
+        self.migrate(t, cpu, now);
+        Some(t)
+    }
+
+    /// A thread just lost `cpu`: move it where it can run sooner, or at all.
+    fn push(&mut self, t: usize, now: Nanos) {
+        let cpu = self.tcbs[t].cpu;
+        let to = self.select_cpu(t);
+        if to == cpu { return; }
+        let stuck = !self.tcbs[t].affinity.contains(cpu);
+        let policy = self.tcbs[t].policy;
+        let sooner = match self.cpus[to].current {
+            None => true,
+            Some(c) => matches!(policy, Policy::RealTime(_)) && policy.rank() > self.tcbs[c as usize].policy.rank(),
+        };
+        if stuck || sooner {
+            self.migrate(t, to, now);
+            self.kick_if_preempts(to, t, now);
+        }
+    }
+
+    /// Best CPU for `t` within its affinity. RT threads go where the least
+    /// important work runs; others prefer an idle CPU, then the last one,
+    /// then the shortest queue.
+    fn select_cpu(&self, t: usize) -> CpuId {
+        let tcb = &self.tcbs[t];
+        let rt = matches!(tcb.policy, Policy::RealTime(_));
+        tcb.affinity
+            .iter()
+            .min_by_key(|&c| {
+                let running = self.cpus[c].current.map_or(0, |r| self.tcbs[r as usize].policy.rank());
+                let busy = running.max(if self.queued(c) > 0 { 1 } else { 0 });
+                let load = self.queued(c) + self.cpus[c].current.is_some() as usize;
+                let class = if rt { busy } else { (busy > 0) as u32 };
+                (class, c != tcb.cpu, load)
+            })
+            .unwrap_or(tcb.cpu)
+    }
+
+    /// Whether queued thread `t` should displace what runs on `cpu`.
+    fn preempts(&mut self, cpu: CpuId, t: usize, now: Nanos) -> bool {
+        let Some(cur) = self.cpus[cpu].current else { return true };
+        self.charge(cpu, now);
+        let (a, b) = (&self.tcbs[t], &self.tcbs[cur as usize]);
+        match (a.policy, b.policy) {
+            (Policy::Normal(_), Policy::Normal(_)) => a.vruntime + WAKEUP_GRANULARITY < b.vruntime,
+            (Policy::Batch(_), _) => false,
+            (p, q) => p.rank() > q.rank(),
+        }
+    }
+
+    /// `t` was just queued on `cpu`: preempt, or shorten the running
+    /// `Normal` thread's slice to its share with `t` there.
+    fn kick_if_preempts(&mut self, cpu: CpuId, t: usize, now: Nanos) {
+        let mut kick = self.preempts(cpu, t, now);
+        if let Some(cur) = self.cpus[cpu].current.map(|c| c as usize) {
+            if matches!(self.tcbs[cur].policy, Policy::Normal(_)) {
+                let share = self.slice(cur, cpu).saturating_sub(self.cpus[cpu].ran);
+                let tcb = &mut self.tcbs[cur];
+                tcb.slice_left = tcb.slice_left.min(share);
+                kick |= tcb.slice_left == 0;
+            }
+        }
+        if kick {
+            self.resched = self.resched.union(CpuSet::single(cpu));
+        }
+    }
+
+    /// Slice for `t` about to run on `cpu`.
+    fn slice(&self, t: usize, cpu: CpuId) -> Nanos {
+        let tcb = &self.tcbs[t];
+        match tcb.policy {
+            Policy::RealTime(_) if tcb.slice_left > 0 => tcb.slice_left,
+            Policy::RealTime(_) => RT_SLICE,
+            Policy::Batch(_) => BATCH_SLICE,
+            Policy::Normal(_) => {
+                let total: u64 = self
+                    .ready_on(cpu)
+                    .filter(|&q| q as usize != t)
+                    .map(|q| self.tcbs[q as usize].policy)
+                    .filter(|p| matches!(p, Policy::Normal(_)))
+                    .map(Policy::weight)
+                    .sum();
+                let w = tcb.policy.weight();
+                (SCHED_LATENCY * w / (total + w)).max(MIN_GRANULARITY)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    type Sched = Scheduler<8, 2>;
+
+    #[test]
+    fn validates_arguments() {
+        let mut s = Sched::new();
+        assert_eq!(s.spawn(Policy::RealTime(100), CpuSet::ALL, 0), Err(SchedError::BadPolicy));
+        assert_eq!(s.spawn(Policy::Normal(20), CpuSet::ALL, 0), Err(SchedError::BadPolicy));
+        assert_eq!(s.spawn(Policy::Batch(0), CpuSet::single(5), 0), Err(SchedError::BadAffinity));
+        let t = s.spawn(Policy::Batch(0), CpuSet::single(1), 0).unwrap();
+        assert_eq!((s.tcb(t).unwrap().state(), s.tcb(t).unwrap().cpu()), (RunState::Ready, 1));
+        assert_eq!(s.wake(t, 0), Err(SchedError::BadState));
+        s.exit(t, 0).unwrap();
+        assert_eq!(s.block(t, 0), Err(SchedError::BadThread));
+        for _ in 0..8 {
+            s.spawn(Policy::Normal(0), CpuSet::ALL, 0).unwrap();
+        }
+        assert_eq!(s.spawn(Policy::Normal(0), CpuSet::ALL, 0), Err(SchedError::Exhausted));
+    }
+
+    #[test]
+    fn classes_preempt_in_order() {
+        let mut s = Scheduler::<8, 1>::new();
+        let batch = s.spawn(Policy::Batch(0), CpuSet::ALL, 0).unwrap();
+        assert_eq!(s.take_resched(), CpuSet::single(0));
+        assert_eq!(s.schedule(0, 0), Some((batch, BATCH_SLICE)));
+        // A second batch thread waits for the slice; a normal one preempts.
+        s.spawn(Policy::Batch(0), CpuSet::ALL, 1).unwrap();
+        assert!(s.take_resched().is_empty());
+        let normal = s.spawn(Policy::Normal(0), CpuSet::ALL, 2).unwrap();
+        assert_eq!(s.take_resched(), CpuSet::single(0));
+        assert_eq!(s.schedule(0, 2), Some((normal, SCHED_LATENCY)));
+        let rt = s.spawn(Policy::RealTime(10), CpuSet::ALL, 3).unwrap();
+        assert_eq!(s.take_resched(), CpuSet::single(0));
+        assert_eq!(s.schedule(0, 3), Some((rt, RT_SLICE)));
+        // A lower RT thread waits; blocking the higher one lets it in.
+        let rt2 = s.spawn(Policy::RealTime(5), CpuSet::ALL, 4).unwrap();
+        assert!(s.take_resched().is_empty());
+        s.block(rt, 5).unwrap();
+        assert_eq!(s.schedule(0, 5), Some((rt2, RT_SLICE)));
+        assert_eq!(s.tcb(rt).unwrap().runtime(), 2);
+        s.exit(rt2, 6).unwrap();
+        assert_eq!(s.schedule(0, 6).map(|(t, _)| t), Some(normal));
+        s.block(normal, 7).unwrap();
+        assert_eq!(s.schedule(0, 7).map(|(t, _)| t), Some(batch));
+    }
+
+    #[test]
+    fn loans_raise_priority_transitively() {
+        let mut s = Scheduler::<8, 1>::new();
+        let server = s.spawn(Policy::Batch(0), CpuSet::ALL, 0).unwrap();
+        let mid = s.spawn(Policy::Normal(0), CpuSet::ALL, 0).unwrap();
+        let hog = s.spawn(Policy::RealTime(10), CpuSet::ALL, 0).unwrap();
+        let client = s.spawn(Policy::RealTime(50), CpuSet::ALL, 0).unwrap();
+        assert_eq!(s.schedule(0, 0).map(|(t, _)| t), Some(client));
+        // client -> mid -> server, as with nested calls.
+        s.block(client, 1).unwrap();
+        s.block(mid, 1).unwrap();
+        s.lend(client, Some(mid), 1).unwrap();
+        s.lend(mid, Some(server), 1).unwrap();
+        let policy = |s: &Scheduler<8, 1>, t| s.tcb(t).unwrap().policy();
+        assert_eq!((policy(&s, mid), policy(&s, server)), (Policy::RealTime(50), Policy::RealTime(50)));
+        assert_eq!(s.schedule(0, 1).map(|(t, _)| t), Some(server));
+        // A cycle of loans terminates.
+        s.lend(server, Some(mid), 2).unwrap();
+        assert_eq!(policy(&s, server), Policy::RealTime(50));
+        s.lend(server, None, 3).unwrap();
+        s.lend(client, None, 3).unwrap();
+        // mid still waits on the server, which runs at mid's priority.
+        assert_eq!((policy(&s, mid), policy(&s, server)), (Policy::Normal(0), Policy::Normal(0)));
+        s.lend(mid, None, 3).unwrap();
+        assert_eq!(policy(&s, server), Policy::Batch(0));
+        assert_eq!(s.tcb(server).unwrap().base_policy(), Policy::Batch(0));
+        assert_eq!(s.take_resched(), CpuSet::single(0));
+        assert_eq!(s.schedule(0, 3).map(|(t, _)| t), Some(hog));
+        assert_eq!(s.lend(client, Some(7), 4), Err(SchedError::BadThread));
+    }
+
+    #[test]
+    fn affinity_is_respected() {
+        let mut s = Sched::new();
+        let a = s.spawn(Policy::Normal(0), CpuSet::ALL, 0).unwrap();
+        let b = s.spawn(Policy::Normal(0), CpuSet::ALL, 0).unwrap();
+        // Spread over both CPUs.
+        assert_eq!((s.tcb(a).unwrap().cpu(), s.tcb(b).unwrap().cpu()), (0, 1));
+        assert_eq!(s.take_resched(), CpuSet::first(2));
+        assert_eq!(s.schedule(0, 0).map(|(t, _)| t), Some(a));
+        assert_eq!(s.schedule(1, 0).map(|(t, _)| t), Some(b));
+        s.set_affinity(a, CpuSet::single(1), 10).unwrap();
+        assert_eq!(s.take_resched(), CpuSet::single(0));
+        assert_eq!(s.schedule(0, 10), None);
+        assert_eq!(s.tcb(a).unwrap().cpu(), 1);
+        assert_eq!(s.queued(1), 1);
+        // CPU 1 is busy, so `a` cannot preempt `b` right away.
+        assert!(s.take_resched().is_empty());
+        // When `b` expires and sleeps, CPU 0 may not steal `a`.
+        s.block(b, 20).unwrap();
+        assert_eq!(s.schedule(0, 20), None);
+        assert_eq!(s.schedule(1, 20).map(|(t, _)| t), Some(a));
+    }
+}
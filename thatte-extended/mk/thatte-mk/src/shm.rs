@@ -0,0 +1,449 @@
+//! Shared-memory regions: the object model behind `MsgType::MapShared`.
+//!
+//! A region is a page-aligned run of memory whose permissions (read-only or
+//! read-write) are fixed at creation. Its creator gets the owner cap, the
+//! only one carrying GRANT; `grant` derives read-only or read-write caps to
+//! hand to other tasks, typically in a `MapShared` message's transfer
+//! section. A cap with MAP maps all or part of the region into an address
+//! space.
+//!
+//! The owner and every mapping hold a reference. `unmap` drops one mapping;
+//! `revoke` tears down every mapping and makes every outstanding cap stale,
+//! handing the owner a fresh cap; once the owner has `release`d its
+//! reference and the last mapping is gone the region is freed.
+//!
+//! Address spaces are plain ids here; installing the page-table entries for
+//! a mapping is up to the caller, and so is removing them for the mappings
+//! `unmap` returns and `revoke` reports.
+
+use crate::mint::{object_id, MintAuthority, MintError, ObjectKind};
+use crate::msg::{MapShared, MapSharedReply};
+use crate::{Cap, MissingRights, Rights};
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Start of the virtual window `map_shared` places mappings in.
+pub const SHM_BASE: u64 = 0x0000_4000_0000_0000;
+/// End (exclusive) of that window.
+pub const SHM_END: u64 = 0x0000_5000_0000_0000;
+
+/// An address space, as named by the caller.
+pub type SpaceId = u16;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ShmError {
+    /// A size, offset or address is zero, not page-aligned or out of range.
+    BadRange,
+    /// Region permissions must be READ, optionally with WRITE.
+    BadPerms,
+    /// The capability is forged or stale.
+    Cap(MintError),
+    /// The capability names an object of the wrong kind.
+    WrongObject,
+    /// The region has been freed.
+    NoRegion,
+    /// The owner cap was already released.
+    Released,
+    /// The capability lacks rights for the operation.
+    Rights(MissingRights),
+    /// The range overlaps an existing mapping in that address space.
+    Overlap,
+    /// Nothing is mapped at that address.
+    NotMapped,
+    /// The region or mapping table is full, or no address space is left.
+    Exhausted,
+}
+
+#[derive(Copy, Clone)]
+struct Region {
+    live: bool,
+    generation: u32,
+    size: u64,
+    perms: Rights,
+    /// Mappings, plus one while the owner has not released the region.
+    refs: u32,
+    owned: bool,
+}
+
+impl Region {
+    const fn new() -> Self { Self { live: false, generation: 0, size: 0, perms: Rights::NONE, refs: 0, owned: false } }
+}
+
+/// One region mapped into one address space.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Mapping {
+    /// Region table index.
+    pub region: usize,
+    pub space: SpaceId,
+    pub addr: u64,
+    /// Byte offset of `addr` within the region.
+    pub offset: u64,
+    pub len: u64,
+    pub writable: bool,
+}
+
+impl Mapping {
+    fn overlaps(&self, space: SpaceId, addr: u64, len: u64) -> bool {
+        self.space == space && addr < self.addr + self.len && self.addr < addr + len
+    }
+}
+
+/// What `stat` reports about a region.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct RegionStat {
+    pub size: u64,
+    pub perms: Rights,
+    pub refs: u32,
+}
+
+const fn aligned(v: u64) -> bool { v.is_multiple_of(PAGE_SIZE) }
+
+/// `REGIONS` regions and up to `MAPPINGS` mappings of them in total.
+pub struct Regions<const REGIONS: usize, const MAPPINGS: usize> {
+    mint: MintAuthority,
+    regions: [Region; REGIONS],
+    mappings: [Option<Mapping>; MAPPINGS],
+}
+
+impl<const REGIONS: usize, const MAPPINGS: usize> Regions<REGIONS, MAPPINGS> {
+    /// `secret` keys the authority that mints region caps.
+    pub const fn new(secret: [u8; 16]) -> Self {
+        Self { mint: MintAuthority::new(secret), regions: [Region::new(); REGIONS], mappings: [None; MAPPINGS] }
+    }
+
+    fn owner_cap(&self, i: usize) -> Cap {
+        let r = &self.regions[i];
+        self.mint.mint(object_id(ObjectKind::Region, i as u32), r.generation, r.perms | Rights::MAP | Rights::GRANT)
+    }
+
+    /// Create a region of `size` bytes with `perms` (READ, or READ | WRITE)
+    /// and return the owner cap.
+    pub fn create(&mut self, size: u64, perms: Rights) -> Result<Cap, ShmError> {
+        if size == 0 || !aligned(size) { return Err(ShmError::BadRange); }
+        if !perms.contains(Rights::READ) || !(Rights::READ | Rights::WRITE).contains(perms) {
+            return Err(ShmError::BadPerms);
+        }
+        let i = self.regions.iter().position(|r| !r.live).ok_or(ShmError::Exhausted)?;
+        let r = &mut self.regions[i];
+        *r = Region { live: true, size, perms, refs: 1, owned: true, ..*r };
+        Ok(self.owner_cap(i))
+    }
+
+    /// Resolve a region cap, requiring `need`.
+    fn region(&self, cap: &Cap, need: Rights) -> Result<usize, ShmError> {
+        let m = self.mint.verify(cap).map_err(ShmError::Cap)?;
+        let i = m.index_of(ObjectKind::Region).filter(|&i| i < REGIONS).ok_or(ShmError::WrongObject)?;
+        let r = &self.regions[i];
+        self.mint.verify_current(cap, r.generation).map_err(ShmError::Cap)?;
+        if !r.live { return Err(ShmError::NoRegion); }
+        cap.require(need).map_err(ShmError::Rights)?;
+        Ok(i)
+    }
+
+    /// Resolve an owner cap that has not been released.
+    fn owned(&self, owner: &Cap) -> Result<usize, ShmError> {
+        let i = self.region(owner, Rights::GRANT)?;
+        if !self.regions[i].owned { return Err(ShmError::Released); }
+        Ok(i)
+    }
+
+    /// Derive a cap that maps the region read-only, or read-write if
+    /// `writable` (which the region must allow). Needs the owner cap.
+    pub fn grant(&self, owner: &Cap, writable: bool) -> Result<Cap, ShmError> {
+        let i = self.owned(owner)?;
+        let rights = if writable { Rights::READ | Rights::WRITE | Rights::MAP } else { Rights::READ | Rights::MAP };
+        if writable && !self.regions[i].perms.contains(Rights::WRITE) {
+            return Err(ShmError::Rights(MissingRights(Rights::WRITE)));
+        }
+        Ok(self.mint.mint(object_id(ObjectKind::Region, i as u32), self.regions[i].generation, rights))
+    }
+
+    pub fn stat(&self, cap: &Cap) -> Result<RegionStat, ShmError> {
+        let r = &self.regions[self.region(cap, Rights::NONE)?];
+        Ok(RegionStat { size: r.size, perms: r.perms, refs: r.refs })
+    }
+
+    /// Map `len` bytes of the region starting at `offset` into `space` at
+    /// `addr` (all page-aligned). Needs MAP and READ, plus WRITE if
+    /// `writable`.
+    pub fn map(&mut self, cap: &Cap, space: SpaceId, addr: u64, offset: u64, len: u64, writable: bool) -> Result<Mapping, ShmError> {
+        let need = if writable { Rights::MAP | Rights::READ | Rights::WRITE } else { Rights::MAP | Rights::READ };
+        let region = self.region(cap, need)?;
+        if len == 0 || !aligned(addr) || !aligned(offset) || !aligned(len) { return Err(ShmError::BadRange); }
+        let end = offset.checked_add(len).ok_or(ShmError::BadRange)?;
+        if end > self.regions[region].size || addr.checked_add(len).is_none() { return Err(ShmError::BadRange); }
+        if self.mappings.iter().flatten().any(|m| m.overlaps(space, addr, len)) { return Err(ShmError::Overlap); }
+        let slot = self.mappings.iter().position(Option::is_none).ok_or(ShmError::Exhausted)?;
+        let m = Mapping { region, space, addr, offset, len, writable };
+        self.mappings[slot] = Some(m);
+        self.regions[region].refs += 1;
+        Ok(m)
+    }
+
+    /// Lowest address in the `map_shared` window where `len` bytes fit in
+    /// `space`.
+    fn free_addr(&self, space: SpaceId, len: u64) -> Option<u64> {
+        let mut addr = SHM_BASE;
+        loop {
+            let end = addr.checked_add(len)?;
+            let blocker = self.mappings.iter().flatten().filter(|m| m.overlaps(space, addr, len)).map(|m| m.addr + m.len).max();
+            match blocker {
+                None if end <= SHM_END => return Some(addr),
+                None => return None,
+                Some(next) => addr = next,
+            }
+        }
+    }
+
+    /// Handle a `MapShared` request from `space` whose region cap resolved to
+    /// `cap`: map the requested range wherever it fits.
+    pub fn map_shared(&mut self, space: SpaceId, cap: &Cap, req: &MapShared) -> Result<MapSharedReply, ShmError> {
+        if req.len == 0 || !aligned(req.len) { return Err(ShmError::BadRange); }
+        let addr = self.free_addr(space, req.len).ok_or(ShmError::Exhausted)?;
+        let m = self.map(cap, space, addr, req.offset, req.len, req.writable)?;
+        Ok(MapSharedReply { addr: m.addr, len: m.len })
+    }
+
+    fn drop_ref(&mut self, i: usize) {
+        let r = &mut self.regions[i];
+        r.refs -= 1;
+        if r.refs == 0 {
+            r.live = false;
+            r.generation = r.generation.wrapping_add(1);
+        }
+    }
+
+    /// Remove the mapping that starts at `addr` in `space`.
+    pub fn unmap(&mut self, space: SpaceId, addr: u64) -> Result<Mapping, ShmError> {
+        let slot = self.mappings.iter().position(|m| matches!(m, Some(m) if m.space == space && m.addr == addr));
+        let m = slot.and_then(|s| self.mappings[s].take()).ok_or(ShmError::NotMapped)?;
+        self.drop_ref(m.region);
+        Ok(m)
+    }
+
+    /// Tear down every mapping of the region and invalidate every cap to it.
+    /// Needs the owner cap; calls `unmapped` with each mapping removed, for
+    /// the caller to clear its page-table entries, and returns the owner's
+    /// replacement cap.
+    pub fn revoke(&mut self, owner: &Cap, mut unmapped: impl FnMut(Mapping)) -> Result<Cap, ShmError> {
+        let i = self.owned(owner)?;
+        for slot in self.mappings.iter_mut() {
+            if let Some(m) = slot.take_if(|m| m.region == i) {
+                self.regions[i].refs -= 1;
+                unmapped(m);
+            }
+        }
+        let r = &mut self.regions[i];
+        r.generation = r.generation.wrapping_add(1);
+        Ok(self.owner_cap(i))
+    }
+
+    /// Drop the owner's reference. The region lives on until its last
+    /// mapping is removed, and granted caps may still map it until then.
+    pub fn release(&mut self, owner: &Cap) -> Result<(), ShmError> {
+        let i = self.owned(owner)?;
+        self.regions[i].owned = false;
+        self.drop_ref(i);
+        Ok(())
+    }
+
+    /// The mapping covering `addr` in `space`, if any.
+    pub fn translate(&self, space: SpaceId, addr: u64) -> Option<&Mapping> {
+        self.mappings.iter().flatten().find(|m| m.overlaps(space, addr, 1))
+    }
+
+    pub fn mappings(&self) -> impl Iterator<Item = &Mapping> { self.mappings.iter().flatten() }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::vec::Vec;
+
+    type Shm = Regions<4, 8>;
+
+    #[test]
+    fn grants_map_read_only_or_read_write() {
+        let mut s = Shm::new([4; 16]);
+        assert_eq!(s.create(100, Rights::READ), Err(ShmError::BadRange));
+        assert_eq!(s.create(PAGE_SIZE, Rights::WRITE), Err(ShmError::BadPerms));
+        let owner = s.create(4 * PAGE_SIZE, Rights::READ | Rights::WRITE).unwrap();
+        let ro = s.grant(&owner, false).unwrap();
+        let rw = s.grant(&owner, true).unwrap();
+        assert_eq!(s.grant(&ro, false), Err(ShmError::Rights(MissingRights(Rights::GRANT))));
+        assert_eq!(s.map(&ro, 1, 0x1000, 0, PAGE_SIZE, true), Err(ShmError::Rights(MissingRights(Rights::WRITE))));
+
+        let req = MapShared { region: crate::msg::CapTransfer::copy(0), offset: PAGE_SIZE, len: 2 * PAGE_SIZE, writable: false };
+        assert_eq!(s.map_shared(1, &ro, &req), Ok(MapSharedReply { addr: SHM_BASE, len: 2 * PAGE_SIZE }));
+        let next = s.map_shared(1, &rw, &MapShared { writable: true, ..req }).unwrap();
+        assert_eq!(next.addr, SHM_BASE + 2 * PAGE_SIZE);
+        let m = s.translate(1, SHM_BASE + PAGE_SIZE + 8).unwrap();
+        assert_eq!((m.offset, m.writable), (PAGE_SIZE, false));
+        assert_eq!(s.stat(&owner).unwrap().refs, 3);
+
+        let ro_only = s.create(PAGE_SIZE, Rights::READ).unwrap();
+        assert_eq!(s.grant(&ro_only, true), Err(ShmError::Rights(MissingRights(Rights::WRITE))));
+    }
+
+    #[test]
+    fn revoke_invalidates_every_mapping_and_cap() {
+        let mut s = Shm::new([4; 16]);
+        let owner = s.create(PAGE_SIZE, Rights::READ | Rights::WRITE).unwrap();
+        let rw = s.grant(&owner, true).unwrap();
+        let a = s.map(&rw, 1, 0x1000, 0, PAGE_SIZE, true).unwrap();
+        let b = s.map(&rw, 2, 0x1000, 0, PAGE_SIZE, false).unwrap();
+        let mut gone = Vec::new();
+        let owner2 = s.revoke(&owner, |m| gone.push(m)).unwrap();
+        assert_eq!(gone, [a, b]);
+        assert!(s.translate(1, 0x1000).is_none() && s.translate(2, 0x1000).is_none());
+        assert_eq!(s.map(&rw, 1, 0x1000, 0, PAGE_SIZE, false), Err(ShmError::Cap(MintError::Stale { current: 1, presented: 0 })));
+        assert!(matches!(s.stat(&owner), Err(ShmError::Cap(MintError::Stale { .. }))));
+        assert_eq!(s.stat(&owner2).unwrap().refs, 1);
+
+        // Released regions live until the last mapping goes.
+        let rw = s.grant(&owner2, true).unwrap();
+        s.map(&rw, 1, 0x1000, 0, PAGE_SIZE, true).unwrap();
+        s.release(&owner2).unwrap();
+        assert_eq!(s.stat(&rw).unwrap().refs, 1);
+        s.unmap(1, 0x1000).unwrap();
+        assert!(s.stat(&rw).is_err());
+        assert_eq!(s.unmap(1, 0x1000), Err(ShmError::NotMapped));
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Create(u64, bool),
+        Grant(usize, bool),
+        Map(usize, SpaceId, u64, u64, u64, bool),
+        Unmap(SpaceId, u64),
+        Revoke(usize),
+        Release(usize),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (1..4u64, any::<bool>()).prop_map(|(p, w)| Op::Create(p, w)),
+            (0..8usize, any::<bool>()).prop_map(|(c, w)| Op::Grant(c, w)),
+            (0..8usize, 0..3u16, 0..8u64, 0..3u64, 1..3u64, any::<bool>()).prop_map(|(c, s, a, o, l, w)| Op::Map(c, s, a, o, l, w)),
+            (0..3u16, 0..8u64).prop_map(|(s, a)| Op::Unmap(s, a)),
+            (0..8usize).prop_map(Op::Revoke),
+            (0..8usize).prop_map(Op::Release),
+        ]
+    }
+
+    /// Reference model: per region, whether it is live, its owner is
+    /// holding it, and its generation.
+    #[derive(Clone, Copy, Default)]
+    struct ModelRegion {
+        live: bool,
+        owned: bool,
+        pages: u64,
+        writable: bool,
+        generation: u32,
+    }
+
+    proptest! {
+        // Refcounts, liveness and every map decision agree with a simple
+        // model; no mapping survives its region or overlaps another.
+        #[test]
+        fn regions_match_model(ops in proptest::collection::vec(op(), 1..60)) {
+            let mut s = Shm::new([9; 16]);
+            let mut model = [ModelRegion::default(); 4];
+            // Every cap ever handed out, with its region, generation and rights.
+            let mut caps: Vec<(Cap, usize, u32, bool, bool)> = Vec::new();
+            let mut maps: Vec<Mapping> = Vec::new();
+            for op in ops {
+                match op {
+                    Op::Create(pages, w) => {
+                        let perms = if w { Rights::READ | Rights::WRITE } else { Rights::READ };
+                        let r = s.create(pages * PAGE_SIZE, perms);
+                        match model.iter().position(|m| !m.live) {
+                            Some(i) => {
+                                let c = r.unwrap();
+                                model[i] = ModelRegion { live: true, owned: true, pages, writable: w, ..model[i] };
+                                caps.push((c, i, model[i].generation, true, w));
+                            }
+                            None => prop_assert_eq!(r, Err(ShmError::Exhausted)),
+                        }
+                    }
+                    Op::Grant(c, w) => {
+                        let Some(&(cap, i, gen, owner, _)) = caps.get(c) else { continue };
+                        let m = model[i];
+                        let ok = owner && m.live && m.owned && m.generation == gen && (!w || m.writable);
+                        let r = s.grant(&cap, w);
+                        prop_assert_eq!(r.is_ok(), ok);
+                        if let Ok(g) = r { caps.push((g, i, gen, false, w)); }
+                    }
+                    Op::Map(c, space, a, o, l, w) => {
+                        let Some(&(cap, i, gen, _, cw)) = caps.get(c) else { continue };
+                        let (addr, offset, len) = (a * PAGE_SIZE, o * PAGE_SIZE, l * PAGE_SIZE);
+                        let m = model[i];
+                        let ok = m.live && m.generation == gen && (!w || cw)
+                            && o + l <= m.pages
+                            && !maps.iter().any(|x| x.overlaps(space, addr, len))
+                            && maps.len() < 8;
+                        let r = s.map(&cap, space, addr, offset, len, w);
+                        prop_assert_eq!(r.is_ok(), ok, "{:?}", r);
+                        if let Ok(x) = r { maps.push(x); }
+                    }
+                    Op::Unmap(space, a) => {
+                        let addr = a * PAGE_SIZE;
+                        let r = s.unmap(space, addr);
+                        match maps.iter().position(|x| x.space == space && x.addr == addr) {
+                            Some(k) => {
+                                let x = maps.remove(k);
+                                prop_assert_eq!(r, Ok(x));
+                                let still = maps.iter().any(|y| y.region == x.region);
+                                if !model[x.region].owned && !still {
+                                    model[x.region].live = false;
+                                    model[x.region].generation += 1;
+                                }
+                            }
+                            None => prop_assert_eq!(r, Err(ShmError::NotMapped)),
+                        }
+                    }
+                    Op::Revoke(c) => {
+                        let Some(&(cap, i, gen, owner, w)) = caps.get(c) else { continue };
+                        let ok = owner && model[i].live && model[i].owned && model[i].generation == gen;
+                        let mut gone = Vec::new();
+                        let r = s.revoke(&cap, |x| gone.push(x));
+                        prop_assert_eq!(r.is_ok(), ok);
+                        if let Ok(fresh) = r {
+                            let mut want: Vec<_> = maps.iter().filter(|x| x.region == i).copied().collect();
+                            want.sort_by_key(|x| (x.space, x.addr));
+                            gone.sort_by_key(|x| (x.space, x.addr));
+                            prop_assert_eq!(gone, want);
+                            maps.retain(|x| x.region != i);
+                            model[i].generation += 1;
+                            caps.push((fresh, i, model[i].generation, true, w));
+                        }
+                    }
+                    Op::Release(c) => {
+                        let Some(&(cap, i, gen, owner, _)) = caps.get(c) else { continue };
+                        let ok = owner && model[i].live && model[i].owned && model[i].generation == gen;
+                        prop_assert_eq!(s.release(&cap).is_ok(), ok);
+                        if ok {
+                            model[i].owned = false;
+                            if !maps.iter().any(|x| x.region == i) {
+                                model[i].live = false;
+                                model[i].generation += 1;
+                            }
+                        }
+                    }
+                }
+                for (i, m) in model.iter().enumerate() {
+                    if !m.live { continue; }
+                    let n = maps.iter().filter(|x| x.region == i).count() as u32;
+                    let owner = s.owner_cap(i);
+                    prop_assert_eq!(s.stat(&owner).map(|st| st.refs), Ok(n + m.owned as u32));
+                }
+                for x in &maps {
+                    prop_assert!(model[x.region].live);
+                    prop_assert_eq!(s.translate(x.space, x.addr + x.len - 1), Some(x));
+                }
+                prop_assert_eq!(s.mappings().count(), maps.len());
+            }
+        }
+    }
+}
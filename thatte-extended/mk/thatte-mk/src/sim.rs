@@ -0,0 +1,476 @@
+//! Deterministic discrete-event simulation of the scheduler (host only).
+//!
+//! Each thread follows a `Workload`: run for a burst of CPU time, sleep,
+//! wake, repeat. The simulator plays the part of the kernel: it turns
+//! slice ends, burst ends and wakeups into `Scheduler` calls at the right
+//! virtual times, reschedules every CPU the scheduler flags, and records per
+//! thread runtime and wakeup latency. Events at equal times are handled in
+//! the order they were queued, so a run is a pure function of its inputs.
+//!
+//! Threads may instead be IPC clients and servers on one endpoint of a real
+//! `Ipc`: a client runs, then calls the server for some service time and
+//! blocks until the reply; a server receives a call, runs the service time
+//! and replies. After every event the scheduler re-reads the callers' loans
+//! from the IPC state, so servers inherit their callers' priority.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+use crate::ipc::{Delivery, Ipc, Received, Sent, ThreadId, MSG_MAX};
+use crate::msg::{Ping, PingReply, Reply, Request};
+use crate::sched::{CpuId, CpuSet, Nanos, Policy, RunState, SchedError, Scheduler};
+use crate::{Cap, MsgFlags};
+
+/// What a simulated thread does.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Workload {
+    /// CPU time per burst; `Nanos::MAX` never blocks.
+    pub run: Nanos,
+    /// Sleep between bursts.
+    pub sleep: Nanos,
+}
+
+impl Workload {
+    pub const CPU_BOUND: Self = Self { run: Nanos::MAX, sleep: 0 };
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Role {
+    Worker(Workload),
+    /// Runs `run`, then calls the server for `service` of its CPU time.
+    Client { run: Nanos, service: Nanos },
+    Server,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct ThreadStats {
+    pub wakeups: u64,
+    pub dispatches: u64,
+    /// Longest and total time from wakeup to first dispatch.
+    pub max_latency: Nanos,
+    pub total_latency: Nanos,
+    /// Calls answered, and the longest time from call to reply.
+    pub calls: u64,
+    pub max_call: Nanos,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+enum Event {
+    /// The dispatch `gen` on `cpu` reaches its slice or burst end.
+    Stop { cpu: CpuId, gen: u64 },
+    Wake(ThreadId),
+}
+
+/// A simulated machine of `CPUS` CPUs running up to `THREADS` threads.
+pub struct Simulator<const THREADS: usize, const CPUS: usize> {
+    sched: Scheduler<THREADS, CPUS>,
+    now: Nanos,
+    /// (time, order queued, event)
+    events: BinaryHeap<Reverse<(Nanos, u64, Event)>>,
+    order: u64,
+    roles: Vec<Role>,
+    ipc: Ipc<THREADS, 1, THREADS, 1>,
+    ep: Cap,
+    /// Whether servers inherit their callers' priority.
+    inheritance: bool,
+    /// Reply cap and client of the call a server is serving, and when each
+    /// client's call was made.
+    reply: Vec<Option<(Cap, ThreadId)>>,
+    called_at: Vec<Nanos>,
+    /// Burst time left per thread.
+    left: Vec<Nanos>,
+    woke_at: Vec<Option<Nanos>>,
+    stats: Vec<ThreadStats>,
+    /// Thread on each CPU and when it was dispatched.
+    running: [Option<(ThreadId, Nanos)>; CPUS],
+    /// Current stop time on each CPU, and its generation (stale `Stop`s
+    /// are ignored).
+    armed: [Option<Nanos>; CPUS],
+    gen: [u64; CPUS],
+}
+
+impl<const THREADS: usize, const CPUS: usize> Default for Simulator<THREADS, CPUS> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<const THREADS: usize, const CPUS: usize> Simulator<THREADS, CPUS> {
+    pub fn new() -> Self {
+        let mut ipc = Ipc::new([0x5a; 16]);
+        let ep = ipc.create_endpoint().expect("endpoint table has room");
+        Self {
+            sched: Scheduler::new(),
+            now: 0,
+            events: BinaryHeap::new(),
+            order: 0,
+            roles: Vec::new(),
+            ipc,
+            ep,
+            inheritance: true,
+            reply: std::vec![None; THREADS],
+            called_at: std::vec![0; THREADS],
+            left: std::vec![0; THREADS],
+            woke_at: std::vec![None; THREADS],
+            stats: std::vec![ThreadStats::default(); THREADS],
+            running: [None; CPUS],
+            armed: [None; CPUS],
+            gen: [0; CPUS],
+        }
+    }
+
+    pub fn now(&self) -> Nanos { self.now }
+    pub fn sched(&self) -> &Scheduler<THREADS, CPUS> { &self.sched }
+    pub fn stats(&self, tid: ThreadId) -> ThreadStats { self.stats[tid as usize] }
+
+    /// Turn priority inheritance across calls on or off (on by default).
+    pub fn set_inheritance(&mut self, on: bool) { self.inheritance = on; }
+
+    /// Start a thread now.
+    pub fn spawn(&mut self, policy: Policy, affinity: CpuSet, work: Workload) -> Result<ThreadId, SchedError> {
+        assert!(work.run > 0);
+        self.start(policy, affinity, Role::Worker(work), work.run)
+    }
+
+    /// Start a client that runs `run`, then calls the server for `service`,
+    /// over and over.
+    pub fn spawn_client(&mut self, policy: Policy, affinity: CpuSet, run: Nanos, service: Nanos) -> Result<ThreadId, SchedError> {
+        assert!(run > 0 && service > 0);
+        self.start(policy, affinity, Role::Client { run, service }, run)
+    }
+
+    /// Start a server waiting for calls.
+    pub fn spawn_server(&mut self, policy: Policy, affinity: CpuSet) -> Result<ThreadId, SchedError> {
+        let tid = self.start(policy, affinity, Role::Server, 0)?;
+        self.serve_next(tid);
+        self.settle();
+        Ok(tid)
+    }
+
+    fn start(&mut self, policy: Policy, affinity: CpuSet, role: Role, run: Nanos) -> Result<ThreadId, SchedError> {
+        let tid = self.sched.spawn(policy, affinity, self.now)?;
+        let t = tid as usize;
+        if self.roles.len() <= t { self.roles.resize(t + 1, role); }
+        self.roles[t] = role;
+        self.left[t] = run;
+        self.woke_at[t] = Some(self.now);
+        self.stats[t] = ThreadStats { wakeups: 1, ..ThreadStats::default() };
+        self.settle();
+        Ok(tid)
+    }
+
+    /// Process every event up to `end`, checking scheduler invariants after
+    /// each; then charge all running threads up to `end`.
+    pub fn run_until(&mut self, end: Nanos) -> Result<(), String> {
+        while let Some(&Reverse((at, _, ev))) = self.events.peek() {
+            if at > end { break; }
+            self.events.pop();
+            self.now = at;
+            self.handle(ev);
+            if self.inheritance { self.sched.inherit(&self.ipc, self.now); }
+            self.settle();
+            self.check()?;
+        }
+        self.now = end;
+        for cpu in 0..CPUS {
+            self.sched.tick(cpu, end);
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, at: Nanos, ev: Event) {
+        self.order += 1;
+        self.events.push(Reverse((at, self.order, ev)));
+    }
+
+    fn handle(&mut self, ev: Event) {
+        match ev {
+            Event::Stop { cpu, gen } => {
+                if gen != self.gen[cpu] { return; }
+                let Some((tid, since)) = self.running[cpu] else { return };
+                let t = tid as usize;
+                if self.now - since < self.left[t] {
+                    // Slice end.
+                    self.reschedule(cpu);
+                    return;
+                }
+                match self.roles[t] {
+                    Role::Worker(work) => {
+                        self.stop(tid);
+                        self.push(self.now + work.sleep, Event::Wake(tid));
+                    }
+                    Role::Client { service, .. } => {
+                        self.stop(tid);
+                        self.call(tid, service);
+                    }
+                    Role::Server => {
+                        self.answer(tid);
+                        self.serve_next(tid);
+                    }
+                }
+            }
+            Event::Wake(tid) => {
+                let t = tid as usize;
+                let Role::Worker(work) = self.roles[t] else { unreachable!("only workers sleep") };
+                self.left[t] = work.run;
+                self.woke_at[t] = Some(self.now);
+                self.stats[t].wakeups += 1;
+                self.sched.wake(tid, self.now).expect("sleeping thread wakes");
+            }
+        }
+    }
+
+    /// Take a thread off its CPU and block it.
+    fn stop(&mut self, tid: ThreadId) {
+        if let Some(cpu) = self.running.iter().position(|r| r.is_some_and(|r| r.0 == tid)) {
+            self.running[cpu] = None;
+            self.armed[cpu] = None;
+        }
+        self.sched.block(tid, self.now).expect("thread blocks");
+    }
+
+    fn call(&mut self, tid: ThreadId, service: Nanos) {
+        let mut buf = [0u8; MSG_MAX];
+        let n = Request::Ping(Ping { seq: service }).encode(Cap::nil(), self.ep, MsgFlags::REPLY_EXPECTED, &mut buf).unwrap();
+        self.called_at[tid as usize] = self.now;
+        if let Sent::Delivered(server) = self.ipc.call(tid, &buf[..n]).expect("call is valid") {
+            let d = self.ipc.take(server).expect("server was waiting");
+            self.serve(server, d);
+            self.sched.wake(server, self.now).expect("server was blocked");
+        }
+    }
+
+    /// Reply to the call a server has just finished and wake its client.
+    fn answer(&mut self, server: ThreadId) {
+        let (rc, client) = self.reply[server as usize].take().expect("server holds a reply cap");
+        let mut buf = [0u8; MSG_MAX];
+        let n = Reply::Ping(PingReply { seq: 0 }).encode(Cap::nil(), Cap::nil(), MsgFlags::NONE, &mut buf).unwrap();
+        self.ipc.reply(server, &rc, &buf[..n]).expect("client waits for the reply");
+        self.ipc.take(client);
+        let c = client as usize;
+        let Role::Client { run, .. } = self.roles[c] else { unreachable!("only clients call") };
+        let st = &mut self.stats[c];
+        st.calls += 1;
+        st.max_call = st.max_call.max(self.now - self.called_at[c]);
+        st.wakeups += 1;
+        self.left[c] = run;
+        self.woke_at[c] = Some(self.now);
+        self.sched.wake(client, self.now).expect("client was blocked");
+    }
+
+    /// Take the next queued call, or block until one arrives.
+    fn serve_next(&mut self, server: ThreadId) {
+        match self.ipc.recv(server, &self.ep, true).expect("recv is valid") {
+            Received::Msg(d) => self.serve(server, d),
+            Received::Blocked => self.stop(server),
+        }
+    }
+
+    /// Start serving `d`; the service time counts from now even if the
+    /// server is already mid-dispatch.
+    fn serve(&mut self, server: ThreadId, d: Delivery) {
+        let Ok((_, Request::Ping(p))) = Request::decode(d.msg.bytes()) else { unreachable!("clients send pings") };
+        let s = server as usize;
+        let ran = self.running.iter().flatten().find(|r| r.0 == server).map_or(0, |r| self.now - r.1);
+        self.left[s] = p.seq + ran;
+        self.reply[s] = Some((d.reply.expect("pings are calls"), d.sender));
+    }
+
+    /// Reschedule every CPU the scheduler flags until none are left, then
+    /// re-arm each CPU for its slice deadline or burst end.
+    fn settle(&mut self) {
+        loop {
+            let set = self.sched.take_resched();
+            if set.is_empty() { break; }
+            for cpu in set.iter() {
+                self.reschedule(cpu);
+            }
+        }
+        for cpu in 0..CPUS {
+            let Some((tid, since)) = self.running[cpu] else { continue };
+            let deadline = self.sched.deadline(cpu).expect("running thread has a slice");
+            let stop = deadline.min(since.saturating_add(self.left[tid as usize]));
+            if self.armed[cpu] != Some(stop) {
+                self.gen[cpu] += 1;
+                self.armed[cpu] = Some(stop);
+                self.push(stop, Event::Stop { cpu, gen: self.gen[cpu] });
+            }
+        }
+    }
+
+    fn reschedule(&mut self, cpu: CpuId) {
+        if let Some((tid, since)) = self.running[cpu].take() {
+            self.left[tid as usize] -= self.now - since;
+        }
+        self.gen[cpu] += 1;
+        self.armed[cpu] = None;
+        let Some((tid, _)) = self.sched.schedule(cpu, self.now) else { return };
+        let t = tid as usize;
+        self.stats[t].dispatches += 1;
+        if let Some(at) = self.woke_at[t].take() {
+            let st = &mut self.stats[t];
+            st.max_latency = st.max_latency.max(self.now - at);
+            st.total_latency += self.now - at;
+        }
+        self.running[cpu] = Some((tid, self.now));
+    }
+
+    /// Per-CPU ordering and affinity, and no CPU idle while work it may run
+    /// is queued anywhere.
+    fn check(&self) -> Result<(), String> {
+        let s = &self.sched;
+        let ready = |t: ThreadId| s.tcb(t).filter(|t| t.state() == RunState::Ready);
+        for cpu in 0..CPUS {
+            let cur = s.current(cpu).map(|t| s.tcb(t).unwrap());
+            if cur.is_some_and(|c| c.state() != RunState::Running || !c.affinity().contains(cpu) || c.cpu() != cpu) {
+                return Err(std::format!("cpu {} runs {:?}", cpu, cur));
+            }
+            for t in 0..THREADS as ThreadId {
+                let Some(q) = ready(t) else { continue };
+                if !q.affinity().contains(cpu) { continue; }
+                match cur {
+                    None => return Err(std::format!("cpu {} idle while {} is queued", cpu, t)),
+                    Some(c) if q.cpu() == cpu && c.policy().rank() < q.policy().rank()
+                        && !matches!((c.policy(), q.policy()), (Policy::Normal(_), Policy::Normal(_))) =>
+                    {
+                        return Err(std::format!("cpu {} runs {:?} while {} ({:?}) waits", cpu, c.policy(), t, q.policy()));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if self.running.iter().zip(0..).any(|(r, cpu)| r.map(|r| r.0) != s.current(cpu)) {
+            return Err("simulator and scheduler disagree".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sched::{MIN_GRANULARITY, NICE_0_WEIGHT, RT_SLICE, SCHED_LATENCY};
+    use proptest::prelude::*;
+
+    const MS: Nanos = 1_000_000;
+
+    fn nice() -> impl Strategy<Value = i8> { -20i8..=19 }
+
+    proptest! {
+        // CPU-bound normal threads on one CPU: the CPU never idles and
+        // virtual runtimes stay within one slice of each other, so each
+        // thread gets CPU in proportion to its weight.
+        #[test]
+        fn normal_threads_share_fairly(nices in proptest::collection::vec(nice(), 1..8), end in 200u64..2000) {
+            let mut sim = Simulator::<8, 1>::new();
+            let tids: Vec<_> = nices.iter().map(|&n| sim.spawn(Policy::Normal(n), CpuSet::ALL, Workload::CPU_BOUND).unwrap()).collect();
+            sim.run_until(end * MS).map_err(TestCaseError::fail)?;
+            let s = sim.sched();
+            let total: u64 = nices.iter().map(|&n| Policy::Normal(n).weight()).sum();
+            let runtime: u64 = tids.iter().map(|&t| s.tcb(t).unwrap().runtime()).sum();
+            prop_assert_eq!(runtime, end * MS);
+            // Largest vruntime step any thread takes in one slice.
+            let step = nices.iter().map(|&n| {
+                let w = Policy::Normal(n).weight();
+                (SCHED_LATENCY * w / total).max(MIN_GRANULARITY) * NICE_0_WEIGHT / w
+            }).max().unwrap();
+            let v: Vec<_> = tids.iter().map(|&t| s.tcb(t).unwrap().vruntime()).collect();
+            let spread = v.iter().max().unwrap() - v.iter().min().unwrap();
+            prop_assert!(spread <= step + 1, "spread {} > {}", spread, step);
+        }
+
+        // Under any mix of load and affinities on 4 CPUs, the top-priority
+        // RT thread runs the instant it wakes, no CPU runs less important
+        // work than it has queued, and no CPU idles while it could run
+        // something (checked after every event).
+        #[test]
+        fn rt_threads_run_immediately(
+            period in 1u64..20,
+            burst in 1u64..1000,
+            others in proptest::collection::vec((0u8..4, 0u8..50, 1u64..16, 1u64..20, 1u64..20), 0..12),
+        ) {
+            let mut sim = Simulator::<16, 4>::new();
+            let top = sim.spawn(Policy::RealTime(90), CpuSet::ALL, Workload { run: burst * 10_000, sleep: period * MS }).unwrap();
+            for (class, prio, affinity, run, sleep) in others {
+                let policy = match class {
+                    0 => Policy::RealTime(prio),
+                    1 => Policy::Normal(prio as i8 % 40 - 20),
+                    _ => Policy::Batch(prio as i8 % 40 - 20),
+                };
+                let work = if class == 3 { Workload::CPU_BOUND } else { Workload { run: run * MS, sleep: sleep * MS } };
+                sim.spawn(policy, CpuSet::from_bits(affinity), work).unwrap();
+            }
+            sim.run_until(500 * MS).map_err(TestCaseError::fail)?;
+            let st = sim.stats(top);
+            prop_assert!(st.wakeups > 1);
+            prop_assert_eq!(st.max_latency, 0);
+        }
+
+        // A low-priority server shared by an RT client and normal clients,
+        // on one CPU with RT and normal hogs that outrank the server. The
+        // RT client's call waits at most for the calls queued ahead of it
+        // plus its own service: hogs never delay it.
+        #[test]
+        fn inheritance_bounds_inversion(
+            run in 10u64..1000,
+            service in 10u64..500,
+            clients in proptest::collection::vec((nice(), 1u64..5000, 1u64..5000), 0..4),
+            hogs in proptest::collection::vec((0u8..50, any::<bool>()), 0..4),
+        ) {
+            const US: Nanos = 1000;
+            let mut sim = Simulator::<16, 1>::new();
+            sim.spawn_server(Policy::Batch(0), CpuSet::ALL).unwrap();
+            let top = sim.spawn_client(Policy::RealTime(50), CpuSet::ALL, run * US, service * US).unwrap();
+            for &(nice, run, service) in &clients {
+                sim.spawn_client(Policy::Normal(nice), CpuSet::ALL, run * US, service * US).unwrap();
+            }
+            for (prio, rt) in hogs {
+                let policy = if rt { Policy::RealTime(prio) } else { Policy::Normal(prio as i8 % 40 - 20) };
+                sim.spawn(policy, CpuSet::ALL, Workload::CPU_BOUND).unwrap();
+            }
+            sim.run_until(200 * MS).map_err(TestCaseError::fail)?;
+            let bound = (service + clients.iter().map(|c| c.2).sum::<u64>()) * US;
+            let st = sim.stats(top);
+            prop_assert!(st.calls > 0);
+            prop_assert!(st.max_call <= bound, "call took {} > {}", st.max_call, bound);
+        }
+    }
+
+    #[test]
+    fn equal_rt_priorities_round_robin() {
+        let mut sim = Simulator::<4, 1>::new();
+        let a = sim.spawn(Policy::RealTime(10), CpuSet::ALL, Workload::CPU_BOUND).unwrap();
+        let b = sim.spawn(Policy::RealTime(10), CpuSet::ALL, Workload::CPU_BOUND).unwrap();
+        let n = sim.spawn(Policy::Normal(-20), CpuSet::ALL, Workload::CPU_BOUND).unwrap();
+        sim.run_until(10 * RT_SLICE + 1).unwrap();
+        let rt = |t| sim.sched().tcb(t).unwrap().runtime();
+        assert_eq!((rt(a), rt(b), rt(n)), (5 * RT_SLICE + 1, 5 * RT_SLICE, 0));
+    }
+
+    #[test]
+    fn batch_waits_for_normal_work() {
+        let mut sim = Simulator::<4, 1>::new();
+        let batch = sim.spawn(Policy::Batch(-20), CpuSet::ALL, Workload::CPU_BOUND).unwrap();
+        let n = sim.spawn(Policy::Normal(19), CpuSet::ALL, Workload { run: MS, sleep: MS }).unwrap();
+        sim.run_until(100 * MS).unwrap();
+        let rt = |t| sim.sched().tcb(t).unwrap().runtime();
+        assert_eq!((rt(batch), rt(n)), (50 * MS, 50 * MS));
+        assert_eq!(sim.stats(n).max_latency, 0);
+    }
+
+    #[test]
+    fn without_inheritance_rt_client_starves() {
+        let run = |inherit| {
+            let mut sim = Simulator::<4, 1>::new();
+            sim.set_inheritance(inherit);
+            sim.spawn_server(Policy::Batch(0), CpuSet::ALL).unwrap();
+            let client = sim.spawn_client(Policy::RealTime(50), CpuSet::ALL, MS, MS).unwrap();
+            sim.spawn(Policy::RealTime(10), CpuSet::ALL, Workload::CPU_BOUND).unwrap();
+            sim.run_until(100 * MS).unwrap();
+            sim.stats(client)
+        };
+        let st = run(true);
+        assert_eq!((st.calls, st.max_call), (50, MS));
+        assert_eq!(run(false).calls, 0);
+    }
+}
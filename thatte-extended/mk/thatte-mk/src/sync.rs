@@ -0,0 +1,56 @@
+//! Minimal busy-waiting lock for kernel globals.
+//!
+//! Nothing here masks interrupts: state shared with an interrupt handler
+//! must be locked with interrupts disabled, or the handler can spin forever
+//! on a lock its own CPU holds.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: the lock hands out at most one guard at a time.
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self { Self { locked: AtomicBool::new(false), value: UnsafeCell::new(value) } }
+
+    pub fn lock(&self) -> SpinGuard<'_, T> {
+        loop {
+            if let Some(g) = self.try_lock() { return g; }
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinGuard<'_, T>> {
+        self.locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| SpinGuard { lock: self })
+    }
+}
+
+pub struct SpinGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinGuard<'_, T> {
+    type Target = T;
+    // SAFETY: the guard proves exclusive access.
+    fn deref(&self) -> &T { unsafe { &*self.lock.value.get() } }
+}
+
+impl<T> DerefMut for SpinGuard<'_, T> {
+    // SAFETY: the guard proves exclusive access.
+    fn deref_mut(&mut self) -> &mut T { unsafe { &mut *self.lock.value.get() } }
+}
+
+impl<T> Drop for SpinGuard<'_, T> {
+    fn drop(&mut self) { self.lock.locked.store(false, Ordering::Release) }
+}
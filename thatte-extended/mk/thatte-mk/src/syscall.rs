@@ -0,0 +1,529 @@
+//! The system call ABI: syscall numbers, the x86_64 register convention,
+//! error codes, and the dispatcher that turns a saved register frame into a
+//! typed `Syscall` and writes the outcome back.
+//!
+//! Calling convention (`syscall` / `sysret`):
+//!
+//! | register | on entry            | on return                        |
+//! |----------|---------------------|----------------------------------|
+//! | `rax`    | `Sysno`             | 0, or a `SysError` code          |
+//! | `rdi`    | argument 0          | result 0                         |
+//! | `rsi`    | argument 1          | result 1                         |
+//! | `rdx`    | argument 2          | result 2                         |
+//! | `r10`    | argument 3          | result 3                         |
+//! | `r8`     | argument 4          | preserved                        |
+//! | `r9`     | argument 5          | preserved                        |
+//! | `rcx`    | (user `rip`)        | clobbered                        |
+//! | `r11`    | (user `rflags`)     | clobbered                        |
+//!
+//! `r10` stands in for `rcx`, which `syscall` overwrites. Every other
+//! register is preserved. Argument registers a call does not use must be
+//! zero, so they can gain meaning later. On error all results are zero.
+//!
+//! Capabilities are named by slot in the caller's cspace, except reply
+//! caps, which `recv` returns and `reply` takes as two registers (bytes
+//! `0..8` and `8..16`, little-endian). Messages and buffers are user
+//! pointers; copying them is up to the kernel's `SyscallHandler`. Timeouts
+//! are relative nanoseconds: `NO_TIMEOUT` waits forever and 0 fails with
+//! `WouldBlock` instead of waiting.
+
+use crate::cspace::CapError;
+use crate::ipc::{IpcError, ThreadId, MSG_MAX};
+use crate::sched::Nanos;
+use crate::shm::ShmError;
+use crate::{Cap, Rights};
+
+/// Timeout register value that waits forever.
+pub const NO_TIMEOUT: u64 = u64::MAX;
+
+/// End (exclusive) of user addresses: the lower canonical half.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// `Map` flag: map writable.
+pub const MAP_WRITABLE: u64 = 1;
+
+/// Syscall numbers (in `rax`).
+#[repr(u64)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Sysno {
+    Send = 1,
+    Recv = 2,
+    Call = 3,
+    Reply = 4,
+    ReplyRecv = 5,
+    CapCopy = 6,
+    CapDerive = 7,
+    CapMove = 8,
+    CapDelete = 9,
+    CapRevoke = 10,
+    Map = 11,
+    Unmap = 12,
+    Yield = 13,
+}
+
+impl TryFrom<u64> for Sysno {
+    type Error = SysError;
+    fn try_from(n: u64) -> Result<Self, SysError> {
+        Ok(match n {
+            1 => Self::Send,
+            2 => Self::Recv,
+            3 => Self::Call,
+            4 => Self::Reply,
+            5 => Self::ReplyRecv,
+            6 => Self::CapCopy,
+            7 => Self::CapDerive,
+            8 => Self::CapMove,
+            9 => Self::CapDelete,
+            10 => Self::CapRevoke,
+            11 => Self::Map,
+            12 => Self::Unmap,
+            13 => Self::Yield,
+            _ => return Err(SysError::UnknownSyscall),
+        })
+    }
+}
+
+/// Error codes returned in `rax`.
+#[repr(u64)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SysError {
+    /// `rax` is not a `Sysno`.
+    UnknownSyscall = 1,
+    /// An argument is out of range, or an unused argument is not zero.
+    BadArgument = 2,
+    /// A buffer is not in user memory.
+    BadAddress = 3,
+    /// A cspace slot is out of range.
+    BadSlot = 4,
+    /// A cspace slot is empty.
+    EmptySlot = 5,
+    /// The destination slot is occupied.
+    SlotOccupied = 6,
+    /// The capability is forged or stale.
+    BadCap = 7,
+    /// The capability names the wrong kind of object, or one that is gone.
+    WrongObject = 8,
+    /// The capability lacks rights for the operation.
+    NoRights = 9,
+    /// The message does not parse or its flags do not fit the call.
+    Malformed = 10,
+    /// The message type is unknown or not allowed by its destination.
+    Denied = 11,
+    /// The operation would have to wait and the timeout was 0.
+    WouldBlock = 12,
+    /// The timeout expired first.
+    TimedOut = 13,
+    /// The reply target is not waiting for this reply.
+    NotWaiting = 14,
+    /// The thread has undelivered state (e.g. an uncollected message).
+    NotReady = 15,
+    /// A kernel table is full.
+    Exhausted = 16,
+    /// The mapping overlaps an existing one.
+    Overlap = 17,
+    /// Nothing is mapped there.
+    NotMapped = 18,
+}
+
+impl SysError {
+    /// Number of distinct codes (codes are `1..=COUNT`).
+    pub const COUNT: u64 = 18;
+
+    pub const fn code(self) -> u64 { self as u64 }
+
+    /// The error a nonzero `rax` stands for (user side).
+    pub const fn from_code(code: u64) -> Option<Self> {
+        Some(match code {
+            1 => Self::UnknownSyscall,
+            2 => Self::BadArgument,
+            3 => Self::BadAddress,
+            4 => Self::BadSlot,
+            5 => Self::EmptySlot,
+            6 => Self::SlotOccupied,
+            7 => Self::BadCap,
+            8 => Self::WrongObject,
+            9 => Self::NoRights,
+            10 => Self::Malformed,
+            11 => Self::Denied,
+            12 => Self::WouldBlock,
+            13 => Self::TimedOut,
+            14 => Self::NotWaiting,
+            15 => Self::NotReady,
+            16 => Self::Exhausted,
+            17 => Self::Overlap,
+            18 => Self::NotMapped,
+            _ => return None,
+        })
+    }
+}
+
+impl From<CapError> for SysError {
+    fn from(e: CapError) -> Self {
+        match e {
+            CapError::BadSpace | CapError::BadSlot => Self::BadSlot,
+            CapError::Empty => Self::EmptySlot,
+            CapError::Occupied => Self::SlotOccupied,
+            CapError::Cap(_) => Self::BadCap,
+            CapError::NoMemory => Self::Exhausted,
+        }
+    }
+}
+
+impl From<IpcError> for SysError {
+    fn from(e: IpcError) -> Self {
+        match e {
+            IpcError::BadThread => Self::BadArgument,
+            IpcError::NotReady | IpcError::Unclaimed => Self::NotReady,
+            IpcError::Malformed(_) | IpcError::BadFlags => Self::Malformed,
+            IpcError::Slot(e) => e.into(),
+            IpcError::Cap(_) => Self::BadCap,
+            IpcError::WrongObject | IpcError::NoEndpoint => Self::WrongObject,
+            IpcError::Rights(_) => Self::NoRights,
+            IpcError::Denied(_) => Self::Denied,
+            IpcError::WouldBlock => Self::WouldBlock,
+            IpcError::NotWaiting => Self::NotWaiting,
+            IpcError::Exhausted => Self::Exhausted,
+        }
+    }
+}
+
+impl From<ShmError> for SysError {
+    fn from(e: ShmError) -> Self {
+        match e {
+            ShmError::BadRange | ShmError::BadPerms => Self::BadArgument,
+            ShmError::Cap(_) => Self::BadCap,
+            ShmError::WrongObject | ShmError::NoRegion => Self::WrongObject,
+            ShmError::Released | ShmError::Rights(_) => Self::NoRights,
+            ShmError::Overlap => Self::Overlap,
+            ShmError::NotMapped => Self::NotMapped,
+            ShmError::Exhausted => Self::Exhausted,
+        }
+    }
+}
+
+/// Registers saved by the syscall entry stub, in the order it pushes them.
+#[repr(C)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct SyscallFrame {
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    /// User `rip`, for `sysret`.
+    pub rcx: u64,
+    /// User `rflags`, for `sysret`.
+    pub r11: u64,
+}
+
+impl SyscallFrame {
+    pub const fn args(&self) -> [u64; 6] { [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9] }
+
+    /// Whether returning with `sysret` is safe: on Intel CPUs a
+    /// non-canonical `rcx` faults in ring 0, so such frames must return
+    /// through `iretq` instead.
+    pub const fn sysret_safe(&self) -> bool { self.rcx < USER_END }
+}
+
+/// A range of user memory (not yet checked against the task's mappings).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct UserBuf {
+    pub addr: u64,
+    pub len: usize,
+}
+
+impl UserBuf {
+    /// Check that `addr..addr + len` lies in the user half (a null pointer
+    /// only with length 0).
+    pub fn new(addr: u64, len: u64) -> Result<Self, SysError> {
+        let end = addr.checked_add(len).ok_or(SysError::BadAddress)?;
+        if end > USER_END || (addr == 0 && len != 0) { return Err(SysError::BadAddress); }
+        Ok(Self { addr, len: len as usize })
+    }
+
+    /// An outgoing message: also at most `MSG_MAX` bytes.
+    fn message(addr: u64, len: u64) -> Result<Self, SysError> {
+        if len > MSG_MAX as u64 { return Err(SysError::BadArgument); }
+        Self::new(addr, len)
+    }
+}
+
+/// A decoded system call.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Syscall {
+    /// `rdi` message, `rsi` length, `rdx` timeout.
+    Send { msg: UserBuf, timeout: Option<Nanos> },
+    /// `rdi` endpoint slot, `rsi` buffer, `rdx` capacity, `r10` timeout.
+    Recv { ep: u16, buf: UserBuf, timeout: Option<Nanos> },
+    /// `rdi` message, `rsi` length, `rdx` reply buffer, `r10` capacity,
+    /// `r8` timeout.
+    Call { msg: UserBuf, buf: UserBuf, timeout: Option<Nanos> },
+    /// `rdi`, `rsi` reply cap; `rdx` message; `r10` length.
+    Reply { reply: Cap, msg: UserBuf },
+    /// `rdi`, `rsi` reply cap; `rdx` buffer; `r10` reply length; `r8`
+    /// buffer capacity; `r9` endpoint slot. The reply is read from the
+    /// front of the buffer and the next message received into it.
+    ReplyRecv { reply: Cap, buf: UserBuf, len: usize, ep: u16 },
+    /// `rdi` source slot, `rsi` destination slot.
+    CapCopy { src: u16, dst: u16 },
+    /// `rdi` source slot, `rsi` destination slot, `rdx` rights mask.
+    CapDerive { src: u16, dst: u16, rights: Rights },
+    /// `rdi` source slot, `rsi` destination slot.
+    CapMove { src: u16, dst: u16 },
+    /// `rdi` slot.
+    CapDelete { slot: u16 },
+    /// `rdi` slot.
+    CapRevoke { slot: u16 },
+    /// `rdi` region slot, `rsi` address, `rdx` offset, `r10` length, `r8`
+    /// flags (`MAP_WRITABLE`).
+    Map { region: u16, addr: u64, offset: u64, len: u64, writable: bool },
+    /// `rdi` address of the mapping.
+    Unmap { addr: u64 },
+    Yield,
+}
+
+fn slot(v: u64) -> Result<u16, SysError> { u16::try_from(v).map_err(|_| SysError::BadSlot) }
+
+fn timeout(v: u64) -> Option<Nanos> { (v != NO_TIMEOUT).then_some(v) }
+
+fn timeout_reg(t: Option<Nanos>) -> u64 { t.unwrap_or(NO_TIMEOUT) }
+
+fn cap(lo: u64, hi: u64) -> Cap {
+    let mut b = [0u8; 16];
+    b[..8].copy_from_slice(&lo.to_le_bytes());
+    b[8..].copy_from_slice(&hi.to_le_bytes());
+    Cap::new(b)
+}
+
+fn cap_regs(c: &Cap) -> (u64, u64) {
+    let b = c.bytes();
+    (u64::from_le_bytes(b[..8].try_into().unwrap()), u64::from_le_bytes(b[8..].try_into().unwrap()))
+}
+
+impl Syscall {
+    /// Decode a frame's number and arguments.
+    pub fn decode(f: &SyscallFrame) -> Result<Self, SysError> {
+        let no = Sysno::try_from(f.rax)?;
+        let [a0, a1, a2, a3, a4, a5] = f.args();
+        let (call, used) = match no {
+            Sysno::Send => (Self::Send { msg: UserBuf::message(a0, a1)?, timeout: timeout(a2) }, 3),
+            Sysno::Recv => (Self::Recv { ep: slot(a0)?, buf: UserBuf::new(a1, a2)?, timeout: timeout(a3) }, 4),
+            Sysno::Call => (Self::Call { msg: UserBuf::message(a0, a1)?, buf: UserBuf::new(a2, a3)?, timeout: timeout(a4) }, 5),
+            Sysno::Reply => (Self::Reply { reply: cap(a0, a1), msg: UserBuf::message(a2, a3)? }, 4),
+            Sysno::ReplyRecv => {
+                if a3 > a4 { return Err(SysError::BadArgument); }
+                UserBuf::message(a2, a3)?;
+                (Self::ReplyRecv { reply: cap(a0, a1), buf: UserBuf::new(a2, a4)?, len: a3 as usize, ep: slot(a5)? }, 6)
+            }
+            Sysno::CapCopy => (Self::CapCopy { src: slot(a0)?, dst: slot(a1)? }, 2),
+            Sysno::CapDerive => {
+                let rights = Rights::from_bits_truncate(a2 as u16);
+                if rights.bits() as u64 != a2 { return Err(SysError::BadArgument); }
+                (Self::CapDerive { src: slot(a0)?, dst: slot(a1)?, rights }, 3)
+            }
+            Sysno::CapMove => (Self::CapMove { src: slot(a0)?, dst: slot(a1)? }, 2),
+            Sysno::CapDelete => (Self::CapDelete { slot: slot(a0)? }, 1),
+            Sysno::CapRevoke => (Self::CapRevoke { slot: slot(a0)? }, 1),
+            Sysno::Map => {
+                if a4 & !MAP_WRITABLE != 0 { return Err(SysError::BadArgument); }
+                (Self::Map { region: slot(a0)?, addr: a1, offset: a2, len: a3, writable: a4 & MAP_WRITABLE != 0 }, 5)
+            }
+            Sysno::Unmap => (Self::Unmap { addr: a0 }, 1),
+            Sysno::Yield => (Self::Yield, 0),
+        };
+        if f.args()[used..].iter().any(|&a| a != 0) { return Err(SysError::BadArgument); }
+        Ok(call)
+    }
+
+    /// The number and argument registers that make this call (user side).
+    pub fn encode(&self) -> SyscallFrame {
+        let (no, args): (Sysno, [u64; 6]) = match *self {
+            Self::Send { msg, timeout } => (Sysno::Send, [msg.addr, msg.len as u64, timeout_reg(timeout), 0, 0, 0]),
+            Self::Recv { ep, buf, timeout } => (Sysno::Recv, [ep as u64, buf.addr, buf.len as u64, timeout_reg(timeout), 0, 0]),
+            Self::Call { msg, buf, timeout } => {
+                (Sysno::Call, [msg.addr, msg.len as u64, buf.addr, buf.len as u64, timeout_reg(timeout), 0])
+            }
+            Self::Reply { reply, msg } => {
+                let (lo, hi) = cap_regs(&reply);
+                (Sysno::Reply, [lo, hi, msg.addr, msg.len as u64, 0, 0])
+            }
+            Self::ReplyRecv { reply, buf, len, ep } => {
+                let (lo, hi) = cap_regs(&reply);
+                (Sysno::ReplyRecv, [lo, hi, buf.addr, len as u64, buf.len as u64, ep as u64])
+            }
+            Self::CapCopy { src, dst } => (Sysno::CapCopy, [src as u64, dst as u64, 0, 0, 0, 0]),
+            Self::CapDerive { src, dst, rights } => (Sysno::CapDerive, [src as u64, dst as u64, rights.bits() as u64, 0, 0, 0]),
+            Self::CapMove { src, dst } => (Sysno::CapMove, [src as u64, dst as u64, 0, 0, 0, 0]),
+            Self::CapDelete { slot } => (Sysno::CapDelete, [slot as u64, 0, 0, 0, 0, 0]),
+            Self::CapRevoke { slot } => (Sysno::CapRevoke, [slot as u64, 0, 0, 0, 0, 0]),
+            Self::Map { region, addr, offset, len, writable } => {
+                (Sysno::Map, [region as u64, addr, offset, len, if writable { MAP_WRITABLE } else { 0 }, 0])
+            }
+            Self::Unmap { addr } => (Sysno::Unmap, [addr, 0, 0, 0, 0, 0]),
+            Self::Yield => (Sysno::Yield, [0; 6]),
+        };
+        let [rdi, rsi, rdx, r10, r8, r9] = args;
+        SyscallFrame { rax: no as u64, rdi, rsi, rdx, r10, r8, r9, rcx: 0, r11: 0 }
+    }
+}
+
+/// What a completed call returns.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SysReply {
+    /// No results.
+    Done,
+    /// A message landed in the buffer (`recv`, `call`, `reply_recv`):
+    /// `rdi` length, `rsi` sender, `rdx`/`r10` the reply cap if the
+    /// message was a call (else zero).
+    Received { len: usize, sender: ThreadId, reply: Option<Cap> },
+    /// A count (`rdi`), e.g. of caps revoked.
+    Count(usize),
+    /// The thread blocked: the frame is left alone and the kernel
+    /// `complete`s it when the thread resumes.
+    Blocked,
+}
+
+/// The kernel side of the syscalls: carry out one decoded call for the
+/// current thread.
+pub trait SyscallHandler {
+    fn handle(&mut self, call: Syscall) -> Result<SysReply, SysError>;
+}
+
+/// Write the outcome of a call into its frame.
+pub fn complete(f: &mut SyscallFrame, result: Result<SysReply, SysError>) {
+    let (rax, [rdi, rsi, rdx, r10]) = match result {
+        Ok(SysReply::Blocked) => return,
+        Ok(SysReply::Done) => (0, [0; 4]),
+        Ok(SysReply::Received { len, sender, reply }) => {
+            let (lo, hi) = reply.as_ref().map_or((0, 0), cap_regs);
+            (0, [len as u64, sender as u64, lo, hi])
+        }
+        Ok(SysReply::Count(n)) => (0, [n as u64, 0, 0, 0]),
+        Err(e) => (e.code(), [0; 4]),
+    };
+    *f = SyscallFrame { rax, rdi, rsi, rdx, r10, ..*f };
+}
+
+/// Decode the frame, hand the call to `h` and write back its outcome.
+/// Returns whether the call completed (false if the thread blocked).
+pub fn dispatch(f: &mut SyscallFrame, h: &mut impl SyscallHandler) -> bool {
+    let result = Syscall::decode(f).and_then(|call| h.handle(call));
+    complete(f, result);
+    result != Ok(SysReply::Blocked)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::vec::Vec;
+
+    /// Records calls and answers from a script.
+    struct Fake {
+        calls: Vec<Syscall>,
+        answers: Vec<Result<SysReply, SysError>>,
+    }
+
+    impl SyscallHandler for Fake {
+        fn handle(&mut self, call: Syscall) -> Result<SysReply, SysError> {
+            self.calls.push(call);
+            self.answers.remove(0)
+        }
+    }
+
+    fn frame(rax: u64, args: [u64; 6]) -> SyscallFrame {
+        let [rdi, rsi, rdx, r10, r8, r9] = args;
+        SyscallFrame { rax, rdi, rsi, rdx, r10, r8, r9, rcx: 0x40_1000, r11: 0x202 }
+    }
+
+    #[test]
+    fn decodes_argument_registers() {
+        let reply = Cap::new([7; 16]);
+        let (lo, hi) = cap_regs(&reply);
+        let cases = [
+            (frame(1, [0x1000, 64, NO_TIMEOUT, 0, 0, 0]), Ok(Syscall::Send { msg: UserBuf { addr: 0x1000, len: 64 }, timeout: None })),
+            (frame(1, [0x1000, 129, 0, 0, 0, 0]), Err(SysError::BadArgument)),
+            (frame(1, [USER_END - 8, 16, 0, 0, 0, 0]), Err(SysError::BadAddress)),
+            (frame(1, [0, 8, 0, 0, 0, 0]), Err(SysError::BadAddress)),
+            (frame(2, [3, 0x2000, 4096, 5_000_000, 0, 0]), Ok(Syscall::Recv { ep: 3, buf: UserBuf { addr: 0x2000, len: 4096 }, timeout: Some(5_000_000) })),
+            (frame(2, [1 << 16, 0x2000, 128, 0, 0, 0]), Err(SysError::BadSlot)),
+            (frame(4, [lo, hi, 0x3000, 8, 0, 0]), Ok(Syscall::Reply { reply, msg: UserBuf { addr: 0x3000, len: 8 } })),
+            (frame(5, [lo, hi, 0x3000, 200, 128, 1]), Err(SysError::BadArgument)),
+            (frame(7, [1, 2, Rights::READ.bits() as u64, 0, 0, 0]), Ok(Syscall::CapDerive { src: 1, dst: 2, rights: Rights::READ })),
+            (frame(7, [1, 2, 1 << 6, 0, 0, 0]), Err(SysError::BadArgument)),
+            (frame(11, [4, 0x4000_0000, 0, 8192, MAP_WRITABLE, 0]), Ok(Syscall::Map { region: 4, addr: 0x4000_0000, offset: 0, len: 8192, writable: true })),
+            (frame(11, [4, 0x4000_0000, 0, 8192, 2, 0]), Err(SysError::BadArgument)),
+            (frame(13, [0; 6]), Ok(Syscall::Yield)),
+            // Unused registers must be zero.
+            (frame(13, [0, 0, 0, 0, 0, 1]), Err(SysError::BadArgument)),
+            (frame(9, [2, 1, 0, 0, 0, 0]), Err(SysError::BadArgument)),
+            (frame(0, [0; 6]), Err(SysError::UnknownSyscall)),
+            (frame(14, [0; 6]), Err(SysError::UnknownSyscall)),
+        ];
+        for (f, want) in cases {
+            assert_eq!(Syscall::decode(&f), want, "{:?}", f);
+        }
+    }
+
+    #[test]
+    fn dispatch_writes_results() {
+        let reply = Cap::new([9; 16]);
+        let mut k = Fake {
+            calls: Vec::new(),
+            answers: std::vec![
+                Ok(SysReply::Received { len: 40, sender: 3, reply: Some(reply) }),
+                Err(SysError::TimedOut),
+                Ok(SysReply::Blocked),
+            ],
+        };
+        let mut f = frame(2, [1, 0x2000, 128, NO_TIMEOUT, 0, 0]);
+        assert!(dispatch(&mut f, &mut k));
+        let (lo, hi) = cap_regs(&reply);
+        assert_eq!((f.rax, f.rdi, f.rsi, f.rdx, f.r10), (0, 40, 3, lo, hi));
+        // Errors clear the results; rcx/r11 survive for sysret.
+        let mut f = frame(3, [0x1000, 64, 0x2000, 128, 1_000, 0]);
+        assert!(dispatch(&mut f, &mut k));
+        assert_eq!(f, SyscallFrame { rax: SysError::TimedOut.code(), rdi: 0, rsi: 0, rdx: 0, r10: 0, ..frame(3, [0, 0, 0, 0, 1_000, 0]) });
+        // A blocked call leaves the frame for `complete`.
+        let mut f = frame(13, [0; 6]);
+        assert!(!dispatch(&mut f, &mut k));
+        assert_eq!(f, frame(13, [0; 6]));
+        complete(&mut f, Ok(SysReply::Count(2)));
+        assert_eq!((f.rax, f.rdi), (0, 2));
+        // Undecodable frames never reach the handler.
+        let mut f = frame(99, [0; 6]);
+        assert!(dispatch(&mut f, &mut k));
+        assert_eq!(f.rax, SysError::UnknownSyscall.code());
+        assert_eq!(k.calls.len(), 3);
+        assert!(frame(1, [0; 6]).sysret_safe() && !SyscallFrame { rcx: USER_END, ..frame(1, [0; 6]) }.sysret_safe());
+    }
+
+    #[test]
+    fn error_codes_are_dense() {
+        for code in 1..=SysError::COUNT {
+            assert_eq!(SysError::from_code(code).map(SysError::code), Some(code));
+        }
+        assert_eq!(SysError::from_code(0), None);
+        assert_eq!(SysError::from_code(SysError::COUNT + 1), None);
+    }
+
+    fn arg() -> impl Strategy<Value = u64> {
+        prop_oneof![Just(0u64), 0u64..0x1_0000, 0u64..0x200, Just(NO_TIMEOUT), Just(USER_END), any::<u64>()]
+    }
+
+    proptest! {
+        // Arbitrary frames never panic the decoder, and whatever decodes
+        // re-encodes to the same registers.
+        #[test]
+        fn decode_encode_roundtrip(rax in 0u64..16, args in proptest::array::uniform6(arg())) {
+            let f = frame(rax, args);
+            if let Ok(call) = Syscall::decode(&f) {
+                let g = call.encode();
+                prop_assert_eq!((g.rax, g.args()), (f.rax, f.args()));
+                prop_assert_eq!(Syscall::decode(&g), Ok(call));
+            }
+        }
+    }
+}
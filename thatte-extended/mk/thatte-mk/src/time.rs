@@ -0,0 +1,712 @@
+//! Timekeeping: a monotonic clock over a free-running hardware counter, a
+//! hierarchical timer wheel for sleeps and timeouts, and per-thread timeouts
+//! for blocking IPC.
+//!
+//! Hardware sits behind two traits. A `Counter` counts up at a fixed rate
+//! (the TSC, or the HPET main counter when the TSC is not invariant); a
+//! `OneShot` raises an interrupt after a delay (the local APIC timer). The
+//! kernel reads `Clock::now` on every timer interrupt, hands the time to
+//! `Timeouts::expire`, and re-arms the one-shot for `next_deadline`. Nothing
+//! but `Tsc`, `Hpet` and `LapicTimer` touches hardware, so the rest runs on
+//! the host against a fake counter.
+//!
+//! `Clock::publish` also keeps the shared `TimePage` (see `thatte-vdso`)
+//! current, so tasks that map it read the time without a `GetTime` call.
+
+use crate::frame::PhysAddr;
+use crate::ipc::ThreadId;
+use crate::msg::GetTimeReply;
+use crate::paging::{AddressSpace, FrameSource, PageFlags, PageSize, PagingError, PhysMem, VirtAddr};
+use crate::sched::Nanos;
+use thatte_vdso::{Source, TimePage, TimeParams};
+
+pub const NS_PER_SEC: u64 = 1_000_000_000;
+
+/// A free-running counter that ticks `frequency` times a second and wraps
+/// at `bits` bits.
+pub trait Counter {
+    fn read(&self) -> u64;
+    fn frequency(&self) -> u64;
+    fn bits(&self) -> u32 { 64 }
+    /// How user tasks can read this counter themselves.
+    fn source(&self) -> Source { Source::None }
+}
+
+/// A timer that interrupts once, `after` nanoseconds from now.
+pub trait OneShot {
+    fn arm(&mut self, after: Nanos);
+    fn disarm(&mut self);
+}
+
+/// The time-stamp counter, at a rate found by `calibrate`. Only usable as a
+/// clock if invariant (see `Tsc::invariant`), and only across CPUs if the
+/// firmware synchronized them.
+#[cfg(target_arch = "x86_64")]
+#[derive(Copy, Clone, Debug)]
+pub struct Tsc {
+    hz: u64,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Tsc {
+    pub const fn new(hz: u64) -> Self { Self { hz } }
+
+    /// Whether the TSC runs at a constant rate in every power state.
+    pub fn invariant() -> bool {
+        use core::arch::x86_64::__cpuid;
+        let max = __cpuid(0x8000_0000).eax;
+        max >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Counter for Tsc {
+    // SAFETY: rdtsc has no side effects.
+    fn read(&self) -> u64 { unsafe { core::arch::x86_64::_rdtsc() } }
+    fn frequency(&self) -> u64 { self.hz }
+    fn source(&self) -> Source { Source::Tsc }
+}
+
+/// HPET main counter (register block at a mapped virtual address).
+#[derive(Debug)]
+pub struct Hpet {
+    base: *mut u64,
+    hz: u64,
+    bits: u32,
+}
+
+impl Hpet {
+    const CAPABILITIES: usize = 0;
+    const CONFIG: usize = 0x10 / 8;
+    const COUNTER: usize = 0xf0 / 8;
+    const ENABLE: u64 = 1;
+    const COUNT_SIZE_64: u64 = 1 << 13;
+
+    /// Start the main counter of the HPET whose registers are mapped at
+    /// `base`.
+    ///
+    /// # Safety
+    /// `base` must be the uncached mapping of an HPET register block, used
+    /// by nothing else.
+    pub unsafe fn new(base: *mut u64) -> Self {
+        let caps = base.add(Self::CAPABILITIES).read_volatile();
+        // Counter period in femtoseconds.
+        let period = (caps >> 32).max(1);
+        let bits = if caps & Self::COUNT_SIZE_64 != 0 { 64 } else { 32 };
+        let cfg = base.add(Self::CONFIG);
+        cfg.write_volatile(cfg.read_volatile() | Self::ENABLE);
+        Self { base, hz: 1_000_000_000_000_000 / period, bits }
+    }
+}
+
+impl Counter for Hpet {
+    // SAFETY: `new` checked the mapping.
+    fn read(&self) -> u64 { unsafe { self.base.add(Self::COUNTER).read_volatile() } }
+    fn frequency(&self) -> u64 { self.hz }
+    fn bits(&self) -> u32 { self.bits }
+}
+
+/// Local APIC timer in one-shot mode (register block at a mapped virtual
+/// address), raising `vector` when it fires.
+#[derive(Debug)]
+pub struct LapicTimer {
+    base: *mut u32,
+    hz: u64,
+    vector: u8,
+}
+
+impl LapicTimer {
+    const LVT_TIMER: usize = 0x320 / 4;
+    const INITIAL_COUNT: usize = 0x380 / 4;
+    const CURRENT_COUNT: usize = 0x390 / 4;
+    const DIVIDE: usize = 0x3e0 / 4;
+    const DIVIDE_BY_1: u32 = 0b1011;
+    const MASKED: u32 = 1 << 16;
+
+    /// Measure the timer's rate against `reference` over `window`, leaving
+    /// it stopped.
+    ///
+    /// # Safety
+    /// `base` must be the uncached mapping of this CPU's local APIC, and no
+    /// one else may program its timer.
+    pub unsafe fn calibrate(base: *mut u32, vector: u8, reference: &impl Counter, window: Nanos) -> Self {
+        let reg = |r: usize| base.add(r);
+        reg(Self::DIVIDE).write_volatile(Self::DIVIDE_BY_1);
+        reg(Self::LVT_TIMER).write_volatile(Self::MASKED | vector as u32);
+        reg(Self::INITIAL_COUNT).write_volatile(u32::MAX);
+        let elapsed = spin(reference, window);
+        let counted = u32::MAX - reg(Self::CURRENT_COUNT).read_volatile();
+        reg(Self::INITIAL_COUNT).write_volatile(0);
+        Self { base, hz: (counted as u128 * NS_PER_SEC as u128 / elapsed.max(1) as u128) as u64, vector }
+    }
+
+    pub fn frequency(&self) -> u64 { self.hz }
+}
+
+impl OneShot for LapicTimer {
+    fn arm(&mut self, after: Nanos) {
+        let ticks = (after as u128 * self.hz as u128 / NS_PER_SEC as u128).clamp(1, u32::MAX as u128) as u32;
+        // SAFETY: `calibrate` checked the mapping; one-shot mode is LVT mode 0.
+        unsafe {
+            self.base.add(Self::LVT_TIMER).write_volatile(self.vector as u32);
+            self.base.add(Self::INITIAL_COUNT).write_volatile(ticks);
+        }
+    }
+
+    // SAFETY: as for `arm`; a zero initial count stops the timer.
+    fn disarm(&mut self) { unsafe { self.base.add(Self::INITIAL_COUNT).write_volatile(0) } }
+}
+
+/// Busy-wait until `reference` has advanced at least `window`; returns the
+/// time that actually passed.
+fn spin(reference: &impl Counter, window: Nanos) -> Nanos {
+    let (hz, mask) = (reference.frequency(), mask(reference.bits()));
+    let start = reference.read();
+    loop {
+        let ns = to_ns(reference.read().wrapping_sub(start) & mask, hz);
+        if ns >= window { return ns; }
+        core::hint::spin_loop();
+    }
+}
+
+/// Rate of `target` in Hz, measured against `reference` over `window`
+/// (e.g. the TSC against the HPET).
+pub fn calibrate(reference: &impl Counter, target: &impl Counter, window: Nanos) -> u64 {
+    let start = target.read();
+    let elapsed = spin(reference, window);
+    let counted = target.read().wrapping_sub(start) & mask(target.bits());
+    (counted as u128 * NS_PER_SEC as u128 / elapsed.max(1) as u128) as u64
+}
+
+const fn mask(bits: u32) -> u64 { if bits >= 64 { u64::MAX } else { (1 << bits) - 1 } }
+
+fn to_ns(ticks: u64, hz: u64) -> Nanos { (ticks as u128 * NS_PER_SEC as u128 / hz as u128) as Nanos }
+
+/// Monotonic time since the clock was created, and wall-clock time once
+/// set.
+///
+/// Whole seconds are folded into the base as they pass, so conversion
+/// never drifts. A read more than half the counter's range ahead of the
+/// base is taken for one behind it (the counter stepped back) and changes
+/// nothing, so a counter narrower than 64 bits must be read at least once
+/// per `max_gap`.
+pub struct Clock<C: Counter> {
+    counter: C,
+    hz: u64,
+    mask: u64,
+    /// Counter value and time at the last fold.
+    base: u64,
+    base_ns: Nanos,
+    /// Latest time returned, so a glitching counter cannot go backwards.
+    last: Nanos,
+    /// Wall-clock nanoseconds at monotonic zero.
+    epoch: Option<u64>,
+}
+
+impl<C: Counter> Clock<C> {
+    pub fn new(counter: C) -> Self {
+        let (hz, mask) = (counter.frequency().max(1), mask(counter.bits()));
+        let base = counter.read() & mask;
+        Self { counter, hz, mask, base, base_ns: 0, last: 0, epoch: None }
+    }
+
+    pub fn counter(&self) -> &C { &self.counter }
+
+    /// Longest time between reads: half the counter's range.
+    pub fn max_gap(&self) -> Nanos { to_ns(self.mask / 2, self.hz) }
+
+    pub fn now(&mut self) -> Nanos {
+        let delta = (self.counter.read() & self.mask).wrapping_sub(self.base) & self.mask;
+        if delta > self.mask / 2 { return self.last; }
+        let secs = delta / self.hz;
+        self.base = self.base.wrapping_add(secs * self.hz) & self.mask;
+        self.base_ns += secs * NS_PER_SEC;
+        self.last = self.last.max(self.base_ns + to_ns(delta % self.hz, self.hz));
+        self.last
+    }
+
+    /// Set the wall clock (nanoseconds since the Unix epoch, e.g. from the
+    /// RTC at boot); later reads advance it with the monotonic clock.
+    pub fn set_wall(&mut self, wall: u64) {
+        let now = self.now();
+        self.epoch = Some(wall.wrapping_sub(now));
+    }
+
+    pub fn wall(&mut self) -> Option<u64> {
+        let now = self.now();
+        self.epoch.map(|e| e.wrapping_add(now))
+    }
+
+    /// Bring `page` up to date. Call whenever the wall clock is set and at
+    /// least once a second, so readers extrapolate over short spans.
+    pub fn publish(&mut self, page: &TimePage) {
+        self.now();
+        let epoch = self.epoch.unwrap_or(0);
+        page.publish(&TimeParams::from_hz(self.counter.source(), self.hz, self.base, self.base_ns, epoch));
+    }
+
+    /// Answer to `MsgType::GetTime`.
+    pub fn get_time(&mut self) -> GetTimeReply {
+        let monotonic_ns = self.now();
+        GetTimeReply { monotonic_ns, wall_ns: self.epoch.map_or(0, |e| e.wrapping_add(monotonic_ns)) }
+    }
+}
+
+/// Map the time page (physical frame `pa`) at `va` in a task's address
+/// space: user-readable, not writable, not executable.
+pub fn map_time_page(
+    space: &mut AddressSpace,
+    mem: &mut impl PhysMem,
+    frames: &mut impl FrameSource,
+    va: VirtAddr,
+    pa: PhysAddr,
+) -> Result<(), PagingError> {
+    space.map(mem, frames, va, pa, PageSize::Size4K, PageFlags::USER | PageFlags::NO_EXECUTE)
+}
+
+/// Slots per wheel level (log2).
+pub const WHEEL_BITS: u32 = 6;
+pub const WHEEL_LEVELS: usize = 4;
+const SLOTS: usize = 1 << WHEEL_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+const NIL: u16 = u16::MAX;
+
+/// Handle to a pending timer; stale once it fires or is cancelled.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct TimerId {
+    index: u16,
+    gen: u16,
+}
+
+#[derive(Copy, Clone)]
+struct Entry<T: Copy> {
+    item: Option<T>,
+    /// Tick at which the timer fires.
+    expires: u64,
+    gen: u16,
+    prev: u16,
+    next: u16,
+    /// Level and slot it is filed in, so unlinking a slot's head is O(1).
+    level: u8,
+    slot: u8,
+}
+
+/// Hierarchical timer wheel holding up to `N` timers of payload `T`.
+///
+/// Time is counted in ticks of `tick` nanoseconds. Level `l` has 64 slots
+/// of 64^l ticks each; a timer sits in the lowest level whose span covers
+/// its distance from now, and drops a level each time the wheel below
+/// wraps. Timers beyond the top level's span wait in its last slot and are
+/// re-filed as it comes round. Adding and cancelling are O(1); `advance`
+/// only visits occupied slots and level boundaries.
+pub struct TimerWheel<T: Copy, const N: usize> {
+    tick: Nanos,
+    /// Current tick.
+    now: u64,
+    entries: [Entry<T>; N],
+    /// First entry in each slot, and which slots are non-empty.
+    heads: [[u16; SLOTS]; WHEEL_LEVELS],
+    occupied: [u64; WHEEL_LEVELS],
+    /// Free entries, linked through `next`.
+    free: u16,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> TimerWheel<T, N> {
+    /// An empty wheel at time zero.
+    pub const fn new(tick: Nanos) -> Self {
+        assert!(tick > 0 && N < NIL as usize);
+        let mut entries = [Entry { item: None, expires: 0, gen: 0, prev: NIL, next: NIL, level: 0, slot: 0 }; N];
+        let mut i = 0;
+        while i < N {
+            entries[i].next = if i + 1 < N { (i + 1) as u16 } else { NIL };
+            i += 1;
+        }
+        let free = if N > 0 { 0 } else { NIL };
+        Self { tick, now: 0, entries, heads: [[NIL; SLOTS]; WHEEL_LEVELS], occupied: [0; WHEEL_LEVELS], free, len: 0 }
+    }
+
+    pub fn len(&self) -> usize { self.len }
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// Time the wheel has advanced to, rounded down to a tick.
+    pub fn now(&self) -> Nanos { self.now * self.tick }
+
+    /// Fire `item` at the first tick at or after `deadline` (the next tick
+    /// if that has passed). `None` if the wheel is full.
+    pub fn add(&mut self, deadline: Nanos, item: T) -> Option<TimerId> {
+        let i = self.free;
+        if i == NIL { return None; }
+        let e = &mut self.entries[i as usize];
+        self.free = e.next;
+        e.item = Some(item);
+        e.expires = deadline.div_ceil(self.tick).max(self.now + 1);
+        e.gen = e.gen.wrapping_add(1);
+        let id = TimerId { index: i, gen: e.gen };
+        self.file(i);
+        self.len += 1;
+        Some(id)
+    }
+
+    /// Stop a pending timer, returning its payload (`None` if it already
+    /// fired or was cancelled).
+    pub fn cancel(&mut self, id: TimerId) -> Option<T> {
+        let e = self.entries.get(id.index as usize)?;
+        if e.gen != id.gen || e.item.is_none() { return None; }
+        self.unlink(id.index);
+        Some(self.release(id.index))
+    }
+
+    /// Earliest time a pending timer fires.
+    pub fn next_deadline(&self) -> Option<Nanos> {
+        self.entries.iter().filter(|e| e.item.is_some()).map(|e| e.expires * self.tick).min()
+    }
+
+    /// Move time forward to `now`, calling `fire` for every timer that
+    /// comes due, in order of expiry.
+    pub fn advance(&mut self, now: Nanos, mut fire: impl FnMut(TimerId, T)) {
+        let to = now / self.tick;
+        while self.now < to {
+            if self.len == 0 {
+                self.now = to;
+                break;
+            }
+            // Next occupied slot of level 0 before it wraps, else the wrap.
+            let next_slot = (self.now & SLOT_MASK) + 1;
+            let ahead = if next_slot < SLOTS as u64 { self.occupied[0] >> next_slot << next_slot } else { 0 };
+            let next = if ahead != 0 { (self.now & !SLOT_MASK) + ahead.trailing_zeros() as u64 } else { (self.now | SLOT_MASK) + 1 };
+            if next > to {
+                self.now = to;
+                break;
+            }
+            self.now = next;
+            if next & SLOT_MASK == 0 { self.cascade(1); }
+            let slot = (next & SLOT_MASK) as usize;
+            while let Some(i) = self.pop(0, slot) {
+                let gen = self.entries[i as usize].gen;
+                let item = self.release(i);
+                fire(TimerId { index: i, gen }, item);
+            }
+        }
+    }
+
+    /// Re-file the current slot of `level` (and, if that level just
+    /// wrapped, of the levels above first).
+    fn cascade(&mut self, level: usize) {
+        if level >= WHEEL_LEVELS { return; }
+        let slot = ((self.now >> (WHEEL_BITS * level as u32)) & SLOT_MASK) as usize;
+        if slot == 0 { self.cascade(level + 1); }
+        while let Some(i) = self.pop(level, slot) {
+            self.file(i);
+        }
+    }
+
+    /// Put entry `i` in the slot for its expiry.
+    fn file(&mut self, i: u16) {
+        let top = WHEEL_BITS * WHEEL_LEVELS as u32;
+        let expires = self.entries[i as usize].expires;
+        // Timers past the top level's span wait in its furthest slot.
+        let at = expires.min(self.now + (1 << top) - 1);
+        let delta = at - self.now;
+        let level = (0..WHEEL_LEVELS).find(|&l| delta < 1 << (WHEEL_BITS * (l as u32 + 1))).unwrap_or(WHEEL_LEVELS - 1);
+        let slot = ((at >> (WHEEL_BITS * level as u32)) & SLOT_MASK) as usize;
+        let head = self.heads[level][slot];
+        let e = &mut self.entries[i as usize];
+        e.prev = NIL;
+        e.next = head;
+        (e.level, e.slot) = (level as u8, slot as u8);
+        if head != NIL { self.entries[head as usize].prev = i; }
+        self.heads[level][slot] = i;
+        self.occupied[level] |= 1 << slot;
+    }
+
+    fn pop(&mut self, level: usize, slot: usize) -> Option<u16> {
+        let i = self.heads[level][slot];
+        if i == NIL { return None; }
+        self.unlink(i);
+        Some(i)
+    }
+
+    fn unlink(&mut self, i: u16) {
+        let Entry { prev, next, level, slot, .. } = self.entries[i as usize];
+        if next != NIL { self.entries[next as usize].prev = prev; }
+        if prev != NIL {
+            self.entries[prev as usize].next = next;
+            return;
+        }
+        let (level, slot) = (level as usize, slot as usize);
+        debug_assert_eq!(self.heads[level][slot], i, "timer {} heads its slot", i);
+        self.heads[level][slot] = next;
+        if next == NIL { self.occupied[level] &= !(1 << slot); }
+    }
+
+    fn release(&mut self, i: u16) -> T {
+        let e = &mut self.entries[i as usize];
+        let item = e.item.take().expect("pending timer has a payload");
+        e.next = self.free;
+        self.free = i;
+        self.len -= 1;
+        item
+    }
+}
+
+/// What a thread's timeout ends.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Timeout {
+    /// A sleep: the thread wakes.
+    Sleep,
+    /// A blocking IPC operation: the kernel `Ipc::cancel`s it, and the
+    /// thread's call fails with a timeout.
+    Ipc,
+}
+
+/// At most one pending timeout per thread, on a timer wheel.
+pub struct Timeouts<const THREADS: usize> {
+    wheel: TimerWheel<(ThreadId, Timeout), THREADS>,
+    ids: [Option<TimerId>; THREADS],
+}
+
+impl<const THREADS: usize> Timeouts<THREADS> {
+    pub const fn new(tick: Nanos) -> Self { Self { wheel: TimerWheel::new(tick), ids: [None; THREADS] } }
+
+    /// Arm `tid`'s timeout for `deadline`, replacing any pending one.
+    /// Unknown threads are ignored.
+    pub fn arm(&mut self, tid: ThreadId, deadline: Nanos, kind: Timeout) {
+        self.disarm(tid);
+        let Some(slot) = self.ids.get_mut(tid as usize) else { return };
+        // One entry per thread, so the wheel cannot fill up.
+        *slot = self.wheel.add(deadline, (tid, kind));
+    }
+
+    /// Cancel `tid`'s timeout because what it waited for happened first.
+    pub fn disarm(&mut self, tid: ThreadId) -> Option<Timeout> {
+        let id = self.ids.get_mut(tid as usize)?.take()?;
+        self.wheel.cancel(id).map(|(_, kind)| kind)
+    }
+
+    /// Advance to `now`, calling `expired` for each thread whose timeout
+    /// passed.
+    pub fn expire(&mut self, now: Nanos, mut expired: impl FnMut(ThreadId, Timeout)) {
+        let ids = &mut self.ids;
+        self.wheel.advance(now, |_, (tid, kind)| {
+            ids[tid as usize] = None;
+            expired(tid, kind);
+        });
+    }
+
+    /// When the one-shot timer must next fire.
+    pub fn next_deadline(&self) -> Option<Nanos> { self.wheel.next_deadline() }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::ipc::{Ipc, Received, Sent, ThreadState, MSG_MAX};
+    use crate::msg::{Ping, Request};
+    use crate::{Cap, MsgFlags};
+    use core::cell::Cell;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    /// Counter over shared fake time (nanoseconds), optionally advancing it
+    /// on every read. It passes for a TSC.
+    struct Fake {
+        time: Rc<Cell<u64>>,
+        hz: u64,
+        bits: u32,
+        step: u64,
+    }
+
+    impl Fake {
+        fn new(time: &Rc<Cell<u64>>, hz: u64, bits: u32) -> Self { Self { time: time.clone(), hz, bits, step: 0 } }
+    }
+
+    impl Counter for Fake {
+        fn read(&self) -> u64 {
+            let t = self.time.get();
+            self.time.set(t + self.step);
+            (t as u128 * self.hz as u128 / NS_PER_SEC as u128) as u64 & mask(self.bits)
+        }
+        fn frequency(&self) -> u64 { self.hz }
+        fn bits(&self) -> u32 { self.bits }
+        fn source(&self) -> Source { Source::Tsc }
+    }
+
+    #[test]
+    fn clock_survives_counter_wrap() {
+        let time = Rc::new(Cell::new(5 * NS_PER_SEC));
+        // A 32-bit counter at 14.318 MHz (an HPET) wraps every ~300 s.
+        let mut clock = Clock::new(Fake::new(&time, 14_318_180, 32));
+        assert!(clock.max_gap() > 149 * NS_PER_SEC && clock.max_gap() < 150 * NS_PER_SEC);
+        assert_eq!(clock.get_time().wall_ns, 0);
+        clock.set_wall(1_700_000_000 * NS_PER_SEC);
+        let mut last = 0;
+        for i in 1..=40u64 {
+            time.set(5 * NS_PER_SEC + i * 100 * NS_PER_SEC + i);
+            let now = clock.now();
+            // One counter tick is ~70 ns.
+            assert!(now.abs_diff(i * 100 * NS_PER_SEC + i) < 100, "{} at step {}", now, i);
+            assert!(now >= last);
+            last = now;
+        }
+        let t = clock.get_time();
+        assert_eq!(t.wall_ns - t.monotonic_ns, 1_700_000_000 * NS_PER_SEC);
+    }
+
+    #[test]
+    fn clock_ignores_counter_stepping_back() {
+        for bits in [64, 32] {
+            let time = Rc::new(Cell::new(5 * NS_PER_SEC));
+            // At 1 GHz one tick is one nanosecond.
+            let mut clock = Clock::new(Fake::new(&time, NS_PER_SEC, bits));
+            // Within half the 32-bit range (~2.1 s) of the base.
+            time.set(6 * NS_PER_SEC + NS_PER_SEC / 2);
+            assert_eq!(clock.now(), NS_PER_SEC + NS_PER_SEC / 2);
+            // The fold left the base at 6 s; read one tick below it.
+            time.set(6 * NS_PER_SEC - 1);
+            assert_eq!(clock.now(), NS_PER_SEC + NS_PER_SEC / 2, "{} bits", bits);
+            time.set(7 * NS_PER_SEC);
+            assert_eq!(clock.now(), 2 * NS_PER_SEC, "{} bits", bits);
+        }
+    }
+
+    #[test]
+    fn time_page_tracks_clock() {
+        let time = Rc::new(Cell::new(3 * NS_PER_SEC));
+        let mut clock = Clock::new(Fake::new(&time, 2_900_000_000, 64));
+        let page = TimePage::new();
+        let tsc = Fake::new(&time, 2_900_000_000, 64);
+        let reader = thatte_vdso::Reader::with_counter(&page, || tsc.read());
+        assert_eq!(reader.now(), None);
+        clock.set_wall(1_700_000_000 * NS_PER_SEC);
+        clock.publish(&page);
+        // Readers extrapolate between updates, across the clock's folds.
+        for step in [1, 999_999_999, 1_500_000_000, 7] {
+            time.set(time.get() + step);
+            let (mono, wall) = reader.now().unwrap();
+            let t = clock.get_time();
+            assert!(mono.abs_diff(t.monotonic_ns) <= 1, "{} vs {}", mono, t.monotonic_ns);
+            assert_eq!(wall, Some(mono + 1_700_000_000 * NS_PER_SEC));
+        }
+        clock.publish(&page);
+        assert_eq!(page.generation(), 4);
+        assert!(reader.monotonic_ns().unwrap().abs_diff(clock.now()) <= 1);
+    }
+
+    #[test]
+    fn calibrates_against_reference() {
+        let time = Rc::new(Cell::new(0));
+        let hpet = Fake { step: 1000, ..Fake::new(&time, 14_318_180, 32) };
+        let tsc = Fake::new(&time, 2_900_000_000, 64);
+        let hz = calibrate(&hpet, &tsc, 10_000_000);
+        assert!(hz.abs_diff(2_900_000_000) < 2_900_000_000 / 1000, "{}", hz);
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Add(Nanos),
+        Cancel(usize),
+        Advance(Nanos),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            // Near, mid-range and far deadlines (the top level spans ~4.6 h at 1 ms).
+            prop_oneof![0u64..100, 0u64..10_000_000, 0u64..40_000_000_000_000].prop_map(Op::Add),
+            any::<usize>().prop_map(Op::Cancel),
+            prop_oneof![0u64..70, 0u64..100_000_000, 0u64..20_000_000_000_000].prop_map(Op::Advance),
+        ]
+    }
+
+    proptest! {
+        // Against a model: every timer fires exactly once, at the first
+        // advance that reaches its deadline rounded up to a tick, in
+        // deadline order; cancelling works exactly until it fires.
+        #[test]
+        fn wheel_matches_model(ops in proptest::collection::vec(op(), 1..200)) {
+            const TICK: Nanos = 1_000_000;
+            let mut w = TimerWheel::<u32, 64>::new(TICK);
+            let mut model: BTreeMap<u32, (TimerId, u64)> = BTreeMap::new();
+            let mut now = 0u64;
+            let mut next = 0u32;
+            for op in ops {
+                match op {
+                    Op::Add(d) => {
+                        let deadline = now + d;
+                        match w.add(deadline, next) {
+                            Some(id) => { model.insert(next, (id, deadline.div_ceil(TICK).max(now / TICK + 1))); }
+                            None => prop_assert_eq!(model.len(), 64),
+                        }
+                        next += 1;
+                    }
+                    Op::Cancel(k) => {
+                        if model.is_empty() { continue; }
+                        let key = *model.keys().nth(k % model.len()).unwrap();
+                        let (id, _) = model.remove(&key).unwrap();
+                        prop_assert_eq!(w.cancel(id), Some(key));
+                        prop_assert_eq!(w.cancel(id), None);
+                    }
+                    Op::Advance(d) => {
+                        now += d;
+                        let mut fired = Vec::new();
+                        w.advance(now, |_, k| fired.push(k));
+                        let mut due: Vec<_> = model.iter().filter(|(_, &(_, t))| t <= now / TICK).map(|(&k, &(_, t))| (t, k)).collect();
+                        due.sort();
+                        let ticks: Vec<_> = fired.iter().map(|k| model[k].1).collect();
+                        prop_assert!(ticks.windows(2).all(|w| w[0] <= w[1]), "out of order: {:?}", ticks);
+                        fired.sort_by_key(|k| (model[k].1, *k));
+                        prop_assert_eq!(fired.clone(), due.iter().map(|&(_, k)| k).collect::<Vec<_>>());
+                        for k in fired { model.remove(&k); }
+                    }
+                }
+                prop_assert_eq!(w.len(), model.len());
+                prop_assert_eq!(w.next_deadline(), model.values().map(|&(_, t)| t * TICK).min());
+            }
+        }
+    }
+
+    #[test]
+    fn call_times_out() {
+        let time = Rc::new(Cell::new(0));
+        let mut clock = Clock::new(Fake::new(&time, 1_000_000_000, 64));
+        let mut ipc = Ipc::<4, 1, 2, 4>::new([7; 16]);
+        let mut timeouts = Timeouts::<4>::new(1_000_000);
+        let ep = ipc.create_endpoint().unwrap();
+        let mut buf = [0u8; MSG_MAX];
+        let n = Request::Ping(Ping { seq: 1 }).encode(Cap::nil(), ep, MsgFlags::REPLY_EXPECTED, &mut buf).unwrap();
+
+        // Nobody serves the endpoint: the call times out after 5 ms.
+        assert_eq!(ipc.call(1, &buf[..n]), Ok(Sent::Queued));
+        timeouts.arm(1, clock.now() + 5_000_000, Timeout::Ipc);
+        let expire = |ipc: &mut Ipc<4, 1, 2, 4>, timeouts: &mut Timeouts<4>, now| {
+            let mut woke = Vec::new();
+            timeouts.expire(now, |tid, kind| {
+                assert_eq!(kind, Timeout::Ipc);
+                woke.push((tid, ipc.cancel(tid)));
+            });
+            woke
+        };
+        time.set(4_999_999);
+        assert_eq!(expire(&mut ipc, &mut timeouts, clock.now()), []);
+        assert_eq!(timeouts.next_deadline(), Some(5_000_000));
+        time.set(5_000_000);
+        assert_eq!(expire(&mut ipc, &mut timeouts, clock.now()), [(1, Some(ThreadState::ReplyBlocked))]);
+        assert_eq!(ipc.state(1), Some(ThreadState::Ready));
+        assert_eq!(timeouts.next_deadline(), None);
+
+        // A receive that completes in time disarms its timeout.
+        assert_eq!(ipc.recv(0, &ep, true), Ok(Received::Blocked));
+        timeouts.arm(0, clock.now() + 1_000_000, Timeout::Ipc);
+        let n = Request::Ping(Ping { seq: 2 }).encode(Cap::nil(), ep, MsgFlags::NONE, &mut buf).unwrap();
+        assert_eq!(ipc.send(3, &buf[..n], false), Ok(Sent::Delivered(0)));
+        assert_eq!(timeouts.disarm(0), Some(Timeout::Ipc));
+        time.set(10_000_000);
+        assert_eq!(expire(&mut ipc, &mut timeouts, clock.now()), []);
+
+        // Threads past the table are ignored, as by `disarm`.
+        timeouts.arm(4, clock.now() + 1, Timeout::Ipc);
+        assert_eq!((timeouts.next_deadline(), timeouts.disarm(4)), (None, None));
+    }
+}
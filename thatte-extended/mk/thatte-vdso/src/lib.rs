@@ -0,0 +1,269 @@
+//! The kernel's shared time page, and the user-side helper that reads the
+//! time from it without entering the kernel.
+//!
+//! `thatte-mk` keeps one `TimePage` and maps it read-only into tasks that
+//! ask for it. The page holds what converts a raw counter reading into
+//! nanoseconds: the counter value and monotonic time at the kernel's last
+//! update, a fixed-point rate (`ns = base_ns + (count - base_count) * mult
+//! >> shift`) and the wall-clock time at monotonic zero (the boot epoch).
+//!
+//! Updates are guarded by a seqlock: `generation` is odd while the kernel
+//! is writing and changes on every update, so a reader that sees the same
+//! even generation before and after copying the fields has a consistent
+//! set. The kernel is the only writer.
+//!
+//! Only counters a task can read itself are published (the TSC); otherwise
+//! `source` is `Source::None` and tasks fall back to the `GetTime` call.
+
+#![no_std]
+
+#[cfg(test)]
+extern crate std;
+
+use core::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+
+/// First word of the page ("THTP").
+pub const MAGIC: u32 = u32::from_le_bytes(*b"THTP");
+
+/// Layout version; bumped on any incompatible change.
+pub const VERSION: u32 = 1;
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Fixed-point shift of `mult`.
+pub const SHIFT: u32 = 32;
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+/// Counter a task reads to extrapolate from the page.
+#[repr(u32)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Source {
+    /// Nothing user-readable; ask the kernel.
+    None = 0,
+    /// `rdtsc`.
+    Tsc = 1,
+}
+
+/// Conversion parameters, as published in one update.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct TimeParams {
+    pub source: Source,
+    /// Counter value at monotonic time `base_ns`.
+    pub base_count: u64,
+    pub base_ns: u64,
+    /// Nanoseconds per count, scaled by 2^`shift`.
+    pub mult: u64,
+    pub shift: u32,
+    /// Wall-clock nanoseconds (since the Unix epoch) at monotonic zero, or
+    /// 0 if the kernel does not know the wall time.
+    pub epoch_ns: u64,
+}
+
+impl TimeParams {
+    /// Parameters for a counter running at `hz` that read `base_count` at
+    /// monotonic time `base_ns`.
+    pub const fn from_hz(source: Source, hz: u64, base_count: u64, base_ns: u64, epoch_ns: u64) -> Self {
+        let mult = ((NS_PER_SEC as u128) << SHIFT) / if hz == 0 { 1 } else { hz as u128 };
+        Self { source, base_count, base_ns, mult: mult as u64, shift: SHIFT, epoch_ns }
+    }
+
+    /// Monotonic time at counter value `count` (taken after the parameters
+    /// were read).
+    pub const fn ns_at(&self, count: u64) -> u64 {
+        let delta = count.saturating_sub(self.base_count);
+        self.base_ns + ((delta as u128 * self.mult as u128) >> self.shift) as u64
+    }
+}
+
+/// The shared page. Every field is an atomic so the kernel can update it
+/// while tasks read.
+#[repr(C, align(4096))]
+pub struct TimePage {
+    magic: AtomicU32,
+    version: AtomicU32,
+    /// Seqlock generation: odd during an update.
+    generation: AtomicU32,
+    source: AtomicU32,
+    base_count: AtomicU64,
+    base_ns: AtomicU64,
+    mult: AtomicU64,
+    shift: AtomicU32,
+    _reserved: AtomicU32,
+    epoch_ns: AtomicU64,
+}
+
+const _: () = assert!(core::mem::size_of::<TimePage>() == PAGE_SIZE);
+
+impl Default for TimePage {
+    fn default() -> Self { Self::new() }
+}
+
+impl TimePage {
+    /// A page with nothing published yet.
+    pub const fn new() -> Self {
+        Self {
+            magic: AtomicU32::new(MAGIC),
+            version: AtomicU32::new(VERSION),
+            generation: AtomicU32::new(0),
+            source: AtomicU32::new(Source::None as u32),
+            base_count: AtomicU64::new(0),
+            base_ns: AtomicU64::new(0),
+            mult: AtomicU64::new(0),
+            shift: AtomicU32::new(0),
+            _reserved: AtomicU32::new(0),
+            epoch_ns: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether this is a time page of a layout this crate reads.
+    pub fn is_valid(&self) -> bool {
+        self.magic.load(Ordering::Relaxed) == MAGIC && self.version.load(Ordering::Relaxed) == VERSION
+    }
+
+    pub fn generation(&self) -> u32 { self.generation.load(Ordering::Acquire) }
+
+    /// Replace the parameters (kernel only; updates must not race each
+    /// other).
+    pub fn publish(&self, p: &TimeParams) {
+        let g = self.generation.load(Ordering::Relaxed);
+        self.generation.store(g.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.source.store(p.source as u32, Ordering::Relaxed);
+        self.base_count.store(p.base_count, Ordering::Relaxed);
+        self.base_ns.store(p.base_ns, Ordering::Relaxed);
+        self.mult.store(p.mult, Ordering::Relaxed);
+        self.shift.store(p.shift, Ordering::Relaxed);
+        self.epoch_ns.store(p.epoch_ns, Ordering::Relaxed);
+        self.generation.store(g.wrapping_add(2), Ordering::Release);
+    }
+
+    /// A consistent copy of the parameters, retrying while an update is in
+    /// flight. `None` if nothing was published or the layout is unknown.
+    pub fn snapshot(&self) -> Option<TimeParams> {
+        if !self.is_valid() { return None; }
+        loop {
+            let g = self.generation.load(Ordering::Acquire);
+            if g % 2 == 1 {
+                core::hint::spin_loop();
+                continue;
+            }
+            let source = match self.source.load(Ordering::Relaxed) {
+                1 => Source::Tsc,
+                _ => Source::None,
+            };
+            let p = TimeParams {
+                source,
+                base_count: self.base_count.load(Ordering::Relaxed),
+                base_ns: self.base_ns.load(Ordering::Relaxed),
+                mult: self.mult.load(Ordering::Relaxed),
+                shift: self.shift.load(Ordering::Relaxed),
+                epoch_ns: self.epoch_ns.load(Ordering::Relaxed),
+            };
+            fence(Ordering::Acquire);
+            if self.generation.load(Ordering::Relaxed) != g { continue; }
+            return (g != 0).then_some(p);
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn rdtsc() -> u64 {
+    // SAFETY: rdtsc has no side effects (and the kernel leaves CR4.TSD clear
+    // when it publishes `Source::Tsc`).
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Reads the time from a mapped `TimePage`.
+///
+/// Results never go backwards for one reader, even across kernel updates
+/// that round differently.
+pub struct Reader<'a, F: Fn() -> u64 = fn() -> u64> {
+    page: &'a TimePage,
+    counter: F,
+    last: AtomicU64,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl<'a> Reader<'a> {
+    /// Read `page` with the CPU's TSC.
+    pub fn new(page: &'a TimePage) -> Self { Self::with_counter(page, rdtsc) }
+}
+
+impl<'a, F: Fn() -> u64> Reader<'a, F> {
+    /// Read `page` with `counter` standing in for the published source.
+    pub fn with_counter(page: &'a TimePage, counter: F) -> Self { Self { page, counter, last: AtomicU64::new(0) } }
+
+    /// Nanoseconds since boot, or `None` if the page has no user-readable
+    /// source (use `GetTime` instead).
+    pub fn monotonic_ns(&self) -> Option<u64> { self.now().map(|(m, _)| m) }
+
+    /// Nanoseconds since the Unix epoch, if the kernel knows it.
+    pub fn wall_ns(&self) -> Option<u64> { self.now().and_then(|(_, w)| w) }
+
+    /// Monotonic and (if known) wall time from one snapshot.
+    pub fn now(&self) -> Option<(u64, Option<u64>)> {
+        let p = self.page.snapshot().filter(|p| p.source != Source::None)?;
+        let ns = p.ns_at((self.counter)());
+        let ns = self.last.fetch_max(ns, Ordering::Relaxed).max(ns);
+        Some((ns, (p.epoch_ns != 0).then(|| p.epoch_ns + ns)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::vec::Vec;
+
+    #[test]
+    fn converts_and_stays_monotonic() {
+        let page = TimePage::new();
+        let count = AtomicU64::new(1_000);
+        let r = Reader::with_counter(&page, || count.load(Ordering::Relaxed));
+        assert_eq!(r.now(), None);
+        // 2 GHz: 2 counts per nanosecond.
+        page.publish(&TimeParams::from_hz(Source::Tsc, 2_000_000_000, 1_000, 500, 0));
+        count.store(1_000 + 2_000_000_000, Ordering::Relaxed);
+        assert_eq!(r.now(), Some((1_000_000_500, None)));
+        // An update that rounds a little behind the last reading.
+        page.publish(&TimeParams::from_hz(Source::Tsc, 2_000_000_000, 1_000 + 2_000_000_000, 1_000_000_490, 7_000));
+        assert_eq!(r.now(), Some((1_000_000_500, Some(1_000_007_500))));
+        count.fetch_add(200, Ordering::Relaxed);
+        assert_eq!(r.monotonic_ns(), Some(1_000_000_590));
+        assert_eq!(page.generation(), 4);
+        page.publish(&TimeParams { source: Source::None, ..page.snapshot().unwrap() });
+        assert_eq!(r.monotonic_ns(), None);
+    }
+
+    #[test]
+    fn readers_never_see_torn_updates() {
+        // Every published set satisfies base_ns == 2 * base_count ==
+        // epoch_ns / 3 == mult; a torn read would break that.
+        let page = Arc::new(TimePage::new());
+        let stop = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let (page, stop) = (page.clone(), stop.clone());
+                std::thread::spawn(move || {
+                    let mut seen = 0;
+                    while !stop.load(Ordering::Relaxed) {
+                        if let Some(p) = page.snapshot() {
+                            assert_eq!((p.base_ns, p.epoch_ns, p.mult), (2 * p.base_count, 6 * p.base_count, p.base_ns));
+                            seen += 1;
+                        }
+                    }
+                    seen
+                })
+            })
+            .collect();
+        for i in 1..200_000u64 {
+            page.publish(&TimeParams { source: Source::Tsc, base_count: i, base_ns: 2 * i, mult: 2 * i, shift: SHIFT, epoch_ns: 6 * i });
+        }
+        stop.store(true, Ordering::Relaxed);
+        for r in readers {
+            r.join().unwrap();
+        }
+    }
+}
@@ -26,22 +26,29 @@ OVMF_VARS_SRC="${OVMF_VARS:-$(find_first CANDIDATES_VARS || true)}"
 [[ -z "${OVMF_CODE}" || -z "${OVMF_VARS_SRC}" ]] && { echo "ERROR: OVMF not found"; exit 1; }
 
 BUILD_DIR="build"
-ESP_IMG="${BUILD_DIR}/esp.img"
+ESP_IMG="${ESP_IMG:-${BUILD_DIR}/esp.img}"
 OVMF_VARS="${BUILD_DIR}/OVMF_VARS.fd"
 [[ -f "${ESP_IMG}" ]] || { echo "ERROR: ${ESP_IMG} not found. Run: make esp"; exit 1; }
 
-cp -f "${OVMF_VARS_SRC}" "${OVMF_VARS}"
+# The variable store persists across runs so the A/B slot state does;
+# RESET_VARS=1 starts over from the pristine copy.
+if [[ ! -f "${OVMF_VARS}" || "${RESET_VARS:-0}" == 1 ]]; then
+  cp -f "${OVMF_VARS_SRC}" "${OVMF_VARS}"
+fi
 
+# `-cpu host` needs KVM; under TCG emulate everything QEMU can.
 ACCEL="tcg"
-[[ -w /dev/kvm ]] && ACCEL="kvm:tcg"
+CPU="max"
+[[ -w /dev/kvm ]] && { ACCEL="kvm:tcg"; CPU="host"; }
 
 exec qemu-system-x86_64 \
   -machine q35,accel=${ACCEL} \
-  -cpu host \
+  -cpu ${CPU} \
   -m 1024 \
   -serial stdio \
   -drive if=pflash,format=raw,readonly=on,file="${OVMF_CODE}" \
   -drive if=pflash,format=raw,file="${OVMF_VARS}" \
   -drive format=raw,file="${ESP_IMG}",if=virtio \
   -name "THATTE UEFI Hello" \
-  -no-reboot
+  -no-reboot \
+  "$@"
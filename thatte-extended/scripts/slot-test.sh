@@ -0,0 +1,69 @@
+#!/usr/bin/env bash
+# A/B rollback in QEMU. Slot A, the default, gets a kernel that does not
+# load: each boot spends one of its tries and falls back to slot B, whose
+# kernel marks B successful on the first boot. Once A has no tries left the
+# loader goes straight to B, and B's success is still recorded on every
+# later boot. The serial log of each boot is checked against that sequence;
+# any difference fails the test.
+set -euo pipefail
+
+BUILD_DIR="build"
+ESP_SRC="${BUILD_DIR}/esp.img"
+ESP_IMG="${BUILD_DIR}/slot-test.img"
+LOG_DIR="${BUILD_DIR}/slot-test"
+BOOTS="${BOOTS:-5}"
+[[ -f "${ESP_SRC}" ]] || { echo "ERROR: ${ESP_SRC} not found. Run: make esp"; exit 1; }
+(( BOOTS >= 4 )) || { echo "ERROR: BOOTS must be at least 4 to see slot A run out of tries"; exit 1; }
+
+cp -f "${ESP_SRC}" "${ESP_IMG}"
+echo "not an ELF" > "${BUILD_DIR}/broken.elf"
+mcopy -o -i "${ESP_IMG}" "${BUILD_DIR}/broken.elf" ::/EFI/THATTE/SLOT_A/KERNEL.ELF
+mkdir -p "${LOG_DIR}"
+
+# What boot $1 must print, one glob pattern per line. Slot A starts with 3
+# tries and loses one per boot; B loses one on its first boot only.
+expected() {
+  local boot=$1
+  if (( boot <= 3 )); then
+    echo "slot a: priority 15, $((3 - boot)) tries left, not yet booted"
+    echo "slot a: cannot start *"
+  fi
+  if (( boot == 1 )); then
+    echo "slot b: priority 15, 2 tries left, not yet booted"
+    echo "slot b: marked successful"
+  else
+    echo "slot b: priority 15, 2 tries left, booted before"
+    echo "slot b: already marked successful"
+  fi
+}
+
+failed=0
+for i in $(seq 1 "${BOOTS}"); do
+  log="${LOG_DIR}/boot-${i}.log"
+  # The kernel idles once it is up, so each boot ends with the timeout.
+  RESET_VARS=$([[ ${i} == 1 ]] && echo 1 || echo 0) ESP_IMG="${ESP_IMG}" \
+    timeout "${BOOT_TIMEOUT:-30}" bash scripts/run-qemu.sh -display none </dev/null >"${log}" 2>&1 || true
+  mapfile -t got < <(tr -d '\r' <"${log}" | sed 's/\x1b\[[0-9;]*[A-Za-z]//g' | grep -a -o 'slot [ab]:.*' | sed 's/[[:space:]]*$//')
+  mapfile -t want < <(expected "${i}")
+
+  ok=1
+  (( ${#got[@]} == ${#want[@]} )) || ok=0
+  for j in "${!want[@]}"; do
+    # shellcheck disable=SC2053 # the expected lines are glob patterns
+    [[ ${got[j]:-} == ${want[j]} ]] || ok=0
+  done
+  if (( ok )); then
+    echo "boot ${i}: ok"
+  else
+    failed=1
+    echo "boot ${i}: FAILED (log: ${log})"
+    printf '  expected: %s\n' "${want[@]}"
+    printf '  got:      %s\n' "${got[@]:-<nothing>}"
+  fi
+done
+
+if (( failed )); then
+  echo "slot-test: FAILED"
+  exit 1
+fi
+echo "slot-test: rollback from slot A to slot B verified over ${BOOTS} boots"
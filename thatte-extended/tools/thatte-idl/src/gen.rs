@@ -0,0 +1,271 @@
+//! Rust code generation for `thatte-mk`.
+//!
+//! The output is meant to be `include!`d into `thatte_mk::msg`, which
+//! provides the `Body`, `Wire` and `Transport` traits and the framing
+//! helpers the generated code calls.
+
+use std::fmt::Write;
+
+use crate::{Field, Method, Schema, Type, CAP_ENTRY_LEN};
+
+fn snake(name: &str) -> String {
+    let mut s = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 { s.push('_'); }
+            s.push(c.to_ascii_lowercase());
+        } else {
+            s.push(c);
+        }
+    }
+    s
+}
+
+fn rust_type(t: Type) -> &'static str {
+    match t {
+        Type::U8 => "u8",
+        Type::U16 => "u16",
+        Type::U32 => "u32",
+        Type::U64 => "u64",
+        Type::I64 => "i64",
+        Type::Bool => "bool",
+        Type::Cap => "CapTransfer",
+    }
+}
+
+fn doc(out: &mut String, indent: &str, lines: &[String]) {
+    for l in lines {
+        let _ = writeln!(out, "{}/// {}", indent, l);
+    }
+}
+
+/// Bytes a section's fields occupy in the body (attached caps excluded).
+fn body_len(fields: &[Field]) -> usize {
+    fields.iter().filter(|f| f.ty != Type::Cap).map(|f| f.ty.size()).sum()
+}
+
+fn cap_count(fields: &[Field]) -> usize { fields.iter().filter(|f| f.ty == Type::Cap).count() }
+
+fn body(out: &mut String, name: &str, msg: &str, docs: &[String], fields: &[Field]) {
+    doc(out, "", docs);
+    out.push_str("#[derive(Copy, Clone, Eq, PartialEq, Debug)]\n");
+    if fields.is_empty() {
+        let _ = writeln!(out, "pub struct {};\n", name);
+    } else {
+        let _ = writeln!(out, "pub struct {} {{", name);
+        for f in fields {
+            doc(out, "    ", &f.doc);
+            let _ = writeln!(out, "    pub {}: {},", f.name, rust_type(f.ty));
+        }
+        out.push_str("}\n\n");
+    }
+
+    let (len, ncaps) = (body_len(fields), cap_count(fields));
+    let _ = writeln!(out, "impl Body for {} {{", name);
+    let _ = writeln!(out, "    const TY: MsgType = MsgType::{};", msg);
+    let _ = writeln!(out, "    const LEN: usize = {};", len);
+    let _ = writeln!(out, "    const CAPS: usize = {};", ncaps);
+    let (o, c) = (if len == 0 { "_out" } else { "out" }, if ncaps == 0 { "_caps" } else { "caps" });
+    if fields.is_empty() {
+        let _ = writeln!(out, "    fn encode(&self, {}: &mut [u8], {}: &mut [CapTransfer]) {{}}", o, c);
+    } else {
+        let _ = writeln!(out, "    fn encode(&self, {}: &mut [u8], {}: &mut [CapTransfer]) {{", o, c);
+        let (mut at, mut ci) = (0, 0);
+        for f in fields {
+            if f.ty == Type::Cap {
+                let _ = writeln!(out, "        caps[{}] = self.{};", ci, f.name);
+                ci += 1;
+            } else {
+                let _ = writeln!(out, "        self.{}.put(out, {});", f.name, at);
+                at += f.ty.size();
+            }
+        }
+        out.push_str("    }\n");
+    }
+    let (b, c) = (if len == 0 { "_buf" } else { "buf" }, if ncaps == 0 { "_caps" } else { "caps" });
+    if fields.is_empty() {
+        let _ = writeln!(out, "    fn decode({}: &[u8], {}: &[CapTransfer]) -> Result<Self, ParseError> {{ Ok(Self) }}", b, c);
+    } else {
+        let _ = writeln!(out, "    fn decode({}: &[u8], {}: &[CapTransfer]) -> Result<Self, ParseError> {{", b, c);
+        out.push_str("        Ok(Self {\n");
+        let (mut at, mut ci) = (0, 0);
+        for f in fields {
+            if f.ty == Type::Cap {
+                let _ = writeln!(out, "            {}: nth_cap(caps, {})?,", f.name, ci);
+                ci += 1;
+            } else {
+                let _ = writeln!(out, "            {}: Wire::get(buf, {})?,", f.name, at);
+                at += f.ty.size();
+            }
+        }
+        out.push_str("        })\n    }\n");
+    }
+    out.push_str("}\n\n");
+}
+
+fn rights_expr(m: &Method) -> String {
+    if m.requires.is_empty() { return "Rights::NONE".into(); }
+    let parts: Vec<String> = m.requires.iter().map(|r| format!("Rights::{}", r.to_uppercase())).collect();
+    parts.join(".union(") + &")".repeat(parts.len() - 1)
+}
+
+fn msg_enum(out: &mut String, name: &str, suffix: &str, methods: &[&Method], reply: bool) {
+    let _ = writeln!(out, "/// A {} message of any type.", if reply { "reply" } else { "request" });
+    out.push_str("#[derive(Copy, Clone, Eq, PartialEq, Debug)]\n");
+    let _ = writeln!(out, "pub enum {} {{", name);
+    for m in methods {
+        let _ = writeln!(out, "    {}({}{}),", m.name, m.name, suffix);
+    }
+    out.push_str("}\n\n");
+
+    let _ = writeln!(out, "impl {} {{", name);
+    out.push_str("    pub fn ty(&self) -> MsgType {\n        match self {\n");
+    for m in methods {
+        let _ = writeln!(out, "            Self::{}(_) => MsgType::{},", m.name, m.name);
+    }
+    out.push_str("        }\n    }\n\n");
+
+    out.push_str("    /// Serialize as a full message; returns bytes written, or `None` if\n");
+    out.push_str("    /// `out` is too small or `flags` do not fit a typed message. `GRANT_CAPS`\n");
+    out.push_str("    /// is added when the body has caps.\n");
+    out.push_str("    pub fn encode(&self, src: Cap, dst: Cap, flags: MsgFlags, out: &mut [u8]) -> Option<usize> {\n");
+    let bit = if reply { " | REPLY_BIT" } else { "" };
+    let _ = writeln!(out, "        let ty = self.ty() as u16{};", bit);
+    out.push_str("        match self {\n");
+    for m in methods {
+        let _ = writeln!(out, "            Self::{}(b) => encode_msg(b, ty, src, dst, flags, out),", m.name);
+    }
+    out.push_str("        }\n    }\n\n");
+
+    let _ = writeln!(out, "    /// Parse a complete {} message.", if reply { "reply" } else { "request" });
+    out.push_str("    pub fn decode(buf: &[u8]) -> Result<(IpcHeader, Self), ParseError> {\n");
+    out.push_str("        let h = parse_header(buf)?;\n");
+    let cmp = if reply { "==" } else { "!=" };
+    let _ = writeln!(out, "        if h.ty & REPLY_BIT {} 0 {{ return Err(ParseError::WrongDirection(h.ty)); }}", cmp);
+    out.push_str("        let m = match MsgType::try_from(h.ty & !REPLY_BIT)? {\n");
+    for m in methods {
+        let _ = writeln!(out, "            MsgType::{} => Self::{}(body_of(&h, buf)?),", m.name, m.name);
+    }
+    out.push_str("        };\n        Ok((h, m))\n    }\n}\n\n");
+}
+
+/// Generate Rust source for `schema`.
+pub fn generate(schema: &Schema) -> String {
+    let mut out = String::new();
+    out.push_str("// @generated by thatte-idl. Do not edit.\n\n");
+    let methods: Vec<&Method> = schema.interfaces.iter().flat_map(|i| &i.methods).collect();
+
+    out.push_str("/// Message types, one per IDL method.\n");
+    out.push_str("#[repr(u16)]\n#[derive(Copy, Clone, Eq, PartialEq, Debug)]\npub enum MsgType {\n");
+    for m in &methods {
+        doc(&mut out, "    ", &m.doc);
+        let _ = writeln!(out, "    {} = {},", m.name, m.id);
+    }
+    out.push_str("}\n\nimpl TryFrom<u16> for MsgType {\n    type Error = ParseError;\n\n");
+    out.push_str("    fn try_from(v: u16) -> Result<Self, ParseError> {\n        match v {\n");
+    for m in &methods {
+        let _ = writeln!(out, "            {} => Ok(Self::{}),", m.id, m.name);
+    }
+    out.push_str("            _ => Err(ParseError::UnknownType(v)),\n        }\n    }\n}\n\nimpl MsgType {\n");
+    out.push_str("    /// Rights the destination capability must carry to deliver this message.\n");
+    out.push_str("    pub fn required_rights(self) -> Rights {\n        match self {\n");
+    for m in &methods {
+        let _ = writeln!(out, "            Self::{} => {},", m.name, rights_expr(m));
+    }
+    out.push_str("        }\n    }\n}\n\n");
+
+    let size = |f: &[Field]| body_len(f) + cap_count(f) * CAP_ENTRY_LEN;
+    let max = methods.iter().map(|m| size(&m.request).max(size(&m.reply))).max().unwrap_or(0);
+    out.push_str("/// Largest encoded message (header + caps + body) of any type.\n");
+    let _ = writeln!(out, "pub const MAX_MSG_LEN: usize = HEADER_LEN + {};\n", max);
+
+    for m in &methods {
+        body(&mut out, &m.name, &m.name, &m.doc, &m.request);
+        let rdoc = [format!("Reply to `{}`.", m.name)];
+        body(&mut out, &format!("{}Reply", m.name), &m.name, &rdoc, &m.reply);
+    }
+
+    msg_enum(&mut out, "Request", "", &methods, false);
+    msg_enum(&mut out, "Reply", "Reply", &methods, true);
+
+    for iface in &schema.interfaces {
+        let server = format!("{}Server", iface.name);
+        let client = format!("{}Client", iface.name);
+        let partial = iface.methods.len() < methods.len();
+
+        if !iface.doc.is_empty() {
+            doc(&mut out, "", &iface.doc);
+            out.push_str("///\n");
+        }
+        let _ = writeln!(out, "/// Server side of interface `{}`.", iface.name);
+        let _ = writeln!(out, "pub trait {} {{", server);
+        for m in &iface.methods {
+            doc(&mut out, "    ", &m.doc);
+            let _ = writeln!(out, "    fn {}(&mut self, req: {}) -> {}Reply;", snake(&m.name), m.name, m.name);
+        }
+        out.push_str("}\n\n");
+
+        let _ = writeln!(out, "/// Route a decoded request to the matching `{}` method; `None` if it", server);
+        out.push_str("/// belongs to another interface.\n");
+        let _ = writeln!(
+            out,
+            "pub fn dispatch_{}<S: {} + ?Sized>(server: &mut S, req: Request) -> Option<Reply> {{",
+            snake(&iface.name),
+            server
+        );
+        out.push_str("    match req {\n");
+        for m in &iface.methods {
+            let _ = writeln!(out, "        Request::{}(r) => Some(Reply::{}(server.{}(r))),", m.name, m.name, snake(&m.name));
+        }
+        if partial { out.push_str("        _ => None,\n"); }
+        out.push_str("    }\n}\n\n");
+
+        let _ = writeln!(out, "/// Client stub for interface `{}`.", iface.name);
+        let _ = writeln!(out, "pub struct {}<T: Transport> {{", client);
+        out.push_str("    pub transport: T,\n    pub src: Cap,\n    pub dst: Cap,\n}\n\n");
+        let _ = writeln!(out, "impl<T: Transport> {}<T> {{", client);
+        out.push_str("    pub fn new(transport: T, src: Cap, dst: Cap) -> Self { Self { transport, src, dst } }\n");
+        for m in &iface.methods {
+            out.push('\n');
+            doc(&mut out, "    ", &m.doc);
+            let _ = writeln!(
+                out,
+                "    pub fn {}(&mut self, req: {}) -> Option<{}Reply> {{",
+                snake(&m.name),
+                m.name,
+                m.name
+            );
+            let _ = writeln!(out, "        match call(&mut self.transport, self.src, self.dst, &Request::{}(req))? {{", m.name);
+            let _ = writeln!(out, "            Reply::{}(r) => Some(r),", m.name);
+            if methods.len() > 1 { out.push_str("            _ => None,\n"); }
+            out.push_str("        }\n    }\n");
+        }
+        out.push_str("}\n\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn generates_enum_and_stubs() {
+        let s = parse(
+            "interface Kernel { method GetTime = 2 requires(call, read) { request { c: cap } reply { ns: u64, ok: bool } } }",
+        )
+        .unwrap();
+        let code = generate(&s);
+        assert!(code.contains("    GetTime = 2,"));
+        assert!(code.contains("Self::GetTime => Rights::CALL.union(Rights::READ),"));
+        assert!(code.contains("    pub c: CapTransfer,"));
+        assert!(code.contains("            c: nth_cap(caps, 0)?,"));
+        assert!(code.contains("            2 => Ok(Self::GetTime),"));
+        assert!(code.contains("ok: Wire::get(buf, 8)?,"));
+        assert!(code.contains("fn get_time(&mut self, req: GetTime) -> GetTimeReply;"));
+        assert!(code.contains("pub const MAX_MSG_LEN: usize = HEADER_LEN + 9;"));
+        assert!(code.contains("const CAPS: usize = 1;"));
+        assert_eq!(snake("MapShared"), "map_shared");
+    }
+}
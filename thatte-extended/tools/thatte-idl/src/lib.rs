@@ -0,0 +1,113 @@
+//! THATTE interface definition language (IDL) compiler.
+//!
+//! A schema declares interfaces, their methods (with wire numbers and the
+//! rights a caller's capability must carry) and the request/reply payload
+//! fields. `generate` turns it into Rust source for `thatte-mk`: the
+//! `MsgType` enum, body structs and codecs, `Request`/`Reply` enums, and a
+//! client stub plus server dispatch trait per interface.
+//!
+//! ```text
+//! /// Core kernel services.
+//! interface Kernel {
+//!     /// Liveness probe.
+//!     method Ping = 1 requires(call) {
+//!         request { seq: u64 }
+//!         reply { seq: u64 }
+//!     }
+//! }
+//! ```
+//!
+//! Field types: `u8 u16 u32 u64 i64 bool cap`. `cap` fields are capabilities
+//! attached to the message rather than plain data: they are carried in the
+//! message's capability-transfer section (at most `MAX_CAPS` per section),
+//! not in the body.
+
+mod gen;
+mod parse;
+
+pub use gen::generate;
+pub use parse::{parse, ParseError};
+
+/// A whole IDL file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schema {
+    pub interfaces: Vec<Interface>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Interface {
+    pub name: String,
+    pub doc: Vec<String>,
+    pub methods: Vec<Method>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Method {
+    pub name: String,
+    pub doc: Vec<String>,
+    /// Wire number, used as the `MsgType` discriminant.
+    pub id: u16,
+    /// Right names from `requires(...)`, lowercase.
+    pub requires: Vec<String>,
+    pub request: Vec<Field>,
+    pub reply: Vec<Field>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    pub doc: Vec<String>,
+    pub ty: Type,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+    U8,
+    U16,
+    U32,
+    U64,
+    I64,
+    Bool,
+    Cap,
+}
+
+impl Type {
+    fn from_name(s: &str) -> Option<Self> {
+        Some(match s {
+            "u8" => Self::U8,
+            "u16" => Self::U16,
+            "u32" => Self::U32,
+            "u64" => Self::U64,
+            "i64" => Self::I64,
+            "bool" => Self::Bool,
+            "cap" => Self::Cap,
+            _ => return None,
+        })
+    }
+
+    /// Encoded size in bytes (for `cap`, the size of its transfer entry).
+    pub fn size(self) -> usize {
+        match self {
+            Self::U8 | Self::Bool => 1,
+            Self::U16 => 2,
+            Self::U32 => 4,
+            Self::U64 | Self::I64 => 8,
+            Self::Cap => CAP_ENTRY_LEN,
+        }
+    }
+}
+
+/// Most `cap` fields one request or reply section may declare; matches
+/// `thatte_mk::msg::MAX_CAPS`.
+pub const MAX_CAPS: usize = 4;
+
+/// Encoded size of one capability-transfer entry.
+pub const CAP_ENTRY_LEN: usize = 4;
+
+/// Rights a `requires(...)` clause may name.
+pub const RIGHTS: [&str; 6] = ["read", "write", "grant", "map", "call", "reply"];
+
+/// Parse and generate in one step.
+pub fn compile(src: &str) -> Result<String, ParseError> {
+    Ok(generate(&parse(src)?))
+}
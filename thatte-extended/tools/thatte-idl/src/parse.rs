@@ -0,0 +1,260 @@
+//! Tokenizer and recursive-descent parser for the IDL.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::{Field, Interface, Method, Schema, Type, MAX_CAPS, RIGHTS};
+
+/// A syntax or semantic error with the 1-based line it was found on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Tok {
+    Ident(String),
+    Int(u64),
+    Punct(char),
+    Doc(String),
+}
+
+fn lex(src: &str) -> Result<Vec<(usize, Tok)>, ParseError> {
+    let mut out = Vec::new();
+    for (i, line) in src.lines().enumerate() {
+        let line_no = i + 1;
+        let mut chars = line.char_indices().peekable();
+        while let Some(&(at, c)) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            } else if line[at..].starts_with("///") {
+                out.push((line_no, Tok::Doc(line[at + 3..].trim().to_string())));
+                break;
+            } else if line[at..].starts_with("//") {
+                break;
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                let mut s = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_') { break; }
+                    s.push(c);
+                    chars.next();
+                }
+                out.push((line_no, Tok::Ident(s)));
+            } else if c.is_ascii_digit() {
+                let mut s = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_') { break; }
+                    s.push(c);
+                    chars.next();
+                }
+                let s = s.replace('_', "");
+                let v = match s.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16),
+                    None => s.parse(),
+                };
+                let v = v.map_err(|_| ParseError { line: line_no, msg: format!("bad integer `{}`", s) })?;
+                out.push((line_no, Tok::Int(v)));
+            } else if "{}()=:,;".contains(c) {
+                out.push((line_no, Tok::Punct(c)));
+                chars.next();
+            } else {
+                return Err(ParseError { line: line_no, msg: format!("unexpected character `{}`", c) });
+            }
+        }
+    }
+    Ok(out)
+}
+
+struct Parser {
+    toks: Vec<(usize, Tok)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn line(&self) -> usize {
+        self.toks.get(self.pos).or(self.toks.last()).map_or(1, |t| t.0)
+    }
+
+    fn err<T>(&self, msg: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError { line: self.line(), msg: msg.into() })
+    }
+
+    fn peek(&self) -> Option<&Tok> { self.toks.get(self.pos).map(|t| &t.1) }
+
+    fn docs(&mut self) -> Vec<String> {
+        let mut d = Vec::new();
+        while let Some(Tok::Doc(s)) = self.peek() {
+            d.push(s.clone());
+            self.pos += 1;
+        }
+        d
+    }
+
+    fn punct(&mut self, c: char) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(Tok::Punct(p)) if *p == c => { self.pos += 1; Ok(()) }
+            _ => self.err(format!("expected `{}`", c)),
+        }
+    }
+
+    fn eat_punct(&mut self, c: char) -> bool {
+        let hit = matches!(self.peek(), Some(Tok::Punct(p)) if *p == c);
+        if hit { self.pos += 1; }
+        hit
+    }
+
+    fn ident(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some(Tok::Ident(s)) => { let s = s.clone(); self.pos += 1; Ok(s) }
+            _ => self.err("expected identifier"),
+        }
+    }
+
+    fn keyword(&mut self, kw: &str) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(Tok::Ident(s)) if s == kw => { self.pos += 1; Ok(()) }
+            _ => self.err(format!("expected `{}`", kw)),
+        }
+    }
+
+    fn int(&mut self) -> Result<u64, ParseError> {
+        match self.peek() {
+            Some(Tok::Int(v)) => { let v = *v; self.pos += 1; Ok(v) }
+            _ => self.err("expected integer"),
+        }
+    }
+
+    fn interface(&mut self) -> Result<Interface, ParseError> {
+        let doc = self.docs();
+        self.keyword("interface")?;
+        let name = self.ident()?;
+        self.punct('{')?;
+        let mut methods = Vec::new();
+        loop {
+            let doc = self.docs();
+            if self.eat_punct('}') { break; }
+            methods.push(self.method(doc)?);
+        }
+        Ok(Interface { name, doc, methods })
+    }
+
+    fn method(&mut self, doc: Vec<String>) -> Result<Method, ParseError> {
+        self.keyword("method")?;
+        let name = self.ident()?;
+        self.punct('=')?;
+        let id = self.int()?;
+        if id == 0 || id >= 0x8000 {
+            return self.err(format!("method id {} out of range 1..=0x7fff", id));
+        }
+        let mut requires = Vec::new();
+        if matches!(self.peek(), Some(Tok::Ident(s)) if s == "requires") {
+            self.pos += 1;
+            self.punct('(')?;
+            while !self.eat_punct(')') {
+                let r = self.ident()?;
+                if !RIGHTS.contains(&r.as_str()) { return self.err(format!("unknown right `{}`", r)); }
+                requires.push(r);
+                if !self.eat_punct(',') { self.punct(')')?; break; }
+            }
+        }
+        self.punct('{')?;
+        let (mut request, mut reply) = (None, None);
+        while !self.eat_punct('}') {
+            let which = self.ident()?;
+            let slot = match which.as_str() {
+                "request" => &mut request,
+                "reply" => &mut reply,
+                _ => return self.err(format!("expected `request` or `reply`, found `{}`", which)),
+            };
+            if slot.is_some() { return self.err(format!("duplicate `{}` section", which)); }
+            *slot = Some(self.fields()?);
+        }
+        Ok(Method {
+            name,
+            doc,
+            id: id as u16,
+            requires,
+            request: request.unwrap_or_default(),
+            reply: reply.unwrap_or_default(),
+        })
+    }
+
+    fn fields(&mut self) -> Result<Vec<Field>, ParseError> {
+        self.punct('{')?;
+        let mut fields: Vec<Field> = Vec::new();
+        loop {
+            let doc = self.docs();
+            if self.eat_punct('}') { break; }
+            let name = self.ident()?;
+            if fields.iter().any(|f| f.name == name) { return self.err(format!("duplicate field `{}`", name)); }
+            self.punct(':')?;
+            let tname = self.ident()?;
+            let Some(ty) = Type::from_name(&tname) else { return self.err(format!("unknown type `{}`", tname)) };
+            fields.push(Field { name, doc, ty });
+            if !self.eat_punct(',') { self.punct('}')?; break; }
+        }
+        if fields.iter().filter(|f| f.ty == Type::Cap).count() > MAX_CAPS {
+            return self.err(format!("more than {} cap fields in one section", MAX_CAPS));
+        }
+        Ok(fields)
+    }
+}
+
+/// Parse an IDL source file.
+pub fn parse(src: &str) -> Result<Schema, ParseError> {
+    let mut p = Parser { toks: lex(src)?, pos: 0 };
+    let mut interfaces = Vec::new();
+    let (mut names, mut ids) = (HashSet::new(), HashSet::new());
+    while p.pos < p.toks.len() {
+        let line = p.line();
+        let iface = p.interface()?;
+        for m in &iface.methods {
+            if !names.insert(m.name.clone()) {
+                return Err(ParseError { line, msg: format!("duplicate method `{}`", m.name) });
+            }
+            if !ids.insert(m.id) {
+                return Err(ParseError { line, msg: format!("duplicate method id {}", m.id) });
+            }
+        }
+        interfaces.push(iface);
+    }
+    Ok(Schema { interfaces })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_methods_and_fields() {
+        let s = parse(
+            "/// Doc.\ninterface K {\n  method Ping = 1 requires(call) {\n    request { seq: u64 }\n    reply { seq: u64, }\n  }\n  method Nop = 0x2 {}\n}\n",
+        )
+        .unwrap();
+        let k = &s.interfaces[0];
+        assert_eq!(k.doc, ["Doc."]);
+        assert_eq!(k.methods[0].requires, ["call"]);
+        assert_eq!(k.methods[0].reply[0], Field { name: "seq".into(), doc: vec![], ty: Type::U64 });
+        assert_eq!((k.methods[1].id, k.methods[1].request.len()), (2, 0));
+    }
+
+    #[test]
+    fn reports_errors_with_lines() {
+        let e = parse("interface K {\n method A = 1 { request { x: f32 } }\n}").unwrap_err();
+        assert_eq!((e.line, e.msg.as_str()), (2, "unknown type `f32`"));
+        let e = parse("interface K { method A = 1 {} }\ninterface L { method B = 1 {} }").unwrap_err();
+        assert_eq!(e.msg, "duplicate method id 1");
+        assert!(parse("interface K { method A = 1 requires(root) {} }").is_err());
+        assert!(parse("interface K { method A = 32768 {} }").is_err());
+        assert!(parse("interface K { method A = 1 { request { a: cap, b: cap, c: cap, d: cap, e: cap } } }").is_err());
+    }
+}